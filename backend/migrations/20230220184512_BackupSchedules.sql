@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS backup_schedules (
+    id TEXT NOT NULL PRIMARY KEY,
+    key TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    wrapped_key TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS s3_targets (
+    id SERIAL PRIMARY KEY,
+    endpoint TEXT NOT NULL,
+    region TEXT NOT NULL,
+    bucket TEXT NOT NULL,
+    prefix TEXT NOT NULL,
+    access_key_id TEXT NOT NULL,
+    secret_access_key TEXT NOT NULL
+);
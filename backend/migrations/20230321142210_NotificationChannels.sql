@@ -0,0 +1,5 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS notification_channels (
+    name TEXT PRIMARY KEY,
+    config TEXT NOT NULL
+);
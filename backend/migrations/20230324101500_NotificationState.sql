@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE notifications ADD COLUMN IF NOT EXISTS read_at TIMESTAMP;
+ALTER TABLE notifications ADD COLUMN IF NOT EXISTS acked_at TIMESTAMP;
+ALTER TABLE notifications ADD COLUMN IF NOT EXISTS dismissed_at TIMESTAMP;
+ALTER TABLE notifications ADD COLUMN IF NOT EXISTS action_package_id TEXT;
+ALTER TABLE notifications ADD COLUMN IF NOT EXISTS action_id TEXT;
+UPDATE notifications SET read_at = created_at WHERE read_at IS NULL;
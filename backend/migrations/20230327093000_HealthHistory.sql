@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS health_history (
+    id SERIAL PRIMARY KEY,
+    package_id TEXT NOT NULL,
+    check_id TEXT NOT NULL,
+    at TIMESTAMP NOT NULL,
+    state TEXT NOT NULL,
+    message TEXT
+);
+CREATE INDEX IF NOT EXISTS health_history_package_id_at ON health_history (package_id, at);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS log_forwarders (
+    name TEXT PRIMARY KEY,
+    config TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS log_forward_cursors (
+    name TEXT PRIMARY KEY,
+    cursor TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS config_history (
+    package_id TEXT NOT NULL,
+    revision INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    version TEXT NOT NULL,
+    session TEXT,
+    config TEXT NOT NULL,
+    PRIMARY KEY (package_id, revision)
+);
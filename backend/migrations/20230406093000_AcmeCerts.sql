@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS acme_accounts (
+    directory TEXT PRIMARY KEY,
+    key TEXT NOT NULL,
+    url TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS acme_certs (
+    domain TEXT PRIMARY KEY,
+    config TEXT NOT NULL,
+    key TEXT NULL,
+    fullchain TEXT NULL,
+    expires_at TIMESTAMP NULL,
+    last_attempt TIMESTAMP NULL,
+    last_error TEXT NULL
+);
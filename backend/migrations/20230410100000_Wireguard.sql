@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS wireguard_server (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    private_key TEXT NOT NULL,
+    port INTEGER NOT NULL,
+    endpoint TEXT NULL,
+    enabled BOOLEAN NOT NULL
+);
+CREATE TABLE IF NOT EXISTS wireguard_peers (
+    name TEXT PRIMARY KEY,
+    ip TEXT NOT NULL UNIQUE,
+    public_key TEXT NOT NULL UNIQUE,
+    private_key TEXT NULL,
+    preshared_key TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
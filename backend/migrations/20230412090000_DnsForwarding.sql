@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS dns_upstreams (
+    id SERIAL PRIMARY KEY,
+    upstream TEXT NOT NULL UNIQUE,
+    tor BOOLEAN NOT NULL
+);
+CREATE TABLE IF NOT EXISTS dns_blocklists (
+    url TEXT PRIMARY KEY,
+    entries INTEGER NULL,
+    last_updated TIMESTAMP NULL,
+    last_error TEXT NULL
+);
@@ -389,6 +389,7 @@ pub async fn reset_password(
     }
     account.set_password(&new_password)?;
     account.save(&ctx.secret_store).await?;
+    crate::backup::schedule::rewrap_keys(&ctx.secret_store, &account.key, &new_password).await?;
     crate::db::DatabaseModel::new()
         .server_info()
         .password_hash()
@@ -1,16 +1,24 @@
 use std::collections::{BTreeMap, BTreeSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use chrono::Utc;
 use clap::ArgMatches;
 use color_eyre::eyre::eyre;
-use helpers::AtomicFile;
+use futures::StreamExt;
+use helpers::{AtomicFile, NonDetachingJoinHandle};
 use patch_db::{DbHandle, LockType, PatchDbHandle};
 use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
 use tokio::io::AsyncWriteExt;
 use tracing::instrument;
 
-use super::target::BackupTargetId;
+use super::live::VolumeSnapshot;
+use super::snapshot::{
+    load_latest_manifest, prune, save_snapshot, snapshot_id, snapshot_package, RetentionPolicy,
+    SnapshotInfo, SnapshotManifest, SnapshotTree,
+};
+use super::target::{BackupTargetId, PackageBackupInfo};
 use super::PackageBackupReport;
 use crate::auth::check_password_against_db;
 use crate::backup::os::OsBackup;
@@ -18,22 +26,42 @@ use crate::backup::{BackupReport, ServerBackupReport};
 use crate::context::RpcContext;
 use crate::db::model::BackupProgress;
 use crate::disk::mount::backup::BackupMountGuard;
-use crate::disk::mount::filesystem::ReadWrite;
 use crate::disk::mount::guard::TmpMountGuard;
 use crate::notifications::NotificationLevel;
 use crate::s9pk::manifest::PackageId;
 use crate::status::MainStatus;
 use crate::util::display_none;
+use crate::util::io::{dir_size, dir_size_changed_since, RateLimiter};
 use crate::util::serde::IoFormat;
 use crate::version::VersionT;
+use crate::volume::{data_dir, Volume, Volumes, BACKUP_DIR};
 use crate::{Error, ErrorKind, ResultExt};
 
-fn parse_comma_separated(arg: &str, _: &ArgMatches) -> Result<BTreeSet<PackageId>, Error> {
+pub(super) fn parse_comma_separated(
+    arg: &str,
+    _: &ArgMatches,
+) -> Result<BTreeSet<PackageId>, Error> {
     arg.split(',')
         .map(|s| s.trim().parse().map_err(Error::from))
         .collect()
 }
 
+/// Options controlling how a backup runs, which can be given to `backup create` or saved with a
+/// backup schedule
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupOptions {
+    /// Snapshots to keep on the target after the backup. Everything is kept if not set.
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+    /// Number of packages to back up at the same time. Defaults to 1.
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+    /// Cap on the combined rate at which backup data is written to the target, in MB/s
+    #[serde(default)]
+    pub max_throughput: Option<u64>,
+}
+
 #[command(rename = "create", display(display_none))]
 #[instrument(skip_all)]
 pub async fn backup_all(
@@ -48,27 +76,50 @@ pub async fn backup_all(
         parse(parse_comma_separated)
     )]
     package_ids: Option<BTreeSet<PackageId>>,
+    #[arg(long = "retention")] retention: Option<RetentionPolicy>,
+    #[arg(long = "concurrency")] concurrency: Option<usize>,
+    #[arg(rename = "max-throughput", long = "max-throughput")] max_throughput: Option<u64>,
     #[arg] password: crate::auth::PasswordType,
 ) -> Result<(), Error> {
     let mut db = ctx.db.handle();
     let old_password_decrypted = old_password
         .as_ref()
-        .unwrap_or(&password)
-        .clone()
-        .decrypt(&ctx)?;
+        .map(|p| p.clone().decrypt(&ctx))
+        .transpose()?;
     let password = password.decrypt(&ctx)?;
     check_password_against_db(&mut ctx.secret_store.acquire().await?, &password).await?;
     let fs = target_id
         .load(&mut ctx.secret_store.acquire().await?)
         .await?;
-    let mut backup_guard = BackupMountGuard::mount(
-        TmpMountGuard::mount(&fs, ReadWrite).await?,
-        &old_password_decrypted,
-    )
-    .await?;
+    let mut backup_guard = fs
+        .mount_backup(old_password_decrypted.as_deref().unwrap_or(&password))
+        .await?;
+    if old_password.is_some() {
+        backup_guard.change_password(&password)?;
+    }
+    let (backup_guard, package_ids) = start_backup(&mut db, backup_guard, package_ids).await?;
+    let options = BackupOptions {
+        retention,
+        concurrency,
+        max_throughput,
+    };
+    tokio::task::spawn(async move {
+        backup_and_report(&ctx, &mut db, backup_guard, &package_ids, &options).await
+    });
+    Ok(())
+}
+
+/// Marks the selected packages as backing up on a target that has already been unlocked. If
+/// `package_ids` is `None`, every installed package is selected.
+#[instrument(skip_all)]
+pub(super) async fn start_backup(
+    db: &mut PatchDbHandle,
+    backup_guard: BackupMountGuard<TmpMountGuard>,
+    package_ids: Option<BTreeSet<PackageId>>,
+) -> Result<(BackupMountGuard<TmpMountGuard>, BTreeSet<PackageId>), Error> {
     let all_packages = crate::db::DatabaseModel::new()
         .package_data()
-        .get(&mut db)
+        .get(db)
         .await?
         .0
         .keys()
@@ -76,89 +127,96 @@ pub async fn backup_all(
         .cloned()
         .collect();
     let package_ids = package_ids.unwrap_or(all_packages);
-    if old_password.is_some() {
-        backup_guard.change_password(&password)?;
-    }
-    assure_backing_up(&mut db, &package_ids).await?;
-    tokio::task::spawn(async move {
-        let backup_res = perform_backup(&ctx, &mut db, backup_guard, &package_ids).await;
-        let backup_progress = crate::db::DatabaseModel::new()
-            .server_info()
-            .status_info()
-            .backup_progress();
-        backup_progress
-            .clone()
-            .lock(&mut db, LockType::Write)
+    assure_backing_up(db, &package_ids).await?;
+    Ok((backup_guard, package_ids))
+}
+
+/// Runs a backup that was prepared by [`start_backup`] to completion, and reports the result
+/// through the notification manager.
+#[instrument(skip_all)]
+pub(super) async fn backup_and_report(
+    ctx: &RpcContext,
+    db: &mut PatchDbHandle,
+    backup_guard: BackupMountGuard<TmpMountGuard>,
+    package_ids: &BTreeSet<PackageId>,
+    options: &BackupOptions,
+) {
+    let backup_res = perform_backup(ctx, &mut *db, backup_guard, package_ids, options).await;
+    let backup_progress = crate::db::DatabaseModel::new()
+        .server_info()
+        .status_info()
+        .backup_progress();
+    backup_progress
+        .clone()
+        .lock(db, LockType::Write)
+        .await
+        .expect("failed to lock server status");
+    match backup_res {
+        Ok(report) if report.iter().all(|(_, rep)| rep.error.is_none()) => ctx
+            .notification_manager
+            .notify(
+                db,
+                None,
+                NotificationLevel::Success,
+                "Backup Complete".to_owned(),
+                "Your backup has completed".to_owned(),
+                BackupReport {
+                    server: ServerBackupReport {
+                        attempted: true,
+                        error: None,
+                    },
+                    packages: report,
+                },
+                None,
+            )
             .await
-            .expect("failed to lock server status");
-        match backup_res {
-            Ok(report) if report.iter().all(|(_, rep)| rep.error.is_none()) => ctx
-                .notification_manager
-                .notify(
-                    &mut db,
-                    None,
-                    NotificationLevel::Success,
-                    "Backup Complete".to_owned(),
-                    "Your backup has completed".to_owned(),
-                    BackupReport {
-                        server: ServerBackupReport {
-                            attempted: true,
-                            error: None,
-                        },
-                        packages: report,
+            .expect("failed to send notification"),
+        Ok(report) => ctx
+            .notification_manager
+            .notify(
+                db,
+                None,
+                NotificationLevel::Warning,
+                "Backup Complete".to_owned(),
+                "Your backup has completed, but some package(s) failed to backup".to_owned(),
+                BackupReport {
+                    server: ServerBackupReport {
+                        attempted: true,
+                        error: None,
                     },
-                    None,
-                )
-                .await
-                .expect("failed to send notification"),
-            Ok(report) => ctx
-                .notification_manager
+                    packages: report,
+                },
+                None,
+            )
+            .await
+            .expect("failed to send notification"),
+        Err(e) => {
+            tracing::error!("Backup Failed: {}", e);
+            tracing::debug!("{:?}", e);
+            ctx.notification_manager
                 .notify(
-                    &mut db,
+                    db,
                     None,
-                    NotificationLevel::Warning,
-                    "Backup Complete".to_owned(),
-                    "Your backup has completed, but some package(s) failed to backup".to_owned(),
+                    NotificationLevel::Error,
+                    "Backup Failed".to_owned(),
+                    "Your backup failed to complete.".to_owned(),
                     BackupReport {
                         server: ServerBackupReport {
                             attempted: true,
-                            error: None,
+                            error: Some(e.to_string()),
                         },
-                        packages: report,
+                        packages: BTreeMap::new(),
                     },
                     None,
                 )
                 .await
-                .expect("failed to send notification"),
-            Err(e) => {
-                tracing::error!("Backup Failed: {}", e);
-                tracing::debug!("{:?}", e);
-                ctx.notification_manager
-                    .notify(
-                        &mut db,
-                        None,
-                        NotificationLevel::Error,
-                        "Backup Failed".to_owned(),
-                        "Your backup failed to complete.".to_owned(),
-                        BackupReport {
-                            server: ServerBackupReport {
-                                attempted: true,
-                                error: Some(e.to_string()),
-                            },
-                            packages: BTreeMap::new(),
-                        },
-                        None,
-                    )
-                    .await
-                    .expect("failed to send notification");
-            }
+                .expect("failed to send notification");
         }
-        backup_progress
-            .delete(&mut db)
-            .await
-            .expect("failed to change server status");
-    });
-    Ok(())
+    }
+    backup_progress
+        .delete(db)
+        .await
+        .expect("failed to change server status");
 }
 
 #[instrument(skip_all)]
@@ -192,7 +250,7 @@ async fn assure_backing_up(
     *backing_up = Some(
         packages
             .into_iter()
-            .map(|x| (x.clone(), BackupProgress { complete: false }))
+            .map(|x| (x.clone(), BackupProgress::default()))
             .collect(),
     );
     backing_up.save(&mut tx).await?;
@@ -206,133 +264,73 @@ async fn perform_backup<Db: DbHandle>(
     mut db: Db,
     mut backup_guard: BackupMountGuard<TmpMountGuard>,
     package_ids: &BTreeSet<PackageId>,
+    options: &BackupOptions,
 ) -> Result<BTreeMap<PackageId, PackageBackupReport>, Error> {
-    let mut backup_report = BTreeMap::new();
-    for package_id in crate::db::DatabaseModel::new()
+    let root = backup_guard.as_ref().to_owned();
+    let previous = load_latest_manifest(&root, &backup_guard.metadata)
+        .await?
+        .unwrap_or_default();
+    let limiter = options
+        .max_throughput
+        .map(|mb_per_sec| RateLimiter::new(mb_per_sec * 1_000_000));
+    let concurrency = options.concurrency.unwrap_or(1).max(1);
+    // backup procedures are capped per container, so each gets an even share of the cap
+    let disk_read_mbps = options
+        .max_throughput
+        .map(|mb_per_sec| (mb_per_sec / concurrency as u64).max(1));
+    let package_ids: Vec<PackageId> = crate::db::DatabaseModel::new()
         .package_data()
         .keys(&mut db)
         .await?
         .into_iter()
         .filter(|id| package_ids.contains(id))
-    {
-        let mut tx = db.begin().await?; // for lock scope
-        let installed_model = if let Some(installed_model) = crate::db::DatabaseModel::new()
-            .package_data()
-            .idx_model(&package_id)
-            .and_then(|m| m.installed())
-            .check(&mut tx)
-            .await?
-        {
-            installed_model
+        .collect();
+    let results = futures::stream::iter(package_ids)
+        .map(|package_id| {
+            let backup_guard = &backup_guard;
+            let previous = &previous;
+            let limiter = limiter.as_ref();
+            async move {
+                let res = backup_package(
+                    ctx,
+                    &mut ctx.db.handle(),
+                    backup_guard,
+                    &package_id,
+                    previous.packages.get(&package_id),
+                    limiter,
+                    disk_read_mbps,
+                )
+                .await;
+                (package_id, res)
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut snapshot = SnapshotManifest::default();
+    let mut snapshot_packages = BTreeMap::new();
+    let mut backup_report = BTreeMap::new();
+    for (package_id, res) in results {
+        let res = if let Some(res) = res? {
+            res
         } else {
             continue;
         };
-        let main_status_model = installed_model.clone().status().main();
-
-        main_status_model.lock(&mut tx, LockType::Write).await?;
-        let (started, health) = match main_status_model.get(&mut tx).await?.into_owned() {
-            MainStatus::Starting { .. } => (Some(Utc::now()), Default::default()),
-            MainStatus::Running { started, health } => (Some(started), health.clone()),
-            MainStatus::Stopped | MainStatus::Stopping | MainStatus::Restarting => {
-                (None, Default::default())
-            }
-            MainStatus::BackingUp { .. } => {
-                backup_report.insert(
-                    package_id,
-                    PackageBackupReport {
-                        error: Some(
-                            "Can't do backup because service is in a backing up state".to_owned(),
-                        ),
-                    },
-                );
-                continue;
-            }
-        };
-        main_status_model
-            .put(
-                &mut tx,
-                &MainStatus::BackingUp {
-                    started,
-                    health: health.clone(),
-                },
-            )
-            .await?;
-        tx.save().await?; // drop locks
-
-        let manifest = installed_model.clone().manifest().get(&mut db).await?;
-
-        ctx.managers
-            .get(&(manifest.id.clone(), manifest.version.clone()))
-            .await
-            .ok_or_else(|| {
-                Error::new(eyre!("Manager not found"), crate::ErrorKind::InvalidRequest)
-            })?
-            .synchronize()
-            .await;
-
-        let mut tx = db.begin().await?;
-
-        installed_model.lock(&mut tx, LockType::Write).await?;
-
-        let guard = backup_guard.mount_package_backup(&package_id).await?;
-        let res = manifest
-            .backup
-            .create(
-                ctx,
-                &mut tx,
-                &package_id,
-                &manifest.title,
-                &manifest.version,
-                &manifest.interfaces,
-                &manifest.volumes,
-            )
-            .await;
-        guard.unmount().await?;
         backup_report.insert(
             package_id.clone(),
             PackageBackupReport {
                 error: res.as_ref().err().map(|e| e.to_string()),
             },
         );
-
-        if let Ok(pkg_meta) = res {
-            installed_model
-                .last_backup()
-                .put(&mut tx, &Some(pkg_meta.timestamp))
-                .await?;
+        if let Ok((pkg_meta, tree)) = res {
+            snapshot.packages.insert(package_id.clone(), tree);
+            snapshot_packages.insert(package_id.clone(), pkg_meta.clone());
             backup_guard
                 .metadata
                 .package_backups
-                .insert(package_id.clone(), pkg_meta);
+                .insert(package_id, pkg_meta);
         }
-
-        main_status_model
-            .put(
-                &mut tx,
-                &match started {
-                    Some(started) => MainStatus::Running { started, health },
-                    None => MainStatus::Stopped,
-                },
-            )
-            .await?;
-
-        let mut backup_progress = crate::db::DatabaseModel::new()
-            .server_info()
-            .status_info()
-            .backup_progress()
-            .get_mut(&mut tx)
-            .await?;
-        if backup_progress.is_none() {
-            *backup_progress = Some(Default::default());
-        }
-        if let Some(mut backup_progress) = backup_progress
-            .as_mut()
-            .and_then(|bp| bp.get_mut(&package_id))
-        {
-            (*backup_progress).complete = true;
-        }
-        backup_progress.save(&mut tx).await?;
-        tx.save().await?;
     }
 
     let ui = crate::db::DatabaseModel::new()
@@ -358,7 +356,42 @@ async fn perform_backup<Db: DbHandle>(
         .await
         .with_kind(ErrorKind::Filesystem)?;
 
-    let timestamp = Some(Utc::now());
+    let timestamp = Utc::now();
+
+    // packages that were not backed up this time keep the contents of their previous snapshot
+    let previous_packages = backup_guard
+        .metadata
+        .snapshots
+        .values()
+        .next_back()
+        .map(|info| info.package_backups.clone())
+        .unwrap_or_default();
+    for (package_id, tree) in previous.packages {
+        if snapshot.packages.contains_key(&package_id) {
+            continue;
+        }
+        if let Some(pkg_meta) = previous_packages.get(&package_id) {
+            snapshot_packages.insert(package_id.clone(), pkg_meta.clone());
+        }
+        snapshot.packages.insert(package_id, tree);
+    }
+    save_snapshot(
+        &root,
+        &mut backup_guard.metadata,
+        snapshot_id(&timestamp),
+        SnapshotInfo {
+            timestamp,
+            os_version: crate::version::Current::new().semver().into(),
+            package_backups: snapshot_packages,
+        },
+        &snapshot,
+    )
+    .await?;
+    if let Some(retention) = &options.retention {
+        prune(&root, &mut backup_guard.metadata, retention).await?;
+    }
+
+    let timestamp = Some(timestamp);
 
     backup_guard.unencrypted_metadata.version = crate::version::Current::new().semver().into();
     backup_guard.unencrypted_metadata.full = true;
@@ -374,3 +407,224 @@ async fn perform_backup<Db: DbHandle>(
         .await?;
     Ok(backup_report)
 }
+
+/// Backs up a single package into its directory on the target, and records it in the object
+/// store. Returns `None` if the package is not installed. Errors specific to the package are
+/// returned in the inner result, so they can be reported without failing the whole backup.
+#[instrument(skip_all)]
+async fn backup_package(
+    ctx: &RpcContext,
+    db: &mut PatchDbHandle,
+    backup_guard: &BackupMountGuard<TmpMountGuard>,
+    package_id: &PackageId,
+    previous: Option<&SnapshotTree>,
+    limiter: Option<&RateLimiter>,
+    disk_read_mbps: Option<u64>,
+) -> Result<Option<Result<(PackageBackupInfo, SnapshotTree), Error>>, Error> {
+    let installed_model = if let Some(installed_model) = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(package_id)
+        .and_then(|m| m.installed())
+        .check(db)
+        .await?
+    {
+        installed_model
+    } else {
+        return Ok(None);
+    };
+    let manifest = installed_model
+        .clone()
+        .manifest()
+        .get(db)
+        .await?
+        .into_owned();
+    let main_status_model = installed_model.clone().status().main();
+
+    // a service that is not running can be backed up directly, even if it supports live backups
+    let volume_snapshot =
+        if manifest.backup.live && main_status_model.clone().get(db).await?.running() {
+            match VolumeSnapshot::prepare(&ctx.datadir, package_id, &manifest.volumes).await {
+                Ok(volume_snapshot) => Some(volume_snapshot),
+                Err(e) => return Ok(Some(Err(e))),
+            }
+        } else {
+            None
+        };
+
+    let mut tx = db.begin().await?; // for lock scope
+    main_status_model.lock(&mut tx, LockType::Write).await?;
+    let (started, health) = match main_status_model.get(&mut tx).await?.into_owned() {
+        MainStatus::Starting { .. } => (Some(Utc::now()), Default::default()),
+        MainStatus::Running { started, health } => (Some(started), health.clone()),
+        MainStatus::Stopped | MainStatus::Stopping | MainStatus::Restarting => {
+            (None, Default::default())
+        }
+        MainStatus::BackingUp { .. } => {
+            return Ok(Some(Err(Error::new(
+                eyre!("Can't do backup because service is in a backing up state"),
+                crate::ErrorKind::InvalidRequest,
+            ))));
+        }
+    };
+    main_status_model
+        .put(
+            &mut tx,
+            &MainStatus::BackingUp {
+                started,
+                health: health.clone(),
+            },
+        )
+        .await?;
+    tx.save().await?; // drop locks
+    let resumed_status = match started {
+        Some(started) => MainStatus::Running { started, health },
+        None => MainStatus::Stopped,
+    };
+
+    let manager = ctx
+        .managers
+        .get(&(manifest.id.clone(), manifest.version.clone()))
+        .await
+        .ok_or_else(|| Error::new(eyre!("Manager not found"), crate::ErrorKind::InvalidRequest))?;
+    manager.synchronize().await;
+
+    let live = volume_snapshot.is_some();
+    let volumes = if let Some(volume_snapshot) = &volume_snapshot {
+        let res = volume_snapshot.finish().await;
+        main_status_model.put(db, &resumed_status).await?;
+        manager.synchronize().await;
+        if let Err(e) = res {
+            return Ok(Some(Err(e)));
+        }
+        volume_snapshot.volumes()
+    } else {
+        &manifest.volumes
+    };
+
+    let mut tx = db.begin().await?;
+
+    installed_model.lock(&mut tx, LockType::Write).await?;
+
+    let guard = backup_guard.mount_package_backup(package_id).await?;
+    let progress = NonDetachingJoinHandle::from(tokio::spawn(track_backup_progress(
+        ctx.clone(),
+        package_id.clone(),
+        manifest.volumes.clone(),
+    )));
+    let res = manifest
+        .backup
+        .create(
+            ctx,
+            &mut tx,
+            package_id,
+            &manifest.title,
+            &manifest.version,
+            &manifest.interfaces,
+            volumes,
+            limiter,
+            disk_read_mbps,
+        )
+        .await;
+    drop(progress);
+    guard.unmount().await?;
+    if let Some(volume_snapshot) = volume_snapshot {
+        volume_snapshot.delete().await?;
+    }
+    let res = match res {
+        Ok(pkg_meta) => snapshot_package(backup_guard.as_ref(), package_id, previous, limiter)
+            .await
+            .map(|tree| (pkg_meta, tree)),
+        Err(e) => Err(e),
+    };
+
+    if let Ok((pkg_meta, _)) = &res {
+        installed_model
+            .last_backup()
+            .put(&mut tx, &Some(pkg_meta.timestamp))
+            .await?;
+    }
+
+    if !live {
+        main_status_model.put(&mut tx, &resumed_status).await?;
+    }
+
+    let mut backup_progress = crate::db::DatabaseModel::new()
+        .server_info()
+        .status_info()
+        .backup_progress()
+        .get_mut(&mut tx)
+        .await?;
+    if backup_progress.is_none() {
+        *backup_progress = Some(Default::default());
+    }
+    if let Some(mut backup_progress) = backup_progress
+        .as_mut()
+        .and_then(|bp| bp.get_mut(package_id))
+    {
+        (*backup_progress).complete = true;
+        (*backup_progress).eta = None;
+    }
+    backup_progress.save(&mut tx).await?;
+    tx.save().await?;
+
+    Ok(Some(res))
+}
+
+/// Periodically records how many bytes have been written to the backup directory of a package
+/// since the backup started, and an estimate of when it will be done, until the task is aborted.
+/// The total is estimated from the size of the package's data volumes.
+async fn track_backup_progress(ctx: RpcContext, package_id: PackageId, volumes: Volumes) {
+    let since = Utc::now().timestamp();
+    let mut total_bytes = 0;
+    for (volume_id, volume) in volumes.iter() {
+        if let Volume::Data { .. } = volume {
+            total_bytes += dir_size(data_dir(&ctx.datadir, &package_id, volume_id))
+                .await
+                .unwrap_or_default();
+        }
+    }
+    let dir = Path::new(BACKUP_DIR).join(&package_id);
+    let start = Instant::now();
+    let mut db = ctx.db.handle();
+    loop {
+        // the directory still holds the previous backup, which is only partly rewritten
+        let bytes = dir_size_changed_since(&dir, since)
+            .await
+            .unwrap_or_default();
+        let rate = bytes as f64 / start.elapsed().as_secs_f64();
+        let eta = if total_bytes > bytes && rate > 0.0 {
+            Some(
+                Utc::now()
+                    + chrono::Duration::seconds(((total_bytes - bytes) as f64 / rate) as i64),
+            )
+        } else {
+            None
+        };
+        if let Err(e) = async {
+            let mut tx = db.begin().await?;
+            let mut backup_progress = crate::db::DatabaseModel::new()
+                .server_info()
+                .status_info()
+                .backup_progress()
+                .get_mut(&mut tx)
+                .await?;
+            if let Some(mut backup_progress) = backup_progress
+                .as_mut()
+                .and_then(|bp| bp.get_mut(&package_id))
+            {
+                (*backup_progress).bytes = bytes;
+                (*backup_progress).total_bytes = Some(total_bytes);
+                (*backup_progress).eta = eta;
+            }
+            backup_progress.save(&mut tx).await?;
+            tx.commit().await?;
+            Ok::<_, Error>(())
+        }
+        .await
+        {
+            tracing::error!("Error updating backup progress for {}: {}", package_id, e);
+            tracing::debug!("{:?}", e);
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
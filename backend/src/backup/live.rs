@@ -0,0 +1,99 @@
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+use tracing::instrument;
+
+use crate::s9pk::manifest::PackageId;
+use crate::util::Invoke;
+use crate::volume::{data_dir, Volume, Volumes};
+use crate::Error;
+
+pub const PKG_BACKUP_SNAPSHOT_DIR: &str = "package-data/backup-snapshots";
+
+/// A point in time copy of the data volumes of a package, so that a package that opts into live
+/// backups can keep running while its backup procedure runs.
+///
+/// The copy is made in two passes: [`prepare`](Self::prepare) copies everything while the service
+/// is still running, and [`finish`](Self::finish) is called with the service paused so that only
+/// the changes made in the meantime have to be copied. Pointer volumes into other packages are
+/// not snapshotted.
+pub struct VolumeSnapshot {
+    path: Option<PathBuf>,
+    sources: Vec<(PathBuf, PathBuf)>,
+    volumes: Volumes,
+}
+impl VolumeSnapshot {
+    #[instrument(skip_all)]
+    pub async fn prepare(
+        datadir: impl AsRef<Path>,
+        pkg_id: &PackageId,
+        volumes: &Volumes,
+    ) -> Result<Self, Error> {
+        let path = datadir.as_ref().join(PKG_BACKUP_SNAPSHOT_DIR).join(pkg_id);
+        let mut sources = Vec::new();
+        let mut snapshot_volumes = volumes.to_readonly();
+        for (volume_id, volume) in volumes.iter() {
+            if let Volume::Data { .. } = volume {
+                let dst = path.join(volume_id);
+                sources.push((data_dir(datadir.as_ref(), pkg_id, volume_id), dst.clone()));
+                snapshot_volumes.insert(volume_id.clone(), Volume::Snapshot { path: dst });
+            }
+        }
+        let snapshot = VolumeSnapshot {
+            path: Some(path),
+            sources,
+            volumes: snapshot_volumes,
+        };
+        snapshot.sync().await?;
+        Ok(snapshot)
+    }
+
+    async fn sync(&self) -> Result<(), Error> {
+        for (src, dst) in &self.sources {
+            tokio::fs::create_dir_all(dst).await?;
+            if tokio::fs::metadata(src).await.is_err() {
+                continue;
+            }
+            Command::new("rsync")
+                .arg("-aAXH")
+                .arg("--delete")
+                .arg(format!("{}/", src.display()))
+                .arg(dst)
+                .invoke(crate::ErrorKind::Filesystem)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Brings the snapshot up to date. The service must not be writing to its volumes while this
+    /// runs.
+    #[instrument(skip_all)]
+    pub async fn finish(&self) -> Result<(), Error> {
+        self.sync().await
+    }
+
+    /// The volumes of the package, with its data volumes replaced by their snapshots
+    pub fn volumes(&self) -> &Volumes {
+        &self.volumes
+    }
+
+    #[instrument(skip_all)]
+    pub async fn delete(mut self) -> Result<(), Error> {
+        if let Some(path) = self.path.take() {
+            tokio::fs::remove_dir_all(&path).await?;
+        }
+        Ok(())
+    }
+}
+impl Drop for VolumeSnapshot {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            tokio::spawn(async move {
+                if let Err(e) = tokio::fs::remove_dir_all(&path).await {
+                    tracing::error!("Error removing backup snapshot: {}", e);
+                    tracing::debug!("{:?}", e);
+                }
+            });
+        }
+    }
+}
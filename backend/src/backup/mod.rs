@@ -14,6 +14,8 @@ use tokio::io::AsyncWriteExt;
 use tracing::instrument;
 
 use self::target::PackageBackupInfo;
+use self::verify::write_checksums;
+use crate::config::history::PendingHistory;
 use crate::context::RpcContext;
 use crate::dependencies::reconfigure_dependents_with_live_pointers;
 use crate::install::PKG_ARCHIVE_DIR;
@@ -22,6 +24,7 @@ use crate::net::keys::Key;
 use crate::procedure::docker::DockerContainers;
 use crate::procedure::{NoOutput, PackageProcedure, ProcedureName};
 use crate::s9pk::manifest::PackageId;
+use crate::util::io::{copy_limited, RateLimiter};
 use crate::util::serde::{Base32, Base64, IoFormat};
 use crate::util::Version;
 use crate::version::{Current, VersionT};
@@ -29,9 +32,13 @@ use crate::volume::{backup_dir, Volume, VolumeId, Volumes, BACKUP_DIR};
 use crate::{Error, ErrorKind, ResultExt};
 
 pub mod backup_bulk;
+pub mod live;
 pub mod os;
 pub mod restore;
+pub mod schedule;
+pub mod snapshot;
 pub mod target;
+pub mod verify;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct BackupReport {
@@ -50,7 +57,7 @@ pub struct PackageBackupReport {
     error: Option<String>,
 }
 
-#[command(subcommands(backup_bulk::backup_all, target::target))]
+#[command(subcommands(backup_bulk::backup_all, schedule::schedule, target::target))]
 pub fn backup() -> Result<(), Error> {
     Ok(())
 }
@@ -74,6 +81,10 @@ struct BackupMetadata {
 pub struct BackupActions {
     pub create: PackageProcedure,
     pub restore: PackageProcedure,
+    /// Back up from a snapshot of the data volumes instead of pausing the service for the whole
+    /// backup. The service is only paused briefly while the snapshot is finalized.
+    #[serde(default)]
+    pub live: bool,
 }
 impl BackupActions {
     pub fn validate(
@@ -102,6 +113,8 @@ impl BackupActions {
         pkg_version: &Version,
         interfaces: &Interfaces,
         volumes: &Volumes,
+        limiter: Option<&RateLimiter>,
+        disk_read_mbps: Option<u64>,
     ) -> Result<PackageBackupInfo, Error> {
         let mut volumes = volumes.to_readonly();
         volumes.insert(VolumeId::Backup, Volume::Backup { readonly: false });
@@ -109,7 +122,18 @@ impl BackupActions {
         if tokio::fs::metadata(&backup_dir).await.is_err() {
             tokio::fs::create_dir_all(&backup_dir).await?
         }
-        self.create
+        let mut create = self.create.clone();
+        if let (PackageProcedure::Docker(procedure), Some(mbps)) = (&mut create, disk_read_mbps) {
+            // everything the procedure backs up is read from the data volumes, so capping reads
+            // caps what it writes to the target
+            procedure.resources.disk_read_mbps = Some(
+                procedure
+                    .resources
+                    .disk_read_mbps
+                    .map_or(mbps, |cap| cap.min(mbps)),
+            );
+        }
+        create
             .execute::<(), NoOutput>(
                 ctx,
                 pkg_id,
@@ -158,7 +182,7 @@ impl BackupActions {
         let mut outfile = AtomicFile::new(&tmp_path, None::<PathBuf>)
             .await
             .with_kind(ErrorKind::Filesystem)?;
-        tokio::io::copy(&mut infile, &mut *outfile)
+        copy_limited(&mut infile, &mut *outfile, limiter)
             .await
             .with_ctx(|_| {
                 (
@@ -181,6 +205,7 @@ impl BackupActions {
             })?)
             .await?;
         outfile.save().await.with_kind(ErrorKind::Filesystem)?;
+        write_checksums(&Path::new(BACKUP_DIR).join(pkg_id)).await?;
         Ok(PackageBackupInfo {
             os_version: Current::new().semver().into(),
             title: pkg_title.to_owned(),
@@ -198,7 +223,7 @@ impl BackupActions {
         pkg_version: &Version,
         interfaces: &Interfaces,
         volumes: &Volumes,
-    ) -> Result<(), Error> {
+    ) -> Result<PendingHistory, Error> {
         let mut volumes = volumes.clone();
         volumes.insert(VolumeId::Backup, Volume::Backup { readonly: true });
         self.restore
@@ -247,8 +272,6 @@ impl BackupActions {
             .await?;
 
         let receipts = crate::config::ConfigReceipts::new(db).await?;
-        reconfigure_dependents_with_live_pointers(ctx, db, &receipts, &entry).await?;
-
-        Ok(())
+        reconfigure_dependents_with_live_pointers(ctx, db, &receipts, &entry).await
     }
 }
@@ -16,14 +16,13 @@ use tokio::fs::File;
 use torut::onion::OnionAddressV3;
 use tracing::instrument;
 
-use super::target::BackupTargetId;
+use super::target::{BackupTargetFS, BackupTargetId};
 use crate::backup::os::OsBackup;
 use crate::backup::BackupMetadata;
 use crate::context::rpc::RpcContextConfig;
 use crate::context::{RpcContext, SetupContext};
 use crate::db::model::{PackageDataEntry, StaticFiles};
 use crate::disk::mount::backup::{BackupMountGuard, PackageBackupMountGuard};
-use crate::disk::mount::filesystem::ReadWrite;
 use crate::disk::mount::guard::TmpMountGuard;
 use crate::hostname::Hostname;
 use crate::init::init;
@@ -51,16 +50,17 @@ pub async fn restore_packages_rpc(
     #[context] ctx: RpcContext,
     #[arg(parse(parse_comma_separated))] ids: Vec<PackageId>,
     #[arg(rename = "target-id")] target_id: BackupTargetId,
+    #[arg(rename = "snapshot-id", long = "snapshot-id")] snapshot_id: Option<String>,
     #[arg] password: String,
 ) -> Result<(), Error> {
     let mut db = ctx.db.handle();
     let fs = target_id
         .load(&mut ctx.secret_store.acquire().await?)
         .await?;
-    let backup_guard =
-        BackupMountGuard::mount(TmpMountGuard::mount(&fs, ReadWrite).await?, &password).await?;
+    let backup_guard = fs.mount_backup(&password).await?;
 
-    let (backup_guard, tasks, _) = restore_packages(&ctx, &mut db, backup_guard, ids).await?;
+    let (backup_guard, tasks, _) =
+        restore_packages(&ctx, &mut db, backup_guard, ids, snapshot_id.as_deref()).await?;
 
     tokio::spawn(async move {
         stream::iter(tasks.into_iter().map(|x| (x, ctx.clone())))
@@ -174,14 +174,12 @@ pub async fn recover_full_embassy(
     ctx: SetupContext,
     disk_guid: Arc<String>,
     embassy_password: String,
-    recovery_source: TmpMountGuard,
+    recovery_source: BackupTargetFS,
     recovery_password: Option<String>,
 ) -> Result<(Arc<String>, Hostname, OnionAddressV3, X509), Error> {
-    let backup_guard = BackupMountGuard::mount(
-        recovery_source,
-        recovery_password.as_deref().unwrap_or_default(),
-    )
-    .await?;
+    let backup_guard = recovery_source
+        .mount_backup(recovery_password.as_deref().unwrap_or_default())
+        .await?;
 
     let os_backup_path = backup_guard.as_ref().join("os-backup.cbor");
     let mut os_backup: OsBackup =
@@ -220,7 +218,7 @@ pub async fn recover_full_embassy(
         .cloned()
         .collect();
     let (backup_guard, tasks, progress_info) =
-        restore_packages(&rpc_ctx, &mut db, backup_guard, ids).await?;
+        restore_packages(&rpc_ctx, &mut db, backup_guard, ids, None).await?;
     let task_consumer_rpc_ctx = rpc_ctx.clone();
     tokio::select! {
         _ = async move {
@@ -266,6 +264,7 @@ async fn restore_packages(
     db: &mut PatchDbHandle,
     backup_guard: BackupMountGuard<TmpMountGuard>,
     ids: Vec<PackageId>,
+    snapshot_id: Option<&str>,
 ) -> Result<
     (
         BackupMountGuard<TmpMountGuard>,
@@ -274,7 +273,7 @@ async fn restore_packages(
     ),
     Error,
 > {
-    let guards = assure_restoring(ctx, db, ids, &backup_guard).await?;
+    let guards = assure_restoring(ctx, db, ids, &backup_guard, snapshot_id).await?;
 
     let mut progress_info = ProgressInfo::default();
 
@@ -312,6 +311,7 @@ async fn assure_restoring(
     db: &mut PatchDbHandle,
     ids: Vec<PackageId>,
     backup_guard: &BackupMountGuard<TmpMountGuard>,
+    snapshot_id: Option<&str>,
 ) -> Result<Vec<(Manifest, PackageBackupMountGuard)>, Error> {
     let mut tx = db.begin().await?;
 
@@ -331,7 +331,13 @@ async fn assure_restoring(
             ));
         }
 
-        let guard = backup_guard.mount_package_backup(&id).await?;
+        let guard = if let Some(snapshot_id) = snapshot_id {
+            backup_guard
+                .mount_package_snapshot(&id, snapshot_id)
+                .await?
+        } else {
+            backup_guard.mount_package_backup(&id).await?
+        };
         let s9pk_path = Path::new(BACKUP_DIR).join(&id).join(format!("{}.s9pk", id));
         let mut rdr = S9pkReader::open(&s9pk_path, false).await?;
 
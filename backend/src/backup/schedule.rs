@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::sync::broadcast::Receiver;
+use tracing::instrument;
+
+use super::backup_bulk::{backup_and_report, parse_comma_separated, start_backup, BackupOptions};
+use super::snapshot::RetentionPolicy;
+use super::target::BackupTargetId;
+use crate::auth::check_password_against_db;
+use crate::backup::{BackupReport, ServerBackupReport};
+use crate::context::RpcContext;
+use crate::disk::mount::backup::BackupKey;
+use crate::hostname::generate_id;
+use crate::middleware::encrypt::{decrypt_slice, encrypt_slice};
+use crate::net::keys::Key;
+use crate::notifications::NotificationLevel;
+use crate::s9pk::manifest::PackageId;
+use crate::shutdown::Shutdown;
+use crate::util::cron::CronSchedule;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat, KeyVal};
+use crate::Error;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupSchedule {
+    pub target_id: BackupTargetId,
+    pub cron: CronSchedule,
+    /// `None` means every installed package at the time the backup runs
+    pub package_ids: Option<BTreeSet<PackageId>>,
+    #[serde(flatten)]
+    pub options: BackupOptions,
+    pub created_at: DateTime<Utc>,
+    pub last_run: Option<DateTime<Utc>>,
+}
+impl BackupSchedule {
+    pub fn next_run(&self) -> Option<DateTime<Utc>> {
+        self.cron
+            .next_after(self.last_run.unwrap_or(self.created_at))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupScheduleInfo {
+    #[serde(flatten)]
+    pub schedule: BackupSchedule,
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+#[command(subcommands(add, list, remove))]
+pub fn schedule() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg(rename = "target-id")] target_id: BackupTargetId,
+    #[arg] cron: CronSchedule,
+    #[arg(
+        rename = "package-ids",
+        long = "package-ids",
+        parse(parse_comma_separated)
+    )]
+    package_ids: Option<BTreeSet<PackageId>>,
+    #[arg(long = "retention")] retention: Option<RetentionPolicy>,
+    #[arg(long = "concurrency")] concurrency: Option<usize>,
+    #[arg(rename = "max-throughput", long = "max-throughput")] max_throughput: Option<u64>,
+    #[arg] password: crate::auth::PasswordType,
+) -> Result<KeyVal<String, BackupScheduleInfo>, Error> {
+    let password = password.decrypt(&ctx)?;
+    let mut secrets = ctx.secret_store.acquire().await?;
+    check_password_against_db(&mut secrets, &password).await?;
+    // unlock the target now, so that scheduled runs only need its backup key. A target that has
+    // not been backed up to yet is initialized with the key it is given here.
+    let backup_guard = target_id
+        .clone()
+        .load(&mut secrets)
+        .await?
+        .mount_backup(&password)
+        .await?;
+    let key = BackupKey::new(backup_guard.key().to_owned(), &password)?;
+    backup_guard.save_and_unmount().await?;
+    let id = generate_id();
+    let schedule = BackupSchedule {
+        target_id,
+        cron,
+        package_ids,
+        options: BackupOptions {
+            retention,
+            concurrency,
+            max_throughput,
+        },
+        created_at: Utc::now(),
+        last_run: None,
+    };
+    sqlx::query!(
+        "INSERT INTO backup_schedules (id, key, password_hash, wrapped_key) VALUES ($1, $2, $3, $4)",
+        id,
+        wrap_key(&ctx.account.read().await.key, &key.key),
+        key.password_hash,
+        key.wrapped_key,
+    )
+    .execute(&mut secrets)
+    .await?;
+    let mut db = ctx.db.handle();
+    let mut schedules = crate::db::DatabaseModel::new()
+        .server_info()
+        .backup_schedules()
+        .get_mut(&mut db)
+        .await?;
+    schedules.insert(id.clone(), schedule.clone());
+    schedules.save(&mut db).await?;
+    Ok(KeyVal {
+        key: id,
+        value: BackupScheduleInfo {
+            next_run: schedule.next_run(),
+            schedule,
+        },
+    })
+}
+
+/// Backup keys are stored wrapped with the server's network key
+fn wrap_key(network_key: &Key, key: &str) -> String {
+    base32::encode(
+        base32::Alphabet::RFC4648 { padding: true },
+        &encrypt_slice(key, network_key.as_bytes()),
+    )
+}
+
+fn unwrap_key(network_key: &Key, wrapped_key: &str) -> Result<String, Error> {
+    let wrapped_key = base32::decode(base32::Alphabet::RFC4648 { padding: true }, wrapped_key)
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("failed to decode wrapped key"),
+                crate::ErrorKind::Backup,
+            )
+        })?;
+    Ok(String::from_utf8(decrypt_slice(
+        wrapped_key,
+        network_key.as_bytes(),
+    ))?)
+}
+
+/// Re-wraps the backup key of every schedule for a new master password, so that the targets they
+/// back up to can be unlocked with it after their next run
+#[instrument(skip_all)]
+pub async fn rewrap_keys(secrets: &PgPool, network_key: &Key, password: &str) -> Result<(), Error> {
+    for schedule in sqlx::query!("SELECT id, key FROM backup_schedules")
+        .fetch_all(secrets)
+        .await?
+    {
+        let key = BackupKey::new(unwrap_key(network_key, &schedule.key)?, password)?;
+        sqlx::query!(
+            "UPDATE backup_schedules SET password_hash = $1, wrapped_key = $2 WHERE id = $3",
+            key.password_hash,
+            key.wrapped_key,
+            schedule.id,
+        )
+        .execute(secrets)
+        .await?;
+    }
+    Ok(())
+}
+
+fn display_schedules(schedules: BTreeMap<String, BackupScheduleInfo>, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(schedules, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc =>
+        "ID",
+        "TARGET",
+        "SCHEDULE",
+        "PACKAGES",
+        "RETENTION",
+        "LAST RUN",
+        "NEXT RUN",
+    ]);
+    for (id, info) in schedules {
+        let row = row![
+            &id,
+            &info.schedule.target_id.to_string(),
+            &info.schedule.cron.to_string(),
+            &if let Some(package_ids) = &info.schedule.package_ids {
+                package_ids
+                    .iter()
+                    .map(|id| id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            } else {
+                "ALL".to_owned()
+            },
+            &if let Some(retention) = &info.schedule.options.retention {
+                retention.to_string()
+            } else {
+                "ALL".to_owned()
+            },
+            &if let Some(ts) = &info.schedule.last_run {
+                ts.to_string()
+            } else {
+                "N/A".to_owned()
+            },
+            &if let Some(ts) = &info.next_run {
+                ts.to_string()
+            } else {
+                "N/A".to_owned()
+            },
+        ];
+        table.add_row(row);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_schedules))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<BTreeMap<String, BackupScheduleInfo>, Error> {
+    Ok(crate::db::DatabaseModel::new()
+        .server_info()
+        .backup_schedules()
+        .get(&mut ctx.db.handle())
+        .await?
+        .into_owned()
+        .into_iter()
+        .map(|(id, schedule)| {
+            (
+                id,
+                BackupScheduleInfo {
+                    next_run: schedule.next_run(),
+                    schedule,
+                },
+            )
+        })
+        .collect())
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] id: String) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let mut schedules = crate::db::DatabaseModel::new()
+        .server_info()
+        .backup_schedules()
+        .get_mut(&mut db)
+        .await?;
+    if schedules.remove(&id).is_none() {
+        return Err(Error::new(
+            eyre!("Backup Schedule {} Not Found", id),
+            crate::ErrorKind::NotFound,
+        ));
+    }
+    schedules.save(&mut db).await?;
+    sqlx::query!("DELETE FROM backup_schedules WHERE id = $1", id)
+        .execute(&ctx.secret_store)
+        .await?;
+    Ok(())
+}
+
+/// Checks the stored schedules periodically and starts any backup that is due. A backup that was
+/// missed while the server was off is started once on the next check.
+pub async fn launch_backup_scheduler(ctx: &RpcContext, mut shutdown: Receiver<Option<Shutdown>>) {
+    loop {
+        if let Err(e) = run_due_schedules(ctx).await {
+            tracing::error!("Error running scheduled backups: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = tokio::time::sleep(Duration::from_secs(30)) => (),
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn run_due_schedules(ctx: &RpcContext) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let now = Utc::now();
+    let schedules = crate::db::DatabaseModel::new()
+        .server_info()
+        .backup_schedules()
+        .get(&mut db)
+        .await?
+        .into_owned();
+    for (id, schedule) in schedules {
+        if !schedule.next_run().map_or(false, |next| next <= now) {
+            continue;
+        }
+        if crate::db::DatabaseModel::new()
+            .server_info()
+            .status_info()
+            .backup_progress()
+            .get(&mut db)
+            .await?
+            .is_some()
+        {
+            // try again once the running backup completes
+            tracing::info!("Delaying scheduled backup {}: already backing up", id);
+            return Ok(());
+        }
+        let mut stored = crate::db::DatabaseModel::new()
+            .server_info()
+            .backup_schedules()
+            .get_mut(&mut db)
+            .await?;
+        if let Some(stored) = stored.get_mut(&id) {
+            stored.last_run = Some(now);
+        }
+        stored.save(&mut db).await?;
+
+        tracing::info!("Starting scheduled backup {} to {}", id, schedule.target_id);
+        let started = async {
+            let stored = sqlx::query!(
+                "SELECT key, password_hash, wrapped_key FROM backup_schedules WHERE id = $1",
+                id
+            )
+            .fetch_one(&ctx.secret_store)
+            .await?;
+            let key = BackupKey {
+                key: unwrap_key(&ctx.account.read().await.key, &stored.key)?,
+                password_hash: stored.password_hash,
+                wrapped_key: stored.wrapped_key,
+            };
+            let backup_guard = schedule
+                .target_id
+                .clone()
+                .load(&mut ctx.secret_store.acquire().await?)
+                .await?
+                .mount_backup_with_key(key)
+                .await?;
+            start_backup(&mut db, backup_guard, schedule.package_ids.clone()).await
+        }
+        .await;
+        match started {
+            Ok((backup_guard, package_ids)) => {
+                let ctx = ctx.clone();
+                tokio::spawn(async move {
+                    let mut db = ctx.db.handle();
+                    backup_and_report(&ctx, &mut db, backup_guard, &package_ids, &schedule.options)
+                        .await
+                });
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::error!("Scheduled Backup {} Failed: {}", id, e);
+                tracing::debug!("{:?}", e);
+                ctx.notification_manager
+                    .notify(
+                        &mut db,
+                        None,
+                        NotificationLevel::Error,
+                        "Scheduled Backup Failed".to_owned(),
+                        format!(
+                            "Your scheduled backup to {} could not be started.",
+                            schedule.target_id
+                        ),
+                        BackupReport {
+                            server: ServerBackupReport {
+                                attempted: false,
+                                error: Some(e.to_string()),
+                            },
+                            packages: BTreeMap::new(),
+                        },
+                        None,
+                    )
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
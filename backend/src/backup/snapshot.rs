@@ -0,0 +1,549 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Utc};
+use color_eyre::eyre::eyre;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use helpers::AtomicFile;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::instrument;
+
+use super::target::{BackupInfo, PackageBackupInfo};
+use crate::s9pk::manifest::PackageId;
+use crate::util::io::{copy_limited, RateLimiter};
+use crate::util::serde::IoFormat;
+use crate::util::Version;
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Snapshot manifests, relative to the root of the encrypted backup
+pub const SNAPSHOT_DIR: &str = "snapshots";
+/// Content addressed file store shared by all snapshots, relative to the root of the encrypted
+/// backup
+pub const OBJECT_DIR: &str = "objects";
+/// Scratch space for materializing a snapshot of a package for restore
+pub const CHECKOUT_DIR: &str = "checkout";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SnapshotInfo {
+    pub timestamp: DateTime<Utc>,
+    pub os_version: Version,
+    pub package_backups: BTreeMap<PackageId, PackageBackupInfo>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotEntry {
+    Dir {
+        mode: u32,
+    },
+    File {
+        hash: String,
+        size: u64,
+        mtime: i64,
+        mode: u32,
+    },
+    Symlink {
+        target: PathBuf,
+    },
+}
+
+/// Contents of a package backup directory, keyed by path relative to that directory
+pub type SnapshotTree = BTreeMap<PathBuf, SnapshotEntry>;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SnapshotManifest {
+    pub packages: BTreeMap<PackageId, SnapshotTree>,
+}
+impl SnapshotManifest {
+    fn objects(&self) -> impl Iterator<Item = &str> {
+        self.packages
+            .values()
+            .flat_map(|tree| tree.values())
+            .filter_map(|entry| match entry {
+                SnapshotEntry::File { hash, .. } => Some(hash.as_str()),
+                _ => None,
+            })
+    }
+}
+
+pub fn snapshot_id(timestamp: &DateTime<Utc>) -> String {
+    timestamp.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+fn manifest_path(root: &Path, id: &str) -> PathBuf {
+    root.join(SNAPSHOT_DIR).join(id).with_extension("cbor")
+}
+
+pub(crate) fn object_path(root: &Path, hash: &str) -> PathBuf {
+    root.join(OBJECT_DIR).join(&hash[..2]).join(hash)
+}
+
+#[instrument(skip_all)]
+pub async fn load_manifest(root: &Path, id: &str) -> Result<SnapshotManifest, Error> {
+    let path = manifest_path(root, id);
+    IoFormat::Cbor.from_slice(
+        &tokio::fs::read(&path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?,
+    )
+}
+
+/// Manifest of the most recent snapshot on the target, if any
+pub async fn load_latest_manifest(
+    root: &Path,
+    info: &BackupInfo,
+) -> Result<Option<SnapshotManifest>, Error> {
+    match info.snapshots.keys().next_back() {
+        Some(id) => Ok(Some(load_manifest(root, id).await?)),
+        None => Ok(None),
+    }
+}
+
+#[instrument(skip_all)]
+pub async fn save_snapshot(
+    root: &Path,
+    info: &mut BackupInfo,
+    id: String,
+    snapshot: SnapshotInfo,
+    manifest: &SnapshotManifest,
+) -> Result<(), Error> {
+    let path = manifest_path(root, &id);
+    tokio::fs::create_dir_all(root.join(SNAPSHOT_DIR)).await?;
+    let mut file = AtomicFile::new(&path, None::<PathBuf>)
+        .await
+        .with_kind(ErrorKind::Filesystem)?;
+    file.write_all(&IoFormat::Cbor.to_vec(manifest)?).await?;
+    file.save().await.with_kind(ErrorKind::Filesystem)?;
+    info.snapshots.insert(id, snapshot);
+    Ok(())
+}
+
+pub(super) async fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+async fn store_object(
+    root: &Path,
+    src: &Path,
+    hash: &str,
+    limiter: Option<&RateLimiter>,
+) -> Result<(), Error> {
+    let dst = object_path(root, hash);
+    if tokio::fs::metadata(&dst).await.is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = dst.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // packages may be snapshotted concurrently, so a temporary file must not be shared
+    let tmp = dst.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+    let mut infile = tokio::fs::File::open(src)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, src.display().to_string()))?;
+    let mut outfile = tokio::fs::File::create(&tmp)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, tmp.display().to_string()))?;
+    copy_limited(&mut infile, &mut outfile, limiter)
+        .await
+        .with_ctx(|_| {
+            (
+                ErrorKind::Filesystem,
+                format!("cp {} -> {}", src.display(), tmp.display()),
+            )
+        })?;
+    outfile.sync_all().await?;
+    tokio::fs::rename(&tmp, &dst).await?;
+    Ok(())
+}
+
+fn walk<'a>(
+    root: &'a Path,
+    base: &'a Path,
+    rel: PathBuf,
+    previous: Option<&'a SnapshotTree>,
+    limiter: Option<&'a RateLimiter>,
+    tree: &'a mut SnapshotTree,
+) -> BoxFuture<'a, Result<(), Error>> {
+    async move {
+        let mut dir = tokio::fs::read_dir(base.join(&rel)).await?;
+        while let Some(e) = dir.next_entry().await? {
+            let rel = rel.join(e.file_name());
+            let path = e.path();
+            let m = tokio::fs::symlink_metadata(&path).await?;
+            let mode = m.permissions().mode();
+            if m.file_type().is_symlink() {
+                tree.insert(
+                    rel,
+                    SnapshotEntry::Symlink {
+                        target: tokio::fs::read_link(&path).await?,
+                    },
+                );
+            } else if m.is_dir() {
+                tree.insert(rel.clone(), SnapshotEntry::Dir { mode });
+                walk(root, base, rel, previous, limiter, tree).await?;
+            } else if m.is_file() {
+                let size = m.len();
+                let mtime = m
+                    .modified()?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or_default();
+                let mut unchanged = None;
+                if let Some(SnapshotEntry::File {
+                    hash,
+                    size: prev_size,
+                    mtime: prev_mtime,
+                    ..
+                }) = previous.and_then(|p| p.get(&rel))
+                {
+                    if *prev_size == size
+                        && *prev_mtime == mtime
+                        && tokio::fs::metadata(object_path(root, hash)).await.is_ok()
+                    {
+                        unchanged = Some(hash.clone());
+                    }
+                }
+                let hash = if let Some(hash) = unchanged {
+                    hash
+                } else {
+                    let hash = hash_file(&path).await?;
+                    store_object(root, &path, &hash, limiter).await?;
+                    hash
+                };
+                tree.insert(
+                    rel,
+                    SnapshotEntry::File {
+                        hash,
+                        size,
+                        mtime,
+                        mode,
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Records the current contents of a package's backup directory into the object store. Files
+/// with the same size and modification time as in `previous` are not re-hashed. New objects are
+/// written at the rate allowed by `limiter`.
+#[instrument(skip_all)]
+pub async fn snapshot_package(
+    root: &Path,
+    id: &PackageId,
+    previous: Option<&SnapshotTree>,
+    limiter: Option<&RateLimiter>,
+) -> Result<SnapshotTree, Error> {
+    let mut tree = SnapshotTree::new();
+    walk(
+        root,
+        &root.join(id),
+        PathBuf::new(),
+        previous,
+        limiter,
+        &mut tree,
+    )
+    .await?;
+    Ok(tree)
+}
+
+/// Materializes a snapshotted package backup directory at `dst`
+#[instrument(skip_all)]
+pub async fn checkout(root: &Path, tree: &SnapshotTree, dst: &Path) -> Result<(), Error> {
+    tokio::fs::create_dir_all(dst).await?;
+    // BTreeMap ordering guarantees parents are visited before their children
+    for (rel, entry) in tree {
+        let path = dst.join(rel);
+        match entry {
+            SnapshotEntry::Dir { mode } => {
+                tokio::fs::create_dir_all(&path).await?;
+                tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(*mode)).await?;
+            }
+            SnapshotEntry::File { hash, mode, .. } => {
+                let src = object_path(root, hash);
+                tokio::fs::copy(&src, &path).await.with_ctx(|_| {
+                    (
+                        ErrorKind::Filesystem,
+                        format!("cp {} -> {}", src.display(), path.display()),
+                    )
+                })?;
+                tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(*mode)).await?;
+            }
+            SnapshotEntry::Symlink { target } => {
+                tokio::fs::symlink(target, &path).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Which snapshots to keep when pruning a backup target. A snapshot is kept if any rule selects
+/// it, and the most recent snapshot is always kept. If no rule is set, nothing is pruned.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RetentionPolicy {
+    pub keep_last: Option<usize>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+}
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.keep_last.is_none()
+            && self.keep_daily.is_none()
+            && self.keep_weekly.is_none()
+            && self.keep_monthly.is_none()
+    }
+
+    /// Ids of the snapshots selected by this policy
+    pub fn retained(&self, snapshots: &BTreeMap<String, SnapshotInfo>) -> BTreeSet<String> {
+        let mut newest_first: Vec<(&String, &SnapshotInfo)> = snapshots.iter().collect();
+        newest_first.sort_by(|a, b| b.1.timestamp.cmp(&a.1.timestamp));
+        if self.is_empty() {
+            return newest_first.into_iter().map(|(id, _)| id.clone()).collect();
+        }
+        let mut keep = BTreeSet::new();
+        if let Some((id, _)) = newest_first.first() {
+            keep.insert((*id).clone());
+        }
+        if let Some(n) = self.keep_last {
+            keep.extend(newest_first.iter().take(n).map(|(id, _)| (*id).clone()));
+        }
+        fn keep_buckets<K: PartialEq>(
+            newest_first: &[(&String, &SnapshotInfo)],
+            n: Option<usize>,
+            bucket: impl Fn(&DateTime<Utc>) -> K,
+            keep: &mut BTreeSet<String>,
+        ) {
+            let n = if let Some(n) = n { n } else { return };
+            let mut last = None;
+            let mut count = 0;
+            for (id, info) in newest_first {
+                if count >= n {
+                    break;
+                }
+                let b = bucket(&info.timestamp);
+                if last.as_ref() != Some(&b) {
+                    keep.insert((*id).clone());
+                    last = Some(b);
+                    count += 1;
+                }
+            }
+        }
+        keep_buckets(
+            &newest_first,
+            self.keep_daily,
+            |t| t.date_naive(),
+            &mut keep,
+        );
+        keep_buckets(
+            &newest_first,
+            self.keep_weekly,
+            |t| {
+                let w = t.iso_week();
+                (w.year(), w.week())
+            },
+            &mut keep,
+        );
+        keep_buckets(
+            &newest_first,
+            self.keep_monthly,
+            |t| (t.year(), t.month()),
+            &mut keep,
+        );
+        keep
+    }
+}
+impl FromStr for RetentionPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut res = RetentionPolicy::default();
+        for rule in s.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+            let (kind, count) = rule.split_once('=').ok_or_else(|| {
+                Error::new(
+                    eyre!("Invalid retention rule {}: expected <kind>=<count>", rule),
+                    ErrorKind::InvalidRequest,
+                )
+            })?;
+            let count = Some(count.trim().parse()?);
+            match kind.trim() {
+                "last" => res.keep_last = count,
+                "daily" => res.keep_daily = count,
+                "weekly" => res.keep_weekly = count,
+                "monthly" => res.keep_monthly = count,
+                a => {
+                    return Err(Error::new(
+                        eyre!("Unknown retention rule: {}", a),
+                        ErrorKind::InvalidRequest,
+                    ))
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+impl fmt::Display for RetentionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules = [
+            ("last", self.keep_last),
+            ("daily", self.keep_daily),
+            ("weekly", self.keep_weekly),
+            ("monthly", self.keep_monthly),
+        ];
+        let mut first = true;
+        for (kind, count) in rules {
+            if let Some(count) = count {
+                if !first {
+                    write!(f, ",")?;
+                }
+                write!(f, "{}={}", kind, count)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Deletes the snapshots not selected by `policy`, then removes every object no longer
+/// referenced by a remaining snapshot
+#[instrument(skip_all)]
+pub async fn prune(
+    root: &Path,
+    info: &mut BackupInfo,
+    policy: &RetentionPolicy,
+) -> Result<(), Error> {
+    let retained = policy.retained(&info.snapshots);
+    let removed: Vec<String> = info
+        .snapshots
+        .keys()
+        .filter(|id| !retained.contains(*id))
+        .cloned()
+        .collect();
+    if removed.is_empty() {
+        return Ok(());
+    }
+    for id in removed {
+        tracing::info!("Pruning backup snapshot {}", id);
+        info.snapshots.remove(&id);
+        let path = manifest_path(root, &id);
+        if tokio::fs::metadata(&path).await.is_ok() {
+            tokio::fs::remove_file(&path).await?;
+        }
+    }
+    let mut referenced = BTreeSet::new();
+    for id in info.snapshots.keys() {
+        referenced.extend(
+            load_manifest(root, id)
+                .await?
+                .objects()
+                .map(|o| o.to_owned()),
+        );
+    }
+    let objects = root.join(OBJECT_DIR);
+    if tokio::fs::metadata(&objects).await.is_err() {
+        return Ok(());
+    }
+    let mut prefixes = tokio::fs::read_dir(&objects).await?;
+    while let Some(prefix) = prefixes.next_entry().await? {
+        let mut entries = tokio::fs::read_dir(prefix.path()).await?;
+        while let Some(e) = entries.next_entry().await? {
+            if !referenced.contains(&*e.file_name().to_string_lossy()) {
+                tokio::fs::remove_file(e.path()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_snapshots(timestamps: &[&str]) -> BTreeMap<String, SnapshotInfo> {
+    timestamps
+        .iter()
+        .map(|ts| {
+            let timestamp = DateTime::parse_from_rfc3339(ts)
+                .unwrap()
+                .with_timezone(&Utc);
+            (
+                snapshot_id(&timestamp),
+                SnapshotInfo {
+                    timestamp,
+                    os_version: emver::Version::new(0, 3, 4, 0).into(),
+                    package_backups: BTreeMap::new(),
+                },
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn retention_keep_all_by_default() {
+    let snapshots = test_snapshots(&["2023-01-01T00:00:00Z", "2023-01-02T00:00:00Z"]);
+    assert_eq!(RetentionPolicy::default().retained(&snapshots).len(), 2);
+}
+
+#[test]
+fn retention_daily_weekly() {
+    let snapshots = test_snapshots(&[
+        "2023-01-01T03:00:00Z", // sunday
+        "2023-01-02T03:00:00Z",
+        "2023-01-03T03:00:00Z",
+        "2023-01-03T15:00:00Z",
+        "2023-01-09T03:00:00Z",
+        "2023-01-10T03:00:00Z",
+    ]);
+    let policy: RetentionPolicy = "daily=2,weekly=3".parse().unwrap();
+    let retained = policy.retained(&snapshots);
+    assert_eq!(
+        retained,
+        [
+            "20230101T030000Z", // week 52 of 2022
+            "20230103T150000Z", // week 1
+            "20230109T030000Z", // daily
+            "20230110T030000Z", // daily, week 2
+        ]
+        .into_iter()
+        .map(|s| s.to_owned())
+        .collect()
+    );
+}
+
+#[test]
+fn retention_always_keeps_newest() {
+    let snapshots = test_snapshots(&["2023-01-01T00:00:00Z", "2023-01-02T00:00:00Z"]);
+    let policy: RetentionPolicy = "last=0".parse().unwrap();
+    assert_eq!(
+        policy.retained(&snapshots),
+        ["20230102T000000Z".to_owned()].into_iter().collect()
+    );
+}
+
+#[test]
+fn retention_parse_roundtrip() {
+    let policy: RetentionPolicy = "weekly=4, daily=7".parse().unwrap();
+    assert_eq!(policy.to_string(), "daily=7,weekly=4");
+    assert!("hourly=1".parse::<RetentionPolicy>().is_err());
+    assert!("daily".parse::<RetentionPolicy>().is_err());
+}
@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use digest::generic_array::GenericArray;
+use digest::OutputSizeUser;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use self::cifs::CifsBackupTarget;
+use self::s3::S3BackupTarget;
+use self::sftp::SftpBackupTarget;
+use super::snapshot::SnapshotInfo;
+use crate::context::RpcContext;
+use crate::disk::mount::backup::{BackupKey, BackupMountGuard};
+use crate::disk::mount::filesystem::block_dev::BlockDev;
+use crate::disk::mount::filesystem::cifs::Cifs;
+use crate::disk::mount::filesystem::s3::S3;
+use crate::disk::mount::filesystem::sshfs::Sshfs;
+use crate::disk::mount::filesystem::{FileSystem, MountType, ReadWrite};
+use crate::disk::mount::guard::TmpMountGuard;
+use crate::disk::util::PartitionInfo;
+use crate::s9pk::manifest::PackageId;
+use crate::util::serde::{deserialize_from_str, display_serializable, serialize_display};
+use crate::util::Version;
+use crate::Error;
+
+pub mod cifs;
+pub mod s3;
+pub mod sftp;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum BackupTarget {
+    #[serde(rename_all = "kebab-case")]
+    Disk {
+        vendor: Option<String>,
+        model: Option<String>,
+        #[serde(flatten)]
+        partition_info: PartitionInfo,
+    },
+    Cifs(CifsBackupTarget),
+    Sftp(SftpBackupTarget),
+    S3(S3BackupTarget),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BackupTargetId {
+    Disk { logicalname: PathBuf },
+    Cifs { id: i32 },
+    Sftp { id: i32 },
+    S3 { id: i32 },
+}
+impl BackupTargetId {
+    pub async fn load<Ex>(self, secrets: &mut Ex) -> Result<BackupTargetFS, Error>
+    where
+        for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+    {
+        Ok(match self {
+            BackupTargetId::Disk { logicalname } => {
+                BackupTargetFS::Disk(BlockDev::new(logicalname))
+            }
+            BackupTargetId::Cifs { id } => BackupTargetFS::Cifs(cifs::load(secrets, id).await?),
+            BackupTargetId::Sftp { id } => BackupTargetFS::Sftp(sftp::load(secrets, id).await?),
+            BackupTargetId::S3 { id } => BackupTargetFS::S3(s3::load(secrets, id).await?),
+        })
+    }
+}
+impl std::fmt::Display for BackupTargetId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupTargetId::Disk { logicalname } => write!(f, "disk-{}", logicalname.display()),
+            BackupTargetId::Cifs { id } => write!(f, "cifs-{}", id),
+            BackupTargetId::Sftp { id } => write!(f, "sftp-{}", id),
+            BackupTargetId::S3 { id } => write!(f, "s3-{}", id),
+        }
+    }
+}
+impl std::str::FromStr for BackupTargetId {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("-") {
+            Some(("disk", logicalname)) => Ok(BackupTargetId::Disk {
+                logicalname: Path::new(logicalname).to_owned(),
+            }),
+            Some(("cifs", id)) => Ok(BackupTargetId::Cifs { id: id.parse()? }),
+            Some(("sftp", id)) => Ok(BackupTargetId::Sftp { id: id.parse()? }),
+            Some(("s3", id)) => Ok(BackupTargetId::S3 { id: id.parse()? }),
+            _ => Err(Error::new(
+                eyre!("Invalid Backup Target ID"),
+                crate::ErrorKind::InvalidBackupTargetId,
+            )),
+        }
+    }
+}
+impl<'de> Deserialize<'de> for BackupTargetId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserialize_from_str(deserializer)
+    }
+}
+impl Serialize for BackupTargetId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serialize_display(self, serializer)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum BackupTargetFS {
+    Disk(BlockDev<PathBuf>),
+    Cifs(Cifs),
+    Sftp(Sshfs),
+    S3(S3),
+}
+impl BackupTargetFS {
+    /// Mounts the target and unlocks the backup on it with `password`
+    #[instrument(skip_all)]
+    pub async fn mount_backup(
+        &self,
+        password: &str,
+    ) -> Result<BackupMountGuard<TmpMountGuard>, Error> {
+        let guard = TmpMountGuard::mount(self, ReadWrite).await?;
+        match self {
+            BackupTargetFS::S3(s3) => {
+                BackupMountGuard::mount_remote(guard, s3.clone(), password).await
+            }
+            _ => BackupMountGuard::mount(guard, password).await,
+        }
+    }
+
+    /// Mounts the target and unlocks the backup on it with a key recorded ahead of time
+    #[instrument(skip_all)]
+    pub async fn mount_backup_with_key(
+        &self,
+        key: BackupKey,
+    ) -> Result<BackupMountGuard<TmpMountGuard>, Error> {
+        let guard = TmpMountGuard::mount(self, ReadWrite).await?;
+        let remote = match self {
+            BackupTargetFS::S3(s3) => Some(s3.clone()),
+            _ => None,
+        };
+        BackupMountGuard::mount_with_key(guard, remote, key).await
+    }
+}
+#[async_trait]
+impl FileSystem for BackupTargetFS {
+    async fn mount<P: AsRef<Path> + Send + Sync>(
+        &self,
+        mountpoint: P,
+        mount_type: MountType,
+    ) -> Result<(), Error> {
+        match self {
+            BackupTargetFS::Disk(a) => a.mount(mountpoint, mount_type).await,
+            BackupTargetFS::Cifs(a) => a.mount(mountpoint, mount_type).await,
+            BackupTargetFS::Sftp(a) => a.mount(mountpoint, mount_type).await,
+            BackupTargetFS::S3(a) => a.mount(mountpoint, mount_type).await,
+        }
+    }
+    async fn source_hash(
+        &self,
+    ) -> Result<GenericArray<u8, <Sha256 as OutputSizeUser>::OutputSize>, Error> {
+        match self {
+            BackupTargetFS::Disk(a) => a.source_hash().await,
+            BackupTargetFS::Cifs(a) => a.source_hash().await,
+            BackupTargetFS::Sftp(a) => a.source_hash().await,
+            BackupTargetFS::S3(a) => a.source_hash().await,
+        }
+    }
+}
+
+#[command(subcommands(
+    cifs::cifs,
+    sftp::sftp,
+    s3::s3,
+    list,
+    info,
+    crate::backup::verify::verify
+))]
+pub fn target() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_serializable))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+) -> Result<BTreeMap<BackupTargetId, BackupTarget>, Error> {
+    let mut cifs_handle = ctx.secret_store.acquire().await?;
+    let mut sftp_handle = ctx.secret_store.acquire().await?;
+    let mut s3_handle = ctx.secret_store.acquire().await?;
+    let (disks_res, cifs, sftp, s3) = tokio::try_join!(
+        crate::disk::util::list(&ctx.os_partitions),
+        cifs::list(&mut cifs_handle),
+        sftp::list(&mut sftp_handle),
+        s3::list(&mut s3_handle),
+    )?;
+    Ok(disks_res
+        .into_iter()
+        .flat_map(|mut disk| {
+            std::mem::take(&mut disk.partitions)
+                .into_iter()
+                .map(|part| {
+                    (
+                        BackupTargetId::Disk {
+                            logicalname: part.logicalname.clone(),
+                        },
+                        BackupTarget::Disk {
+                            vendor: disk.vendor.clone(),
+                            model: disk.model.clone(),
+                            partition_info: part,
+                        },
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+        .chain(
+            cifs.into_iter()
+                .map(|(id, cifs)| (BackupTargetId::Cifs { id }, BackupTarget::Cifs(cifs))),
+        )
+        .chain(
+            sftp.into_iter()
+                .map(|(id, sftp)| (BackupTargetId::Sftp { id }, BackupTarget::Sftp(sftp))),
+        )
+        .chain(
+            s3.into_iter()
+                .map(|(id, s3)| (BackupTargetId::S3 { id }, BackupTarget::S3(s3))),
+        )
+        .collect())
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupInfo {
+    pub version: Version,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub package_backups: BTreeMap<PackageId, PackageBackupInfo>,
+    #[serde(default)]
+    pub snapshots: BTreeMap<String, SnapshotInfo>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PackageBackupInfo {
+    pub title: String,
+    pub version: Version,
+    pub os_version: Version,
+    pub timestamp: DateTime<Utc>,
+}
+
+fn display_backup_info(info: BackupInfo, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(info, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc =>
+        "ID",
+        "VERSION",
+        "OS VERSION",
+        "TIMESTAMP",
+    ]);
+    table.add_row(row![
+        "EMBASSY OS",
+        info.version.as_str(),
+        info.version.as_str(),
+        &if let Some(ts) = &info.timestamp {
+            ts.to_string()
+        } else {
+            "N/A".to_owned()
+        },
+    ]);
+    for (id, info) in info.package_backups {
+        let row = row![
+            id.as_str(),
+            info.version.as_str(),
+            info.os_version.as_str(),
+            &info.timestamp.to_string(),
+        ];
+        table.add_row(row);
+    }
+    table.print_tty(false).unwrap();
+
+    if info.snapshots.is_empty() {
+        return;
+    }
+    let mut table = Table::new();
+    table.add_row(row![bc =>
+        "SNAPSHOT",
+        "OS VERSION",
+        "TIMESTAMP",
+        "PACKAGES",
+    ]);
+    for (id, snapshot) in info.snapshots.into_iter().rev() {
+        let row = row![
+            &id,
+            snapshot.os_version.as_str(),
+            &snapshot.timestamp.to_string(),
+            &snapshot
+                .package_backups
+                .keys()
+                .map(|id| id.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+        ];
+        table.add_row(row);
+    }
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_backup_info))]
+#[instrument(skip_all)]
+pub async fn info(
+    #[context] ctx: RpcContext,
+    #[arg(rename = "target-id")] target_id: BackupTargetId,
+    #[arg] password: String,
+) -> Result<BackupInfo, Error> {
+    let guard = target_id
+        .load(&mut ctx.secret_store.acquire().await?)
+        .await?
+        .mount_backup(&password)
+        .await?;
+
+    let res = guard.metadata.clone();
+
+    guard.unmount().await?;
+
+    Ok(res)
+}
@@ -0,0 +1,244 @@
+use color_eyre::eyre::eyre;
+use futures::TryStreamExt;
+use reqwest::Url;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+
+use super::{BackupTarget, BackupTargetId};
+use crate::context::RpcContext;
+use crate::disk::mount::filesystem::s3::S3;
+use crate::disk::mount::filesystem::ReadOnly;
+use crate::disk::mount::guard::TmpMountGuard;
+use crate::disk::util::{recovery_info, EmbassyOsRecoveryInfo};
+use crate::util::display_none;
+use crate::util::serde::KeyVal;
+use crate::Error;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct S3BackupTarget {
+    endpoint: Url,
+    region: String,
+    bucket: String,
+    prefix: String,
+    access_key_id: String,
+    mountable: bool,
+    embassy_os: Option<EmbassyOsRecoveryInfo>,
+}
+impl S3BackupTarget {
+    fn new(s3: S3, embassy_os: Option<EmbassyOsRecoveryInfo>) -> Self {
+        S3BackupTarget {
+            endpoint: s3.endpoint,
+            region: s3.region,
+            bucket: s3.bucket,
+            prefix: s3.prefix,
+            access_key_id: s3.access_key_id,
+            mountable: true,
+            embassy_os,
+        }
+    }
+}
+
+#[command(subcommands(add, update, remove))]
+pub fn s3() -> Result<(), Error> {
+    Ok(())
+}
+
+async fn connect(
+    endpoint: Url,
+    region: Option<String>,
+    bucket: String,
+    prefix: Option<String>,
+    access_key_id: String,
+    secret_access_key: String,
+) -> Result<(S3, Option<EmbassyOsRecoveryInfo>), Error> {
+    let s3 = S3 {
+        endpoint,
+        region: region.unwrap_or_else(|| "us-east-1".to_owned()),
+        bucket,
+        prefix: prefix.unwrap_or_default(),
+        access_key_id,
+        secret_access_key,
+    };
+    let guard = TmpMountGuard::mount(&s3, ReadOnly).await?;
+    let embassy_os = recovery_info(&guard).await?;
+    guard.unmount().await?;
+    Ok((s3, embassy_os))
+}
+
+#[command(display(display_none))]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] endpoint: Url,
+    #[arg(long = "region")] region: Option<String>,
+    #[arg] bucket: String,
+    #[arg(long = "prefix")] prefix: Option<String>,
+    #[arg(rename = "access-key-id")] access_key_id: String,
+    #[arg(rename = "secret-access-key")] secret_access_key: String,
+) -> Result<KeyVal<BackupTargetId, BackupTarget>, Error> {
+    let (s3, embassy_os) = connect(
+        endpoint,
+        region,
+        bucket,
+        prefix,
+        access_key_id,
+        secret_access_key,
+    )
+    .await?;
+    let id: i32 = sqlx::query!(
+        "INSERT INTO s3_targets (endpoint, region, bucket, prefix, access_key_id, secret_access_key) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        s3.endpoint.as_str(),
+        s3.region,
+        s3.bucket,
+        s3.prefix,
+        s3.access_key_id,
+        s3.secret_access_key,
+    )
+    .fetch_one(&ctx.secret_store)
+    .await?.id;
+    Ok(KeyVal {
+        key: BackupTargetId::S3 { id },
+        value: BackupTarget::S3(S3BackupTarget::new(s3, embassy_os)),
+    })
+}
+
+#[command(display(display_none))]
+pub async fn update(
+    #[context] ctx: RpcContext,
+    #[arg] id: BackupTargetId,
+    #[arg] endpoint: Url,
+    #[arg(long = "region")] region: Option<String>,
+    #[arg] bucket: String,
+    #[arg(long = "prefix")] prefix: Option<String>,
+    #[arg(rename = "access-key-id")] access_key_id: String,
+    #[arg(rename = "secret-access-key")] secret_access_key: String,
+) -> Result<KeyVal<BackupTargetId, BackupTarget>, Error> {
+    let id = if let BackupTargetId::S3 { id } = id {
+        id
+    } else {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", id),
+            crate::ErrorKind::NotFound,
+        ));
+    };
+    let (s3, embassy_os) = connect(
+        endpoint,
+        region,
+        bucket,
+        prefix,
+        access_key_id,
+        secret_access_key,
+    )
+    .await?;
+    if sqlx::query!(
+        "UPDATE s3_targets SET endpoint = $1, region = $2, bucket = $3, prefix = $4, access_key_id = $5, secret_access_key = $6 WHERE id = $7",
+        s3.endpoint.as_str(),
+        s3.region,
+        s3.bucket,
+        s3.prefix,
+        s3.access_key_id,
+        s3.secret_access_key,
+        id,
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected()
+        == 0
+    {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", BackupTargetId::S3 { id }),
+            crate::ErrorKind::NotFound,
+        ));
+    };
+    Ok(KeyVal {
+        key: BackupTargetId::S3 { id },
+        value: BackupTarget::S3(S3BackupTarget::new(s3, embassy_os)),
+    })
+}
+
+#[command(display(display_none))]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] id: BackupTargetId) -> Result<(), Error> {
+    let id = if let BackupTargetId::S3 { id } = id {
+        id
+    } else {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", id),
+            crate::ErrorKind::NotFound,
+        ));
+    };
+    if sqlx::query!("DELETE FROM s3_targets WHERE id = $1", id)
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected()
+        == 0
+    {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", BackupTargetId::S3 { id }),
+            crate::ErrorKind::NotFound,
+        ));
+    };
+    Ok(())
+}
+
+pub async fn load<Ex>(secrets: &mut Ex, id: i32) -> Result<S3, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let record = sqlx::query!(
+        "SELECT endpoint, region, bucket, prefix, access_key_id, secret_access_key FROM s3_targets WHERE id = $1",
+        id
+    )
+    .fetch_one(secrets)
+    .await?;
+
+    Ok(S3 {
+        endpoint: record.endpoint.parse()?,
+        region: record.region,
+        bucket: record.bucket,
+        prefix: record.prefix,
+        access_key_id: record.access_key_id,
+        secret_access_key: record.secret_access_key,
+    })
+}
+
+pub async fn list<Ex>(secrets: &mut Ex) -> Result<Vec<(i32, S3BackupTarget)>, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let mut records = sqlx::query!(
+        "SELECT id, endpoint, region, bucket, prefix, access_key_id, secret_access_key FROM s3_targets"
+    )
+    .fetch_many(secrets);
+
+    let mut s3 = Vec::new();
+    while let Some(query_result) = records.try_next().await? {
+        if let Some(record) = query_result.right() {
+            let mount_info = S3 {
+                endpoint: record.endpoint.parse()?,
+                region: record.region,
+                bucket: record.bucket,
+                prefix: record.prefix,
+                access_key_id: record.access_key_id,
+                secret_access_key: record.secret_access_key,
+            };
+            let embassy_os = async {
+                let guard = TmpMountGuard::mount(&mount_info, ReadOnly).await?;
+                let embassy_os = recovery_info(&guard).await?;
+                guard.unmount().await?;
+                Ok::<_, Error>(embassy_os)
+            }
+            .await;
+            s3.push((
+                record.id,
+                S3BackupTarget {
+                    mountable: embassy_os.is_ok(),
+                    embassy_os: embassy_os.ok().and_then(|a| a),
+                    ..S3BackupTarget::new(mount_info, None)
+                },
+            ));
+        }
+    }
+
+    Ok(s3)
+}
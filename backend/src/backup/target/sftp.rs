@@ -0,0 +1,266 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::eyre;
+use futures::TryStreamExt;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+
+use super::{BackupTarget, BackupTargetId};
+use crate::context::RpcContext;
+use crate::disk::mount::filesystem::sshfs::{scan_host_keys, Sshfs};
+use crate::disk::mount::filesystem::ReadOnly;
+use crate::disk::mount::guard::TmpMountGuard;
+use crate::disk::util::{recovery_info, EmbassyOsRecoveryInfo};
+use crate::util::display_none;
+use crate::util::serde::KeyVal;
+use crate::Error;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SftpBackupTarget {
+    hostname: String,
+    port: u16,
+    path: PathBuf,
+    username: String,
+    mountable: bool,
+    embassy_os: Option<EmbassyOsRecoveryInfo>,
+}
+impl SftpBackupTarget {
+    fn new(sshfs: Sshfs, embassy_os: Option<EmbassyOsRecoveryInfo>) -> Self {
+        SftpBackupTarget {
+            hostname: sshfs.hostname,
+            port: sshfs.port,
+            path: sshfs.path,
+            username: sshfs.username,
+            mountable: true,
+            embassy_os,
+        }
+    }
+}
+
+#[command(subcommands(add, update, remove))]
+pub fn sftp() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Builds the filesystem for a target, and checks that it can be mounted. If `host_keys` is not
+/// provided, the keys the server presents now are trusted from then on.
+async fn connect(
+    hostname: String,
+    port: Option<u16>,
+    path: PathBuf,
+    username: String,
+    password: Option<String>,
+    private_key: Option<String>,
+    host_keys: Option<String>,
+) -> Result<(Sshfs, Option<EmbassyOsRecoveryInfo>), Error> {
+    if password.is_none() && private_key.is_none() {
+        return Err(Error::new(
+            eyre!("A password or private key is required"),
+            crate::ErrorKind::InvalidRequest,
+        ));
+    }
+    let port = port.unwrap_or(22);
+    let host_keys = match host_keys {
+        Some(a) => a,
+        None => scan_host_keys(&hostname, port).await?,
+    };
+    let sshfs = Sshfs {
+        hostname,
+        port,
+        path,
+        username,
+        password,
+        private_key,
+        host_keys,
+    };
+    let guard = TmpMountGuard::mount(&sshfs, ReadOnly).await?;
+    let embassy_os = recovery_info(&guard).await?;
+    guard.unmount().await?;
+    Ok((sshfs, embassy_os))
+}
+
+#[command(display(display_none))]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] hostname: String,
+    #[arg(long = "port")] port: Option<u16>,
+    #[arg] path: PathBuf,
+    #[arg] username: String,
+    #[arg(long = "password")] password: Option<String>,
+    #[arg(rename = "private-key", long = "private-key")] private_key: Option<String>,
+    #[arg(rename = "host-keys", long = "host-keys")] host_keys: Option<String>,
+) -> Result<KeyVal<BackupTargetId, BackupTarget>, Error> {
+    let (sshfs, embassy_os) = connect(
+        hostname,
+        port,
+        path,
+        username,
+        password,
+        private_key,
+        host_keys,
+    )
+    .await?;
+    let id: i32 = sqlx::query!(
+        "INSERT INTO sftp_targets (hostname, port, path, username, password, private_key, host_keys) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+        sshfs.hostname,
+        sshfs.port as i32,
+        sshfs.path.display().to_string(),
+        sshfs.username,
+        sshfs.password,
+        sshfs.private_key,
+        sshfs.host_keys,
+    )
+    .fetch_one(&ctx.secret_store)
+    .await?.id;
+    Ok(KeyVal {
+        key: BackupTargetId::Sftp { id },
+        value: BackupTarget::Sftp(SftpBackupTarget::new(sshfs, embassy_os)),
+    })
+}
+
+#[command(display(display_none))]
+pub async fn update(
+    #[context] ctx: RpcContext,
+    #[arg] id: BackupTargetId,
+    #[arg] hostname: String,
+    #[arg(long = "port")] port: Option<u16>,
+    #[arg] path: PathBuf,
+    #[arg] username: String,
+    #[arg(long = "password")] password: Option<String>,
+    #[arg(rename = "private-key", long = "private-key")] private_key: Option<String>,
+    #[arg(rename = "host-keys", long = "host-keys")] host_keys: Option<String>,
+) -> Result<KeyVal<BackupTargetId, BackupTarget>, Error> {
+    let id = if let BackupTargetId::Sftp { id } = id {
+        id
+    } else {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", id),
+            crate::ErrorKind::NotFound,
+        ));
+    };
+    let (sshfs, embassy_os) = connect(
+        hostname,
+        port,
+        path,
+        username,
+        password,
+        private_key,
+        host_keys,
+    )
+    .await?;
+    if sqlx::query!(
+        "UPDATE sftp_targets SET hostname = $1, port = $2, path = $3, username = $4, password = $5, private_key = $6, host_keys = $7 WHERE id = $8",
+        sshfs.hostname,
+        sshfs.port as i32,
+        sshfs.path.display().to_string(),
+        sshfs.username,
+        sshfs.password,
+        sshfs.private_key,
+        sshfs.host_keys,
+        id,
+    )
+    .execute(&ctx.secret_store)
+    .await?
+    .rows_affected()
+        == 0
+    {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", BackupTargetId::Sftp { id }),
+            crate::ErrorKind::NotFound,
+        ));
+    };
+    Ok(KeyVal {
+        key: BackupTargetId::Sftp { id },
+        value: BackupTarget::Sftp(SftpBackupTarget::new(sshfs, embassy_os)),
+    })
+}
+
+#[command(display(display_none))]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] id: BackupTargetId) -> Result<(), Error> {
+    let id = if let BackupTargetId::Sftp { id } = id {
+        id
+    } else {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", id),
+            crate::ErrorKind::NotFound,
+        ));
+    };
+    if sqlx::query!("DELETE FROM sftp_targets WHERE id = $1", id)
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected()
+        == 0
+    {
+        return Err(Error::new(
+            eyre!("Backup Target ID {} Not Found", BackupTargetId::Sftp { id }),
+            crate::ErrorKind::NotFound,
+        ));
+    };
+    Ok(())
+}
+
+pub async fn load<Ex>(secrets: &mut Ex, id: i32) -> Result<Sshfs, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let record = sqlx::query!(
+        "SELECT hostname, port, path, username, password, private_key, host_keys FROM sftp_targets WHERE id = $1",
+        id
+    )
+    .fetch_one(secrets)
+    .await?;
+
+    Ok(Sshfs {
+        hostname: record.hostname,
+        port: record.port as u16,
+        path: PathBuf::from(record.path),
+        username: record.username,
+        password: record.password,
+        private_key: record.private_key,
+        host_keys: record.host_keys,
+    })
+}
+
+pub async fn list<Ex>(secrets: &mut Ex) -> Result<Vec<(i32, SftpBackupTarget)>, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    let mut records = sqlx::query!(
+        "SELECT id, hostname, port, path, username, password, private_key, host_keys FROM sftp_targets"
+    )
+    .fetch_many(secrets);
+
+    let mut sftp = Vec::new();
+    while let Some(query_result) = records.try_next().await? {
+        if let Some(record) = query_result.right() {
+            let mount_info = Sshfs {
+                hostname: record.hostname,
+                port: record.port as u16,
+                path: PathBuf::from(record.path),
+                username: record.username,
+                password: record.password,
+                private_key: record.private_key,
+                host_keys: record.host_keys,
+            };
+            let embassy_os = async {
+                let guard = TmpMountGuard::mount(&mount_info, ReadOnly).await?;
+                let embassy_os = recovery_info(&guard).await?;
+                guard.unmount().await?;
+                Ok::<_, Error>(embassy_os)
+            }
+            .await;
+            sftp.push((
+                record.id,
+                SftpBackupTarget {
+                    mountable: embassy_os.is_ok(),
+                    embassy_os: embassy_os.ok().and_then(|a| a),
+                    ..SftpBackupTarget::new(mount_info, None)
+                },
+            ));
+        }
+    }
+
+    Ok(sftp)
+}
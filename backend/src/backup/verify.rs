@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use helpers::AtomicFile;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::instrument;
+
+use super::os::OsBackup;
+use super::snapshot::hash_file;
+use super::target::{BackupTargetId, PackageBackupInfo};
+use super::BackupMetadata;
+use crate::context::RpcContext;
+use crate::s9pk::manifest::PackageId;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Checksums of every other file in a package backup directory, written at the end of
+/// [`BackupActions::create`](super::BackupActions::create)
+pub const CHECKSUMS_FILE: &str = "checksums.cbor";
+
+/// sha256 of each file, keyed by path relative to the package backup directory
+pub type Checksums = BTreeMap<PathBuf, String>;
+
+fn checksum_walk<'a>(
+    base: &'a Path,
+    rel: PathBuf,
+    checksums: &'a mut Checksums,
+) -> BoxFuture<'a, Result<(), Error>> {
+    async move {
+        let mut dir = tokio::fs::read_dir(base.join(&rel)).await?;
+        while let Some(e) = dir.next_entry().await? {
+            let rel = rel.join(e.file_name());
+            let m = tokio::fs::symlink_metadata(e.path()).await?;
+            if m.is_dir() {
+                checksum_walk(base, rel, checksums).await?;
+            } else if m.is_file() && rel != Path::new(CHECKSUMS_FILE) {
+                checksums.insert(rel, hash_file(&e.path()).await?);
+            }
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+#[instrument(skip_all)]
+pub async fn write_checksums(dir: &Path) -> Result<(), Error> {
+    let mut checksums = Checksums::new();
+    checksum_walk(dir, PathBuf::new(), &mut checksums).await?;
+    let mut file = AtomicFile::new(dir.join(CHECKSUMS_FILE), None::<PathBuf>)
+        .await
+        .with_kind(ErrorKind::Filesystem)?;
+    file.write_all(&IoFormat::Cbor.to_vec(&checksums)?).await?;
+    file.save().await.with_kind(ErrorKind::Filesystem)?;
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupVerifyReport {
+    pub os_backup_error: Option<String>,
+    pub packages: BTreeMap<PackageId, PackageVerifyReport>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PackageVerifyReport {
+    pub files_checked: usize,
+    pub errors: Vec<String>,
+}
+
+async fn verify_os_backup(root: &Path) -> Result<(), Error> {
+    let path = root.join("os-backup.cbor");
+    IoFormat::Cbor.from_slice::<OsBackup>(
+        &tokio::fs::read(&path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?,
+    )?;
+    Ok(())
+}
+
+async fn verify_package(dir: &Path, info: &PackageBackupInfo) -> PackageVerifyReport {
+    let mut report = PackageVerifyReport::default();
+    let metadata_path = dir.join("metadata.cbor");
+    match tokio::fs::read(&metadata_path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, metadata_path.display().to_string()))
+        .and_then(|bytes| IoFormat::Cbor.from_slice::<BackupMetadata>(&bytes))
+    {
+        Ok(metadata) if metadata.timestamp != info.timestamp => report.errors.push(format!(
+            "metadata.cbor is from {}, but the backup was taken at {}",
+            metadata.timestamp, info.timestamp
+        )),
+        Ok(_) => (),
+        Err(e) => report.errors.push(e.to_string()),
+    }
+    let checksums_path = dir.join(CHECKSUMS_FILE);
+    let checksums: Checksums = match tokio::fs::read(&checksums_path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, checksums_path.display().to_string()))
+        .and_then(|bytes| IoFormat::Cbor.from_slice(&bytes))
+    {
+        Ok(a) => a,
+        Err(e) => {
+            report.errors.push(e.to_string());
+            return report;
+        }
+    };
+    for (rel, expected) in checksums {
+        report.files_checked += 1;
+        match hash_file(&dir.join(&rel)).await {
+            Ok(actual) if actual == expected => (),
+            Ok(_) => report
+                .errors
+                .push(format!("{}: checksum mismatch", rel.display())),
+            Err(e) => report.errors.push(e.to_string()),
+        }
+    }
+    report
+}
+
+fn display_verify_report(report: BackupVerifyReport, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(report, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "ID", "FILES CHECKED", "RESULT"]);
+    table.add_row(row![
+        "EMBASSY OS",
+        "1",
+        report.os_backup_error.as_deref().unwrap_or("OK"),
+    ]);
+    for (id, package) in report.packages {
+        let row = row![
+            id.as_str(),
+            &package.files_checked.to_string(),
+            &if package.errors.is_empty() {
+                "OK".to_owned()
+            } else {
+                package.errors.join("\n")
+            },
+        ];
+        table.add_row(row);
+    }
+    table.print_tty(false).unwrap();
+}
+
+/// Reads back everything on a backup target and checks it against the checksums recorded when
+/// the backup was made, without restoring anything
+#[command(display(display_verify_report))]
+#[instrument(skip_all)]
+pub async fn verify(
+    #[context] ctx: RpcContext,
+    #[arg(rename = "target-id")] target_id: BackupTargetId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+    #[arg] password: String,
+) -> Result<BackupVerifyReport, Error> {
+    let guard = target_id
+        .load(&mut ctx.secret_store.acquire().await?)
+        .await?
+        .mount_backup(&password)
+        .await?;
+
+    let mut report = BackupVerifyReport {
+        os_backup_error: verify_os_backup(guard.as_ref())
+            .await
+            .err()
+            .map(|e| e.to_string()),
+        packages: BTreeMap::new(),
+    };
+    for (id, info) in &guard.metadata.package_backups {
+        let package_report = match guard.fetch(id).await {
+            Ok(()) => verify_package(&guard.as_ref().join(id), info).await,
+            Err(e) => PackageVerifyReport {
+                files_checked: 0,
+                errors: vec![e.to_string()],
+            },
+        };
+        report.packages.insert(id.clone(), package_report);
+    }
+
+    guard.unmount().await?;
+
+    Ok(report)
+}
@@ -0,0 +1,83 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use embassy::net::web_server::WebServer;
+use embassy::registry::server::registry_router;
+use embassy::registry::Registry;
+use embassy::util::logger::EmbassyLogger;
+use embassy::version::{Current, VersionT};
+use embassy::{Error, ErrorKind, ResultExt};
+
+lazy_static::lazy_static! {
+    static ref VERSION_STRING: String = Current::new().semver().to_string();
+}
+
+async fn inner_main(bind: SocketAddr, registry: Registry) -> Result<(), Error> {
+    let registry = Arc::new(registry);
+    // index up front, so that problems with the directory show up immediately
+    registry.index().await?;
+    let server = WebServer::new(bind, registry_router(registry));
+    tracing::info!("Serving registry on {}", bind);
+    tokio::signal::ctrl_c()
+        .await
+        .with_kind(ErrorKind::Unknown)?;
+    server.shutdown().await;
+    Ok(())
+}
+
+fn main() {
+    let matches = clap::App::new("Embassy Registry")
+        .version(&**VERSION_STRING)
+        .about("Serves a directory of s9pk files as a marketplace")
+        .arg(
+            clap::Arg::with_name("dir")
+                .required(true)
+                .takes_value(true)
+                .help("Directory containing s9pk files"),
+        )
+        .arg(
+            clap::Arg::with_name("bind")
+                .short('b')
+                .long("bind")
+                .takes_value(true)
+                .default_value("0.0.0.0:8080"),
+        )
+        .arg(
+            clap::Arg::with_name("name")
+                .short('n')
+                .long("name")
+                .takes_value(true)
+                .default_value("Local Registry"),
+        )
+        .get_matches();
+
+    if let Err(_) = std::env::var("RUST_LOG") {
+        std::env::set_var("RUST_LOG", "embassy=info");
+    }
+    EmbassyLogger::init();
+
+    let res = (|| {
+        let bind: SocketAddr = matches
+            .value_of("bind")
+            .unwrap()
+            .parse()
+            .with_kind(ErrorKind::ParseNetAddress)?;
+        let registry = Registry::new(
+            matches.value_of("name").unwrap().to_owned(),
+            PathBuf::from(matches.value_of("dir").unwrap()),
+        );
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("failed to initialize runtime");
+        rt.block_on(inner_main(bind, registry))
+    })();
+
+    if let Err(e) = res {
+        eprintln!("{}", e.source);
+        tracing::debug!("{:?}", e.source);
+        drop(e.source);
+        std::process::exit(e.kind as i32)
+    }
+}
@@ -2,7 +2,11 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use color_eyre::eyre::eyre;
+use embassy::backup::schedule::launch_backup_scheduler;
 use embassy::context::{DiagnosticContext, RpcContext};
+use embassy::metrics::launch_metrics_history_task;
+use embassy::net::acme::launch_acme_renewal_task;
+use embassy::net::dns_forward::launch_blocklist_refresh_task;
 use embassy::net::web_server::WebServer;
 use embassy::shutdown::Shutdown;
 use embassy::system::launch_metrics_task;
@@ -66,6 +70,38 @@ async fn inner_main(cfg_path: Option<PathBuf>) -> Result<Option<Shutdown>, Error
             .await
         });
 
+        let metrics_history_ctx = rpc_ctx.clone();
+        let metrics_history_task = tokio::spawn(async move {
+            launch_metrics_history_task(
+                &metrics_history_ctx,
+                metrics_history_ctx.shutdown.subscribe(),
+            )
+            .await
+        });
+
+        let backup_scheduler_ctx = rpc_ctx.clone();
+        let backup_scheduler_task = tokio::spawn(async move {
+            launch_backup_scheduler(
+                &backup_scheduler_ctx,
+                backup_scheduler_ctx.shutdown.subscribe(),
+            )
+            .await
+        });
+
+        let acme_renewal_ctx = rpc_ctx.clone();
+        let acme_renewal_task = tokio::spawn(async move {
+            launch_acme_renewal_task(&acme_renewal_ctx, acme_renewal_ctx.shutdown.subscribe()).await
+        });
+
+        let blocklist_refresh_ctx = rpc_ctx.clone();
+        let blocklist_refresh_task = tokio::spawn(async move {
+            launch_blocklist_refresh_task(
+                &blocklist_refresh_ctx,
+                blocklist_refresh_ctx.shutdown.subscribe(),
+            )
+            .await
+        });
+
         embassy::sound::CHIME.play().await?;
 
         metrics_task
@@ -78,6 +114,46 @@ async fn inner_main(cfg_path: Option<PathBuf>) -> Result<Option<Shutdown>, Error
             .map_ok(|_| tracing::debug!("Metrics daemon Shutdown"))
             .await?;
 
+        metrics_history_task
+            .map_err(|e| {
+                Error::new(
+                    eyre!("{}", e).wrap_err("Metrics history daemon panicked!"),
+                    ErrorKind::Unknown,
+                )
+            })
+            .map_ok(|_| tracing::debug!("Metrics history daemon Shutdown"))
+            .await?;
+
+        backup_scheduler_task
+            .map_err(|e| {
+                Error::new(
+                    eyre!("{}", e).wrap_err("Backup scheduler panicked!"),
+                    ErrorKind::Unknown,
+                )
+            })
+            .map_ok(|_| tracing::debug!("Backup scheduler Shutdown"))
+            .await?;
+
+        acme_renewal_task
+            .map_err(|e| {
+                Error::new(
+                    eyre!("{}", e).wrap_err("ACME renewal daemon panicked!"),
+                    ErrorKind::Unknown,
+                )
+            })
+            .map_ok(|_| tracing::debug!("ACME renewal daemon Shutdown"))
+            .await?;
+
+        blocklist_refresh_task
+            .map_err(|e| {
+                Error::new(
+                    eyre!("{}", e).wrap_err("DNS blocklist refresh daemon panicked!"),
+                    ErrorKind::Unknown,
+                )
+            })
+            .map_ok(|_| tracing::debug!("DNS blocklist refresh daemon Shutdown"))
+            .await?;
+
         let shutdown = shutdown_recv
             .recv()
             .await
@@ -0,0 +1,498 @@
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use itertools::Itertools;
+use rpc_toolkit::command;
+use rpc_toolkit::command_helpers::prelude::RequestParts;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Executor, PgPool, Postgres};
+use tracing::instrument;
+
+use super::spec::{ConfigSpec, ValueSpecAny, ValueSpecList, ValueSpecUnion};
+use super::{set_dry, set_impl, Config, ConfigGetReceipts};
+use crate::context::RpcContext;
+use crate::middleware::auth::HashSessionToken;
+use crate::s9pk::manifest::PackageId;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Version};
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Revisions kept per package, older ones are pruned
+const CONFIG_HISTORY_REVISIONS: i32 = 50;
+const MASKED: &str = "********";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigRevision {
+    pub revision: i32,
+    pub created_at: DateTime<Utc>,
+    /// Version of the package the config was set on
+    pub version: Version,
+    /// Hash of the session that set the config, if it was not set by the system
+    pub session: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigChange {
+    /// Keys from the root of the config
+    pub path: Vec<String>,
+    /// Names of the keys in the config spec, for display
+    pub name: Vec<String>,
+    /// `None` if the value did not exist
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+/// Configs set in a database transaction, to be recorded once it has committed, so that the
+/// history never has a revision the database rolled back
+#[derive(Debug, Default)]
+pub struct PendingHistory(Vec<(PackageId, Version, Config)>);
+impl PendingHistory {
+    pub fn push(&mut self, id: PackageId, version: Version, config: Config) {
+        self.0.push((id, version, config))
+    }
+
+    pub fn append(&mut self, other: PendingHistory) {
+        self.0.extend(other.0)
+    }
+
+    /// Failures are only logged, since the configs have already been set
+    pub async fn record(self, secrets: &PgPool, session: Option<&str>) {
+        for (id, version, config) in self.0 {
+            if let Err(e) = record(secrets, &id, &version, session, &config).await {
+                tracing::error!("Could not record config history of {}: {}", id, e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+    }
+}
+
+/// Stores `config` as the newest revision, unless it is the same as the newest revision already
+#[instrument(skip_all)]
+pub async fn record(
+    secrets: &PgPool,
+    id: &PackageId,
+    version: &Version,
+    session: Option<&str>,
+    config: &Config,
+) -> Result<Option<i32>, Error> {
+    let latest = sqlx::query!(
+        "SELECT revision, config FROM config_history WHERE package_id = $1 ORDER BY revision DESC LIMIT 1",
+        id.as_str(),
+    )
+    .fetch_optional(secrets)
+    .await?;
+    if let Some(latest) = &latest {
+        if serde_json::from_str::<Config>(&latest.config).ok().as_ref() == Some(config) {
+            return Ok(None);
+        }
+    }
+    let revision = latest.map_or(1, |r| r.revision + 1);
+    sqlx::query!(
+        "INSERT INTO config_history (package_id, revision, created_at, version, session, config) VALUES ($1, $2, $3, $4, $5, $6)",
+        id.as_str(),
+        revision,
+        Utc::now().naive_utc(),
+        version.to_string(),
+        session,
+        serde_json::to_string(config).with_kind(ErrorKind::Serialization)?,
+    )
+    .execute(secrets)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM config_history WHERE package_id = $1 AND revision <= $2",
+        id.as_str(),
+        revision - CONFIG_HISTORY_REVISIONS,
+    )
+    .execute(secrets)
+    .await?;
+    Ok(Some(revision))
+}
+
+#[instrument(skip_all)]
+pub async fn remove<Ex>(secrets: &mut Ex, id: &PackageId) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!(
+        "DELETE FROM config_history WHERE package_id = $1",
+        id.as_str()
+    )
+    .execute(secrets)
+    .await?;
+    Ok(())
+}
+
+async fn load(secrets: &PgPool, id: &PackageId, revision: i32) -> Result<Config, Error> {
+    let config = sqlx::query!(
+        "SELECT config FROM config_history WHERE package_id = $1 AND revision = $2",
+        id.as_str(),
+        revision,
+    )
+    .fetch_optional(secrets)
+    .await?
+    .ok_or_else(|| {
+        Error::new(
+            eyre!("Config Revision {} of {} Not Found", revision, id),
+            ErrorKind::NotFound,
+        )
+    })?
+    .config;
+    serde_json::from_str(&config).with_kind(ErrorKind::Deserialization)
+}
+
+fn mask_config(spec: &ConfigSpec, config: &Config) -> Value {
+    let mut res = config.clone();
+    for (key, value_spec) in spec.0.iter() {
+        if let Some(value) = res.get_mut(key) {
+            *value = mask(value_spec, value);
+        }
+    }
+    Value::Object(res)
+}
+
+fn mask_union(union: &ValueSpecUnion, value: &Value) -> Value {
+    match value {
+        Value::Object(obj) => match obj
+            .get(&union.tag.id)
+            .and_then(|tag| tag.as_str())
+            .and_then(|tag| union.variants.get(tag))
+        {
+            Some(variant) => mask_config(variant, obj),
+            None => value.clone(),
+        },
+        _ => value.clone(),
+    }
+}
+
+/// `value` with every masked string under it hidden, following the spec into objects, lists and
+/// whichever variant each union has
+fn mask(spec: &ValueSpecAny, value: &Value) -> Value {
+    match (spec, value) {
+        (_, Value::Null) => Value::Null,
+        (ValueSpecAny::String(s), _) if s.inner.inner.inner.masked => {
+            Value::String(MASKED.to_owned())
+        }
+        (ValueSpecAny::Object(o), Value::Object(obj)) => mask_config(&o.inner.spec, obj),
+        (ValueSpecAny::Union(u), _) => mask_union(&u.inner.inner, value),
+        (ValueSpecAny::List(l), Value::Array(items)) => Value::Array(
+            items
+                .iter()
+                .map(|item| match (l, item) {
+                    (ValueSpecList::String(s), Value::String(_)) if s.inner.inner.spec.masked => {
+                        Value::String(MASKED.to_owned())
+                    }
+                    (ValueSpecList::Object(o), Value::Object(obj)) => {
+                        mask_config(&o.inner.inner.spec.spec, obj)
+                    }
+                    (ValueSpecList::Union(u), _) => mask_union(&u.inner.inner.spec.inner, item),
+                    _ => item.clone(),
+                })
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+/// `spec` is `None` for values the spec does not know about
+fn push_change(
+    changes: &mut Vec<ConfigChange>,
+    path: &[String],
+    name: &[String],
+    old: &Value,
+    new: &Value,
+    spec: Option<&ValueSpecAny>,
+) {
+    let show = |v: &Value| match (v, spec) {
+        (Value::Null, _) => None,
+        (v, Some(spec)) => Some(mask(spec, v)),
+        (v, None) => Some(v.clone()),
+    };
+    changes.push(ConfigChange {
+        path: path.to_vec(),
+        name: name.to_vec(),
+        old: show(old),
+        new: show(new),
+    });
+}
+
+fn diff_rec(
+    spec: &ConfigSpec,
+    old: &Config,
+    new: &Config,
+    path: &mut Vec<String>,
+    name: &mut Vec<String>,
+    changes: &mut Vec<ConfigChange>,
+) {
+    for (key, value_spec) in spec.0.iter() {
+        let old_value = old.get(key).unwrap_or(&Value::Null);
+        let new_value = new.get(key).unwrap_or(&Value::Null);
+        if old_value == new_value {
+            continue;
+        }
+        path.push(key.clone());
+        name.push(value_spec.name().to_owned());
+        match (value_spec, old_value, new_value) {
+            (ValueSpecAny::Object(o), Value::Object(old_obj), Value::Object(new_obj)) => {
+                diff_rec(&o.inner.spec, old_obj, new_obj, path, name, changes)
+            }
+            (ValueSpecAny::Union(u), Value::Object(old_obj), Value::Object(new_obj))
+                if old_obj.get(&u.inner.inner.tag.id) == new_obj.get(&u.inner.inner.tag.id) =>
+            {
+                let variant = new_obj
+                    .get(&u.inner.inner.tag.id)
+                    .and_then(|tag| tag.as_str())
+                    .and_then(|tag| u.inner.inner.variants.get(tag));
+                if let Some(variant) = variant {
+                    diff_rec(variant, old_obj, new_obj, path, name, changes)
+                } else {
+                    push_change(changes, path, name, old_value, new_value, Some(value_spec))
+                }
+            }
+            _ => push_change(changes, path, name, old_value, new_value, Some(value_spec)),
+        }
+        path.pop();
+        name.pop();
+    }
+    // values the spec no longer knows about
+    for key in old
+        .keys()
+        .chain(new.keys())
+        .filter(|key| !spec.0.contains_key(*key))
+        .unique()
+    {
+        let old_value = old.get(key).unwrap_or(&Value::Null);
+        let new_value = new.get(key).unwrap_or(&Value::Null);
+        if old_value != new_value {
+            path.push(key.clone());
+            name.push(key.clone());
+            push_change(changes, path, name, old_value, new_value, None);
+            path.pop();
+            name.pop();
+        }
+    }
+}
+
+/// Changes from `old` to `new`, descending into objects and unions that keep their variant, with
+/// masked strings hidden wherever they are nested
+pub fn diff_configs(spec: &ConfigSpec, old: &Config, new: &Config) -> Vec<ConfigChange> {
+    let mut changes = Vec::new();
+    diff_rec(
+        spec,
+        old,
+        new,
+        &mut Vec::new(),
+        &mut Vec::new(),
+        &mut changes,
+    );
+    changes
+}
+
+/// Newest first
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn history(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<ConfigRevision>, Error> {
+    sqlx::query!(
+        "SELECT revision, created_at, version, session FROM config_history WHERE package_id = $1 ORDER BY revision DESC",
+        id.as_str(),
+    )
+    .fetch_all(&ctx.secret_store)
+    .await?
+    .into_iter()
+    .map(|r| {
+        Ok(ConfigRevision {
+            revision: r.revision,
+            created_at: DateTime::from_utc(r.created_at, Utc),
+            version: r.version.parse()?,
+            session: r.session,
+        })
+    })
+    .collect()
+}
+
+/// Changes from revision `from` to revision `to`, or to the current config if `to` is not passed
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn diff(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[arg] from: i32,
+    #[arg] to: Option<i32>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<ConfigChange>, Error> {
+    let mut db = ctx.db.handle();
+    let receipts = ConfigGetReceipts::new(&mut db, &id).await?;
+    let action = receipts
+        .manifest_config
+        .get(&mut db)
+        .await?
+        .ok_or_else(|| Error::new(eyre!("{} has no config", id), ErrorKind::NotFound))?;
+    let volumes = receipts.manifest_volumes.get(&mut db).await?;
+    let version = receipts.manifest_version.get(&mut db).await?;
+    let current = action.get(&ctx, &id, &version, &volumes).await?;
+    let old = load(&ctx.secret_store, &id, from).await?;
+    let new = if let Some(to) = to {
+        load(&ctx.secret_store, &id, to).await?
+    } else {
+        current.config.unwrap_or_default()
+    };
+    Ok(diff_configs(&current.spec, &old, &new))
+}
+
+/// Sets the config of revision `revision` again, failing if that would break a dependent unless
+/// `--force` is passed
+#[command(display(display_none), metadata(sync_db = true))]
+#[instrument(skip_all)]
+pub async fn rollback(
+    #[context] ctx: RpcContext,
+    #[request] req: &RequestParts,
+    #[parent_data] id: PackageId,
+    #[arg] revision: i32,
+    #[arg(long = "force", default)] force: bool,
+) -> Result<(), Error> {
+    let config = load(&ctx.secret_store, &id, revision).await?;
+    let breakages = set_dry(ctx.clone(), (id.clone(), Some(config.clone()), None, None)).await?;
+    if !breakages.0.is_empty() && !force {
+        return Err(Error::new(
+            eyre!(
+                "Rolling back {} to revision {} would break {}",
+                id,
+                revision,
+                breakages.0.keys().join(", ")
+            ),
+            ErrorKind::Dependency,
+        ));
+    }
+    let session = HashSessionToken::from_request_parts(req)
+        .ok()
+        .map(|s| s.as_hash());
+    set_impl(ctx, (id, Some(config), None, session)).await
+}
+
+#[test]
+fn test_diff() {
+    let spec: ConfigSpec = serde_json::from_value(serde_json::json!({
+        "rpc": {
+            "type": "object",
+            "name": "RPC Settings",
+            "description": null,
+            "spec": {
+                "username": {
+                    "type": "string",
+                    "name": "Username",
+                    "description": null,
+                    "nullable": false,
+                    "default": "bitcoin",
+                    "masked": false,
+                    "copyable": false
+                },
+                "password": {
+                    "type": "string",
+                    "name": "Password",
+                    "description": null,
+                    "nullable": false,
+                    "default": "secret",
+                    "masked": true,
+                    "copyable": true
+                }
+            }
+        },
+        "txindex": {
+            "type": "boolean",
+            "name": "Transaction Index",
+            "description": null,
+            "default": false
+        }
+    }))
+    .unwrap();
+    let old: Config = serde_json::from_value(serde_json::json!({
+        "rpc": { "username": "bitcoin", "password": "hunter2" },
+        "txindex": false,
+        "legacy": 1
+    }))
+    .unwrap();
+    let new: Config = serde_json::from_value(serde_json::json!({
+        "rpc": { "username": "satoshi", "password": "hunter3" },
+        "txindex": false
+    }))
+    .unwrap();
+    let changes = diff_configs(&spec, &old, &new);
+    assert_eq!(changes.len(), 3);
+    assert_eq!(changes[0].path, vec!["rpc", "username"]);
+    assert_eq!(changes[0].name, vec!["RPC Settings", "Username"]);
+    assert_eq!(changes[0].new, Some(Value::String("satoshi".to_owned())));
+    assert_eq!(changes[1].old, Some(Value::String(MASKED.to_owned())));
+    assert_eq!(changes[2].path, vec!["legacy"]);
+    assert_eq!(changes[2].new, None);
+}
+
+#[test]
+fn test_diff_masks_nested() {
+    let password = serde_json::json!({
+        "type": "string",
+        "name": "Password",
+        "description": null,
+        "nullable": false,
+        "default": null,
+        "masked": true,
+        "copyable": false
+    });
+    let spec: ConfigSpec = serde_json::from_value(serde_json::json!({
+        "users": {
+            "type": "list",
+            "subtype": "object",
+            "name": "Users",
+            "description": null,
+            "range": "[0,*)",
+            "default": [],
+            "spec": {
+                "spec": { "password": password },
+                "display-as": null
+            }
+        },
+        "auth": {
+            "type": "union",
+            "name": "Authentication",
+            "description": null,
+            "default": "none",
+            "tag": "type",
+            "variants": {
+                "none": {},
+                "password": { "password": password }
+            },
+            "display-as": null
+        }
+    }))
+    .unwrap();
+    let old: Config = serde_json::from_value(serde_json::json!({
+        "users": [{ "password": "hunter2" }],
+        "auth": { "type": "none" }
+    }))
+    .unwrap();
+    let new: Config = serde_json::from_value(serde_json::json!({
+        "users": [{ "password": "hunter3" }],
+        "auth": { "type": "password", "password": "hunter4" }
+    }))
+    .unwrap();
+    let changes = diff_configs(&spec, &old, &new);
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].path, vec!["users"]);
+    assert_eq!(
+        changes[0].old,
+        Some(serde_json::json!([{ "password": MASKED }]))
+    );
+    assert_eq!(changes[1].path, vec!["auth"]);
+    assert_eq!(
+        changes[1].new,
+        Some(serde_json::json!({ "type": "password", "password": MASKED }))
+    );
+}
@@ -10,6 +10,7 @@ use patch_db::{DbHandle, LockReceipt, LockTarget, LockTargetId, LockType, Verifi
 use rand::SeedableRng;
 use regex::Regex;
 use rpc_toolkit::command;
+use rpc_toolkit::command_helpers::prelude::RequestParts;
 use serde_json::Value;
 use tracing::instrument;
 
@@ -21,6 +22,7 @@ use crate::dependencies::{
     DependencyErrors, DependencyReceipt, TaggedDependencyError, TryHealReceipts,
 };
 use crate::install::cleanup::{remove_from_current_dependents_lists, UpdateDependencyReceipts};
+use crate::middleware::auth::HashSessionToken;
 use crate::procedure::docker::DockerContainers;
 use crate::s9pk::manifest::{Manifest, PackageId};
 use crate::util::display_none;
@@ -28,6 +30,7 @@ use crate::util::serde::{display_serializable, parse_stdin_deserializable, IoFor
 use crate::Error;
 
 pub mod action;
+pub mod history;
 pub mod spec;
 pub mod util;
 
@@ -35,6 +38,7 @@ pub use spec::{ConfigSpec, Defaultable};
 use util::NumRange;
 
 use self::action::{ConfigActions, ConfigRes};
+use self::history::PendingHistory;
 use self::spec::{ConfigPointerReceipts, PackagePointerSpec, ValueSpecPointer};
 
 pub type Config = serde_json::Map<String, Value>;
@@ -159,7 +163,7 @@ pub fn verify_spec(#[arg] path: PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
-#[command(subcommands(get, set))]
+#[command(subcommands(get, set, history::history, history::diff, history::rollback))]
 pub fn config(#[arg] id: PackageId) -> Result<PackageId, Error> {
     Ok(id)
 }
@@ -242,14 +246,18 @@ pub async fn get(
 )]
 #[instrument(skip_all)]
 pub fn set(
+    #[request] req: &RequestParts,
     #[parent_data] id: PackageId,
     #[allow(unused_variables)]
     #[arg(long = "format")]
     format: Option<IoFormat>,
     #[arg(long = "timeout")] timeout: Option<crate::util::serde::Duration>,
     #[arg(stdin, parse(parse_stdin_deserializable))] config: Option<Config>,
-) -> Result<(PackageId, Option<Config>, Option<Duration>), Error> {
-    Ok((id, config, timeout.map(|d| *d)))
+) -> Result<(PackageId, Option<Config>, Option<Duration>, Option<String>), Error> {
+    let session = HashSessionToken::from_request_parts(req)
+        .ok()
+        .map(|s| s.as_hash());
+    Ok((id, config, timeout.map(|d| *d), session))
 }
 
 /// So, the new locking finds all the possible locks and lifts them up into a bundle of locks.
@@ -416,7 +424,12 @@ impl ConfigReceipts {
 #[instrument(skip_all)]
 pub async fn set_dry(
     #[context] ctx: RpcContext,
-    #[parent_data] (id, config, timeout): (PackageId, Option<Config>, Option<Duration>),
+    #[parent_data] (id, config, timeout, _): (
+        PackageId,
+        Option<Config>,
+        Option<Duration>,
+        Option<String>,
+    ),
 ) -> Result<BreakageRes, Error> {
     let mut db = ctx.db.handle();
     let mut tx = db.begin().await?;
@@ -443,13 +456,13 @@ pub async fn set_dry(
 #[instrument(skip_all)]
 pub async fn set_impl(
     ctx: RpcContext,
-    (id, config, timeout): (PackageId, Option<Config>, Option<Duration>),
+    (id, config, timeout, session): (PackageId, Option<Config>, Option<Duration>, Option<String>),
 ) -> Result<(), Error> {
     let mut db = ctx.db.handle();
     let mut tx = db.begin().await?;
     let mut breakages = BTreeMap::new();
     let locks = ConfigReceipts::new(&mut tx).await?;
-    configure(
+    let history = configure(
         &ctx,
         &mut tx,
         &id,
@@ -462,6 +475,7 @@ pub async fn set_impl(
     )
     .await?;
     tx.commit().await?;
+    history.record(&ctx.secret_store, session.as_deref()).await;
     Ok(())
 }
 
@@ -476,13 +490,21 @@ pub async fn configure<'a, Db: DbHandle>(
     overrides: &mut BTreeMap<PackageId, Config>,
     breakages: &mut BTreeMap<PackageId, TaggedDependencyError>,
     receipts: &ConfigReceipts,
-) -> Result<(), Error> {
+) -> Result<PendingHistory, Error> {
     configure_rec(
         ctx, db, id, config, timeout, dry_run, overrides, breakages, receipts,
     )
     .await?;
     receipts.configured.set(db, true, &id).await?;
-    Ok(())
+    let mut history = PendingHistory::default();
+    if !dry_run {
+        // dependents whose pointers changed were configured too
+        for (id, config) in overrides.iter() {
+            let version = receipts.version.get(db, id).await?.ok_or_else(not_found)?;
+            history.push(id.clone(), version, config.clone());
+        }
+    }
+    Ok(history)
 }
 
 #[instrument(skip_all)]
@@ -25,6 +25,7 @@ use crate::db::model::{Database, InstalledPackageDataEntry, PackageDataEntry};
 use crate::disk::OsPartitionInfo;
 use crate::init::{init_postgres, pgloader};
 use crate::install::cleanup::{cleanup_failed, uninstall, CleanupFailedReceipts};
+use crate::logs::forward::LogForwarder;
 use crate::manager::ManagerMap;
 use crate::middleware::auth::HashSessionToken;
 use crate::net::net_controller::NetController;
@@ -123,9 +124,11 @@ pub struct RpcContextSeed {
     pub net_controller: Arc<NetController>,
     pub managers: ManagerMap,
     pub metrics_cache: RwLock<Option<crate::system::Metrics>>,
+    pub metrics_history: RwLock<crate::metrics::MetricsHistory>,
     pub shutdown: broadcast::Sender<Option<Shutdown>>,
     pub tor_socks: SocketAddr,
     pub notification_manager: NotificationManager,
+    pub log_forwarder: LogForwarder,
     pub open_authed_websockets: Mutex<BTreeMap<HashSessionToken, Vec<oneshot::Sender<()>>>>,
     pub rpc_stream_continuations: Mutex<BTreeMap<RequestGuid, RpcContinuation>>,
     pub wifi_manager: Option<Arc<RwLock<WpaCli>>>,
@@ -210,8 +213,20 @@ impl RpcContext {
         tracing::info!("Initialized Net Controller");
         let managers = ManagerMap::default();
         let metrics_cache = RwLock::new(None);
-        let notification_manager = NotificationManager::new(secret_store.clone());
+        let notification_manager = NotificationManager::new(secret_store.clone(), tor_proxy);
         tracing::info!("Initialized Notification Manager");
+        let log_forwarder = LogForwarder::new(
+            secret_store.clone(),
+            tor_proxy,
+            account.hostname.0.clone(),
+            base.log_server.as_ref(),
+        );
+        if let Err(e) = log_forwarder.init().await {
+            tracing::error!("Error initializing Log Forwarder: {}", e);
+            tracing::debug!("{:?}", e);
+        } else {
+            tracing::info!("Initialized Log Forwarder");
+        }
         let seed = Arc::new(RpcContextSeed {
             is_closed: AtomicBool::new(false),
             datadir: base.datadir().to_path_buf(),
@@ -226,9 +241,11 @@ impl RpcContext {
             net_controller,
             managers,
             metrics_cache,
+            metrics_history: RwLock::new(Default::default()),
             shutdown,
             tor_socks: tor_proxy,
             notification_manager,
+            log_forwarder,
             open_authed_websockets: Mutex::new(BTreeMap::new()),
             rpc_stream_continuations: Mutex::new(BTreeMap::new()),
             wifi_manager: base
@@ -257,6 +274,10 @@ impl RpcContext {
             )
             .await?;
         tracing::info!("Initialized Package Managers");
+        if let Err(e) = crate::net::wireguard::sync(&res).await {
+            tracing::error!("Error Starting WireGuard Server: {}", e);
+            tracing::debug!("{:?}", e);
+        }
         Ok(res)
     }
 
@@ -16,10 +16,15 @@ use serde_json::Value;
 use ssh_key::public::Ed25519PublicKey;
 
 use crate::account::AccountInfo;
+use crate::backup::schedule::BackupSchedule;
 use crate::config::spec::{PackagePointerSpec, SystemPointerSpec};
 use crate::install::progress::InstallProgress;
-use crate::net::interface::InterfaceId;
+use crate::keyring::Keyring;
+use crate::manager::restart::{RestartEvent, RestartPolicy};
+use crate::net::interface::{ClearnetAddress, InterfaceId};
 use crate::net::utils::{get_iface_ipv4_addr, get_iface_ipv6_addr};
+use crate::prometheus::MetricsExporter;
+use crate::resources::ResourceLimits;
 use crate::s9pk::manifest::{Manifest, ManifestModel, PackageId};
 use crate::status::health_check::HealthCheckId;
 use crate::status::Status;
@@ -46,6 +51,10 @@ impl Database {
                 version: Current::new().semver().into(),
                 hostname: Some(account.hostname.no_dot_host_name()),
                 last_backup: None,
+                backup_schedules: BTreeMap::new(),
+                keyring: Keyring::default(),
+                metrics_exporter: MetricsExporter::default(),
+                dns_lan: false,
                 last_wifi_region: None,
                 eos_version_compat: Current::new().compat().clone(),
                 lan_address,
@@ -100,6 +109,15 @@ pub struct ServerInfo {
     pub hostname: Option<String>,
     pub version: Version,
     pub last_backup: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub backup_schedules: BTreeMap<String, BackupSchedule>,
+    #[serde(default)]
+    pub keyring: Keyring,
+    #[serde(default)]
+    pub metrics_exporter: MetricsExporter,
+    /// Serve DNS on the LAN addresses of the box, not just to itself and its packages
+    #[serde(default)]
+    pub dns_lan: bool,
     /// Used in the wifi to determine the region to set the system to
     pub last_wifi_region: Option<CountryCode>,
     pub eos_version_compat: VersionRange,
@@ -141,8 +159,17 @@ impl IpInfo {
 }
 
 #[derive(Debug, Default, Deserialize, Serialize, HasModel)]
+#[serde(rename_all = "kebab-case")]
 pub struct BackupProgress {
     pub complete: bool,
+    /// Bytes written to the package's backup directory so far
+    #[serde(default)]
+    pub bytes: u64,
+    /// Estimated size of the backup, based on the size of the package's data
+    #[serde(default)]
+    pub total_bytes: Option<u64>,
+    #[serde(default)]
+    pub eta: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize, HasModel)]
@@ -326,6 +353,12 @@ pub struct InstalledPackageDataEntry {
     pub current_dependencies: CurrentDependencies,
     #[model]
     pub interface_addresses: InterfaceAddressMap,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub restart_history: Vec<RestartEvent>,
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -390,7 +423,7 @@ pub struct CurrentDependencyInfo {
     pub health_checks: BTreeSet<HealthCheckId>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct InterfaceAddressMap(pub BTreeMap<InterfaceId, InterfaceAddresses>);
 impl Map for InterfaceAddressMap {
     type Key = InterfaceId;
@@ -403,13 +436,16 @@ impl HasModel for InterfaceAddressMap {
     type Model = MapModel<Self>;
 }
 
-#[derive(Debug, Deserialize, Serialize, HasModel)]
+#[derive(Clone, Debug, Deserialize, Serialize, HasModel)]
 #[serde(rename_all = "kebab-case")]
 pub struct InterfaceAddresses {
     #[model]
     pub tor_address: Option<String>,
     #[model]
     pub lan_address: Option<String>,
+    /// Owner-assigned clearnet domains, by domain
+    #[serde(default)]
+    pub clearnet_addresses: BTreeMap<String, ClearnetAddress>,
 }
 
 #[derive(Debug, Deserialize, Serialize, HasModel)]
@@ -11,14 +11,17 @@ use patch_db::{
 };
 use rand::SeedableRng;
 use rpc_toolkit::command;
+use rpc_toolkit::command_helpers::prelude::RequestParts;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
 use crate::config::action::{ConfigActions, ConfigRes};
+use crate::config::history::PendingHistory;
 use crate::config::spec::PackagePointerSpec;
 use crate::config::{not_found, Config, ConfigReceipts, ConfigSpec};
 use crate::context::RpcContext;
 use crate::db::model::{CurrentDependencies, CurrentDependents, InstalledPackageDataEntry};
+use crate::middleware::auth::HashSessionToken;
 use crate::procedure::docker::DockerContainers;
 use crate::procedure::{NoOutput, PackageProcedure, ProcedureName};
 use crate::s9pk::manifest::{Manifest, PackageId};
@@ -650,16 +653,20 @@ impl DependencyConfigReceipts {
     subcommands(self(configure_impl(async)), configure_dry),
     display(display_none)
 )]
-pub async fn configure(
+pub fn configure(
+    #[request] req: &RequestParts,
     #[arg(rename = "dependent-id")] dependent_id: PackageId,
     #[arg(rename = "dependency-id")] dependency_id: PackageId,
-) -> Result<(PackageId, PackageId), Error> {
-    Ok((dependent_id, dependency_id))
+) -> Result<(PackageId, PackageId, Option<String>), Error> {
+    let session = HashSessionToken::from_request_parts(req)
+        .ok()
+        .map(|s| s.as_hash());
+    Ok((dependent_id, dependency_id, session))
 }
 
 pub async fn configure_impl(
     ctx: RpcContext,
-    (pkg_id, dep_id): (PackageId, PackageId),
+    (pkg_id, dep_id, session): (PackageId, PackageId, Option<String>),
 ) -> Result<(), Error> {
     let mut db = ctx.db.handle();
     let receipts = DependencyConfigReceipts::new(&mut db, &pkg_id, &dep_id).await?;
@@ -670,7 +677,7 @@ pub async fn configure_impl(
     } = configure_logic(ctx.clone(), &mut db, (pkg_id, dep_id.clone()), &receipts).await?;
 
     let locks = &receipts.config;
-    Ok(crate::config::configure(
+    crate::config::configure(
         &ctx,
         &mut db,
         &dep_id,
@@ -681,7 +688,10 @@ pub async fn configure_impl(
         &mut BTreeMap::new(),
         locks,
     )
-    .await?)
+    .await?
+    .record(&ctx.secret_store, session.as_deref())
+    .await;
+    Ok(())
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -696,7 +706,7 @@ pub struct ConfigDryRes {
 #[instrument(skip_all)]
 pub async fn configure_dry(
     #[context] ctx: RpcContext,
-    #[parent_data] (pkg_id, dependency_id): (PackageId, PackageId),
+    #[parent_data] (pkg_id, dependency_id, _): (PackageId, PackageId, Option<String>),
 ) -> Result<ConfigDryRes, Error> {
     let mut db = ctx.db.handle();
     let receipts = DependencyConfigReceipts::new(&mut db, &pkg_id, &dependency_id).await?;
@@ -1045,7 +1055,8 @@ pub async fn reconfigure_dependents_with_live_pointers(
     mut tx: impl DbHandle,
     receipts: &ConfigReceipts,
     pde: &InstalledPackageDataEntry,
-) -> Result<(), Error> {
+) -> Result<PendingHistory, Error> {
+    let mut history = PendingHistory::default();
     let dependents = &pde.current_dependents;
     let me = &pde.manifest.id;
     for (dependent_id, dependency_info) in &dependents.0 {
@@ -1057,21 +1068,23 @@ pub async fn reconfigure_dependents_with_live_pointers(
             PackagePointerSpec::TorKey(_) => false,
             PackagePointerSpec::Config(_) => false,
         }) {
-            crate::config::configure(
-                ctx,
-                &mut tx,
-                dependent_id,
-                None,
-                &None,
-                false,
-                &mut BTreeMap::new(),
-                &mut BTreeMap::new(),
-                receipts,
-            )
-            .await?;
+            history.append(
+                crate::config::configure(
+                    ctx,
+                    &mut tx,
+                    dependent_id,
+                    None,
+                    &None,
+                    false,
+                    &mut BTreeMap::new(),
+                    &mut BTreeMap::new(),
+                    receipts,
+                )
+                .await?,
+            );
         }
     }
-    Ok(())
+    Ok(history)
 }
 
 #[derive(Clone)]
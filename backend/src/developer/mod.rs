@@ -8,6 +8,7 @@ use rpc_toolkit::command;
 use tracing::instrument;
 
 use crate::context::SdkContext;
+use crate::keyring::DeveloperKey;
 use crate::util::display_none;
 use crate::{Error, ResultExt};
 
@@ -43,3 +44,9 @@ pub fn init(#[context] ctx: SdkContext) -> Result<(), Error> {
 pub fn verify() -> Result<(), Error> {
     Ok(())
 }
+
+/// Prints the public half of the developer key, for adding to the keyring of a server
+#[command(cli_only, blocking)]
+pub fn pubkey(#[context] ctx: SdkContext) -> Result<DeveloperKey, Error> {
+    Ok(DeveloperKey(ctx.developer_key()?.public))
+}
@@ -6,9 +6,14 @@ use tokio::io::AsyncWriteExt;
 use tracing::instrument;
 
 use super::filesystem::ecryptfs::EcryptFS;
+use super::filesystem::s3::S3;
 use super::guard::{GenericMountGuard, TmpMountGuard};
+use super::remote::RemoteBackup;
 use super::util::{bind, unmount};
 use crate::auth::check_password;
+use crate::backup::snapshot::{
+    checkout, load_manifest, object_path, SnapshotEntry, CHECKOUT_DIR, SNAPSHOT_DIR,
+};
 use crate::backup::target::BackupInfo;
 use crate::disk::mount::filesystem::ReadWrite;
 use crate::disk::util::EmbassyOsRecoveryInfo;
@@ -19,9 +24,44 @@ use crate::util::FileLock;
 use crate::volume::BACKUP_DIR;
 use crate::{Error, ErrorKind, ResultExt};
 
+/// A backup key unlocked ahead of time, so that a backup can be made without the master password
+#[derive(Clone)]
+pub struct BackupKey {
+    pub key: String,
+    /// Hash of the master password, as recorded in the target's unencrypted metadata
+    pub password_hash: String,
+    /// `key`, wrapped with the master password
+    pub wrapped_key: String,
+}
+impl BackupKey {
+    pub fn new(key: String, password: &str) -> Result<Self, Error> {
+        Ok(Self {
+            password_hash: argon2::hash_encoded(
+                password.as_bytes(),
+                &rand::random::<[u8; 16]>()[..],
+                &argon2::Config::default(),
+            )
+            .with_kind(crate::ErrorKind::PasswordHashGeneration)?,
+            wrapped_key: base32::encode(
+                base32::Alphabet::RFC4648 { padding: true },
+                &encrypt_slice(&key, password),
+            ),
+            key,
+        })
+    }
+}
+
+enum BackupUnlock<'a> {
+    Password(&'a str),
+    Key(BackupKey),
+}
+
 pub struct BackupMountGuard<G: GenericMountGuard> {
     backup_disk_mount_guard: Option<G>,
     encrypted_guard: Option<TmpMountGuard>,
+    remote: Option<RemoteBackup>,
+    /// Encrypted local copy of a remote backup, removed when it is unmounted
+    staging: Option<PathBuf>,
     enc_key: String,
     pub unencrypted_metadata: EmbassyOsRecoveryInfo,
     pub metadata: BackupInfo,
@@ -37,6 +77,46 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
 
     #[instrument(skip_all)]
     pub async fn mount(backup_disk_mount_guard: G, password: &str) -> Result<Self, Error> {
+        Self::mount_with(
+            backup_disk_mount_guard,
+            None,
+            BackupUnlock::Password(password),
+        )
+        .await
+    }
+
+    /// Like [`mount`](Self::mount), for a backup kept in an S3 bucket rather than on the mounted
+    /// filesystem. `backup_disk_mount_guard` must be a mount of `s3`.
+    #[instrument(skip_all)]
+    pub async fn mount_remote(
+        backup_disk_mount_guard: G,
+        s3: S3,
+        password: &str,
+    ) -> Result<Self, Error> {
+        Self::mount_with(
+            backup_disk_mount_guard,
+            Some(s3),
+            BackupUnlock::Password(password),
+        )
+        .await
+    }
+
+    /// Like [`mount`](Self::mount), unlocked with a key that was recorded ahead of time rather
+    /// than the master password. The target's unencrypted metadata is updated to match `key`.
+    #[instrument(skip_all)]
+    pub async fn mount_with_key(
+        backup_disk_mount_guard: G,
+        remote: Option<S3>,
+        key: BackupKey,
+    ) -> Result<Self, Error> {
+        Self::mount_with(backup_disk_mount_guard, remote, BackupUnlock::Key(key)).await
+    }
+
+    async fn mount_with(
+        backup_disk_mount_guard: G,
+        remote: Option<S3>,
+        unlock: BackupUnlock<'_>,
+    ) -> Result<Self, Error> {
         let backup_disk_path = backup_disk_mount_guard.as_ref();
         let unencrypted_metadata_path =
             backup_disk_path.join("EmbassyBackups/unencrypted-metadata.cbor");
@@ -58,45 +138,51 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
             } else {
                 Default::default()
             };
-        let enc_key = if let (Some(hash), Some(wrapped_key)) = (
-            unencrypted_metadata.password_hash.as_ref(),
-            unencrypted_metadata.wrapped_key.as_ref(),
-        ) {
-            let wrapped_key =
-                base32::decode(base32::Alphabet::RFC4648 { padding: true }, wrapped_key)
-                    .ok_or_else(|| {
-                        Error::new(
-                            eyre!("failed to decode wrapped key"),
-                            crate::ErrorKind::Backup,
-                        )
-                    })?;
-            check_password(hash, password)?;
-            String::from_utf8(decrypt_slice(wrapped_key, password))?
-        } else {
-            base32::encode(
-                base32::Alphabet::RFC4648 { padding: false },
-                &rand::random::<[u8; 32]>()[..],
-            )
+        let enc_key = match unlock {
+            BackupUnlock::Password(password) => {
+                if let (Some(hash), Some(wrapped_key)) = (
+                    unencrypted_metadata.password_hash.as_ref(),
+                    unencrypted_metadata.wrapped_key.as_ref(),
+                ) {
+                    let wrapped_key =
+                        base32::decode(base32::Alphabet::RFC4648 { padding: true }, wrapped_key)
+                            .ok_or_else(|| {
+                                Error::new(
+                                    eyre!("failed to decode wrapped key"),
+                                    crate::ErrorKind::Backup,
+                                )
+                            })?;
+                    check_password(hash, password)?;
+                    String::from_utf8(decrypt_slice(wrapped_key, password))?
+                } else {
+                    let key = BackupKey::new(
+                        base32::encode(
+                            base32::Alphabet::RFC4648 { padding: false },
+                            &rand::random::<[u8; 32]>()[..],
+                        ),
+                        password,
+                    )?;
+                    unencrypted_metadata.password_hash = Some(key.password_hash);
+                    unencrypted_metadata.wrapped_key = Some(key.wrapped_key);
+                    key.key
+                }
+            }
+            BackupUnlock::Key(key) => {
+                unencrypted_metadata.password_hash = Some(key.password_hash);
+                unencrypted_metadata.wrapped_key = Some(key.wrapped_key);
+                key.key
+            }
         };
 
-        if unencrypted_metadata.password_hash.is_none() {
-            unencrypted_metadata.password_hash = Some(
-                argon2::hash_encoded(
-                    password.as_bytes(),
-                    &rand::random::<[u8; 16]>()[..],
-                    &argon2::Config::default(),
-                )
-                .with_kind(crate::ErrorKind::PasswordHashGeneration)?,
-            );
-        }
-        if unencrypted_metadata.wrapped_key.is_none() {
-            unencrypted_metadata.wrapped_key = Some(base32::encode(
-                base32::Alphabet::RFC4648 { padding: true },
-                &encrypt_slice(&enc_key, password),
-            ));
-        }
-
-        let crypt_path = backup_disk_path.join("EmbassyBackups/crypt");
+        let (crypt_path, staging) = if remote.is_some() {
+            // a remote backup is staged in an encrypted area that is removed again on unmount
+            let staging_path = backup_disk_path
+                .join("EmbassyBackups")
+                .join(format!("staging-{:016x}", rand::random::<u64>()));
+            (staging_path.clone(), Some(staging_path))
+        } else {
+            (backup_disk_path.join("EmbassyBackups/crypt"), None)
+        };
         if tokio::fs::metadata(&crypt_path).await.is_err() {
             tokio::fs::create_dir_all(&crypt_path).await.with_ctx(|_| {
                 (
@@ -107,6 +193,15 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
         }
         let encrypted_guard =
             TmpMountGuard::mount(&EcryptFS::new(&crypt_path, &enc_key), ReadWrite).await?;
+        let remote = if let Some(s3) = remote {
+            let remote =
+                RemoteBackup::pull(s3, encrypted_guard.as_ref().to_owned(), &enc_key).await?;
+            // snapshot manifests are needed to back up or restore any package
+            remote.fetch(&enc_key, SNAPSHOT_DIR).await?;
+            Some(remote)
+        } else {
+            None
+        };
 
         let metadata_path = encrypted_guard.as_ref().join("metadata.cbor");
         let metadata: BackupInfo = if tokio::fs::metadata(&metadata_path).await.is_ok() {
@@ -123,6 +218,8 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
         Ok(Self {
             backup_disk_mount_guard: Some(backup_disk_mount_guard),
             encrypted_guard: Some(encrypted_guard),
+            remote,
+            staging,
             enc_key,
             unencrypted_metadata,
             metadata,
@@ -130,18 +227,24 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
     }
 
     pub fn change_password(&mut self, new_password: &str) -> Result<(), Error> {
-        self.unencrypted_metadata.password_hash = Some(
-            argon2::hash_encoded(
-                new_password.as_bytes(),
-                &rand::random::<[u8; 16]>()[..],
-                &argon2::Config::default(),
-            )
-            .with_kind(crate::ErrorKind::PasswordHashGeneration)?,
-        );
-        self.unencrypted_metadata.wrapped_key = Some(base32::encode(
-            base32::Alphabet::RFC4648 { padding: false },
-            &encrypt_slice(&self.enc_key, new_password),
-        ));
+        let key = BackupKey::new(self.enc_key.clone(), new_password)?;
+        self.unencrypted_metadata.password_hash = Some(key.password_hash);
+        self.unencrypted_metadata.wrapped_key = Some(key.wrapped_key);
+        Ok(())
+    }
+
+    /// The key the backup is encrypted with
+    pub fn key(&self) -> &str {
+        &self.enc_key
+    }
+
+    /// Makes sure everything at or under `rel` is available locally. The files of a remote
+    /// backup are only downloaded when they are needed.
+    #[instrument(skip_all)]
+    pub async fn fetch(&self, rel: impl AsRef<Path>) -> Result<(), Error> {
+        if let Some(remote) = &self.remote {
+            remote.fetch(&self.enc_key, rel).await?;
+        }
         Ok(())
     }
 
@@ -151,10 +254,55 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
         id: &PackageId,
     ) -> Result<PackageBackupMountGuard, Error> {
         let lock = FileLock::new(Path::new(BACKUP_DIR).join(format!("{}.lock", id)), false).await?;
+        self.fetch(id).await?;
         let mountpoint = Path::new(BACKUP_DIR).join(id);
         bind(self.as_ref().join(id), &mountpoint, false).await?;
         Ok(PackageBackupMountGuard {
             mountpoint: Some(mountpoint),
+            checkout: None,
+            lock: Some(lock),
+        })
+    }
+
+    /// Like [`mount_package_backup`](Self::mount_package_backup), but exposes the package backup
+    /// as it was at the given snapshot. The snapshot is checked out into a scratch directory on
+    /// the target, which is removed again when the guard is unmounted.
+    #[instrument(skip_all)]
+    pub async fn mount_package_snapshot(
+        &self,
+        id: &PackageId,
+        snapshot_id: &str,
+    ) -> Result<PackageBackupMountGuard, Error> {
+        if !self.metadata.snapshots.contains_key(snapshot_id) {
+            return Err(Error::new(
+                eyre!("Backup Snapshot {} Not Found", snapshot_id),
+                crate::ErrorKind::NotFound,
+            ));
+        }
+        let manifest = load_manifest(self.as_ref(), snapshot_id).await?;
+        let tree = manifest.packages.get(id).ok_or_else(|| {
+            Error::new(
+                eyre!("{} is not in Backup Snapshot {}", id, snapshot_id),
+                crate::ErrorKind::NotFound,
+            )
+        })?;
+        let lock = FileLock::new(Path::new(BACKUP_DIR).join(format!("{}.lock", id)), false).await?;
+        for hash in tree.values().filter_map(|entry| match entry {
+            SnapshotEntry::File { hash, .. } => Some(hash),
+            _ => None,
+        }) {
+            self.fetch(object_path(Path::new(""), hash)).await?;
+        }
+        let checkout_path = self.as_ref().join(CHECKOUT_DIR).join(snapshot_id).join(id);
+        if tokio::fs::metadata(&checkout_path).await.is_ok() {
+            tokio::fs::remove_dir_all(&checkout_path).await?;
+        }
+        checkout(self.as_ref(), tree, &checkout_path).await?;
+        let mountpoint = Path::new(BACKUP_DIR).join(id);
+        bind(&checkout_path, &mountpoint, false).await?;
+        Ok(PackageBackupMountGuard {
+            mountpoint: Some(mountpoint),
+            checkout: Some(checkout_path),
             lock: Some(lock),
         })
     }
@@ -177,14 +325,31 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
         file.write_all(&IoFormat::Cbor.to_vec(&self.unencrypted_metadata)?)
             .await?;
         file.save().await.with_kind(ErrorKind::Filesystem)?;
+        if let Some(remote) = &self.remote {
+            remote
+                .push(
+                    &self.enc_key,
+                    IoFormat::Cbor.to_vec(&self.unencrypted_metadata)?,
+                )
+                .await?;
+        }
         Ok(())
     }
 
     #[instrument(skip_all)]
     pub async fn unmount(mut self) -> Result<(), Error> {
+        self.remote.take();
         if let Some(guard) = self.encrypted_guard.take() {
             guard.unmount().await?;
         }
+        if let Some(staging) = self.staging.take() {
+            tokio::fs::remove_dir_all(&staging).await.with_ctx(|_| {
+                (
+                    crate::ErrorKind::Filesystem,
+                    format!("rm -rf {}", staging.display()),
+                )
+            })?;
+        }
         if let Some(guard) = self.backup_disk_mount_guard.take() {
             guard.unmount().await?;
         }
@@ -210,13 +375,26 @@ impl<G: GenericMountGuard> AsRef<Path> for BackupMountGuard<G> {
 impl<G: GenericMountGuard> Drop for BackupMountGuard<G> {
     fn drop(&mut self) {
         let first = self.encrypted_guard.take();
+        let staging = self.staging.take();
         let second = self.backup_disk_mount_guard.take();
         tokio::spawn(async move {
             if let Some(guard) = first {
-                guard.unmount().await.unwrap();
+                if let Err(e) = guard.unmount().await {
+                    tracing::error!("Error unmounting encrypted backup: {}", e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+            if let Some(staging) = staging {
+                if let Err(e) = tokio::fs::remove_dir_all(&staging).await {
+                    tracing::error!("Error removing backup staging {}: {}", staging.display(), e);
+                    tracing::debug!("{:?}", e);
+                }
             }
             if let Some(guard) = second {
-                guard.unmount().await.unwrap();
+                if let Err(e) = guard.unmount().await {
+                    tracing::error!("Error unmounting backup target: {}", e);
+                    tracing::debug!("{:?}", e);
+                }
             }
         });
     }
@@ -224,6 +402,7 @@ impl<G: GenericMountGuard> Drop for BackupMountGuard<G> {
 
 pub struct PackageBackupMountGuard {
     mountpoint: Option<PathBuf>,
+    checkout: Option<PathBuf>,
     lock: Option<FileLock>,
 }
 impl PackageBackupMountGuard {
@@ -231,6 +410,9 @@ impl PackageBackupMountGuard {
         if let Some(mountpoint) = self.mountpoint.take() {
             unmount(&mountpoint).await?;
         }
+        if let Some(checkout) = self.checkout.take() {
+            tokio::fs::remove_dir_all(&checkout).await?;
+        }
         if let Some(lock) = self.lock.take() {
             lock.unlock().await?;
         }
@@ -249,11 +431,15 @@ impl AsRef<Path> for PackageBackupMountGuard {
 impl Drop for PackageBackupMountGuard {
     fn drop(&mut self) {
         let mountpoint = self.mountpoint.take();
+        let checkout = self.checkout.take();
         let lock = self.lock.take();
         tokio::spawn(async move {
             if let Some(mountpoint) = mountpoint {
                 unmount(&mountpoint).await.unwrap();
             }
+            if let Some(checkout) = checkout {
+                tokio::fs::remove_dir_all(&checkout).await.unwrap();
+            }
             if let Some(lock) = lock {
                 lock.unlock().await.unwrap();
             }
@@ -0,0 +1,104 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use digest::generic_array::GenericArray;
+use digest::{Digest, OutputSizeUser};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::instrument;
+
+use super::{FileSystem, MountType, ReadOnly};
+use crate::disk::mount::util::bind;
+use crate::util::s3::{S3Client, S3Credentials};
+use crate::{Error, ResultExt};
+
+/// Where S3 buckets are staged while mounted. Cleared on startup along with the rest of
+/// `package-data/tmp`.
+pub const S3_STAGING_DIR: &str = "/embassy-data/package-data/tmp/s3";
+
+/// Key of the unencrypted metadata of a backup, relative to the prefix of the target
+pub const S3_UNENCRYPTED_METADATA_KEY: &str = "unencrypted-metadata.cbor";
+
+/// A prefix in an S3 compatible bucket.
+///
+/// There is no filesystem to mount, so mounting only stages the unencrypted metadata of the
+/// backup in a local directory, laid out the same way as on a disk. The backup itself is synced by
+/// [`BackupMountGuard`](crate::disk::mount::backup::BackupMountGuard), which holds the key it is
+/// encrypted with.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct S3 {
+    pub endpoint: Url,
+    pub region: String,
+    pub bucket: String,
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+impl S3 {
+    pub fn client(&self) -> S3Client {
+        S3Client::new(
+            self.endpoint.clone(),
+            self.bucket.clone(),
+            S3Credentials {
+                region: self.region.clone(),
+                access_key_id: self.access_key_id.clone(),
+                secret_access_key: self.secret_access_key.clone(),
+            },
+        )
+    }
+
+    /// The key of an object relative to the prefix of the target
+    pub fn key(&self, path: &str) -> String {
+        let prefix = self.prefix.trim_matches('/');
+        if prefix.is_empty() {
+            path.to_owned()
+        } else {
+            format!("{}/{}", prefix, path)
+        }
+    }
+}
+#[async_trait]
+impl FileSystem for S3 {
+    #[instrument(skip_all)]
+    async fn mount<P: AsRef<Path> + Send + Sync>(
+        &self,
+        mountpoint: P,
+        mount_type: MountType,
+    ) -> Result<(), Error> {
+        let staging = Path::new(S3_STAGING_DIR).join(hex::encode(self.source_hash().await?));
+        let backups = staging.join("EmbassyBackups");
+        tokio::fs::create_dir_all(&backups).await?;
+        let metadata_path = backups.join("unencrypted-metadata.cbor");
+        match self
+            .client()
+            .get(&self.key(S3_UNENCRYPTED_METADATA_KEY))
+            .await?
+        {
+            Some(metadata) => tokio::fs::write(&metadata_path, metadata)
+                .await
+                .with_ctx(|_| {
+                    (
+                        crate::ErrorKind::Filesystem,
+                        metadata_path.display().to_string(),
+                    )
+                })?,
+            None if tokio::fs::metadata(&metadata_path).await.is_ok() => {
+                tokio::fs::remove_file(&metadata_path).await?
+            }
+            None => (),
+        }
+        bind(&staging, mountpoint, mount_type == ReadOnly).await
+    }
+    async fn source_hash(
+        &self,
+    ) -> Result<GenericArray<u8, <Sha256 as OutputSizeUser>::OutputSize>, Error> {
+        let mut sha = Sha256::new();
+        sha.update("S3");
+        sha.update(self.endpoint.as_str());
+        sha.update(self.bucket.as_bytes());
+        sha.update(self.prefix.trim_matches('/').as_bytes());
+        Ok(sha.finalize())
+    }
+}
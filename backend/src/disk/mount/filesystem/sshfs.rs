@@ -0,0 +1,186 @@
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use async_trait::async_trait;
+use color_eyre::eyre::eyre;
+use digest::generic_array::GenericArray;
+use digest::{Digest, OutputSizeUser};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::instrument;
+
+use super::{FileSystem, MountType, ReadOnly};
+use crate::disk::mount::guard::TmpMountGuard;
+use crate::util::Invoke;
+use crate::{Error, ResultExt};
+
+/// Where credentials are staged while sshfs connects
+const SSHFS_RUN_DIR: &str = "/run/embassy/sshfs";
+
+/// Fetches the host keys of an ssh server, in `known_hosts` format
+#[instrument(skip_all)]
+pub async fn scan_host_keys(hostname: &str, port: u16) -> Result<String, Error> {
+    let keys = String::from_utf8(
+        Command::new("ssh-keyscan")
+            .arg("-p")
+            .arg(port.to_string())
+            .arg(hostname)
+            .invoke(crate::ErrorKind::Network)
+            .await?,
+    )?;
+    if keys.trim().is_empty() {
+        return Err(Error::new(
+            eyre!("No host keys found for {}:{}", hostname, port),
+            crate::ErrorKind::Network,
+        ));
+    }
+    Ok(keys)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Sshfs {
+    pub hostname: String,
+    pub port: u16,
+    pub path: PathBuf,
+    pub username: String,
+    pub password: Option<String>,
+    pub private_key: Option<String>,
+    /// Only these keys are accepted from the server, in `known_hosts` format
+    pub host_keys: String,
+}
+impl Sshfs {
+    async fn mount_with(
+        &self,
+        run_dir: &Path,
+        mountpoint: &Path,
+        mount_type: MountType,
+    ) -> Result<(), Error> {
+        let known_hosts = run_dir.join("known_hosts");
+        tokio::fs::write(&known_hosts, &self.host_keys)
+            .await
+            .with_ctx(|_| {
+                (
+                    crate::ErrorKind::Filesystem,
+                    known_hosts.display().to_string(),
+                )
+            })?;
+        let mut cmd = Command::new("sshfs");
+        cmd.arg(format!(
+            "{}@{}:{}",
+            self.username,
+            self.hostname,
+            self.path.display()
+        ))
+        .arg(mountpoint)
+        .arg("-p")
+        .arg(self.port.to_string())
+        .arg("-o")
+        .arg(format!(
+            "UserKnownHostsFile={},StrictHostKeyChecking=yes,allow_other",
+            known_hosts.display()
+        ));
+        if let Some(private_key) = &self.private_key {
+            let identity = run_dir.join("id");
+            tokio::fs::write(&identity, private_key)
+                .await
+                .with_ctx(|_| (crate::ErrorKind::Filesystem, identity.display().to_string()))?;
+            tokio::fs::set_permissions(&identity, std::fs::Permissions::from_mode(0o600)).await?;
+            cmd.arg("-o").arg(format!(
+                "IdentityFile={},IdentitiesOnly=yes",
+                identity.display()
+            ));
+        }
+        if self.password.is_some() {
+            cmd.arg("-o").arg("password_stdin");
+        } else {
+            cmd.arg("-o").arg("BatchMode=yes");
+        }
+        if mount_type == ReadOnly {
+            cmd.arg("-o").arg("ro");
+        }
+        // ssh outlives sshfs, so its output can't be a pipe we wait on
+        let stderr_path = run_dir.join("stderr");
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(std::fs::File::create(&stderr_path)?)
+            .spawn()?;
+        if let (Some(password), Some(mut stdin)) = (&self.password, child.stdin.take()) {
+            stdin.write_all(password.as_bytes()).await?;
+            stdin.write_all(b"\n").await?;
+        }
+        let status = child.wait().await?;
+        crate::ensure_code!(
+            status.success(),
+            crate::ErrorKind::Filesystem,
+            "{}",
+            tokio::fs::read_to_string(&stderr_path)
+                .await
+                .unwrap_or_else(|_| "Unknown Error".to_owned())
+        );
+        Ok(())
+    }
+}
+#[async_trait]
+impl FileSystem for Sshfs {
+    #[instrument(skip_all)]
+    async fn mount<P: AsRef<std::path::Path> + Send + Sync>(
+        &self,
+        mountpoint: P,
+        mount_type: MountType,
+    ) -> Result<(), Error> {
+        tokio::fs::create_dir_all(mountpoint.as_ref()).await?;
+        let run_dir = Path::new(SSHFS_RUN_DIR).join(hex::encode(self.source_hash().await?));
+        tokio::fs::create_dir_all(&run_dir).await?;
+        tokio::fs::set_permissions(&run_dir, std::fs::Permissions::from_mode(0o700)).await?;
+        // ssh has read the credentials by the time sshfs returns
+        let res = self
+            .mount_with(&run_dir, mountpoint.as_ref(), mount_type)
+            .await;
+        tokio::fs::remove_dir_all(&run_dir).await?;
+        res
+    }
+    async fn source_hash(
+        &self,
+    ) -> Result<GenericArray<u8, <Sha256 as OutputSizeUser>::OutputSize>, Error> {
+        let mut sha = Sha256::new();
+        sha.update("Sshfs");
+        sha.update(self.hostname.as_bytes());
+        sha.update(self.port.to_be_bytes());
+        sha.update(self.path.as_os_str().as_bytes());
+        Ok(sha.finalize())
+    }
+}
+
+/// Needs root, sshfs, and an sshd to connect to, e.g.
+/// `docker run -d -p 2222:2222 -e USER_NAME=embassy -e USER_PASSWORD=embassy -e PASSWORD_ACCESS=true linuxserver/openssh-server`
+#[tokio::test]
+#[ignore]
+async fn sshfs_mount_test() {
+    let hostname = std::env::var("SSHFS_TEST_HOST").unwrap_or_else(|_| "localhost".to_owned());
+    let port = std::env::var("SSHFS_TEST_PORT")
+        .map(|p| p.parse().unwrap())
+        .unwrap_or(2222);
+    let sshfs = Sshfs {
+        host_keys: scan_host_keys(&hostname, port).await.unwrap(),
+        hostname,
+        port,
+        path: PathBuf::from("/config"),
+        username: "embassy".to_owned(),
+        password: Some("embassy".to_owned()),
+        private_key: None,
+    };
+    let guard = TmpMountGuard::mount(&sshfs, super::ReadWrite)
+        .await
+        .unwrap();
+    let path = guard.as_ref().join("sshfs-test");
+    tokio::fs::write(&path, "hello").await.unwrap();
+    assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "hello");
+    tokio::fs::remove_file(&path).await.unwrap();
+    guard.unmount().await.unwrap();
+}
@@ -1,4 +1,5 @@
 pub mod backup;
 pub mod filesystem;
 pub mod guard;
+pub mod remote;
 pub mod util;
@@ -0,0 +1,480 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::eyre;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hmac::{Hmac, Mac};
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::{TimeSpec, TimeValLike};
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tracing::instrument;
+
+use super::filesystem::s3::{S3, S3_UNENCRYPTED_METADATA_KEY};
+use crate::middleware::encrypt::{decrypt_slice, encrypt_slice};
+use crate::util::s3::S3Client;
+use crate::util::serde::IoFormat;
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Files are split into chunks of this size before they are encrypted and stored
+const CHUNK_SIZE: usize = 8 * 1024 * 1024;
+const INDEX_KEY: &str = "index";
+const CHUNK_DIR: &str = "chunks";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+enum RemoteEntry {
+    Dir {
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    },
+    File {
+        size: u64,
+        mtime: i64,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        chunks: Vec<String>,
+    },
+    Symlink {
+        target: PathBuf,
+        uid: u32,
+        gid: u32,
+    },
+}
+
+/// Every entry of the backup, keyed by path relative to its root
+type RemoteIndex = BTreeMap<PathBuf, RemoteEntry>;
+
+impl RemoteEntry {
+    fn chunks(&self) -> &[String] {
+        match self {
+            RemoteEntry::File { chunks, .. } => chunks,
+            _ => &[],
+        }
+    }
+}
+
+fn chunk_id(key: &str, data: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(data);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Encrypts `data`, prefixed with a MAC of its plaintext so that tampering is detected on
+/// [`open`]
+fn seal(key: &str, data: &[u8]) -> Vec<u8> {
+    let mut res = hex::decode(chunk_id(key, data)).unwrap();
+    res.extend(encrypt_slice(data, key));
+    res
+}
+
+fn open(key: &str, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    if sealed.len() >= 32 {
+        let (mac, ciphertext) = sealed.split_at(32);
+        let data = decrypt_slice(ciphertext, key);
+        if hex::encode(mac) == chunk_id(key, &data) {
+            return Ok(data);
+        }
+    }
+    Err(Error::new(
+        eyre!("Backup data failed integrity check"),
+        ErrorKind::Backup,
+    ))
+}
+
+fn set_owner(path: &Path, uid: u32, gid: u32) -> Result<(), Error> {
+    fchownat(
+        None,
+        path,
+        Some(Uid::from_raw(uid)),
+        Some(Gid::from_raw(gid)),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .with_ctx(|_| (ErrorKind::Filesystem, format!("chown {}", path.display())))
+}
+
+fn set_mtime(path: &Path, mtime: i64) -> Result<(), Error> {
+    let mtime = TimeSpec::seconds(mtime);
+    utimensat(None, path, &mtime, &mtime, UtimensatFlags::NoFollowSymlink)
+        .with_ctx(|_| (ErrorKind::Filesystem, format!("touch {}", path.display())))
+}
+
+/// A backup stored in an S3 compatible bucket, staged in a local directory while it is mounted.
+///
+/// Files are split into chunks, and each chunk is encrypted with the backup key before it leaves
+/// the device. Chunks are named by a MAC of their plaintext, so identical chunks are only stored
+/// once, and the bucket learns nothing about their contents. An encrypted index maps paths to
+/// their chunks.
+///
+/// Only the index is downloaded when the backup is pulled. Files start out as empty placeholders,
+/// and are downloaded and decrypted when they are [`fetch`](Self::fetch)ed.
+pub struct RemoteBackup {
+    s3: S3,
+    client: S3Client,
+    path: PathBuf,
+    index: Mutex<RemoteIndex>,
+    /// Files that are still placeholders
+    pending: Mutex<BTreeSet<PathBuf>>,
+}
+impl RemoteBackup {
+    /// Downloads the index of the backup, and lays it out in `path` with placeholders for its
+    /// files. Files in the root hold the metadata of the backup, so they are fetched right away.
+    /// A prefix without a backup yields an empty one.
+    #[instrument(skip_all)]
+    pub async fn pull(s3: S3, path: PathBuf, key: &str) -> Result<Self, Error> {
+        let client = s3.client();
+        let index: RemoteIndex = match client.get(&s3.key(INDEX_KEY)).await? {
+            Some(sealed) => IoFormat::Cbor.from_slice(&open(key, &sealed)?)?,
+            None => RemoteIndex::new(),
+        };
+        tokio::fs::create_dir_all(&path).await?;
+        let mut pending = BTreeSet::new();
+        // BTreeMap ordering guarantees parents are visited before their children
+        for (rel, entry) in &index {
+            let dst = path.join(rel);
+            match entry {
+                RemoteEntry::Dir { mode, uid, gid } => {
+                    tokio::fs::create_dir_all(&dst).await?;
+                    tokio::fs::set_permissions(&dst, std::fs::Permissions::from_mode(*mode))
+                        .await?;
+                    set_owner(&dst, *uid, *gid)?;
+                }
+                RemoteEntry::File {
+                    mtime,
+                    mode,
+                    uid,
+                    gid,
+                    ..
+                } => {
+                    tokio::fs::File::create(&dst)
+                        .await
+                        .with_ctx(|_| (ErrorKind::Filesystem, dst.display().to_string()))?;
+                    tokio::fs::set_permissions(&dst, std::fs::Permissions::from_mode(*mode))
+                        .await?;
+                    set_owner(&dst, *uid, *gid)?;
+                    set_mtime(&dst, *mtime)?;
+                    pending.insert(rel.clone());
+                }
+                RemoteEntry::Symlink { target, uid, gid } => {
+                    tokio::fs::symlink(target, &dst).await?;
+                    set_owner(&dst, *uid, *gid)?;
+                }
+            }
+        }
+        let res = RemoteBackup {
+            s3,
+            client,
+            path,
+            index: Mutex::new(index),
+            pending: Mutex::new(pending),
+        };
+        let root_files: Vec<PathBuf> = res
+            .pending
+            .lock()
+            .await
+            .iter()
+            .filter(|rel| rel.parent() == Some(Path::new("")))
+            .cloned()
+            .collect();
+        for rel in root_files {
+            res.fetch(key, &rel).await?;
+        }
+        Ok(res)
+    }
+
+    /// Downloads and decrypts every file at or under `rel` that is still a placeholder
+    #[instrument(skip_all)]
+    pub async fn fetch(&self, key: &str, rel: impl AsRef<Path>) -> Result<(), Error> {
+        let rel = rel.as_ref();
+        let index = self.index.lock().await;
+        let mut pending = self.pending.lock().await;
+        let fetching: Vec<PathBuf> = pending
+            .iter()
+            .filter(|p| p.starts_with(rel))
+            .cloned()
+            .collect();
+        for rel in fetching {
+            let (mtime, chunks) = match index.get(&rel) {
+                Some(RemoteEntry::File { mtime, chunks, .. }) => (*mtime, chunks),
+                _ => continue,
+            };
+            let dst = self.path.join(&rel);
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .open(&dst)
+                .await
+                .with_ctx(|_| (ErrorKind::Filesystem, dst.display().to_string()))?;
+            for id in chunks {
+                let sealed = self
+                    .client
+                    .get(&self.s3.key(&format!("{}/{}", CHUNK_DIR, id)))
+                    .await?
+                    .ok_or_else(|| {
+                        Error::new(eyre!("Backup chunk {} is missing", id), ErrorKind::Backup)
+                    })?;
+                let data = open(key, &sealed)?;
+                if &chunk_id(key, &data) != id {
+                    return Err(Error::new(
+                        eyre!("Backup chunk {} failed integrity check", id),
+                        ErrorKind::Backup,
+                    ));
+                }
+                file.write_all(&data).await?;
+            }
+            file.sync_all().await?;
+            drop(file);
+            set_mtime(&dst, mtime)?;
+            pending.remove(&rel);
+        }
+        Ok(())
+    }
+
+    /// Where the decrypted backup is staged
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    async fn upload_file(
+        &self,
+        key: &str,
+        path: &Path,
+        stored: &mut BTreeSet<String>,
+    ) -> Result<Vec<String>, Error> {
+        let mut file = tokio::fs::File::open(path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+        let mut chunks = Vec::new();
+        let mut buf = Vec::with_capacity(CHUNK_SIZE);
+        loop {
+            buf.clear();
+            (&mut file)
+                .take(CHUNK_SIZE as u64)
+                .read_to_end(&mut buf)
+                .await?;
+            if buf.is_empty() {
+                break;
+            }
+            let id = chunk_id(key, &buf);
+            if !stored.contains(&id) {
+                self.client
+                    .put(
+                        &self.s3.key(&format!("{}/{}", CHUNK_DIR, id)),
+                        seal(key, &buf),
+                    )
+                    .await?;
+                stored.insert(id.clone());
+            }
+            chunks.push(id);
+        }
+        Ok(chunks)
+    }
+
+    fn walk<'a>(
+        &'a self,
+        key: &'a str,
+        rel: PathBuf,
+        previous: &'a RemoteIndex,
+        pending: &'a BTreeSet<PathBuf>,
+        stored: &'a mut BTreeSet<String>,
+        index: &'a mut RemoteIndex,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut dir = tokio::fs::read_dir(self.path.join(&rel)).await?;
+            while let Some(e) = dir.next_entry().await? {
+                let rel = rel.join(e.file_name());
+                let path = e.path();
+                let m = tokio::fs::symlink_metadata(&path).await?;
+                let (uid, gid) = (m.uid(), m.gid());
+                if m.file_type().is_symlink() {
+                    index.insert(
+                        rel,
+                        RemoteEntry::Symlink {
+                            target: tokio::fs::read_link(&path).await?,
+                            uid,
+                            gid,
+                        },
+                    );
+                } else if m.is_dir() {
+                    index.insert(
+                        rel.clone(),
+                        RemoteEntry::Dir {
+                            mode: m.permissions().mode(),
+                            uid,
+                            gid,
+                        },
+                    );
+                    self.walk(key, rel, previous, pending, stored, index)
+                        .await?;
+                } else if m.is_file() {
+                    if pending.contains(&rel) {
+                        if let Some(entry @ RemoteEntry::File { mtime, .. }) = previous.get(&rel) {
+                            if m.len() == 0 && *mtime == m.mtime() {
+                                // a placeholder that was never fetched or written to
+                                index.insert(rel, entry.clone());
+                                continue;
+                            }
+                        }
+                    }
+                    let chunks = match previous.get(&rel) {
+                        Some(RemoteEntry::File {
+                            size,
+                            mtime,
+                            chunks,
+                            ..
+                        }) if *size == m.len() && *mtime == m.mtime() => chunks.clone(),
+                        _ => self.upload_file(key, &path, stored).await?,
+                    };
+                    index.insert(
+                        rel,
+                        RemoteEntry::File {
+                            size: m.len(),
+                            mtime: m.mtime(),
+                            mode: m.permissions().mode(),
+                            uid,
+                            gid,
+                            chunks,
+                        },
+                    );
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Uploads everything that changed since the backup was pulled, then removes the chunks that
+    /// are no longer referenced
+    #[instrument(skip_all)]
+    pub async fn push(&self, key: &str, unencrypted_metadata: Vec<u8>) -> Result<(), Error> {
+        let mut previous = self.index.lock().await;
+        let mut pending = self.pending.lock().await;
+        let mut stored: BTreeSet<String> = previous
+            .values()
+            .flat_map(|e| e.chunks())
+            .cloned()
+            .collect();
+        let mut index = RemoteIndex::new();
+        self.walk(
+            key,
+            PathBuf::new(),
+            &*previous,
+            &*pending,
+            &mut stored,
+            &mut index,
+        )
+        .await?;
+        self.client
+            .put(
+                &self.s3.key(INDEX_KEY),
+                seal(key, &IoFormat::Cbor.to_vec(&index)?),
+            )
+            .await?;
+        self.client
+            .put(
+                &self.s3.key(S3_UNENCRYPTED_METADATA_KEY),
+                unencrypted_metadata,
+            )
+            .await?;
+        let referenced: BTreeSet<&String> = index.values().flat_map(|e| e.chunks()).collect();
+        for id in stored.iter().filter(|id| !referenced.contains(id)) {
+            self.client
+                .delete(&self.s3.key(&format!("{}/{}", CHUNK_DIR, id)))
+                .await?;
+        }
+        pending.retain(|rel| index.contains_key(rel));
+        *previous = index;
+        Ok(())
+    }
+}
+
+/// Needs an S3 compatible server with a bucket named `embassy-test`, e.g.
+/// `docker run -d -p 9000:9000 minio/minio server /data` and `mc mb local/embassy-test`
+#[tokio::test]
+#[ignore]
+async fn remote_backup_roundtrip() {
+    let s3 = S3 {
+        endpoint: std::env::var("S3_TEST_ENDPOINT")
+            .unwrap_or_else(|_| "http://localhost:9000".to_owned())
+            .parse()
+            .unwrap(),
+        region: "us-east-1".to_owned(),
+        bucket: "embassy-test".to_owned(),
+        prefix: format!("remote-backup-{:016x}", rand::random::<u64>()),
+        access_key_id: "minioadmin".to_owned(),
+        secret_access_key: "minioadmin".to_owned(),
+    };
+    let key = "test-key";
+    let tmp = std::env::temp_dir().join(&s3.prefix);
+    let backup = RemoteBackup::pull(s3.clone(), tmp.join("src"), key)
+        .await
+        .unwrap();
+    let big: Vec<u8> = (0..CHUNK_SIZE + 1).map(|i| i as u8).collect();
+    tokio::fs::create_dir_all(backup.path().join("dir"))
+        .await
+        .unwrap();
+    tokio::fs::write(backup.path().join("small"), "hello")
+        .await
+        .unwrap();
+    tokio::fs::write(backup.path().join("dir/big"), &big)
+        .await
+        .unwrap();
+    tokio::fs::symlink("../small", backup.path().join("dir/link"))
+        .await
+        .unwrap();
+    backup.push(key, b"metadata".to_vec()).await.unwrap();
+
+    let restored = RemoteBackup::pull(s3.clone(), tmp.join("dst"), key)
+        .await
+        .unwrap();
+    assert_eq!(
+        tokio::fs::metadata(restored.path().join("dir/big"))
+            .await
+            .unwrap()
+            .len(),
+        0
+    );
+    // an untouched placeholder keeps its contents when pushed again
+    restored.push(key, b"metadata".to_vec()).await.unwrap();
+    restored.fetch(key, "dir").await.unwrap();
+    assert_eq!(
+        tokio::fs::read_to_string(restored.path().join("small"))
+            .await
+            .unwrap(),
+        "hello"
+    );
+    assert_eq!(
+        tokio::fs::read(restored.path().join("dir/big"))
+            .await
+            .unwrap(),
+        big
+    );
+    assert_eq!(
+        tokio::fs::read_link(restored.path().join("dir/link"))
+            .await
+            .unwrap(),
+        Path::new("../small")
+    );
+    assert_eq!(
+        s3.client()
+            .get(&s3.key(S3_UNENCRYPTED_METADATA_KEY))
+            .await
+            .unwrap()
+            .unwrap(),
+        b"metadata"
+    );
+    assert!(RemoteBackup::pull(s3, tmp.join("bad"), "wrong-key")
+        .await
+        .is_err());
+
+    tokio::fs::remove_dir_all(&tmp).await.unwrap();
+}
@@ -212,6 +212,39 @@ pub async fn get_percentage<P: AsRef<Path>>(path: P) -> Result<u64, Error> {
     .parse::<u64>()?)
 }
 
+/// The whole disk that holds `path`, as cgroup IO limits do not apply to partitions
+#[instrument(skip_all)]
+pub async fn get_block_device<P: AsRef<Path>>(path: P) -> Result<PathBuf, Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = path.as_ref();
+    let dev = tokio::fs::metadata(path)
+        .await
+        .with_ctx(|_| (crate::ErrorKind::Filesystem, path.display().to_string()))?
+        .dev();
+    let sys_path = tokio::fs::canonicalize(format!(
+        "/sys/dev/block/{}:{}",
+        nix::sys::stat::major(dev),
+        nix::sys::stat::minor(dev)
+    ))
+    .await
+    .with_ctx(|_| (crate::ErrorKind::Filesystem, path.display().to_string()))?;
+    let disk = if tokio::fs::metadata(sys_path.join("partition"))
+        .await
+        .is_ok()
+    {
+        sys_path.parent().unwrap_or(&sys_path)
+    } else {
+        &sys_path
+    };
+    Ok(Path::new("/dev").join(disk.file_name().ok_or_else(|| {
+        Error::new(
+            eyre!("no block device for {}", path.display()),
+            crate::ErrorKind::Filesystem,
+        )
+    })?))
+}
+
 #[instrument(skip_all)]
 pub async fn pvscan() -> Result<BTreeMap<PathBuf, Option<String>>, Error> {
     let pvscan_out = Command::new("pvscan")
@@ -1,5 +1,6 @@
 use std::collections::{BTreeMap, HashMap};
 use std::fs::Permissions;
+use std::net::Ipv6Addr;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::process::Stdio;
@@ -21,7 +22,7 @@ use crate::middleware::auth::LOCAL_AUTH_COOKIE_PATH;
 use crate::sound::BEP;
 use crate::system::time;
 use crate::util::Invoke;
-use crate::{Error, ARCH};
+use crate::{Error, ARCH, HOST_IPV6};
 
 pub const SYSTEM_REBUILD_PATH: &str = "/media/embassy/config/system-rebuild";
 pub const STANDBY_MODE_PATH: &str = "/media/embassy/config/standby";
@@ -216,6 +217,37 @@ pub struct InitResult {
     pub db: patch_db::PatchDb,
 }
 
+async fn create_docker_network() -> Result<(), Error> {
+    bollard::Docker::connect_with_unix_defaults()?
+        .create_network(bollard::network::CreateNetworkOptions {
+            name: "start9",
+            driver: "bridge",
+            enable_ipv6: true,
+            ipam: bollard::models::Ipam {
+                config: Some(vec![
+                    bollard::models::IpamConfig {
+                        subnet: Some("172.18.0.1/24".into()),
+                        ..Default::default()
+                    },
+                    bollard::models::IpamConfig {
+                        subnet: Some("fd53:7a2e:9c40::/64".into()),
+                        gateway: Some(Ipv6Addr::from(HOST_IPV6).to_string()),
+                        ..Default::default()
+                    },
+                ]),
+                ..Default::default()
+            },
+            options: {
+                let mut m = HashMap::new();
+                m.insert("com.docker.network.bridge.name", "br-start9");
+                m
+            },
+            ..Default::default()
+        })
+        .await?;
+    Ok(())
+}
+
 pub async fn init(cfg: &RpcContextConfig) -> Result<InitResult, Error> {
     tokio::fs::create_dir_all("/run/embassy")
         .await
@@ -342,25 +374,7 @@ pub async fn init(cfg: &RpcContextConfig) -> Result<InitResult, Error> {
 
     if should_rebuild || !tmp_docker_exists {
         tracing::info!("Creating Docker Network");
-        bollard::Docker::connect_with_unix_defaults()?
-            .create_network(bollard::network::CreateNetworkOptions {
-                name: "start9",
-                driver: "bridge",
-                ipam: bollard::models::Ipam {
-                    config: Some(vec![bollard::models::IpamConfig {
-                        subnet: Some("172.18.0.1/24".into()),
-                        ..Default::default()
-                    }]),
-                    ..Default::default()
-                },
-                options: {
-                    let mut m = HashMap::new();
-                    m.insert("com.docker.network.bridge.name", "br-start9");
-                    m
-                },
-                ..Default::default()
-            })
-            .await?;
+        create_docker_network().await?;
         tracing::info!("Created Docker Network");
 
         tracing::info!("Loading System Docker Images");
@@ -370,6 +384,19 @@ pub async fn init(cfg: &RpcContextConfig) -> Result<InitResult, Error> {
         tracing::info!("Loading Package Docker Images");
         crate::install::load_images(cfg.datadir().join(PKG_ARCHIVE_DIR)).await?;
         tracing::info!("Loaded Package Docker Images");
+    } else if !bollard::Docker::connect_with_unix_defaults()?
+        .inspect_network::<String>("start9", None)
+        .await?
+        .enable_ipv6
+        .unwrap_or(false)
+    {
+        // networks can't gain IPv6 in place, and no containers are running yet
+        tracing::info!("Recreating Docker Network with IPv6");
+        bollard::Docker::connect_with_unix_defaults()?
+            .remove_network("start9")
+            .await?;
+        create_docker_network().await?;
+        tracing::info!("Recreated Docker Network with IPv6");
     }
 
     tracing::info!("Enabling Docker QEMU Emulation");
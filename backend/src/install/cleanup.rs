@@ -373,9 +373,11 @@ where
         .flat_map(|x| x.manifest_borrow().volumes.values())
         .flat_map(|x| x.pointer_path(&ctx.datadir))
         .collect();
+    crate::net::domain::sync_connection_addresses(&mut tx, &packages).await?;
     receipts.packages.set(&mut tx, packages).await?;
     // once we have removed the package entry, we can change all the dependent pointers to null
-    reconfigure_dependents_with_live_pointers(ctx, &mut tx, &receipts.config, &entry).await?;
+    let history =
+        reconfigure_dependents_with_live_pointers(ctx, &mut tx, &receipts.config, &entry).await?;
 
     remove_from_current_dependents_lists(
         &mut tx,
@@ -400,7 +402,10 @@ where
     tracing::debug!("Cleaning up {:?} at {:?}", volumes, dependents_paths);
     cleanup_folder(volumes, Arc::new(dependents_paths)).await;
     remove_tor_keys(secrets, &entry.manifest.id).await?;
+    crate::status::health_history::remove(secrets, &entry.manifest.id).await?;
+    crate::config::history::remove(secrets, &entry.manifest.id).await?;
     tx.commit().await?;
+    history.record(&ctx.secret_store, None).await;
     Ok(())
 }
 
@@ -25,6 +25,7 @@ use tokio_stream::wrappers::ReadDirStream;
 use tracing::instrument;
 
 use self::cleanup::{cleanup_failed, remove_from_current_dependents_lists};
+use crate::config::history::PendingHistory;
 use crate::config::ConfigReceipts;
 use crate::context::{CliContext, RpcContext};
 use crate::core::rpc_continuations::{RequestGuid, RpcContinuation};
@@ -39,7 +40,10 @@ use crate::dependencies::{
 };
 use crate::install::cleanup::{cleanup, update_dependency_errors_of_dependents};
 use crate::install::progress::{InstallProgress, InstallProgressTracker};
+use crate::keyring::{DeveloperKey, SignerTrust, UnknownSigners};
+use crate::manager::restart::RestartPolicy;
 use crate::notifications::NotificationLevel;
+use crate::resources::ResourceLimits;
 use crate::s9pk::manifest::{Manifest, PackageId};
 use crate::s9pk::reader::S9pkReader;
 use crate::status::{MainStatus, Status};
@@ -885,6 +889,49 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
     rdr.validate().await?;
     rdr.validated();
     let developer_key = rdr.developer_key().clone();
+    let keyring = crate::db::DatabaseModel::new()
+        .server_info()
+        .keyring()
+        .get(&mut ctx.db.handle())
+        .await?
+        .into_owned();
+    if keyring.check(pkg_id, rdr.signers())? == SignerTrust::Unknown && marketplace_url.is_none() {
+        let developer_key = DeveloperKey(developer_key);
+        match keyring.unknown_signers {
+            UnknownSigners::Reject => {
+                return Err(Error::new(
+                    eyre!(
+                        "{} is signed by untrusted developer key {}",
+                        pkg_id,
+                        developer_key
+                    ),
+                    crate::ErrorKind::InvalidSignature,
+                ))
+            }
+            UnknownSigners::Warn => {
+                tracing::warn!(
+                    "Sideloading {}@{} signed by untrusted developer key {}",
+                    pkg_id,
+                    version,
+                    developer_key
+                );
+                ctx.notification_manager
+                    .notify(
+                        &mut ctx.db.handle(),
+                        Some(pkg_id.clone()),
+                        NotificationLevel::Warning,
+                        String::from("Untrusted Developer Key"),
+                        format!(
+                            "{}@{} is not signed by any key in the keyring. Its developer key is {}",
+                            pkg_id, version, developer_key
+                        ),
+                        (),
+                        None,
+                    )
+                    .await?;
+            }
+        }
+    }
     rdr.reset().await?;
     let model = crate::db::DatabaseModel::new()
         .package_data()
@@ -1112,7 +1159,7 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
     tracing::info!("Install {}@{}: Created volumes", pkg_id, version);
 
     tracing::info!("Install {}@{}: Installing interfaces", pkg_id, version);
-    let interface_addresses = manifest.interfaces.install(&mut sql_tx, pkg_id).await?;
+    let mut interface_addresses = manifest.interfaces.install(&mut sql_tx, pkg_id).await?;
     tracing::info!("Install {}@{}: Installed interfaces", pkg_id, version);
 
     tracing::info!("Install {}@{}: Creating manager", pkg_id, version);
@@ -1187,6 +1234,13 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
         .await?
         .get_mut(&mut tx)
         .await?;
+    if let PackageDataEntry::Updating { installed, .. } = &*pde {
+        for (id, addrs) in &mut interface_addresses.0 {
+            if let Some(prev) = installed.interface_addresses.0.get(id) {
+                addrs.clearnet_addresses = prev.clearnet_addresses.clone();
+            }
+        }
+    }
     let installed = InstalledPackageDataEntry {
         status: Status {
             configured: manifest.config.is_none(),
@@ -1212,6 +1266,15 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
         current_dependents: current_dependents.clone(),
         current_dependencies: current_dependencies.clone(),
         interface_addresses,
+        restart_policy: match &*pde {
+            PackageDataEntry::Updating { installed, .. } => installed.restart_policy.clone(),
+            _ => RestartPolicy::default(),
+        },
+        restart_history: Vec::new(),
+        resource_limits: match &*pde {
+            PackageDataEntry::Updating { installed, .. } => installed.resource_limits.clone(),
+            _ => ResourceLimits::default(),
+        },
     };
 
     let prev = std::mem::replace(
@@ -1245,6 +1308,7 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
     .await?;
     dep_errs.save(&mut tx).await?;
 
+    let mut history = PendingHistory::default();
     if let PackageDataEntry::Updating {
         installed: prev, ..
     } = prev
@@ -1285,7 +1349,7 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
             false
         };
         if configured && manifest.config.is_some() {
-            crate::config::configure(
+            history = crate::config::configure(
                 ctx,
                 &mut tx,
                 pkg_id,
@@ -1345,7 +1409,7 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
             cleanup(ctx, &prev.manifest.id, &prev.manifest.version).await?;
         }
     } else if let PackageDataEntry::Restoring { .. } = prev {
-        manifest
+        history = manifest
             .backup
             .restore(
                 ctx,
@@ -1390,12 +1454,15 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin + Send + Sync>(
     }
 
     if let Some(installed) = pde.installed() {
-        reconfigure_dependents_with_live_pointers(ctx, &mut tx, &receipts.config, installed)
-            .await?;
+        history.append(
+            reconfigure_dependents_with_live_pointers(ctx, &mut tx, &receipts.config, installed)
+                .await?,
+        );
     }
 
     sql_tx.commit().await?;
     tx.commit().await?;
+    history.record(&ctx.secret_store, None).await;
 
     tracing::info!("Install {}@{}: Complete", pkg_id, version);
 
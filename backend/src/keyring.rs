@@ -0,0 +1,434 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use ed25519_dalek::PublicKey;
+use rpc_toolkit::command;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::{deserialize_from_str, display_serializable, serialize_display, IoFormat};
+use crate::{Error, ErrorKind, ResultExt};
+
+/// An ed25519 developer key, displayed as base64
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeveloperKey(pub PublicKey);
+impl PartialOrd for DeveloperKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for DeveloperKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.as_bytes().cmp(other.0.as_bytes())
+    }
+}
+impl std::fmt::Display for DeveloperKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", base64::encode(self.0.as_bytes()))
+    }
+}
+impl FromStr for DeveloperKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = base64::decode(s.trim()).with_kind(ErrorKind::InvalidSignature)?;
+        Ok(DeveloperKey(
+            PublicKey::from_bytes(&bytes).with_kind(ErrorKind::InvalidSignature)?,
+        ))
+    }
+}
+impl Serialize for DeveloperKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_display(self, serializer)
+    }
+}
+impl<'de> Deserialize<'de> for DeveloperKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_from_str(deserializer)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TrustedKey {
+    pub name: String,
+    pub added: DateTime<Utc>,
+}
+
+/// Requires a package to be signed by at least `threshold` of `keys`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SigningPolicy {
+    pub keys: BTreeSet<DeveloperKey>,
+    pub threshold: usize,
+}
+
+/// What to do with a sideloaded package that is not signed by any key in the keyring, and has no
+/// signing policy of its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownSigners {
+    Reject,
+    Warn,
+}
+impl Default for UnknownSigners {
+    fn default() -> Self {
+        UnknownSigners::Warn
+    }
+}
+impl std::fmt::Display for UnknownSigners {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnknownSigners::Reject => write!(f, "reject"),
+            UnknownSigners::Warn => write!(f, "warn"),
+        }
+    }
+}
+impl FromStr for UnknownSigners {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_owned()))
+            .with_kind(ErrorKind::Deserialization)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignerTrust {
+    Trusted,
+    Unknown,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Keyring {
+    pub keys: BTreeMap<DeveloperKey, TrustedKey>,
+    pub policies: BTreeMap<PackageId, SigningPolicy>,
+    pub unknown_signers: UnknownSigners,
+}
+impl Keyring {
+    /// `signers` must only contain keys whose signatures have already been verified. Fails if the
+    /// package has a signing policy that the signers do not satisfy.
+    pub fn check(&self, id: &PackageId, signers: &[PublicKey]) -> Result<SignerTrust, Error> {
+        let signers: BTreeSet<_> = signers.iter().copied().map(DeveloperKey).collect();
+        if let Some(policy) = self.policies.get(id) {
+            let signed = policy.keys.intersection(&signers).count();
+            if signed < policy.threshold {
+                return Err(Error::new(
+                    eyre!(
+                        "{} must be signed by {} of [{}], but is signed by {}",
+                        id,
+                        policy.threshold,
+                        policy
+                            .keys
+                            .iter()
+                            .map(|k| k.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        signed,
+                    ),
+                    ErrorKind::InvalidSignature,
+                ));
+            }
+            return Ok(SignerTrust::Trusted);
+        }
+        if signers.iter().any(|k| self.keys.contains_key(k)) {
+            Ok(SignerTrust::Trusted)
+        } else {
+            Ok(SignerTrust::Unknown)
+        }
+    }
+}
+
+#[command(subcommands(add, remove, list, policy, unknown_signers))]
+pub fn keyring() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] key: DeveloperKey,
+    #[arg] name: String,
+) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let mut keyring = crate::db::DatabaseModel::new()
+        .server_info()
+        .keyring()
+        .get_mut(&mut db)
+        .await?;
+    if keyring.keys.contains_key(&key) {
+        return Err(Error::new(
+            eyre!("Developer key {} is already trusted", key),
+            ErrorKind::Duplicate,
+        ));
+    }
+    keyring.keys.insert(
+        key,
+        TrustedKey {
+            name,
+            added: Utc::now(),
+        },
+    );
+    keyring.save(&mut db).await
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] key: DeveloperKey) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let mut keyring = crate::db::DatabaseModel::new()
+        .server_info()
+        .keyring()
+        .get_mut(&mut db)
+        .await?;
+    if let Some((id, _)) = keyring.policies.iter().find(|(_, p)| p.keys.contains(&key)) {
+        return Err(Error::new(
+            eyre!(
+                "Developer key {} is used by the signing policy of {}",
+                key,
+                id
+            ),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    if keyring.keys.remove(&key).is_none() {
+        return Err(Error::new(
+            eyre!("Developer key {} Not Found", key),
+            ErrorKind::NotFound,
+        ));
+    }
+    keyring.save(&mut db).await
+}
+
+fn display_keyring(keyring: Keyring, matches: &ArgMatches) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(keyring, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "KEY", "NAME", "ADDED"]);
+    for (key, info) in &keyring.keys {
+        table.add_row(row![&key.to_string(), &info.name, &info.added.to_rfc3339()]);
+    }
+    table.print_tty(false).unwrap();
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "PACKAGE", "THRESHOLD", "KEYS"]);
+    for (id, policy) in &keyring.policies {
+        table.add_row(row![
+            &id.to_string(),
+            &format!("{} of {}", policy.threshold, policy.keys.len()),
+            &policy
+                .keys
+                .iter()
+                .map(|k| match keyring.keys.get(k) {
+                    Some(info) => info.name.clone(),
+                    None => k.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        ]);
+    }
+    table.print_tty(false).unwrap();
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "UNKNOWN SIGNERS"]);
+    table.add_row(row![&keyring.unknown_signers.to_string()]);
+    table.print_tty(false).unwrap();
+}
+
+#[command(display(display_keyring))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Keyring, Error> {
+    Ok(crate::db::DatabaseModel::new()
+        .server_info()
+        .keyring()
+        .get(&mut ctx.db.handle())
+        .await?
+        .into_owned())
+}
+
+#[command(subcommands(set_policy, remove_policy))]
+pub fn policy() -> Result<(), Error> {
+    Ok(())
+}
+
+fn parse_keys(arg: &str, _: &ArgMatches) -> Result<BTreeSet<DeveloperKey>, Error> {
+    arg.split(',').map(|s| s.parse()).collect()
+}
+
+/// Every key in the policy must already be in the keyring
+#[command(rename = "set", display(display_none))]
+#[instrument(skip_all)]
+pub async fn set_policy(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(parse(parse_keys))] keys: BTreeSet<DeveloperKey>,
+    #[arg(long = "threshold")] threshold: Option<usize>,
+) -> Result<(), Error> {
+    let threshold = threshold.unwrap_or(keys.len());
+    if threshold == 0 || threshold > keys.len() {
+        return Err(Error::new(
+            eyre!("Threshold must be between 1 and {}", keys.len()),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let mut db = ctx.db.handle();
+    let mut keyring = crate::db::DatabaseModel::new()
+        .server_info()
+        .keyring()
+        .get_mut(&mut db)
+        .await?;
+    if let Some(key) = keys.iter().find(|k| !keyring.keys.contains_key(k)) {
+        return Err(Error::new(
+            eyre!("Developer key {} is not in the keyring", key),
+            ErrorKind::NotFound,
+        ));
+    }
+    keyring
+        .policies
+        .insert(id, SigningPolicy { keys, threshold });
+    keyring.save(&mut db).await
+}
+
+#[command(rename = "remove", display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove_policy(#[context] ctx: RpcContext, #[arg] id: PackageId) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let mut keyring = crate::db::DatabaseModel::new()
+        .server_info()
+        .keyring()
+        .get_mut(&mut db)
+        .await?;
+    if keyring.policies.remove(&id).is_none() {
+        return Err(Error::new(
+            eyre!("No signing policy for {}", id),
+            ErrorKind::NotFound,
+        ));
+    }
+    keyring.save(&mut db).await
+}
+
+#[command(rename = "unknown-signers", display(display_none))]
+#[instrument(skip_all)]
+pub async fn unknown_signers(
+    #[context] ctx: RpcContext,
+    #[arg] action: UnknownSigners,
+) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let mut keyring = crate::db::DatabaseModel::new()
+        .server_info()
+        .keyring()
+        .get_mut(&mut db)
+        .await?;
+    keyring.unknown_signers = action;
+    keyring.save(&mut db).await
+}
+
+#[cfg(test)]
+fn generate_key() -> PublicKey {
+    ed25519_dalek::Keypair::generate(&mut rand_old::thread_rng()).public
+}
+
+#[cfg(test)]
+fn trust(keyring: &mut Keyring, key: PublicKey) {
+    keyring.keys.insert(
+        DeveloperKey(key),
+        TrustedKey {
+            name: "test".to_owned(),
+            added: Utc::now(),
+        },
+    );
+}
+
+#[test]
+fn test_unknown_signers() {
+    let id: PackageId = "hello-world".parse().unwrap();
+    let (trusted, untrusted) = (generate_key(), generate_key());
+    let mut keyring = Keyring::default();
+    trust(&mut keyring, trusted);
+    assert_eq!(
+        keyring.check(&id, &[untrusted, trusted]).unwrap(),
+        SignerTrust::Trusted
+    );
+    assert_eq!(
+        keyring.check(&id, &[untrusted]).unwrap(),
+        SignerTrust::Unknown
+    );
+    assert_eq!(keyring.check(&id, &[]).unwrap(), SignerTrust::Unknown);
+}
+
+#[test]
+fn test_signing_policy_threshold() {
+    let id: PackageId = "hello-world".parse().unwrap();
+    let other: PackageId = "other".parse().unwrap();
+    let keys = [generate_key(), generate_key(), generate_key()];
+    let outsider = generate_key();
+    let mut keyring = Keyring::default();
+    for key in keys {
+        trust(&mut keyring, key);
+    }
+    keyring.policies.insert(
+        id.clone(),
+        SigningPolicy {
+            keys: keys.iter().copied().map(DeveloperKey).collect(),
+            threshold: 2,
+        },
+    );
+    assert!(keyring.check(&id, &[keys[0]]).is_err());
+    assert!(keyring.check(&id, &[keys[0], outsider]).is_err());
+    assert!(keyring.check(&id, &[keys[0], keys[0]]).is_err());
+    assert_eq!(
+        keyring.check(&id, &[keys[2], keys[0]]).unwrap(),
+        SignerTrust::Trusted
+    );
+    assert_eq!(keyring.check(&id, &keys).unwrap(), SignerTrust::Trusted);
+    // the policy only applies to its own package
+    assert_eq!(
+        keyring.check(&other, &[keys[1]]).unwrap(),
+        SignerTrust::Trusted
+    );
+}
+
+#[test]
+fn test_signing_policy_required_key() {
+    let id: PackageId = "hello-world".parse().unwrap();
+    let (required, trusted) = (generate_key(), generate_key());
+    let mut keyring = Keyring::default();
+    trust(&mut keyring, required);
+    trust(&mut keyring, trusted);
+    keyring.policies.insert(
+        id.clone(),
+        SigningPolicy {
+            keys: [DeveloperKey(required)].into_iter().collect(),
+            threshold: 1,
+        },
+    );
+    let err = keyring.check(&id, &[trusted]).unwrap_err();
+    assert_eq!(err.kind, ErrorKind::InvalidSignature);
+    assert_eq!(
+        keyring.check(&id, &[trusted, required]).unwrap(),
+        SignerTrust::Trusted
+    );
+}
+
+#[test]
+fn test_developer_key_serde() {
+    let key = DeveloperKey(generate_key());
+    let json = serde_json::to_string(&key).unwrap();
+    assert_eq!(json, format!("\"{}\"", key));
+    assert_eq!(serde_json::from_str::<DeveloperKey>(&json).unwrap(), key);
+}
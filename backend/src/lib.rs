@@ -4,6 +4,7 @@ pub const DEFAULT_MARKETPLACE: &str = "https://registry.start9.com";
 // pub const COMMUNITY_MARKETPLACE: &str = "https://community-registry.start9.com";
 pub const BUFFER_SIZE: usize = 1024;
 pub const HOST_IP: [u8; 4] = [172, 18, 0, 1];
+pub const HOST_IPV6: [u16; 8] = [0xfd53, 0x7a2e, 0x9c40, 0, 0, 0, 0, 1];
 pub const TARGET: &str = current_platform::CURRENT_PLATFORM;
 lazy_static::lazy_static! {
     pub static ref ARCH: &'static str = {
@@ -33,16 +34,21 @@ pub mod hostname;
 pub mod init;
 pub mod inspect;
 pub mod install;
+pub mod keyring;
 pub mod logs;
 pub mod manager;
 pub mod marketplace;
+pub mod metrics;
 pub mod middleware;
 pub mod migration;
 pub mod net;
 pub mod notifications;
 pub mod os_install;
 pub mod procedure;
+pub mod prometheus;
 pub mod properties;
+pub mod registry;
+pub mod resources;
 pub mod s9pk;
 pub mod setup;
 pub mod shutdown;
@@ -75,6 +81,7 @@ pub fn echo(#[arg] message: String) -> Result<String, RpcError> {
     auth::auth,
     db::db,
     ssh::ssh,
+    keyring::keyring,
     net::wifi::wifi,
     disk::disk,
     notifications::notification,
@@ -89,11 +96,13 @@ pub fn main_api() -> Result<(), RpcError> {
     system::time,
     system::logs,
     system::kernel_logs,
+    logs::forward::log_forward,
     system::metrics,
     shutdown::shutdown,
     shutdown::restart,
     shutdown::rebuild,
     update::update_system,
+    update::check_update,
 ))]
 pub fn server() -> Result<(), RpcError> {
     Ok(())
@@ -110,10 +119,15 @@ pub fn server() -> Result<(), RpcError> {
     control::start,
     control::stop,
     control::restart,
+    manager::restart::restart_policy,
+    resources::resources,
+    status::health_history::health,
+    metrics::package_metrics,
     logs::logs,
     properties::properties,
     dependencies::dependency,
     backup::package_backup,
+    net::domain::domain,
 ))]
 pub fn package() -> Result<(), RpcError> {
     Ok(())
@@ -122,6 +136,8 @@ pub fn package() -> Result<(), RpcError> {
 #[command(subcommands(
     version::git_info,
     s9pk::pack,
+    s9pk::sign,
+    developer::pubkey,
     developer::verify,
     developer::init,
     inspect::inspect
@@ -0,0 +1,843 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, UNIX_EPOCH};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use color_eyre::eyre::eyre;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use helpers::NonDetachingJoinHandle;
+use reqwest::Url;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tokio_stream::wrappers::LinesStream;
+use tracing::instrument;
+
+use super::{deserialize_string_or_utf8_array, LogSource};
+use crate::context::RpcContext;
+use crate::procedure::docker::DockerProcedure;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::net::{connect, tls};
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Entries held in memory while a sink is unreachable. Once full, journald is not read any
+/// further until the sink recovers, so nothing is lost unless the journal itself rotates.
+const BUFFER_CAPACITY: usize = 10_000;
+const BATCH_SIZE: usize = 500;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+const JOURNAL_RESTART_DELAY: Duration = Duration::from_secs(5);
+const LOG_FILE_NAME: &str = "embassy.log";
+const REDACTED: &str = "********";
+/// Name of the sink configured by `log-server` in the device config
+const CONFIG_SINK: &str = "log-server";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "type")]
+pub enum LogSink {
+    /// RFC 5424 messages with octet-counting framing (RFC 6587)
+    Syslog {
+        host: String,
+        #[serde(default)]
+        port: Option<u16>,
+        #[serde(default)]
+        tls: bool,
+    },
+    /// `url` is the push endpoint, e.g. http://loki.local:3100/loki/api/v1/push
+    Loki {
+        url: Url,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+        /// Added to the labels of every stream
+        #[serde(default)]
+        labels: BTreeMap<String, String>,
+    },
+    /// Appends to `embassy.log` in `path`, rotating it to `embassy.log.1` and so on. `path` is
+    /// never created, so nothing is written to the root filesystem while a drive is unmounted.
+    File {
+        path: PathBuf,
+        #[serde(default = "default_max_size")]
+        max_size: u64,
+        #[serde(default = "default_max_files")]
+        max_files: usize,
+    },
+}
+fn default_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+fn default_max_files() -> usize {
+    10
+}
+impl LogSink {
+    /// Interprets the `log-server` device config: `tcp://` and `tls://` for syslog, `http(s)://`
+    /// for Loki and `file://` for a directory
+    pub fn from_url(url: &Url) -> Result<Self, Error> {
+        let host = || {
+            url.host_str().map(|h| h.to_owned()).ok_or_else(|| {
+                Error::new(eyre!("Log server {} has no host", url), ErrorKind::ParseUrl)
+            })
+        };
+        match url.scheme() {
+            "tcp" | "syslog" => Ok(LogSink::Syslog {
+                host: host()?,
+                port: url.port(),
+                tls: false,
+            }),
+            "tls" | "syslog+tls" => Ok(LogSink::Syslog {
+                host: host()?,
+                port: url.port(),
+                tls: true,
+            }),
+            "http" | "https" => Ok(LogSink::Loki {
+                url: url.clone(),
+                headers: BTreeMap::new(),
+                labels: BTreeMap::new(),
+            }),
+            "file" => Ok(LogSink::File {
+                path: url.to_file_path().map_err(|_| {
+                    Error::new(eyre!("Invalid file URL: {}", url), ErrorKind::ParseUrl)
+                })?,
+                max_size: default_max_size(),
+                max_files: default_max_files(),
+            }),
+            scheme => Err(Error::new(
+                eyre!("Unsupported log server scheme: {}", scheme),
+                ErrorKind::ParseUrl,
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogForward {
+    #[serde(flatten)]
+    pub sink: LogSink,
+    /// Forward the logs of embassyd
+    #[serde(default = "const_true")]
+    pub system: bool,
+    #[serde(default)]
+    pub kernel: bool,
+    /// Packages to forward the logs of, or every package if missing
+    #[serde(default)]
+    pub packages: Option<BTreeSet<PackageId>>,
+    /// Least severe syslog priority to forward, from 0 (emerg) to 7 (debug)
+    #[serde(default = "default_priority")]
+    pub priority: u8,
+    /// Connect through the Tor SOCKS proxy
+    #[serde(default)]
+    pub tor: bool,
+}
+fn const_true() -> bool {
+    true
+}
+fn default_priority() -> u8 {
+    7
+}
+impl std::str::FromStr for LogForward {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).with_kind(ErrorKind::Deserialization)
+    }
+}
+impl LogForward {
+    fn accepts(&self, entry: &ForwardEntry) -> bool {
+        entry.priority <= self.priority
+            && match &entry.source {
+                LogSource::Kernel => self.kernel,
+                LogSource::Service(_) => self.system,
+                LogSource::Container(id) => self
+                    .packages
+                    .as_ref()
+                    .map_or(true, |packages| packages.contains(id)),
+            }
+    }
+
+    fn redacted(mut self) -> Self {
+        if let LogSink::Loki { headers, .. } = &mut self.sink {
+            for value in headers.values_mut() {
+                *value = REDACTED.to_owned();
+            }
+        }
+        self
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JournalRecord {
+    #[serde(rename = "__REALTIME_TIMESTAMP")]
+    timestamp: String,
+    #[serde(rename = "__CURSOR")]
+    cursor: String,
+    #[serde(rename = "MESSAGE")]
+    #[serde(default, deserialize_with = "deserialize_string_or_utf8_array")]
+    message: String,
+    #[serde(rename = "PRIORITY")]
+    #[serde(default)]
+    priority: Option<String>,
+    #[serde(rename = "_SYSTEMD_UNIT")]
+    #[serde(default)]
+    unit: Option<String>,
+    #[serde(rename = "_TRANSPORT")]
+    #[serde(default)]
+    transport: Option<String>,
+    #[serde(rename = "CONTAINER_NAME")]
+    #[serde(default)]
+    container_name: Option<String>,
+}
+impl JournalRecord {
+    /// Returns `None` for entries that are neither from embassyd, the kernel nor a package
+    fn source(&self) -> Option<LogSource> {
+        if self.transport.as_deref() == Some("kernel") {
+            Some(LogSource::Kernel)
+        } else if let Some(name) = &self.container_name {
+            DockerProcedure::uncontainer_name(name).map(|(id, _)| LogSource::Container(id))
+        } else if self
+            .unit
+            .as_deref()
+            .and_then(|unit| unit.strip_suffix(".service"))
+            == Some(crate::system::SYSTEMD_UNIT)
+        {
+            Some(LogSource::Service(crate::system::SYSTEMD_UNIT))
+        } else {
+            None
+        }
+    }
+
+    fn forward_entry(self) -> Result<Option<ForwardEntry>, Error> {
+        let source = match self.source() {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        Ok(Some(ForwardEntry {
+            cursor: self.cursor,
+            timestamp: DateTime::<Utc>::from(
+                UNIX_EPOCH + Duration::from_micros(self.timestamp.parse::<u64>()?),
+            ),
+            source,
+            priority: self
+                .priority
+                .and_then(|p| p.parse::<u8>().ok())
+                .unwrap_or(6)
+                .min(7),
+            message: self.message,
+        }))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ForwardEntry {
+    cursor: String,
+    timestamp: DateTime<Utc>,
+    source: LogSource,
+    priority: u8,
+    message: String,
+}
+impl ForwardEntry {
+    fn app_name(&self) -> &str {
+        match &self.source {
+            LogSource::Kernel => "kernel",
+            LogSource::Service(unit) => unit,
+            LogSource::Container(id) => id.as_str(),
+        }
+    }
+
+    fn facility(&self) -> u8 {
+        match &self.source {
+            LogSource::Kernel => 0,
+            LogSource::Service(_) => 3,
+            LogSource::Container(_) => 1,
+        }
+    }
+
+    fn level(&self) -> &'static str {
+        match self.priority {
+            0 => "emerg",
+            1 => "alert",
+            2 => "crit",
+            3 => "error",
+            4 => "warning",
+            5 => "notice",
+            6 => "info",
+            _ => "debug",
+        }
+    }
+
+    /// An RFC 5424 message, prefixed by its length for octet-counting framing
+    fn syslog_frame(&self, hostname: &str) -> String {
+        let message = format!(
+            "<{}>1 {} {} {} - - - {}",
+            self.facility() * 8 + self.priority,
+            self.timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+            hostname,
+            self.app_name(),
+            self.message
+        );
+        format!("{} {}", message.len(), message)
+    }
+
+    fn file_line(&self) -> String {
+        format!(
+            "{} {} {}: {}\n",
+            self.timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.app_name(),
+            self.level(),
+            self.message
+        )
+    }
+}
+
+fn loki_push(
+    hostname: &str,
+    labels: &BTreeMap<String, String>,
+    entries: &[ForwardEntry],
+) -> serde_json::Value {
+    let mut streams: BTreeMap<BTreeMap<&str, &str>, Vec<[String; 2]>> = BTreeMap::new();
+    for entry in entries {
+        let mut stream: BTreeMap<&str, &str> = labels
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        stream.insert("host", hostname);
+        stream.insert(
+            "source",
+            match &entry.source {
+                LogSource::Kernel => "kernel",
+                LogSource::Service(_) => "system",
+                LogSource::Container(_) => "package",
+            },
+        );
+        if let LogSource::Container(id) = &entry.source {
+            stream.insert("package", id.as_str());
+        }
+        stream.insert("level", entry.level());
+        streams.entry(stream).or_default().push([
+            entry.timestamp.timestamp_nanos().to_string(),
+            entry.message.clone(),
+        ]);
+    }
+    serde_json::json!({
+        "streams": streams
+            .into_iter()
+            .map(|(stream, values)| serde_json::json!({ "stream": stream, "values": values }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+async fn rotate(dir: &Path, max_files: usize) -> Result<(), Error> {
+    let rotated = |n: usize| dir.join(format!("{}.{}", LOG_FILE_NAME, n));
+    let current = dir.join(LOG_FILE_NAME);
+    if max_files == 0 {
+        return tokio::fs::remove_file(&current)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, current.display().to_string()));
+    }
+    for n in (1..max_files).rev() {
+        let from = rotated(n);
+        if tokio::fs::metadata(&from).await.is_ok() {
+            tokio::fs::rename(&from, rotated(n + 1))
+                .await
+                .with_ctx(|_| (ErrorKind::Filesystem, from.display().to_string()))?;
+        }
+    }
+    tokio::fs::rename(&current, rotated(1))
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, current.display().to_string()))
+}
+
+async fn append(dir: &Path, max_size: u64, max_files: usize, data: &str) -> Result<(), Error> {
+    if !tokio::fs::metadata(dir)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, dir.display().to_string()))?
+        .is_dir()
+    {
+        return Err(Error::new(
+            eyre!("{} is not a directory", dir.display()),
+            ErrorKind::Filesystem,
+        ));
+    }
+    let path = dir.join(LOG_FILE_NAME);
+    let size = tokio::fs::metadata(&path)
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+    if size > 0 && size + data.len() as u64 > max_size {
+        rotate(dir, max_files).await?;
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    file.write_all(data.as_bytes()).await?;
+    file.sync_data().await?;
+    Ok(())
+}
+
+struct Forwarder {
+    config: LogForward,
+    hostname: String,
+    proxy: Option<SocketAddr>,
+    syslog: Option<Box<dyn AsyncWrite + Unpin + Send>>,
+    client: Option<reqwest::Client>,
+}
+impl Forwarder {
+    fn new(config: LogForward, hostname: String, tor_socks: SocketAddr) -> Self {
+        Forwarder {
+            proxy: if config.tor { Some(tor_socks) } else { None },
+            config,
+            hostname,
+            syslog: None,
+            client: None,
+        }
+    }
+
+    async fn send(&mut self, entries: &[ForwardEntry]) -> Result<(), Error> {
+        match &self.config.sink {
+            LogSink::Syslog { host, port, tls } => {
+                if self.syslog.is_none() {
+                    let port = port.unwrap_or(if *tls { 6514 } else { 514 });
+                    let stream = connect(host, port, self.proxy).await?;
+                    let conn: Box<dyn AsyncWrite + Unpin + Send> = if *tls {
+                        Box::new(tls(host, stream).await?)
+                    } else {
+                        Box::new(stream)
+                    };
+                    self.syslog = Some(conn);
+                }
+                let data: String = entries
+                    .iter()
+                    .map(|e| e.syslog_frame(&self.hostname))
+                    .collect();
+                if let Some(conn) = &mut self.syslog {
+                    conn.write_all(data.as_bytes()).await?;
+                    conn.flush().await?;
+                }
+            }
+            LogSink::Loki {
+                url,
+                headers,
+                labels,
+            } => {
+                if self.client.is_none() {
+                    let mut client = reqwest::Client::builder().timeout(SEND_TIMEOUT);
+                    if let Some(proxy) = self.proxy {
+                        client = client.proxy(
+                            reqwest::Proxy::all(format!("socks5h://{}", proxy))
+                                .with_kind(ErrorKind::Network)?,
+                        );
+                    }
+                    self.client = Some(client.build().with_kind(ErrorKind::Network)?);
+                }
+                if let Some(client) = &self.client {
+                    let mut req =
+                        client
+                            .post(url.clone())
+                            .json(&loki_push(&self.hostname, labels, entries));
+                    for (name, value) in headers {
+                        req = req.header(name, value);
+                    }
+                    req.send()
+                        .await
+                        .with_kind(ErrorKind::Network)?
+                        .error_for_status()
+                        .with_kind(ErrorKind::Network)?;
+                }
+            }
+            LogSink::File {
+                path,
+                max_size,
+                max_files,
+            } => {
+                let data: String = entries.iter().map(|e| e.file_line()).collect();
+                append(path, *max_size, *max_files, &data).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the connection so the next send reconnects
+    fn reset(&mut self) {
+        self.syslog = None;
+    }
+}
+
+async fn journal(
+    cursor: Option<&str>,
+) -> Result<(Child, BoxStream<'static, Result<JournalRecord, Error>>), Error> {
+    let mut cmd = Command::new("journalctl");
+    cmd.kill_on_drop(true);
+    cmd.arg("--output=json");
+    cmd.arg("--output-fields=MESSAGE,PRIORITY,_SYSTEMD_UNIT,_TRANSPORT,CONTAINER_NAME");
+    cmd.arg("--all");
+    cmd.arg("--follow");
+    if let Some(cursor) = cursor {
+        cmd.arg(format!("--after-cursor={}", cursor));
+    } else {
+        cmd.arg("-n0");
+    }
+    let mut child = cmd.stdout(Stdio::piped()).spawn()?;
+    let out = BufReader::new(
+        child
+            .stdout
+            .take()
+            .ok_or_else(|| Error::new(eyre!("No stdout available"), ErrorKind::Journald))?,
+    );
+    let records = LinesStream::new(out.lines())
+        .map_err(|e| Error::new(e, ErrorKind::Journald))
+        .and_then(|s| {
+            futures::future::ready(
+                serde_json::from_str::<JournalRecord>(&s).with_kind(ErrorKind::Deserialization),
+            )
+        })
+        .boxed();
+    Ok((child, records))
+}
+
+async fn load_cursor(secrets: &PgPool, name: &str) -> Result<Option<String>, Error> {
+    Ok(sqlx::query!(
+        "SELECT cursor FROM log_forward_cursors WHERE name = $1",
+        name
+    )
+    .fetch_optional(secrets)
+    .await?
+    .map(|r| r.cursor))
+}
+
+async fn save_cursor(secrets: &PgPool, name: &str, cursor: &str) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT INTO log_forward_cursors (name, cursor) VALUES ($1, $2) ON CONFLICT (name) DO UPDATE SET cursor = $2",
+        name,
+        cursor,
+    )
+    .execute(secrets)
+    .await?;
+    Ok(())
+}
+
+/// Streams the journal to a sink, starting after the last entry it delivered
+#[instrument(skip_all, fields(name = %name))]
+async fn run(
+    name: String,
+    config: LogForward,
+    secrets: PgPool,
+    tor_socks: SocketAddr,
+    hostname: String,
+) {
+    let mut read_cursor = match load_cursor(&secrets, &name).await {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            tracing::error!("Could not load log forwarding cursor for {}: {}", name, e);
+            tracing::debug!("{:?}", e);
+            None
+        }
+    };
+    let mut forwarder = Forwarder::new(config.clone(), hostname, tor_socks);
+    let mut buffer: VecDeque<ForwardEntry> = VecDeque::new();
+    let mut backoff = Duration::ZERO;
+    let mut next_flush: Option<Instant> = None;
+    loop {
+        let (_child, mut records) = match journal(read_cursor.as_deref()).await {
+            Ok(journal) => journal,
+            Err(e) => {
+                tracing::error!("Could not read journal for log forwarding: {}", e);
+                tracing::debug!("{:?}", e);
+                tokio::time::sleep(JOURNAL_RESTART_DELAY).await;
+                continue;
+            }
+        };
+        loop {
+            let flush_at = next_flush;
+            tokio::select! {
+                record = records.try_next(), if buffer.len() < BUFFER_CAPACITY => match record {
+                    Ok(Some(record)) => {
+                        read_cursor = Some(record.cursor.clone());
+                        match record.forward_entry() {
+                            Ok(Some(entry)) if config.accepts(&entry) => {
+                                buffer.push_back(entry);
+                                if buffer.len() >= BATCH_SIZE && backoff.is_zero() {
+                                    next_flush = Some(Instant::now());
+                                } else if next_flush.is_none() {
+                                    next_flush = Some(Instant::now() + FLUSH_INTERVAL);
+                                }
+                            }
+                            Ok(_) => (),
+                            Err(e) => tracing::debug!("Skipping journal entry: {:?}", e),
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => tracing::debug!("Skipping journal entry: {:?}", e),
+                },
+                _ = tokio::time::sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                    let len = buffer.len().min(BATCH_SIZE);
+                    let batch = &buffer.make_contiguous()[..len];
+                    let res = tokio::time::timeout(SEND_TIMEOUT, forwarder.send(batch))
+                        .await
+                        .map_err(|_| Error::new(eyre!("Timed out forwarding logs"), ErrorKind::Network))
+                        .and_then(|res| res);
+                    match res {
+                        Ok(()) => {
+                            if !backoff.is_zero() {
+                                tracing::info!("Log forwarding to {} recovered", name);
+                                backoff = Duration::ZERO;
+                            }
+                            let delivered = buffer.drain(..len).last().map(|e| e.cursor);
+                            let cursor = if buffer.is_empty() { read_cursor.clone() } else { delivered };
+                            if let Some(cursor) = cursor {
+                                if let Err(e) = save_cursor(&secrets, &name, &cursor).await {
+                                    tracing::error!("Could not save log forwarding cursor for {}: {}", name, e);
+                                    tracing::debug!("{:?}", e);
+                                }
+                            }
+                            next_flush = if buffer.is_empty() {
+                                None
+                            } else if buffer.len() >= BATCH_SIZE {
+                                Some(Instant::now())
+                            } else {
+                                Some(Instant::now() + FLUSH_INTERVAL)
+                            };
+                        }
+                        Err(e) => {
+                            if backoff.is_zero() {
+                                tracing::warn!("Could not forward logs to {}, buffering: {}", name, e);
+                                tracing::debug!("{:?}", e);
+                            }
+                            forwarder.reset();
+                            backoff = (backoff * 2).max(FLUSH_INTERVAL).min(MAX_BACKOFF);
+                            next_flush = Some(Instant::now() + backoff);
+                        }
+                    }
+                }
+            }
+        }
+        tracing::warn!("journalctl exited, restarting log forwarding to {}", name);
+        tokio::time::sleep(JOURNAL_RESTART_DELAY).await;
+    }
+}
+
+pub struct LogForwarder {
+    secrets: PgPool,
+    tor_socks: SocketAddr,
+    hostname: String,
+    log_server: Option<LogForward>,
+    tasks: Mutex<BTreeMap<String, NonDetachingJoinHandle<()>>>,
+}
+impl LogForwarder {
+    /// An invalid `log_server` is logged and ignored so that it cannot keep the server from
+    /// starting
+    pub fn new(
+        secrets: PgPool,
+        tor_socks: SocketAddr,
+        hostname: String,
+        log_server: Option<&Url>,
+    ) -> Self {
+        LogForwarder {
+            secrets,
+            tor_socks,
+            hostname,
+            log_server: log_server.and_then(|url| match LogSink::from_url(url) {
+                Ok(sink) => Some(LogForward {
+                    sink,
+                    system: true,
+                    kernel: false,
+                    packages: None,
+                    priority: default_priority(),
+                    tor: false,
+                }),
+                Err(e) => {
+                    tracing::error!("Invalid log server {}: {}", url, e);
+                    tracing::debug!("{:?}", e);
+                    None
+                }
+            }),
+            tasks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    async fn load_all(&self) -> Result<BTreeMap<String, LogForward>, Error> {
+        let mut all: BTreeMap<String, LogForward> =
+            sqlx::query!("SELECT name, config FROM log_forwarders ORDER BY name")
+                .fetch_all(&self.secrets)
+                .await?
+                .into_iter()
+                .map(|r| {
+                    Ok((
+                        r.name,
+                        serde_json::from_str(&r.config).with_kind(ErrorKind::Deserialization)?,
+                    ))
+                })
+                .collect::<Result<_, Error>>()?;
+        if let Some(log_server) = &self.log_server {
+            all.insert(CONFIG_SINK.to_owned(), log_server.clone());
+        }
+        Ok(all)
+    }
+
+    #[instrument(skip_all)]
+    pub async fn init(&self) -> Result<(), Error> {
+        for (name, config) in self.load_all().await? {
+            self.restart(name, Some(config)).await;
+        }
+        Ok(())
+    }
+
+    /// Stops forwarding to `name`, then starts again with `config` if there is one
+    async fn restart(&self, name: String, config: Option<LogForward>) {
+        let mut tasks = self.tasks.lock().await;
+        tasks.remove(&name);
+        if let Some(config) = config {
+            tasks.insert(
+                name.clone(),
+                tokio::spawn(run(
+                    name,
+                    config,
+                    self.secrets.clone(),
+                    self.tor_socks,
+                    self.hostname.clone(),
+                ))
+                .into(),
+            );
+        }
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+    if name == CONFIG_SINK {
+        return Err(Error::new(
+            eyre!("{} is set in the device config", CONFIG_SINK),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    Ok(())
+}
+
+#[command(rename = "log-forward", subcommands(list, set, remove))]
+pub fn log_forward() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Secrets are redacted
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<BTreeMap<String, LogForward>, Error> {
+    Ok(ctx
+        .log_forwarder
+        .load_all()
+        .await?
+        .into_iter()
+        .map(|(name, config)| (name, config.redacted()))
+        .collect())
+}
+
+/// Adds a sink, or replaces the one with the same name. `config` is its JSON config.
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn set(
+    #[context] ctx: RpcContext,
+    #[arg] name: String,
+    #[arg] config: LogForward,
+) -> Result<(), Error> {
+    check_name(&name)?;
+    if let LogSink::File { path, .. } = &config.sink {
+        if !path.is_absolute() {
+            return Err(Error::new(
+                eyre!("{} is not an absolute path", path.display()),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+    }
+    let serialized = serde_json::to_string(&config).with_kind(ErrorKind::Serialization)?;
+    sqlx::query!(
+        "INSERT INTO log_forwarders (name, config) VALUES ($1, $2) ON CONFLICT (name) DO UPDATE SET config = $2",
+        name,
+        serialized,
+    )
+    .execute(&ctx.secret_store)
+    .await?;
+    ctx.log_forwarder.restart(name, Some(config)).await;
+    Ok(())
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] name: String) -> Result<(), Error> {
+    check_name(&name)?;
+    let mut tx = ctx.secret_store.begin().await?;
+    if sqlx::query!("DELETE FROM log_forwarders WHERE name = $1", name)
+        .execute(&mut tx)
+        .await?
+        .rows_affected()
+        == 0
+    {
+        return Err(Error::new(
+            eyre!("Log Forwarder {} Not Found", name),
+            ErrorKind::NotFound,
+        ));
+    }
+    sqlx::query!("DELETE FROM log_forward_cursors WHERE name = $1", name)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    ctx.log_forwarder.restart(name, None).await;
+    Ok(())
+}
+
+#[test]
+fn test_forward_entry() {
+    let config: LogForward = r#"{
+        "type": "syslog",
+        "host": "logs.example.com",
+        "packages": ["bitcoind"],
+        "priority": 4
+    }"#
+    .parse()
+    .unwrap();
+    let record: JournalRecord = serde_json::from_str(
+        r#"{
+            "__REALTIME_TIMESTAMP": "1680000000000000",
+            "__CURSOR": "s=abc",
+            "MESSAGE": "disk full",
+            "PRIORITY": "3",
+            "CONTAINER_NAME": "bitcoind.embassy"
+        }"#,
+    )
+    .unwrap();
+    let entry = record.forward_entry().unwrap().unwrap();
+    assert!(config.accepts(&entry));
+    assert_eq!(
+        entry.syslog_frame("adjective-noun"),
+        "73 <11>1 2023-03-28T10:40:00.000000Z adjective-noun bitcoind - - - disk full"
+    );
+    let info = ForwardEntry {
+        priority: 6,
+        ..entry.clone()
+    };
+    assert!(!config.accepts(&info));
+    let lnd = ForwardEntry {
+        source: LogSource::Container("lnd".parse().unwrap()),
+        ..entry.clone()
+    };
+    assert!(!config.accepts(&lnd));
+    let kernel = ForwardEntry {
+        source: LogSource::Kernel,
+        ..entry
+    };
+    assert!(!config.accepts(&kernel));
+}
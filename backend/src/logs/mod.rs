@@ -4,12 +4,16 @@ use std::ops::{Deref, DerefMut};
 use std::process::Stdio;
 use std::time::{Duration, UNIX_EPOCH};
 
+use async_compression::tokio::bufread::GzipEncoder;
 use chrono::{DateTime, Utc};
 use color_eyre::eyre::eyre;
 use futures::stream::BoxStream;
 use futures::{FutureExt, SinkExt, Stream, StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
 use hyper::upgrade::Upgraded;
-use hyper::Error as HyperError;
+use hyper::{Body, Error as HyperError, Response};
+use regex::Regex;
 use rpc_toolkit::command;
 use rpc_toolkit::yajrc::RpcError;
 use serde::{Deserialize, Serialize};
@@ -21,6 +25,7 @@ use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
+use tokio_util::io::{ReaderStream, StreamReader};
 use tracing::instrument;
 
 use crate::context::{CliContext, RpcContext};
@@ -32,9 +37,13 @@ use crate::util::display_none;
 use crate::util::serde::Reversible;
 use crate::{Error, ErrorKind};
 
+pub mod forward;
+
 #[pin_project::pin_project]
 struct LogStream {
     _child: Child,
+    /// Entries are in reverse chronological order
+    reversed: bool,
     #[pin]
     entries: BoxStream<'static, Result<JournalctlEntry, Error>>,
 }
@@ -140,6 +149,47 @@ impl std::fmt::Display for LogEntry {
     }
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct LogFilter {
+    /// Case insensitive substring of the message
+    pub search: Option<String>,
+    pub regex: Option<String>,
+    /// Least severe syslog priority to return, from 0 (emerg) to 7 (debug)
+    pub priority: Option<u8>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+impl LogFilter {
+    fn matcher(&self) -> Result<Option<LogMatcher>, Error> {
+        if self.search.is_none() && self.regex.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(LogMatcher {
+            search: self.search.as_ref().map(|s| s.to_lowercase()),
+            regex: self
+                .regex
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .with_kind(ErrorKind::InvalidRequest)?,
+        }))
+    }
+}
+
+struct LogMatcher {
+    search: Option<String>,
+    regex: Option<Regex>,
+}
+impl LogMatcher {
+    fn matches(&self, message: &str) -> bool {
+        self.search
+            .as_ref()
+            .map_or(true, |s| message.to_lowercase().contains(s))
+            && self.regex.as_ref().map_or(true, |r| r.is_match(message))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct JournalctlEntry {
     #[serde(rename = "__REALTIME_TIMESTAMP")]
@@ -207,7 +257,7 @@ fn deserialize_string_or_utf8_array<'de, D: serde::de::Deserializer<'de>>(
     deserializer.deserialize_any(Visitor)
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum LogSource {
     Kernel,
     Service(&'static str),
@@ -216,7 +266,7 @@ pub enum LogSource {
 
 #[command(
     custom_cli(cli_logs(async, context(CliContext))),
-    subcommands(self(logs_nofollow(async)), logs_follow),
+    subcommands(self(logs_nofollow(async)), logs_follow, logs_export),
     display(display_none)
 )]
 pub async fn logs(
@@ -225,12 +275,47 @@ pub async fn logs(
     #[arg(short = 'c', long = "cursor")] cursor: Option<String>,
     #[arg(short = 'B', long = "before", default)] before: bool,
     #[arg(short = 'f', long = "follow", default)] follow: bool,
-) -> Result<(PackageId, Option<usize>, Option<String>, bool, bool), Error> {
-    Ok((id, limit, cursor, before, follow))
+    #[arg(short = 's', long = "search")] search: Option<String>,
+    #[arg(short = 'r', long = "regex")] regex: Option<String>,
+    #[arg(short = 'p', long = "priority")] priority: Option<u8>,
+    #[arg(long = "since")] since: Option<DateTime<Utc>>,
+    #[arg(long = "until")] until: Option<DateTime<Utc>>,
+) -> Result<
+    (
+        PackageId,
+        Option<usize>,
+        Option<String>,
+        bool,
+        bool,
+        LogFilter,
+    ),
+    Error,
+> {
+    Ok((
+        id,
+        limit,
+        cursor,
+        before,
+        follow,
+        LogFilter {
+            search,
+            regex,
+            priority,
+            since,
+            until,
+        },
+    ))
 }
 pub async fn cli_logs(
     ctx: CliContext,
-    (id, limit, cursor, before, follow): (PackageId, Option<usize>, Option<String>, bool, bool),
+    (id, limit, cursor, before, follow, filter): (
+        PackageId,
+        Option<usize>,
+        Option<String>,
+        bool,
+        bool,
+        LogFilter,
+    ),
 ) -> Result<(), RpcError> {
     if follow {
         if cursor.is_some() {
@@ -245,23 +330,52 @@ pub async fn cli_logs(
                 crate::ErrorKind::InvalidRequest,
             )));
         }
-        cli_logs_generic_follow(ctx, "package.logs.follow", Some(id), limit).await
+        cli_logs_generic_follow(ctx, "package.logs.follow", Some(id), limit, filter).await
     } else {
-        cli_logs_generic_nofollow(ctx, "package.logs", Some(id), limit, cursor, before).await
+        cli_logs_generic_nofollow(ctx, "package.logs", Some(id), limit, cursor, before, filter)
+            .await
     }
 }
 pub async fn logs_nofollow(
     _ctx: (),
-    (id, limit, cursor, before, _): (PackageId, Option<usize>, Option<String>, bool, bool),
+    (id, limit, cursor, before, _, filter): (
+        PackageId,
+        Option<usize>,
+        Option<String>,
+        bool,
+        bool,
+        LogFilter,
+    ),
 ) -> Result<LogResponse, Error> {
-    fetch_logs(LogSource::Container(id), limit, cursor, before).await
+    fetch_logs(LogSource::Container(id), limit, cursor, before, filter).await
 }
 #[command(rpc_only, rename = "follow", display(display_none))]
 pub async fn logs_follow(
     #[context] ctx: RpcContext,
-    #[parent_data] (id, limit, _, _, _): (PackageId, Option<usize>, Option<String>, bool, bool),
+    #[parent_data] (id, limit, _, _, _, filter): (
+        PackageId,
+        Option<usize>,
+        Option<String>,
+        bool,
+        bool,
+        LogFilter,
+    ),
 ) -> Result<LogFollowResponse, Error> {
-    follow_logs(ctx, LogSource::Container(id), limit).await
+    follow_logs(ctx, LogSource::Container(id), limit, filter).await
+}
+#[command(rpc_only, rename = "export", display(display_none))]
+pub async fn logs_export(
+    #[context] ctx: RpcContext,
+    #[parent_data] (id, _, _, _, _, filter): (
+        PackageId,
+        Option<usize>,
+        Option<String>,
+        bool,
+        bool,
+        LogFilter,
+    ),
+) -> Result<RequestGuid, Error> {
+    export_logs(ctx, LogSource::Container(id), filter).await
 }
 
 pub async fn cli_logs_generic_nofollow(
@@ -271,6 +385,7 @@ pub async fn cli_logs_generic_nofollow(
     limit: Option<usize>,
     cursor: Option<String>,
     before: bool,
+    filter: LogFilter,
 ) -> Result<(), RpcError> {
     let res = rpc_toolkit::command_helpers::call_remote(
         ctx.clone(),
@@ -280,6 +395,11 @@ pub async fn cli_logs_generic_nofollow(
             "limit": limit,
             "cursor": cursor,
             "before": before,
+            "search": filter.search,
+            "regex": filter.regex,
+            "priority": filter.priority,
+            "since": filter.since,
+            "until": filter.until,
         }),
         PhantomData::<LogResponse>,
     )
@@ -298,6 +418,7 @@ pub async fn cli_logs_generic_follow(
     method: &str,
     id: Option<PackageId>,
     limit: Option<usize>,
+    filter: LogFilter,
 ) -> Result<(), RpcError> {
     let res = rpc_toolkit::command_helpers::call_remote(
         ctx.clone(),
@@ -305,6 +426,11 @@ pub async fn cli_logs_generic_follow(
         serde_json::json!({
             "id": id,
             "limit": limit,
+            "search": filter.search,
+            "regex": filter.regex,
+            "priority": filter.priority,
+            "since": filter.since,
+            "until": filter.until,
         }),
         PhantomData::<LogFollowResponse>,
     )
@@ -344,19 +470,35 @@ pub async fn cli_logs_generic_follow(
     Ok(())
 }
 
+/// Without a `limit`, returns every entry matching `filter`
 async fn journalctl(
     id: LogSource,
-    limit: usize,
+    limit: Option<usize>,
     cursor: Option<&str>,
     before: bool,
     follow: bool,
+    filter: &LogFilter,
 ) -> Result<LogStream, Error> {
+    let matcher = filter.matcher()?;
     let mut cmd = Command::new("journalctl");
     cmd.kill_on_drop(true);
 
     cmd.arg("--output=json");
     cmd.arg("--output-fields=MESSAGE");
-    cmd.arg(format!("-n{}", limit));
+    // messages are matched here rather than by journalctl, so it cannot limit them
+    let limit_matches = limit.filter(|_| matcher.is_some() && !follow);
+    if let Some(limit) = limit.filter(|_| limit_matches.is_none()) {
+        cmd.arg(format!("-n{}", limit));
+    }
+    if let Some(priority) = filter.priority {
+        cmd.arg(format!("--priority={}", priority.min(7)));
+    }
+    if let Some(since) = filter.since {
+        cmd.arg(format!("--since=@{}", since.timestamp()));
+    }
+    if let Some(until) = filter.until {
+        cmd.arg(format!("--until=@{}", until.timestamp()));
+    }
     match id {
         LogSource::Kernel => {
             cmd.arg("-k");
@@ -374,11 +516,17 @@ async fn journalctl(
     };
 
     let cursor_formatted = format!("--after-cursor={}", cursor.clone().unwrap_or(""));
+    // without a cursor, the most recent matches are found by reading backwards
+    let reversed = if cursor.is_some() {
+        before
+    } else {
+        limit_matches.is_some()
+    };
     if cursor.is_some() {
         cmd.arg(&cursor_formatted);
-        if before {
-            cmd.arg("--reverse");
-        }
+    }
+    if reversed {
+        cmd.arg("--reverse");
     }
     if follow {
         cmd.arg("--follow");
@@ -403,9 +551,22 @@ async fn journalctl(
             )
         });
 
+    let entries = if let Some(matcher) = matcher {
+        let entries = deserialized_entries
+            .try_filter(move |entry| futures::future::ready(matcher.matches(&entry.message)));
+        if let Some(limit) = limit_matches {
+            entries.take(limit).boxed()
+        } else {
+            entries.boxed()
+        }
+    } else {
+        deserialized_entries.boxed()
+    };
+
     Ok(LogStream {
         _child: child,
-        entries: deserialized_entries.boxed(),
+        reversed,
+        entries,
     })
 }
 
@@ -415,9 +576,11 @@ pub async fn fetch_logs(
     limit: Option<usize>,
     cursor: Option<String>,
     before: bool,
+    filter: LogFilter,
 ) -> Result<LogResponse, Error> {
     let limit = limit.unwrap_or(50);
-    let mut stream = journalctl(id, limit, cursor.as_deref(), before, false).await?;
+    let mut stream = journalctl(id, Some(limit), cursor.as_deref(), before, false, &filter).await?;
+    let reversed = stream.reversed;
 
     let mut entries = Vec::with_capacity(limit);
     let mut start_cursor = None;
@@ -445,7 +608,7 @@ pub async fn fetch_logs(
         .await?;
     let mut entries = Reversible::new(entries);
     // reverse again so output is always in increasing chronological order
-    if cursor.is_some() && before {
+    if reversed {
         entries.reverse();
         std::mem::swap(&mut start_cursor, &mut end_cursor);
     }
@@ -461,9 +624,10 @@ pub async fn follow_logs(
     ctx: RpcContext,
     id: LogSource,
     limit: Option<usize>,
+    filter: LogFilter,
 ) -> Result<LogFollowResponse, Error> {
     let limit = limit.unwrap_or(50);
-    let mut stream = journalctl(id, limit, None, false, true).await?;
+    let mut stream = journalctl(id, Some(limit), None, false, true, &filter).await?;
 
     let mut start_cursor = None;
     let mut first_entry = None;
@@ -491,6 +655,56 @@ pub async fn follow_logs(
     Ok(LogFollowResponse { start_cursor, guid })
 }
 
+/// Prepares a gzipped text file of every entry matching `filter`, to be downloaded from
+/// `/rest/rpc/<guid>`
+#[instrument(skip_all)]
+pub async fn export_logs(
+    ctx: RpcContext,
+    id: LogSource,
+    filter: LogFilter,
+) -> Result<RequestGuid, Error> {
+    filter.matcher()?;
+    let file_name = match &id {
+        LogSource::Kernel => "kernel.log.gz".to_owned(),
+        LogSource::Service(unit) => format!("{}.log.gz", unit),
+        LogSource::Container(id) => format!("{}.log.gz", id),
+    };
+    let guid = RequestGuid::new();
+    ctx.add_continuation(
+        guid.clone(),
+        RpcContinuation::rest(
+            Box::new(move |_| {
+                async move {
+                    let lines = journalctl(id, None, None, false, false, &filter)
+                        .await?
+                        .and_then(|entry| {
+                            futures::future::ready(
+                                entry
+                                    .log_entry()
+                                    .map(|(_, entry)| Bytes::from(format!("{}\n", entry))),
+                            )
+                        })
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.source));
+                    Response::builder()
+                        .header(CONTENT_TYPE, "application/gzip")
+                        .header(
+                            CONTENT_DISPOSITION,
+                            format!("attachment; filename=\"{}\"", file_name),
+                        )
+                        .body(Body::wrap_stream(ReaderStream::new(GzipEncoder::new(
+                            StreamReader::new(lines),
+                        ))))
+                        .with_kind(ErrorKind::Network)
+                }
+                .boxed()
+            }),
+            Duration::from_secs(30),
+        ),
+    )
+    .await;
+    Ok(guid)
+}
+
 // #[tokio::test]
 // pub async fn test_logs() {
 //     let response = fetch_logs(
@@ -534,3 +748,29 @@ pub async fn follow_logs(
 //         dbg!(line);
 //     }
 // }
+
+#[test]
+fn test_log_matcher() {
+    let filter = LogFilter {
+        search: Some("Chain".to_owned()),
+        ..Default::default()
+    };
+    let matcher = filter.matcher().unwrap().unwrap();
+    assert!(matcher.matches("Syncing chain: 50%"));
+    assert!(!matcher.matches("Peer connected"));
+    let filter = LogFilter {
+        search: Some("chain".to_owned()),
+        regex: Some(r"height=\d+".to_owned()),
+        ..Default::default()
+    };
+    let matcher = filter.matcher().unwrap().unwrap();
+    assert!(matcher.matches("Syncing chain: height=1000"));
+    assert!(!matcher.matches("Syncing chain: height=unknown"));
+    assert!(LogFilter::default().matcher().unwrap().is_none());
+    assert!(LogFilter {
+        regex: Some("(".to_owned()),
+        ..Default::default()
+    }
+    .matcher()
+    .is_err());
+}
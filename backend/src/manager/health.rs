@@ -8,8 +8,10 @@ use tracing::instrument;
 use crate::context::RpcContext;
 use crate::db::model::CurrentDependents;
 use crate::dependencies::{break_transitive, heal_transitive, DependencyError};
+use crate::notifications::{HealthDegraded, NotificationLevel};
 use crate::s9pk::manifest::{Manifest, PackageId};
 use crate::status::health_check::{HealthCheckId, HealthCheckResult};
+use crate::status::health_history::{self, HealthState};
 use crate::status::MainStatus;
 use crate::Error;
 
@@ -91,13 +93,14 @@ impl HealthCheckStatusReceipt {
     }
 }
 
+/// Returns the results that were committed, which are empty if the service is not running
 #[instrument(skip_all)]
 pub async fn check<Db: DbHandle>(
     ctx: &RpcContext,
     db: &mut Db,
     id: &PackageId,
     should_commit: &AtomicBool,
-) -> Result<(), Error> {
+) -> Result<BTreeMap<HealthCheckId, HealthCheckResult>, Error> {
     let mut tx = db.begin().await?;
     let (manifest, started) = {
         let mut checkpoint = tx.begin().await?;
@@ -125,11 +128,11 @@ pub async fn check<Db: DbHandle>(
             )
             .await?
     } else {
-        return Ok(());
+        return Ok(BTreeMap::new());
     };
 
     if !should_commit.load(Ordering::SeqCst) {
-        return Ok(());
+        return Ok(BTreeMap::new());
     }
 
     if !health_results
@@ -149,13 +152,21 @@ pub async fn check<Db: DbHandle>(
         );
     }
 
+    let mut changed = Vec::new();
     let current_dependents = {
         let mut checkpoint = tx.begin().await?;
         let receipts = HealthCheckStatusReceipt::new(&mut checkpoint, id).await?;
 
         let status = receipts.status.get(&mut checkpoint).await?;
 
-        if let MainStatus::Running { health: _, started } = status {
+        if let MainStatus::Running { health, started } = status {
+            changed = health_results
+                .iter()
+                .filter(|(check, res)| {
+                    health.get(*check).map(HealthState::from) != Some(HealthState::from(*res))
+                })
+                .map(|(check, res)| (check.clone(), res.clone()))
+                .collect();
             receipts
                 .status
                 .set(
@@ -198,7 +209,49 @@ pub async fn check<Db: DbHandle>(
         }
     }
 
+    for (check, res) in changed {
+        health_history::record(&ctx.secret_store, id, &check, &res).await?;
+        let error = if let HealthCheckResult::Failure { error } = res {
+            error
+        } else {
+            continue;
+        };
+        let name = manifest
+            .health_checks
+            .0
+            .get(&check)
+            .map_or_else(|| check.to_string(), |hc| hc.name.clone());
+        if health_history::check_is_flapping(&ctx.secret_store, id, &check).await? {
+            ctx.notification_manager
+                .notify(
+                    &mut tx,
+                    Some(id.clone()),
+                    NotificationLevel::Warning,
+                    format!("{} Health Check Flapping", name),
+                    format!(
+                        "{} of {} keeps failing and recovering. Further failures will not be notified until it settles down.",
+                        name, manifest.title
+                    ),
+                    HealthDegraded { check, error },
+                    Some(3600),
+                )
+                .await?;
+        } else {
+            ctx.notification_manager
+                .notify(
+                    &mut tx,
+                    Some(id.clone()),
+                    NotificationLevel::Warning,
+                    format!("{} Health Check Failing", name),
+                    format!("{} of {}: {}", name, manifest.title, error),
+                    HealthDegraded { check, error },
+                    Some(3600),
+                )
+                .await?;
+        }
+    }
+
     tx.save().await?;
 
-    Ok(())
+    Ok(health_results)
 }
@@ -1,12 +1,13 @@
 use std::collections::BTreeMap;
 use std::future::Future;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::task::Poll;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use bollard::container::{KillContainerOptions, StopContainerOptions};
+use chrono::Utc;
 use color_eyre::eyre::eyre;
 use embassy_container_init::{ProcessGroupId, SignalGroupParams};
 use helpers::UnixRpcClient;
@@ -15,22 +16,28 @@ use patch_db::DbHandle;
 use sqlx::{Connection, Executor, Postgres};
 use tokio::sync::watch::error::RecvError;
 use tokio::sync::watch::{channel, Receiver, Sender};
-use tokio::sync::{oneshot, Notify, RwLock};
+use tokio::sync::{oneshot, Mutex, Notify, RwLock};
 use tracing::instrument;
 
 use crate::context::RpcContext;
+use crate::manager::restart::{ExitReason, RestartCondition, RestartDecision, RestartEvent};
 use crate::manager::sync::synchronizer;
+use crate::net::interface::{ClearnetAddress, InterfaceId};
 use crate::net::net_controller::NetService;
+use crate::notifications::NotificationLevel;
 use crate::procedure::docker::{DockerContainer, DockerProcedure, LongRunning};
 #[cfg(feature = "js_engine")]
 use crate::procedure::js_scripts::JsProcedure;
 use crate::procedure::{NoOutput, PackageProcedure, ProcedureName};
 use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::status::health_check::HealthCheckResult;
+use crate::status::health_history;
 use crate::util::{ApplyRef, Container, NonDetachingJoinHandle, Version};
 use crate::volume::Volume;
 use crate::Error;
 
 pub mod health;
+pub mod restart;
 mod sync;
 
 pub const HEALTH_CHECK_COOLDOWN_SECONDS: u64 = 15;
@@ -152,6 +159,8 @@ struct ManagerSeed {
     ctx: RpcContext,
     manifest: Manifest,
     container_name: String,
+    /// Bindings of the running container, so they can be changed without a restart
+    net_service: Mutex<Option<NetService>>,
 }
 
 pub struct ManagerSharedState {
@@ -175,41 +184,56 @@ pub enum OnStop {
 }
 
 #[instrument(skip_all)]
-async fn run_main(
-    state: &Arc<ManagerSharedState>,
-) -> Result<Result<NoOutput, (i32, String)>, Error> {
+async fn run_main(state: &Arc<ManagerSharedState>) -> Result<ExitReason, Error> {
     let rt_state = state.clone();
 
     let mut runtime = NonDetachingJoinHandle::from(tokio::spawn(start_up_image(rt_state)));
     let ip = match state.persistent_container.is_some() {
         false => Some(match get_running_ip(state, &mut runtime).await {
-            GetRunningIp::Ip(x) => x,
+            GetRunningIp::Ip(ip, ipv6) => (ip, ipv6),
             GetRunningIp::Error(e) => return Err(e),
-            GetRunningIp::EarlyExit(x) => return Ok(x),
+            GetRunningIp::EarlyExit(x) => return Ok(exit_reason(x)),
         }),
         true => None,
     };
 
-    let svc = if let Some(ip) = ip {
-        Some(add_network_for_main(&*state.seed, ip).await?)
-    } else {
-        None
-    };
+    if let Some((ip, ipv6)) = ip {
+        add_network_for_main(&*state.seed, ip, ipv6).await?;
+    }
 
     set_commit_health_true(state);
     let health = main_health_check_daemon(state.clone());
     fetch_starting_to_running(state);
     let res = tokio::select! {
-        a = runtime => a.map_err(|_| Error::new(eyre!("Manager runtime panicked!"), crate::ErrorKind::Docker)).and_then(|a| a),
-        _ = health => Err(Error::new(eyre!("Health check daemon exited!"), crate::ErrorKind::Unknown)),
-        _ = state.killer.notified() => Ok(Err((137, "Killed".to_string())))
+        a = &mut runtime => a.map_err(|_| Error::new(eyre!("Manager runtime panicked!"), crate::ErrorKind::Docker)).and_then(|a| a).map(exit_reason),
+        failures = health => Ok(ExitReason::Unhealthy { failures }),
+        _ = state.killer.notified() => Ok(ExitReason::Crashed { code: 137, message: "Killed".to_string() })
     };
-    if let Some(svc) = svc {
-        remove_network_for_main(svc).await?;
+    if let Ok(ExitReason::Unhealthy { .. }) = res {
+        // give the service a chance to shut down cleanly before the runtime is dropped
+        if let Err(e) = send_signal(state, &Signal::SIGTERM).await {
+            tracing::error!("Failed to stop unhealthy service: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        let _ = tokio::time::timeout(
+            sigterm_timeout(&state.seed.manifest).unwrap_or(Duration::from_secs(30)),
+            &mut runtime,
+        )
+        .await;
+    }
+    if ip.is_some() {
+        remove_network_for_main(&*state.seed).await?;
     }
     res
 }
 
+fn exit_reason(res: Result<NoOutput, (i32, String)>) -> ExitReason {
+    match res {
+        Ok(NoOutput) => ExitReason::Exited,
+        Err((code, message)) => ExitReason::Crashed { code, message },
+    }
+}
+
 /// We want to start up the manifest, but in this case we want to know that we have generated the certificates.
 /// Note for _generated_certificate: Needed to know that before we start the state we have generated the certificate
 async fn start_up_image(
@@ -239,6 +263,7 @@ impl Manager {
             ctx,
             container_name: DockerProcedure::container_name(&manifest.id, None),
             manifest,
+            net_service: Mutex::new(None),
         });
         let persistent_container = PersistentContainer::init(&seed).await?;
         let shared = Arc::new(ManagerSharedState {
@@ -271,6 +296,35 @@ impl Manager {
         send_signal(&self.shared, signal).await
     }
 
+    /// Serves `domain` right away if the service is running, otherwise it is bound when it starts
+    pub async fn add_clearnet(
+        &self,
+        interface: InterfaceId,
+        domain: String,
+        addr: &ClearnetAddress,
+    ) -> Result<(), Error> {
+        if let Some(svc) = self.shared.seed.net_service.lock().await.as_mut() {
+            let mut secrets = self.shared.seed.ctx.secret_store.acquire().await?;
+            svc.add_clearnet(
+                &mut secrets,
+                interface,
+                domain,
+                addr.external,
+                addr.internal,
+                false,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_clearnet(&self, domain: &str) -> Result<(), Error> {
+        if let Some(svc) = self.shared.seed.net_service.lock().await.as_mut() {
+            svc.remove_clearnet(domain).await?;
+        }
+        Ok(())
+    }
+
     #[instrument(skip_all)]
     async fn exit(&self) -> Result<(), Error> {
         self.shared
@@ -348,6 +402,7 @@ impl Manager {
 }
 
 async fn manager_thread_loop(mut recv: Receiver<OnStop>, thread_shared: &Arc<ManagerSharedState>) {
+    let mut attempt = 0;
     loop {
         fn handle_stop_action<'a>(
             recv: &'a mut Receiver<OnStop>,
@@ -365,6 +420,7 @@ async fn manager_thread_loop(mut recv: Receiver<OnStop>, thread_shared: &Arc<Man
         match stop_action {
             OnStop::Sleep => {
                 if let Some(fut) = fut {
+                    attempt = 0;
                     let _ = thread_shared.status.0.send(Status::Stopped);
                     fut.await.unwrap();
                     continue;
@@ -378,12 +434,11 @@ async fn manager_thread_loop(mut recv: Receiver<OnStop>, thread_shared: &Arc<Man
                 let _ = thread_shared.status.0.send(Status::Running);
             }
         }
-        match run_main(thread_shared).await {
-            Ok(Ok(NoOutput)) => (), // restart
-            Ok(Err(e)) => {
+        let started = Instant::now();
+        let reason = match run_main(thread_shared).await {
+            Ok(ExitReason::Crashed { code, message }) => {
                 #[cfg(feature = "unstable")]
                 {
-                    use crate::notifications::NotificationLevel;
                     use crate::status::MainStatus;
                     let mut db = thread_shared.seed.ctx.db.handle();
                     let started = crate::db::DatabaseModel::new()
@@ -401,7 +456,7 @@ async fn manager_thread_loop(mut recv: Receiver<OnStop>, thread_shared: &Arc<Man
                                     Some(thread_shared.seed.manifest.id.clone()),
                                     NotificationLevel::Warning,
                                     String::from("Service Crashed"),
-                                    format!("The service {} has crashed with the following exit code: {}\nDetails: {}", thread_shared.seed.manifest.id.clone(), e.0, e.1),
+                                    format!("The service {} has crashed with the following exit code: {}\nDetails: {}", thread_shared.seed.manifest.id.clone(), code, message),
                                     (),
                                     Some(3600) // 1 hour
                                 )
@@ -416,17 +471,117 @@ async fn manager_thread_loop(mut recv: Receiver<OnStop>, thread_shared: &Arc<Man
                         }
                     }
                 }
-                tracing::error!("service crashed: {}: {}", e.0, e.1);
-                tokio::time::sleep(Duration::from_secs(15)).await;
+                tracing::error!("service crashed: {}: {}", code, message);
+                ExitReason::Crashed { code, message }
             }
+            Ok(reason) => reason,
             Err(e) => {
                 tracing::error!("failed to start service: {}", e);
                 tracing::debug!("{:?}", e);
+                ExitReason::FailedToStart {
+                    message: e.to_string(),
+                }
             }
+        };
+        if let Err(e) = health_history::record_stopped(
+            &thread_shared.seed.ctx.secret_store,
+            &thread_shared.seed.manifest.id,
+            thread_shared.seed.manifest.health_checks.0.keys(),
+        )
+        .await
+        {
+            tracing::error!("Failed to record health history: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        if !matches!(*recv.borrow(), OnStop::Restart) {
+            // stopped on purpose rather than by the service itself
+            continue;
+        }
+        if let Err(e) =
+            restart_after_exit(thread_shared, &mut recv, &mut attempt, started, reason).await
+        {
+            tracing::error!("Failed to apply restart policy: {}", e);
+            tracing::debug!("{:?}", e);
+            tokio::time::sleep(Duration::from_secs(15)).await;
         }
     }
 }
 
+/// Applies the restart policy of the package after its main process exits on its own
+#[instrument(skip_all)]
+async fn restart_after_exit(
+    shared: &ManagerSharedState,
+    recv: &mut Receiver<OnStop>,
+    attempt: &mut u32,
+    started: Instant,
+    reason: ExitReason,
+) -> Result<(), Error> {
+    let id = &shared.seed.manifest.id;
+    let mut db = shared.seed.ctx.db.handle();
+    let policy = restart::get_policy(&mut db, id).await?;
+    if started.elapsed() >= *policy.reset_after {
+        *attempt = 0;
+    }
+    let decision = policy.decide(&reason, *attempt);
+    restart::record(
+        &mut db,
+        id,
+        RestartEvent {
+            at: Utc::now(),
+            reason: reason.clone(),
+            attempt: *attempt,
+            backoff: match decision {
+                RestartDecision::Restart(backoff) => Some(backoff.into()),
+                _ => None,
+            },
+        },
+    )
+    .await?;
+    match decision {
+        RestartDecision::Restart(backoff) => {
+            *attempt += 1;
+            tracing::info!("{} {}, restarting in {:?}", id, reason, backoff);
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => (),
+                // stopped or restarted by hand in the meantime
+                _ = recv.changed() => (),
+            }
+        }
+        RestartDecision::Stop | RestartDecision::GiveUp => {
+            if decision == RestartDecision::GiveUp {
+                shared
+                    .seed
+                    .ctx
+                    .notification_manager
+                    .notify(
+                        &mut db,
+                        Some(id.clone()),
+                        NotificationLevel::Error,
+                        String::from("Service Stopped"),
+                        format!(
+                            "{} {} after being restarted {} times in a row, so it has been stopped. Start it again once the problem is fixed.",
+                            shared.seed.manifest.title, reason, attempt
+                        ),
+                        (),
+                        None,
+                    )
+                    .await?;
+            } else {
+                tracing::info!("{} {}, leaving it stopped", id, reason);
+            }
+            // the db has to say stopped first, or the synchronizer would start it right back up
+            crate::control::stop_impl(shared.seed.ctx.clone(), id.clone()).await?;
+            shared.on_stop.send_modify(|status| {
+                if matches!(*status, OnStop::Restart) {
+                    *status = OnStop::Sleep;
+                }
+            });
+            *attempt = 0;
+        }
+    }
+    Ok(())
+}
+
 pub struct PersistentContainer {
     _running_docker: NonDetachingJoinHandle<()>,
     rpc_client: Receiver<Arc<UnixRpcClient>>,
@@ -462,8 +617,8 @@ async fn spawn_persistent_container(
                     let (mut runtime, inserter) =
                         long_running_docker(&seed, &container).await?;
 
-                    let ip = match get_long_running_ip(&*seed, &mut runtime).await {
-                        GetRunningIp::Ip(x) => x,
+                    let (ip, ipv6) = match get_long_running_ip(&*seed, &mut runtime).await {
+                        GetRunningIp::Ip(ip, ipv6) => (ip, ipv6),
                         GetRunningIp::Error(e) => return Err(e),
                         GetRunningIp::EarlyExit(e) => {
                             tracing::error!("Early Exit");
@@ -471,7 +626,7 @@ async fn spawn_persistent_container(
                             return Ok(());
                         }
                     };
-                    let svc = add_network_for_main(&*seed, ip).await?;
+                    add_network_for_main(&*seed, ip, ipv6).await?;
 
                     if let Some(inserter_send) = inserter_send.as_mut() {
                         let _ = inserter_send.send(Arc::new(inserter));
@@ -487,7 +642,7 @@ async fn spawn_persistent_container(
                         a = runtime.running_output => a.map_err(|_| Error::new(eyre!("Manager runtime panicked!"), crate::ErrorKind::Docker)).map(|_| ()),
                     };
 
-                    remove_network_for_main(svc).await?;
+                    remove_network_for_main(&*seed).await?;
 
                     res
                 }.await {
@@ -518,8 +673,11 @@ async fn long_running_docker(
         .await
 }
 
-async fn remove_network_for_main(svc: NetService) -> Result<(), Error> {
-    svc.remove_all().await
+async fn remove_network_for_main(seed: &ManagerSeed) -> Result<(), Error> {
+    if let Some(svc) = seed.net_service.lock().await.take() {
+        svc.remove_all().await?;
+    }
+    Ok(())
 }
 
 fn fetch_starting_to_running(state: &Arc<ManagerSharedState>) {
@@ -530,11 +688,14 @@ fn fetch_starting_to_running(state: &Arc<ManagerSharedState>) {
     });
 }
 
-async fn main_health_check_daemon(state: Arc<ManagerSharedState>) {
+/// Only returns once an `on-health-failure` service should be restarted, with the number of rounds
+/// in a row that had a failing health check
+async fn main_health_check_daemon(state: Arc<ManagerSharedState>) -> u32 {
     tokio::time::sleep(Duration::from_secs(HEALTH_CHECK_GRACE_PERIOD_SECONDS)).await;
+    let mut failures = 0;
     loop {
         let mut db = state.seed.ctx.db.handle();
-        if let Err(e) = health::check(
+        match health::check(
             &state.seed.ctx,
             &mut db,
             &state.seed.manifest.id,
@@ -542,12 +703,42 @@ async fn main_health_check_daemon(state: Arc<ManagerSharedState>) {
         )
         .await
         {
-            tracing::error!(
-                "Failed to run health check for {}: {}",
-                &state.seed.manifest.id,
-                e
-            );
-            tracing::debug!("{:?}", e);
+            Ok(results)
+                if results
+                    .values()
+                    .any(|res| matches!(res, HealthCheckResult::Failure { .. })) =>
+            {
+                failures += 1;
+                match restart::get_policy(&mut db, &state.seed.manifest.id).await {
+                    Ok(policy)
+                        if policy.condition == RestartCondition::OnHealthFailure
+                            && failures >= policy.health_failures =>
+                    {
+                        return failures;
+                    }
+                    Ok(_) => (),
+                    Err(e) => {
+                        tracing::error!(
+                            "Failed to get restart policy for {}: {}",
+                            &state.seed.manifest.id,
+                            e
+                        );
+                        tracing::debug!("{:?}", e);
+                    }
+                }
+            }
+            Ok(results) if !results.is_empty() => {
+                failures = 0;
+            }
+            Ok(_) => (),
+            Err(e) => {
+                tracing::error!(
+                    "Failed to run health check for {}: {}",
+                    &state.seed.manifest.id,
+                    e
+                );
+                tracing::debug!("{:?}", e);
+            }
         }
         tokio::time::sleep(Duration::from_secs(HEALTH_CHECK_COOLDOWN_SECONDS)).await;
     }
@@ -561,12 +752,13 @@ fn set_commit_health_true(state: &Arc<ManagerSharedState>) {
 
 async fn add_network_for_main(
     seed: &ManagerSeed,
-    ip: std::net::Ipv4Addr,
-) -> Result<NetService, Error> {
+    ip: Ipv4Addr,
+    ipv6: Option<Ipv6Addr>,
+) -> Result<(), Error> {
     let mut svc = seed
         .ctx
         .net_controller
-        .create_service(seed.manifest.id.clone(), ip)
+        .create_service(seed.manifest.id.clone(), ip, ipv6)
         .await?;
     // DEPRECATED
     let mut secrets = seed.ctx.secret_store.acquire().await?;
@@ -581,17 +773,64 @@ async fn add_network_for_main(
                 .await?;
         }
     }
+    let interface_addresses = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&seed.manifest.id)
+        .and_then(|pde| pde.installed())
+        .map(|installed| installed.interface_addresses())
+        .get(&mut seed.ctx.db.handle())
+        .await?
+        .into_owned();
+    for (id, addrs) in interface_addresses.iter().flat_map(|addrs| &addrs.0) {
+        for (domain, addr) in &addrs.clearnet_addresses {
+            svc.add_clearnet(
+                &mut tx,
+                id.clone(),
+                domain.clone(),
+                addr.external,
+                addr.internal,
+                false,
+            )
+            .await?;
+        }
+    }
     for volume in seed.manifest.volumes.values() {
         if let Volume::Certificate { interface_id } = volume {
-            svc.export_cert(&mut tx, interface_id, ip.into()).await?;
+            svc.export_cert(&mut tx, interface_id).await?;
         }
     }
     tx.commit().await?;
-    Ok(svc)
+    *seed.net_service.lock().await = Some(svc);
+    Ok(())
+}
+
+/// Addresses of the container on the package network, once it has them
+fn start9_ips(
+    res: bollard::models::ContainerInspectResponse,
+) -> Result<Option<(Ipv4Addr, Option<Ipv6Addr>)>, Error> {
+    let endpoint = match res
+        .network_settings
+        .and_then(|ns| ns.networks)
+        .and_then(|mut n| n.remove("start9"))
+    {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    let ipv6 = endpoint
+        .global_ipv6_address
+        .filter(|ip| !ip.is_empty())
+        .map(|ip| ip.parse())
+        .transpose()?;
+    Ok(endpoint
+        .ip_address
+        .filter(|ip| !ip.is_empty())
+        .map(|ip| ip.parse())
+        .transpose()?
+        .map(|ip| (ip, ipv6)))
 }
 
 enum GetRunningIp {
-    Ip(Ipv4Addr),
+    Ip(Ipv4Addr, Option<Ipv6Addr>),
     Error(Error),
     EarlyExit(Result<NoOutput, (i32, String)>),
 }
@@ -604,21 +843,11 @@ async fn get_running_ip(
 ) -> GetRunningIp {
     loop {
         match container_inspect(&*state.seed).await {
-            Ok(res) => {
-                match res
-                    .network_settings
-                    .and_then(|ns| ns.networks)
-                    .and_then(|mut n| n.remove("start9"))
-                    .and_then(|es| es.ip_address)
-                    .filter(|ip| !ip.is_empty())
-                    .map(|ip| ip.parse())
-                    .transpose()
-                {
-                    Ok(Some(ip_addr)) => return GetRunningIp::Ip(ip_addr),
-                    Ok(None) => (),
-                    Err(e) => return GetRunningIp::Error(e.into()),
-                }
-            }
+            Ok(res) => match start9_ips(res) {
+                Ok(Some((ip, ipv6))) => return GetRunningIp::Ip(ip, ipv6),
+                Ok(None) => (),
+                Err(e) => return GetRunningIp::Error(e.into()),
+            },
             Err(bollard::errors::Error::DockerResponseServerError {
                 status_code: 404, // NOT FOUND
                 ..
@@ -656,21 +885,11 @@ async fn get_running_ip(
 async fn get_long_running_ip(seed: &ManagerSeed, runtime: &mut LongRunning) -> GetRunningIp {
     loop {
         match container_inspect(seed).await {
-            Ok(res) => {
-                match res
-                    .network_settings
-                    .and_then(|ns| ns.networks)
-                    .and_then(|mut n| n.remove("start9"))
-                    .and_then(|es| es.ip_address)
-                    .filter(|ip| !ip.is_empty())
-                    .map(|ip| ip.parse())
-                    .transpose()
-                {
-                    Ok(Some(ip_addr)) => return GetRunningIp::Ip(ip_addr),
-                    Ok(None) => (),
-                    Err(e) => return GetRunningIp::Error(e.into()),
-                }
-            }
+            Ok(res) => match start9_ips(res) {
+                Ok(Some((ip, ipv6))) => return GetRunningIp::Ip(ip, ipv6),
+                Ok(None) => (),
+                Err(e) => return GetRunningIp::Error(e.into()),
+            },
             Err(bollard::errors::Error::DockerResponseServerError {
                 status_code: 404, // NOT FOUND
                 ..
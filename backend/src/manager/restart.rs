@@ -0,0 +1,309 @@
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use patch_db::DbHandle;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, Duration, IoFormat};
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Only the most recent restarts of each package are kept
+pub const MAX_RESTART_HISTORY: usize = 50;
+
+/// When the manager brings a service back up after its main process exits
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartCondition {
+    Never,
+    OnFailure,
+    Always,
+    /// Like `on-failure`, but also restarts a service whose health checks keep failing
+    OnHealthFailure,
+}
+impl std::str::FromStr for RestartCondition {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_owned()))
+            .with_kind(ErrorKind::Deserialization)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RestartPolicy {
+    pub condition: RestartCondition,
+    /// Rounds of health checks in a row with a failure before an `on-health-failure` service is
+    /// restarted
+    pub health_failures: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Restarts in a row before giving up, or `None` to retry forever
+    pub max_retries: Option<u32>,
+    /// A service that stays up this long has recovered, and gets its full retry budget back
+    pub reset_after: Duration,
+}
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            condition: RestartCondition::Always,
+            health_failures: 3,
+            initial_backoff: std::time::Duration::from_secs(15).into(),
+            max_backoff: std::time::Duration::from_secs(600).into(),
+            max_retries: None,
+            reset_after: std::time::Duration::from_secs(600).into(),
+        }
+    }
+}
+impl RestartPolicy {
+    fn should_restart(&self, reason: &ExitReason) -> bool {
+        match (self.condition, reason) {
+            (RestartCondition::Never, _) => false,
+            (RestartCondition::Always, _) => true,
+            (_, ExitReason::Exited) => false,
+            _ => true,
+        }
+    }
+
+    /// Doubles with every attempt, up to `max_backoff`
+    pub fn backoff(&self, attempt: u32) -> std::time::Duration {
+        1_u32
+            .checked_shl(attempt)
+            .and_then(|factor| self.initial_backoff.checked_mul(factor))
+            .map_or(*self.max_backoff, |backoff| backoff.min(*self.max_backoff))
+    }
+
+    /// `attempt` is the number of restarts since the service last stayed up for `reset_after`
+    pub fn decide(&self, reason: &ExitReason, attempt: u32) -> RestartDecision {
+        if !self.should_restart(reason) {
+            RestartDecision::Stop
+        } else if self.max_retries.map_or(false, |max| attempt >= max) {
+            RestartDecision::GiveUp
+        } else {
+            RestartDecision::Restart(self.backoff(attempt))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RestartDecision {
+    Restart(std::time::Duration),
+    /// The policy does not restart the service for this exit
+    Stop,
+    /// The retry budget is spent
+    GiveUp,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "reason")]
+pub enum ExitReason {
+    Exited,
+    Crashed { code: i32, message: String },
+    Unhealthy { failures: u32 },
+    FailedToStart { message: String },
+}
+impl std::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::Exited => write!(f, "exited"),
+            ExitReason::Crashed { code, message } => {
+                write!(f, "crashed with exit code {}: {}", code, message)
+            }
+            ExitReason::Unhealthy { failures } => {
+                write!(f, "failed {} health checks in a row", failures)
+            }
+            ExitReason::FailedToStart { message } => write!(f, "failed to start: {}", message),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RestartEvent {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub reason: ExitReason,
+    pub attempt: u32,
+    /// How long the manager waited before restarting, or `None` if it left the service stopped
+    pub backoff: Option<Duration>,
+}
+
+#[instrument(skip_all)]
+pub async fn get_policy<Db: DbHandle>(db: &mut Db, id: &PackageId) -> Result<RestartPolicy, Error> {
+    Ok(crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(id)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.restart_policy())
+        .get(db)
+        .await?
+        .into_owned()
+        .unwrap_or_default())
+}
+
+#[instrument(skip_all)]
+pub async fn record<Db: DbHandle>(
+    db: &mut Db,
+    id: &PackageId,
+    event: RestartEvent,
+) -> Result<(), Error> {
+    let mut history = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(id)
+        .expect(db)
+        .await?
+        .installed()
+        .expect(db)
+        .await?
+        .restart_history()
+        .get_mut(db)
+        .await?;
+    history.push(event);
+    let excess = history.len().saturating_sub(MAX_RESTART_HISTORY);
+    history.drain(..excess);
+    history.save(db).await
+}
+
+#[command(
+    rename = "restart-policy",
+    subcommands(get_restart_policy, set_restart_policy)
+)]
+pub fn restart_policy() -> Result<(), Error> {
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RestartPolicyRes {
+    pub policy: RestartPolicy,
+    pub history: Vec<RestartEvent>,
+}
+
+#[command(rename = "get", display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn get_restart_policy(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<RestartPolicyRes, Error> {
+    let mut db = ctx.db.handle();
+    let installed = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&id)
+        .and_then(|pde| pde.installed())
+        .get(&mut db)
+        .await?
+        .into_owned()
+        .ok_or_else(|| Error::new(eyre!("{} is not installed", id), ErrorKind::NotFound))?;
+    Ok(RestartPolicyRes {
+        policy: installed.restart_policy,
+        history: installed.restart_history,
+    })
+}
+
+/// Options that are not passed keep their current value
+#[command(rename = "set", display(display_none))]
+#[instrument(skip_all)]
+pub async fn set_restart_policy(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] condition: RestartCondition,
+    #[arg(rename = "health-failures", long = "health-failures")] health_failures: Option<u32>,
+    #[arg(rename = "initial-backoff", long = "initial-backoff")] initial_backoff: Option<Duration>,
+    #[arg(rename = "max-backoff", long = "max-backoff")] max_backoff: Option<Duration>,
+    #[arg(rename = "max-retries", long = "max-retries")] max_retries: Option<u32>,
+    #[arg(rename = "retry-forever", long = "retry-forever")] retry_forever: bool,
+    #[arg(rename = "reset-after", long = "reset-after")] reset_after: Option<Duration>,
+) -> Result<(), Error> {
+    if health_failures == Some(0) {
+        return Err(Error::new(
+            eyre!("Health failure threshold must be at least 1"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let mut db = ctx.db.handle();
+    let mut policy = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&id)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.restart_policy())
+        .get_mut(&mut db)
+        .await?;
+    let policy_ref = policy
+        .as_mut()
+        .ok_or_else(|| Error::new(eyre!("{} is not installed", id), ErrorKind::NotFound))?;
+    policy_ref.condition = condition;
+    if let Some(health_failures) = health_failures {
+        policy_ref.health_failures = health_failures;
+    }
+    if let Some(initial_backoff) = initial_backoff {
+        policy_ref.initial_backoff = initial_backoff;
+    }
+    if let Some(max_backoff) = max_backoff {
+        policy_ref.max_backoff = max_backoff;
+    }
+    if retry_forever {
+        policy_ref.max_retries = None;
+    } else if let Some(max_retries) = max_retries {
+        policy_ref.max_retries = Some(max_retries);
+    }
+    if let Some(reset_after) = reset_after {
+        policy_ref.reset_after = reset_after;
+    }
+    if *policy_ref.initial_backoff > *policy_ref.max_backoff {
+        return Err(Error::new(
+            eyre!("Initial backoff must not be longer than the max backoff"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    policy.save(&mut db).await
+}
+
+#[test]
+fn test_backoff() {
+    let policy = RestartPolicy::default();
+    assert_eq!(policy.backoff(0), std::time::Duration::from_secs(15));
+    assert_eq!(policy.backoff(1), std::time::Duration::from_secs(30));
+    assert_eq!(policy.backoff(3), std::time::Duration::from_secs(120));
+    assert_eq!(policy.backoff(6), std::time::Duration::from_secs(600));
+    assert_eq!(policy.backoff(40), std::time::Duration::from_secs(600));
+}
+
+#[test]
+fn test_decide() {
+    let crashed = ExitReason::Crashed {
+        code: 1,
+        message: "oops".to_owned(),
+    };
+    let mut policy = RestartPolicy::default();
+    assert_eq!(
+        policy.decide(&ExitReason::Exited, 0),
+        RestartDecision::Restart(std::time::Duration::from_secs(15))
+    );
+    assert_eq!(
+        policy.decide(&crashed, 10),
+        RestartDecision::Restart(std::time::Duration::from_secs(600))
+    );
+    policy.max_retries = Some(10);
+    assert_eq!(policy.decide(&crashed, 10), RestartDecision::GiveUp);
+    policy.condition = RestartCondition::OnFailure;
+    assert_eq!(policy.decide(&ExitReason::Exited, 0), RestartDecision::Stop);
+    assert_eq!(
+        policy.decide(&crashed, 1),
+        RestartDecision::Restart(std::time::Duration::from_secs(30))
+    );
+    policy.condition = RestartCondition::Never;
+    assert_eq!(policy.decide(&crashed, 0), RestartDecision::Stop);
+    policy.condition = RestartCondition::OnHealthFailure;
+    policy.max_retries = None;
+    assert_eq!(
+        policy.decide(&ExitReason::Unhealthy { failures: 3 }, 1000),
+        RestartDecision::Restart(std::time::Duration::from_secs(600))
+    );
+}
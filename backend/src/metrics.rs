@@ -0,0 +1,371 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use std::time::Duration;
+
+use bollard::container::{Stats, StatsOptions};
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use futures::TryStreamExt;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::sync::broadcast::Receiver;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::notifications::{DiskNearlyFull, NotificationLevel};
+use crate::procedure::docker::DockerProcedure;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::shutdown::Shutdown;
+use crate::system::Metrics;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::Invoke;
+use crate::volume::{Volume, VolumeId};
+use crate::{Error, ErrorKind};
+
+pub const METRICS_HISTORY_INTERVAL: Duration = Duration::from_secs(60);
+/// A day of samples at `METRICS_HISTORY_INTERVAL`
+pub const METRICS_HISTORY_LEN: usize = 1440;
+/// `du` over a large volume is expensive, so volume usage is only measured every this many samples
+const VOLUME_USAGE_SAMPLES: usize = 10;
+/// Share of the data drive in use above which the owner is warned, at most once a day
+const DISK_NEARLY_FULL_PERCENTAGE: f64 = 90.0;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Sample<T> {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub value: T,
+}
+
+/// Keeps the most recent `METRICS_HISTORY_LEN` samples
+#[derive(Debug)]
+pub struct TimeSeries<T>(VecDeque<Sample<T>>);
+impl<T> Default for TimeSeries<T> {
+    fn default() -> Self {
+        TimeSeries(VecDeque::with_capacity(METRICS_HISTORY_LEN))
+    }
+}
+impl<T: Clone> TimeSeries<T> {
+    pub fn push(&mut self, time: DateTime<Utc>, value: T) {
+        if self.0.len() >= METRICS_HISTORY_LEN {
+            self.0.pop_front();
+        }
+        self.0.push_back(Sample { time, value });
+    }
+
+    pub fn since(&self, since: Option<DateTime<Utc>>) -> Vec<Sample<T>> {
+        self.0
+            .iter()
+            .filter(|sample| since.map_or(true, |since| sample.time >= since))
+            .cloned()
+            .collect()
+    }
+
+    pub fn latest(&self) -> Option<&Sample<T>> {
+        self.0.back()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ContainerMetrics {
+    pub cpu_percentage: f64,
+    pub memory_bytes: u64,
+    pub memory_limit_bytes: Option<u64>,
+    pub net_rx_bytes: u64,
+    pub net_tx_bytes: u64,
+    pub block_read_bytes: u64,
+    pub block_write_bytes: u64,
+}
+impl ContainerMetrics {
+    fn from_stats(stats: &Stats) -> Self {
+        let (net_rx_bytes, net_tx_bytes) = stats
+            .networks
+            .iter()
+            .flat_map(|networks| networks.values())
+            .fold((0, 0), |(rx, tx), net| {
+                (rx + net.rx_bytes, tx + net.tx_bytes)
+            });
+        let (block_read_bytes, block_write_bytes) = stats
+            .blkio_stats
+            .io_service_bytes_recursive
+            .iter()
+            .flatten()
+            .fold((0, 0), |(read, write), entry| {
+                // cgroup v1 capitalizes the op, v2 does not
+                match entry.op.to_lowercase().as_str() {
+                    "read" => (read + entry.value, write),
+                    "write" => (read, write + entry.value),
+                    _ => (read, write),
+                }
+            });
+        ContainerMetrics {
+            cpu_percentage: cpu_percentage(
+                stats.cpu_stats.cpu_usage.total_usage,
+                stats.precpu_stats.cpu_usage.total_usage,
+                stats.cpu_stats.system_cpu_usage.unwrap_or_default(),
+                stats.precpu_stats.system_cpu_usage.unwrap_or_default(),
+                stats.cpu_stats.online_cpus.unwrap_or_else(|| {
+                    stats
+                        .cpu_stats
+                        .cpu_usage
+                        .percpu_usage
+                        .as_ref()
+                        .map_or(1, |percpu| percpu.len() as u64)
+                }),
+            ),
+            memory_bytes: stats.memory_stats.usage.unwrap_or_default(),
+            memory_limit_bytes: stats.memory_stats.limit,
+            net_rx_bytes,
+            net_tx_bytes,
+            block_read_bytes,
+            block_write_bytes,
+        }
+    }
+}
+
+/// Usage across all cores, the same way `docker stats` computes it, so 2 busy cores is 200%
+fn cpu_percentage(total: u64, pre_total: u64, system: u64, pre_system: u64, cpus: u64) -> f64 {
+    let cpu_delta = total.saturating_sub(pre_total);
+    let system_delta = system.saturating_sub(pre_system);
+    if cpu_delta == 0 || system_delta == 0 {
+        0.0
+    } else {
+        cpu_delta as f64 / system_delta as f64 * cpus as f64 * 100.0
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PackageMetrics {
+    /// `None` when the service is not running
+    pub container: Option<ContainerMetrics>,
+    /// Bytes used by each data volume
+    pub volumes: BTreeMap<VolumeId, u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct MetricsHistory {
+    pub server: TimeSeries<Metrics>,
+    pub packages: BTreeMap<PackageId, TimeSeries<PackageMetrics>>,
+}
+
+#[instrument(skip_all)]
+async fn container_metrics(
+    ctx: &RpcContext,
+    id: &PackageId,
+) -> Result<Option<ContainerMetrics>, Error> {
+    let mut stats = Box::pin(ctx.docker.stats(
+        &DockerProcedure::container_name(id, None),
+        Some(StatsOptions {
+            stream: false,
+            one_shot: false,
+        }),
+    ));
+    match stats.try_next().await {
+        Ok(stats) => Ok(stats.as_ref().map(ContainerMetrics::from_stats)),
+        Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, // NOT FOUND
+            ..
+        }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[instrument(skip_all)]
+async fn dir_usage(path: &Path) -> Result<u64, Error> {
+    String::from_utf8(
+        Command::new("du")
+            .arg("--summarize")
+            .arg("--bytes")
+            .arg(path)
+            .invoke(ErrorKind::Filesystem)
+            .await?,
+    )?
+    .split_whitespace()
+    .next()
+    .ok_or_else(|| {
+        Error::new(
+            eyre!("No output from du for {}", path.display()),
+            ErrorKind::Filesystem,
+        )
+    })?
+    .parse::<u64>()
+    .map_err(Error::from)
+}
+
+#[instrument(skip_all)]
+async fn volume_usage(ctx: &RpcContext, manifest: &Manifest) -> BTreeMap<VolumeId, u64> {
+    let mut res = BTreeMap::new();
+    for (volume_id, volume) in manifest.volumes.iter() {
+        if !matches!(volume, Volume::Data { .. }) {
+            continue;
+        }
+        let path = volume.path_for(&ctx.datadir, &manifest.id, &manifest.version, volume_id);
+        match dir_usage(&path).await {
+            Ok(bytes) => {
+                res.insert(volume_id.clone(), bytes);
+            }
+            Err(e) => {
+                tracing::warn!("Could not measure {}: {}", path.display(), e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+    }
+    res
+}
+
+#[instrument(skip_all)]
+async fn sample(
+    ctx: &RpcContext,
+    measure_volumes: bool,
+    volumes: &mut BTreeMap<PackageId, BTreeMap<VolumeId, u64>>,
+) -> Result<(), Error> {
+    let time = Utc::now();
+    let mut db = ctx.db.handle();
+    let mut packages = BTreeMap::new();
+    for id in crate::db::DatabaseModel::new()
+        .package_data()
+        .keys(&mut db)
+        .await?
+    {
+        let manifest: Manifest = if let Some(manifest) = crate::db::DatabaseModel::new()
+            .package_data()
+            .idx_model(&id)
+            .and_then(|pkg| pkg.installed())
+            .map(|m| m.manifest())
+            .get(&mut db)
+            .await?
+            .to_owned()
+        {
+            manifest
+        } else {
+            continue;
+        };
+        let container = match container_metrics(ctx, &id).await {
+            Ok(container) => container,
+            Err(e) => {
+                tracing::warn!("Could not get container stats for {}: {}", id, e);
+                tracing::debug!("{:?}", e);
+                None
+            }
+        };
+        if measure_volumes || !volumes.contains_key(&id) {
+            volumes.insert(id.clone(), volume_usage(ctx, &manifest).await);
+        }
+        packages.insert(
+            id.clone(),
+            PackageMetrics {
+                container,
+                volumes: volumes.get(&id).cloned().unwrap_or_default(),
+            },
+        );
+    }
+    volumes.retain(|id, _| packages.contains_key(id));
+
+    let server = ctx.metrics_cache.read().await.clone();
+    if let Some(server) = &server {
+        if server.disk.used_percentage.0 >= DISK_NEARLY_FULL_PERCENTAGE {
+            ctx.notification_manager
+                .notify(
+                    &mut db,
+                    None,
+                    NotificationLevel::Warning,
+                    "Disk Nearly Full".to_owned(),
+                    format!(
+                        "The data drive is {:.0}% full, with {:.1} GB left. Services may stop working when it runs out of space.",
+                        server.disk.used_percentage.0, server.disk.available.0
+                    ),
+                    DiskNearlyFull {
+                        used_percentage: server.disk.used_percentage.0,
+                        available_gb: server.disk.available.0,
+                    },
+                    Some(86400),
+                )
+                .await?;
+        }
+    }
+    let mut history = ctx.metrics_history.write().await;
+    if let Some(server) = server {
+        history.server.push(time, server);
+    }
+    history.packages.retain(|id, _| packages.contains_key(id));
+    for (id, metrics) in packages {
+        history.packages.entry(id).or_default().push(time, metrics);
+    }
+    Ok(())
+}
+
+pub async fn launch_metrics_history_task(
+    ctx: &RpcContext,
+    mut shutdown: Receiver<Option<Shutdown>>,
+) {
+    let mut volumes = BTreeMap::new();
+    for round in 0.. {
+        if let Err(e) = sample(ctx, round % VOLUME_USAGE_SAMPLES == 0, &mut volumes).await {
+            tracing::error!("Could not sample metrics: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = tokio::time::sleep(METRICS_HISTORY_INTERVAL) => (),
+        }
+    }
+}
+
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn history(
+    #[context] ctx: RpcContext,
+    #[arg(long = "since")] since: Option<DateTime<Utc>>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<Sample<Metrics>>, Error> {
+    Ok(ctx.metrics_history.read().await.server.since(since))
+}
+
+#[command(rename = "metrics", display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn package_metrics(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(long = "since")] since: Option<DateTime<Utc>>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<Sample<PackageMetrics>>, Error> {
+    ctx.metrics_history
+        .read()
+        .await
+        .packages
+        .get(&id)
+        .map(|series| series.since(since))
+        .ok_or_else(|| Error::new(eyre!("No metrics for {}", id), ErrorKind::NotFound))
+}
+
+#[test]
+fn test_cpu_percentage() {
+    assert_eq!(cpu_percentage(100, 100, 2000, 1000, 4), 0.0);
+    assert_eq!(cpu_percentage(350, 100, 2000, 1000, 4), 100.0);
+    assert_eq!(cpu_percentage(100, 200, 2000, 1000, 4), 0.0);
+}
+
+#[test]
+fn test_time_series() {
+    let mut series = TimeSeries::default();
+    let start = Utc::now();
+    for i in 0..(METRICS_HISTORY_LEN + 10) {
+        series.push(start + chrono::Duration::minutes(i as i64), i);
+    }
+    let all = series.since(None);
+    assert_eq!(all.len(), METRICS_HISTORY_LEN);
+    assert_eq!(all[0].value, 10);
+    let recent = series.since(Some(start + chrono::Duration::minutes(1440)));
+    assert_eq!(
+        recent.iter().map(|s| s.value).collect::<Vec<_>>(),
+        vec![1440, 1441, 1442, 1443, 1444, 1445, 1446, 1447, 1448, 1449]
+    );
+}
@@ -136,7 +136,9 @@ impl HashSessionToken {
         Self { hashed, token }
     }
     pub fn from_cookie(cookie: &Cookie) -> Self {
-        let token = cookie.get_value().to_owned();
+        Self::from_token(cookie.get_value().to_owned())
+    }
+    pub fn from_token(token: String) -> Self {
         let hashed = Self::hash(&token);
         Self { hashed, token }
     }
@@ -0,0 +1,1099 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use color_eyre::eyre::eyre;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509Req, X509};
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::Url;
+use rpc_toolkit::command;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::{Mutex, RwLock};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::net::domain::normalize_domain;
+use crate::net::ssl::{generate_key, SslManager};
+use crate::notifications::{CertificateExpiring, NotificationLevel};
+use crate::shutdown::Shutdown;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind, ResultExt};
+
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+pub const ACME_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+/// Certificates are renewed once they expire in less than this many days
+const RENEW_DAYS: i64 = 30;
+/// Failed renewals raise a notification once the certificate expires in less than this many days
+const WARN_DAYS: i64 = 14;
+/// Hours to wait after a failed attempt before trying again
+const RETRY_HOURS: i64 = 6;
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 90;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const REDACTED: &str = "********";
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "type")]
+pub enum DnsProvider {
+    /// The token needs the Zone.DNS edit permission for the zone of the domain
+    Cloudflare { token: String },
+    /// POSTs `{"action": "present" | "cleanup", "fqdn": ..., "value": ...}` as JSON
+    Webhook {
+        url: Url,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+}
+impl DnsProvider {
+    const CLOUDFLARE_API: &'static str = "https://api.cloudflare.com/client/v4";
+
+    /// Creates the TXT record, returning what is needed to clean it up again
+    async fn present(
+        &self,
+        http: &reqwest::Client,
+        fqdn: &str,
+        value: &str,
+    ) -> Result<Option<String>, Error> {
+        match self {
+            DnsProvider::Cloudflare { token } => {
+                let mut zone = None;
+                let labels = fqdn.split('.').collect::<Vec<_>>();
+                for i in 1..labels.len().saturating_sub(1) {
+                    let res: Value = http
+                        .get(format!("{}/zones", Self::CLOUDFLARE_API))
+                        .query(&[("name", labels[i..].join("."))])
+                        .bearer_auth(token)
+                        .send()
+                        .await
+                        .with_kind(ErrorKind::Network)?
+                        .error_for_status()
+                        .with_kind(ErrorKind::Network)?
+                        .json()
+                        .await
+                        .with_kind(ErrorKind::Network)?;
+                    if let Some(id) = res["result"][0]["id"].as_str() {
+                        zone = Some(id.to_owned());
+                        break;
+                    }
+                }
+                let zone = zone.ok_or_else(|| {
+                    Error::new(
+                        eyre!("No Cloudflare zone found for {}", fqdn),
+                        ErrorKind::NotFound,
+                    )
+                })?;
+                let res: Value = http
+                    .post(format!(
+                        "{}/zones/{}/dns_records",
+                        Self::CLOUDFLARE_API,
+                        zone
+                    ))
+                    .bearer_auth(token)
+                    .json(&json!({
+                        "type": "TXT",
+                        "name": fqdn,
+                        "content": value,
+                        "ttl": 120,
+                    }))
+                    .send()
+                    .await
+                    .with_kind(ErrorKind::Network)?
+                    .error_for_status()
+                    .with_kind(ErrorKind::Network)?
+                    .json()
+                    .await
+                    .with_kind(ErrorKind::Network)?;
+                Ok(res["result"]["id"]
+                    .as_str()
+                    .map(|id| format!("{}/dns_records/{}", zone, id)))
+            }
+            DnsProvider::Webhook { .. } => {
+                self.webhook(http, "present", fqdn, value).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn cleanup(
+        &self,
+        http: &reqwest::Client,
+        fqdn: &str,
+        value: &str,
+        record: Option<String>,
+    ) -> Result<(), Error> {
+        match self {
+            DnsProvider::Cloudflare { token } => {
+                if let Some(record) = record {
+                    http.delete(format!("{}/zones/{}", Self::CLOUDFLARE_API, record))
+                        .bearer_auth(token)
+                        .send()
+                        .await
+                        .with_kind(ErrorKind::Network)?
+                        .error_for_status()
+                        .with_kind(ErrorKind::Network)?;
+                }
+                Ok(())
+            }
+            DnsProvider::Webhook { .. } => self.webhook(http, "cleanup", fqdn, value).await,
+        }
+    }
+
+    async fn webhook(
+        &self,
+        http: &reqwest::Client,
+        action: &str,
+        fqdn: &str,
+        value: &str,
+    ) -> Result<(), Error> {
+        if let DnsProvider::Webhook { url, headers } = self {
+            let mut req = http.post(url.clone()).json(&json!({
+                "action": action,
+                "fqdn": fqdn,
+                "value": value,
+            }));
+            for (name, value) in headers {
+                req = req.header(name, value);
+            }
+            req.send()
+                .await
+                .with_kind(ErrorKind::Network)?
+                .error_for_status()
+                .with_kind(ErrorKind::Network)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "challenge")]
+pub enum AcmeChallenge {
+    /// Answered by the web server on port 80, which must be reachable at the domain from the
+    /// internet
+    #[serde(rename = "http-01")]
+    Http01,
+    /// Needed when port 80 is not reachable
+    #[serde(rename = "dns-01")]
+    Dns01 {
+        provider: DnsProvider,
+        /// Seconds to wait for the TXT record to propagate before asking the CA to check it
+        #[serde(default = "default_propagation_seconds")]
+        propagation_seconds: u64,
+    },
+}
+fn default_propagation_seconds() -> u64 {
+    30
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AcmeConfig {
+    #[serde(default = "default_directory")]
+    pub directory: Url,
+    /// Email address the CA may send expiry warnings to
+    #[serde(default)]
+    pub contact: Option<String>,
+    #[serde(flatten)]
+    pub challenge: AcmeChallenge,
+    /// PEM certificate to trust for the directory, e.g. the root of a Pebble test server
+    #[serde(default)]
+    pub directory_ca: Option<String>,
+}
+fn default_directory() -> Url {
+    LETS_ENCRYPT_DIRECTORY.parse().unwrap()
+}
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        AcmeConfig {
+            directory: default_directory(),
+            contact: None,
+            challenge: AcmeChallenge::Http01,
+            directory_ca: None,
+        }
+    }
+}
+impl std::str::FromStr for AcmeConfig {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).with_kind(ErrorKind::Deserialization)
+    }
+}
+impl AcmeConfig {
+    fn redacted(mut self) -> Self {
+        if let AcmeChallenge::Dns01 { provider, .. } = &mut self.challenge {
+            match provider {
+                DnsProvider::Cloudflare { token } => *token = REDACTED.to_owned(),
+                DnsProvider::Webhook { headers, .. } => {
+                    for value in headers.values_mut() {
+                        *value = REDACTED.to_owned();
+                    }
+                }
+            }
+        }
+        self
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AcmeCertInfo {
+    #[serde(flatten)]
+    pub config: AcmeConfig,
+    /// `None` until a certificate has been issued
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_attempt: Option<DateTime<Utc>>,
+    /// Why the last attempt failed, if it did
+    pub last_error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: Url,
+    new_account: Url,
+    new_order: Url,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<Url>,
+    finalize: Url,
+    certificate: Option<Url>,
+    error: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    ty: String,
+    url: Url,
+    #[serde(default)]
+    token: String,
+    error: Option<Value>,
+}
+
+/// Public part of the account key, with the members in the order RFC 7638 thumbprints need
+#[derive(Serialize)]
+struct Jwk {
+    crv: &'static str,
+    kty: &'static str,
+    x: String,
+    y: String,
+}
+impl Jwk {
+    fn new(key: &PKey<Private>) -> Result<Self, Error> {
+        let ec = key.ec_key()?;
+        let mut ctx = BigNumContext::new()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        ec.public_key()
+            .affine_coordinates_gfp(ec.group(), &mut x, &mut y, &mut ctx)?;
+        Ok(Jwk {
+            crv: "P-256",
+            kty: "EC",
+            x: b64(&x.to_vec_padded(32)?),
+            y: b64(&y.to_vec_padded(32)?),
+        })
+    }
+    fn thumbprint(&self) -> Result<String, Error> {
+        Ok(b64(&sha256(
+            &serde_json::to_vec(self).with_kind(ErrorKind::Serialization)?,
+        )))
+    }
+}
+
+/// ES256 signature as the fixed size `r || s` JWS wants, instead of DER
+fn sign_es256(key: &PKey<Private>, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let sig = EcdsaSig::sign(&sha256(data), &*key.ec_key()?)?;
+    let mut res = sig.r().to_vec_padded(32)?;
+    res.extend(sig.s().to_vec_padded(32)?);
+    Ok(res)
+}
+
+fn make_csr(key: &PKey<Private>, domain: &str) -> Result<X509Req, Error> {
+    let mut builder = X509Req::builder()?;
+    let mut subject_name_builder = X509NameBuilder::new()?;
+    // CN is limited to 64 characters, the SAN is what counts anyway
+    if domain.len() <= 64 {
+        subject_name_builder.append_entry_by_text("CN", domain)?;
+    }
+    builder.set_subject_name(&subject_name_builder.build())?;
+    builder.set_pubkey(key)?;
+    let mut extensions = Stack::new()?;
+    extensions.push(
+        SubjectAlternativeName::new()
+            .dns(domain)
+            .build(&builder.x509v3_context(None))?,
+    )?;
+    builder.add_extensions(&extensions)?;
+    builder.sign(key, MessageDigest::sha256())?;
+    Ok(builder.build())
+}
+
+fn not_after(cert: &X509) -> Result<DateTime<Utc>, Error> {
+    let diff = Asn1Time::from_unix(0)?.diff(cert.not_after())?;
+    NaiveDateTime::from_timestamp_opt(diff.days as i64 * 86400 + diff.secs as i64, 0)
+        .map(|t| DateTime::from_utc(t, Utc))
+        .ok_or_else(|| Error::new(eyre!("Certificate expiry out of range"), ErrorKind::OpenSsl))
+}
+
+fn problem(res: &Value) -> String {
+    res["detail"]
+        .as_str()
+        .or_else(|| res["type"].as_str())
+        .unwrap_or("unknown error")
+        .to_owned()
+}
+
+async fn read_json<T: DeserializeOwned>(res: reqwest::Response) -> Result<T, Error> {
+    res.json().await.with_kind(ErrorKind::Network)
+}
+
+/// Minimal RFC 8555 client, for one order at a time
+struct AcmeClient {
+    http: reqwest::Client,
+    directory: Directory,
+    key: PKey<Private>,
+    jwk: Jwk,
+    /// Account URL, once the account exists
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+impl AcmeClient {
+    async fn new(
+        config: &AcmeConfig,
+        key: PKey<Private>,
+        kid: Option<String>,
+    ) -> Result<Self, Error> {
+        let mut http = reqwest::Client::builder().timeout(REQUEST_TIMEOUT);
+        if let Some(ca) = &config.directory_ca {
+            http = http.add_root_certificate(
+                reqwest::Certificate::from_pem(ca.as_bytes()).with_kind(ErrorKind::OpenSsl)?,
+            );
+        }
+        let http = http.build().with_kind(ErrorKind::Network)?;
+        let directory = read_json(
+            http.get(config.directory.clone())
+                .send()
+                .await
+                .with_kind(ErrorKind::Network)?
+                .error_for_status()
+                .with_kind(ErrorKind::Network)?,
+        )
+        .await?;
+        Ok(AcmeClient {
+            http,
+            directory,
+            jwk: Jwk::new(&key)?,
+            key,
+            kid,
+            nonce: None,
+        })
+    }
+
+    /// Loads the account for the directory from the secret store, registering one if there is none
+    #[instrument(skip_all)]
+    async fn account(secrets: &PgPool, config: &AcmeConfig) -> Result<Self, Error> {
+        let account = sqlx::query!(
+            "SELECT key, url FROM acme_accounts WHERE directory = $1",
+            config.directory.as_str()
+        )
+        .fetch_optional(secrets)
+        .await?;
+        if let Some(account) = account {
+            let key = PKey::private_key_from_pem(account.key.as_bytes())?;
+            return Self::new(config, key, Some(account.url)).await;
+        }
+        let mut client = Self::new(config, generate_key()?, None).await?;
+        let url = client.register(config.contact.as_deref()).await?;
+        sqlx::query!(
+            "INSERT INTO acme_accounts (directory, key, url) VALUES ($1, $2, $3)",
+            config.directory.as_str(),
+            String::from_utf8(client.key.private_key_to_pem_pkcs8()?)?,
+            url,
+        )
+        .execute(secrets)
+        .await?;
+        Ok(client)
+    }
+
+    /// Creates an account for the client's key, returning its URL
+    async fn register(&mut self, contact: Option<&str>) -> Result<String, Error> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(contact) = contact {
+            payload["contact"] = json!([format!("mailto:{}", contact)]);
+        }
+        let new_account = self.directory.new_account.clone();
+        let url = Self::location(&self.post(&new_account, Some(&payload)).await?)?;
+        self.kid = Some(url.clone());
+        Ok(url)
+    }
+
+    fn location(res: &reqwest::Response) -> Result<String, Error> {
+        res.headers()
+            .get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .map(|l| l.to_owned())
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!("ACME response is missing a Location"),
+                    ErrorKind::Network,
+                )
+            })
+    }
+
+    async fn new_nonce(&self) -> Result<String, Error> {
+        self.http
+            .head(self.directory.new_nonce.clone())
+            .send()
+            .await
+            .with_kind(ErrorKind::Network)?
+            .headers()
+            .get("Replay-Nonce")
+            .and_then(|n| n.to_str().ok())
+            .map(|n| n.to_owned())
+            .ok_or_else(|| Error::new(eyre!("ACME server sent no nonce"), ErrorKind::Network))
+    }
+
+    /// Signed POST, or POST-as-GET if there is no payload. Retries once on a stale nonce.
+    async fn post(
+        &mut self,
+        url: &Url,
+        payload: Option<&Value>,
+    ) -> Result<reqwest::Response, Error> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": nonce,
+                "url": url.as_str(),
+            });
+            if let Some(kid) = &self.kid {
+                protected["kid"] = json!(kid);
+            } else {
+                protected["jwk"] =
+                    serde_json::to_value(&self.jwk).with_kind(ErrorKind::Serialization)?;
+            }
+            let protected =
+                b64(&serde_json::to_vec(&protected).with_kind(ErrorKind::Serialization)?);
+            let payload = match payload {
+                Some(payload) => {
+                    b64(&serde_json::to_vec(payload).with_kind(ErrorKind::Serialization)?)
+                }
+                None => String::new(),
+            };
+            let signature = b64(&sign_es256(
+                &self.key,
+                format!("{}.{}", protected, payload).as_bytes(),
+            )?);
+            let res = self
+                .http
+                .post(url.clone())
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(
+                    serde_json::to_vec(&json!({
+                        "protected": protected,
+                        "payload": payload,
+                        "signature": signature,
+                    }))
+                    .with_kind(ErrorKind::Serialization)?,
+                )
+                .send()
+                .await
+                .with_kind(ErrorKind::Network)?;
+            self.nonce = res
+                .headers()
+                .get("Replay-Nonce")
+                .and_then(|n| n.to_str().ok())
+                .map(|n| n.to_owned());
+            if res.status().is_success() {
+                return Ok(res);
+            }
+            let status = res.status();
+            let err: Value = res.json().await.unwrap_or_default();
+            if !retried && err["type"] == "urn:ietf:params:acme:error:badNonce" {
+                retried = true;
+                continue;
+            }
+            return Err(Error::new(
+                eyre!(
+                    "ACME request to {} failed ({}): {}",
+                    url,
+                    status,
+                    problem(&err)
+                ),
+                ErrorKind::Network,
+            ));
+        }
+    }
+
+    async fn poll_authorization(&mut self, url: &Url) -> Result<Authorization, Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            let authz: Authorization = read_json(self.post(url, None).await?).await?;
+            if authz.status != "pending" {
+                return Ok(authz);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(Error::new(
+            eyre!("Timed out waiting for ACME authorization"),
+            ErrorKind::Network,
+        ))
+    }
+
+    async fn poll_order(&mut self, url: &Url) -> Result<Order, Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = read_json(self.post(url, None).await?).await?;
+            match order.status.as_str() {
+                "pending" | "processing" => tokio::time::sleep(POLL_INTERVAL).await,
+                "invalid" => {
+                    return Err(Error::new(
+                        eyre!(
+                            "ACME order failed: {}",
+                            order.error.as_ref().map(problem).unwrap_or_default()
+                        ),
+                        ErrorKind::Network,
+                    ))
+                }
+                _ => return Ok(order),
+            }
+        }
+        Err(Error::new(
+            eyre!("Timed out waiting for ACME order"),
+            ErrorKind::Network,
+        ))
+    }
+
+    async fn validate(
+        &mut self,
+        challenge: &Url,
+        authorization: &Url,
+    ) -> Result<Authorization, Error> {
+        self.post(challenge, Some(&json!({}))).await?;
+        self.poll_authorization(authorization).await
+    }
+
+    /// Presents the challenge for an authorization, asks the CA to check it, and waits for the result
+    async fn authorize(
+        &mut self,
+        url: &Url,
+        challenge: &AcmeChallenge,
+        http_tokens: &RwLock<BTreeMap<String, String>>,
+    ) -> Result<(), Error> {
+        let authz: Authorization = read_json(self.post(url, None).await?).await?;
+        if authz.status == "valid" {
+            return Ok(());
+        }
+        let ty = match challenge {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::Dns01 { .. } => "dns-01",
+        };
+        let chal = authz
+            .challenges
+            .into_iter()
+            .find(|c| c.ty == ty)
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!(
+                        "ACME server does not offer {} for {}",
+                        ty,
+                        authz.identifier.value
+                    ),
+                    ErrorKind::Network,
+                )
+            })?;
+        let key_authorization = format!("{}.{}", chal.token, self.jwk.thumbprint()?);
+        let res = match challenge {
+            AcmeChallenge::Http01 => {
+                http_tokens
+                    .write()
+                    .await
+                    .insert(chal.token.clone(), key_authorization);
+                let res = self.validate(&chal.url, url).await;
+                http_tokens.write().await.remove(&chal.token);
+                res
+            }
+            AcmeChallenge::Dns01 {
+                provider,
+                propagation_seconds,
+            } => {
+                let fqdn = format!("_acme-challenge.{}", authz.identifier.value);
+                let value = b64(&sha256(key_authorization.as_bytes()));
+                let http = reqwest::Client::builder()
+                    .timeout(REQUEST_TIMEOUT)
+                    .build()
+                    .with_kind(ErrorKind::Network)?;
+                let record = provider.present(&http, &fqdn, &value).await?;
+                tokio::time::sleep(Duration::from_secs(*propagation_seconds)).await;
+                let res = self.validate(&chal.url, url).await;
+                if let Err(e) = provider.cleanup(&http, &fqdn, &value, record).await {
+                    tracing::warn!("Could not remove TXT record {}: {}", fqdn, e);
+                    tracing::debug!("{:?}", e);
+                }
+                res
+            }
+        }?;
+        if res.status != "valid" {
+            let err = res
+                .challenges
+                .iter()
+                .find_map(|c| c.error.as_ref())
+                .map(problem)
+                .unwrap_or(res.status);
+            return Err(Error::new(
+                eyre!(
+                    "ACME {} challenge for {} failed: {}",
+                    ty,
+                    res.identifier.value,
+                    err
+                ),
+                ErrorKind::Network,
+            ));
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn issue(
+        &mut self,
+        domain: &str,
+        challenge: &AcmeChallenge,
+        http_tokens: &RwLock<BTreeMap<String, String>>,
+    ) -> Result<(PKey<Private>, Vec<X509>), Error> {
+        let new_order = self.directory.new_order.clone();
+        let res = self
+            .post(
+                &new_order,
+                Some(&json!({ "identifiers": [{ "type": "dns", "value": domain }] })),
+            )
+            .await?;
+        let order_url: Url = Self::location(&res)?
+            .parse()
+            .with_kind(ErrorKind::ParseUrl)?;
+        let order: Order = read_json(res).await?;
+        for authz in &order.authorizations {
+            self.authorize(authz, challenge, http_tokens).await?;
+        }
+        let key = generate_key()?;
+        let csr = make_csr(&key, domain)?;
+        self.post(
+            &order.finalize,
+            Some(&json!({ "csr": b64(&csr.to_der()?) })),
+        )
+        .await?;
+        let certificate = self
+            .poll_order(&order_url)
+            .await?
+            .certificate
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!("ACME order completed without a certificate"),
+                    ErrorKind::Network,
+                )
+            })?;
+        let chain = self
+            .post(&certificate, None)
+            .await?
+            .text()
+            .await
+            .with_kind(ErrorKind::Network)?;
+        Ok((key, X509::stack_from_pem(chain.as_bytes())?))
+    }
+}
+
+pub struct AcmeManager {
+    ssl: Arc<SslManager>,
+    /// Key authorizations of pending HTTP-01 challenges, by token
+    http_tokens: RwLock<BTreeMap<String, String>>,
+    /// Orders are placed one at a time
+    issuing: Mutex<()>,
+}
+impl AcmeManager {
+    pub fn new(ssl: Arc<SslManager>) -> Self {
+        Self {
+            ssl,
+            http_tokens: RwLock::new(BTreeMap::new()),
+            issuing: Mutex::new(()),
+        }
+    }
+
+    pub async fn http_challenge(&self, token: &str) -> Option<String> {
+        self.http_tokens.read().await.get(token).cloned()
+    }
+
+    /// Serves the stored certificates
+    #[instrument(skip_all)]
+    pub async fn load(&self, secrets: &PgPool) -> Result<(), Error> {
+        for r in sqlx::query!(
+            "SELECT domain, key, fullchain FROM acme_certs WHERE key IS NOT NULL AND fullchain IS NOT NULL"
+        )
+        .fetch_all(secrets)
+        .await?
+        {
+            if let (Some(key), Some(fullchain)) = (r.key, r.fullchain) {
+                self.ssl
+                    .set_acme_cert(
+                        r.domain,
+                        PKey::private_key_from_pem(key.as_bytes())?,
+                        X509::stack_from_pem(fullchain.as_bytes())?,
+                    )
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Orders a new certificate for a domain with its stored config, and serves it once issued
+    #[instrument(skip_all)]
+    pub async fn issue(&self, secrets: &PgPool, domain: &str) -> Result<DateTime<Utc>, Error> {
+        let _guard = self.issuing.lock().await;
+        let config: AcmeConfig = serde_json::from_str(
+            &sqlx::query!("SELECT config FROM acme_certs WHERE domain = $1", domain)
+                .fetch_optional(secrets)
+                .await?
+                .ok_or_else(|| {
+                    Error::new(
+                        eyre!("ACME Domain {} Not Found", domain),
+                        ErrorKind::NotFound,
+                    )
+                })?
+                .config,
+        )
+        .with_kind(ErrorKind::Deserialization)?;
+        let res = async {
+            let mut client = AcmeClient::account(secrets, &config).await?;
+            let (key, chain) = client
+                .issue(domain, &config.challenge, &self.http_tokens)
+                .await?;
+            let expires_at = chain.first().map(not_after).transpose()?.ok_or_else(|| {
+                Error::new(eyre!("ACME server sent no certificate"), ErrorKind::Network)
+            })?;
+            Ok::<_, Error>((key, chain, expires_at))
+        }
+        .await;
+        let now = Utc::now().naive_utc();
+        match res {
+            Ok((key, chain, expires_at)) => {
+                let fullchain = chain
+                    .iter()
+                    .map(|c| Ok(String::from_utf8(c.to_pem()?)?))
+                    .collect::<Result<String, Error>>()?;
+                sqlx::query!(
+                    "UPDATE acme_certs SET key = $2, fullchain = $3, expires_at = $4, last_attempt = $5, last_error = NULL WHERE domain = $1",
+                    domain,
+                    String::from_utf8(key.private_key_to_pem_pkcs8()?)?,
+                    fullchain,
+                    expires_at.naive_utc(),
+                    now,
+                )
+                .execute(secrets)
+                .await?;
+                self.ssl.set_acme_cert(domain.to_owned(), key, chain).await;
+                Ok(expires_at)
+            }
+            Err(e) => {
+                sqlx::query!(
+                    "UPDATE acme_certs SET last_attempt = $2, last_error = $3 WHERE domain = $1",
+                    domain,
+                    now,
+                    e.to_string(),
+                )
+                .execute(secrets)
+                .await?;
+                Err(e)
+            }
+        }
+    }
+}
+
+pub async fn launch_acme_renewal_task(ctx: &RpcContext, mut shutdown: Receiver<Option<Shutdown>>) {
+    if let Err(e) = ctx.net_controller.acme.load(&ctx.secret_store).await {
+        tracing::error!("Could not load ACME certificates: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+    loop {
+        if let Err(e) = renew_due(ctx).await {
+            tracing::error!("Error renewing ACME certificates: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = tokio::time::sleep(RENEWAL_CHECK_INTERVAL) => (),
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn renew_due(ctx: &RpcContext) -> Result<(), Error> {
+    let now = Utc::now();
+    for r in sqlx::query!("SELECT domain, expires_at, last_attempt FROM acme_certs")
+        .fetch_all(&ctx.secret_store)
+        .await?
+    {
+        let expires_at = r.expires_at.map(|t| DateTime::<Utc>::from_utc(t, Utc));
+        if expires_at.map_or(false, |t| t - now > chrono::Duration::days(RENEW_DAYS)) {
+            continue;
+        }
+        if r.last_attempt.map_or(false, |t| {
+            now - DateTime::<Utc>::from_utc(t, Utc) < chrono::Duration::hours(RETRY_HOURS)
+        }) {
+            continue;
+        }
+        match ctx
+            .net_controller
+            .acme
+            .issue(&ctx.secret_store, &r.domain)
+            .await
+        {
+            Ok(expires_at) => {
+                tracing::info!("Renewed certificate for {} until {}", r.domain, expires_at)
+            }
+            Err(e) => {
+                tracing::warn!("Could not renew certificate for {}: {}", r.domain, e);
+                tracing::debug!("{:?}", e);
+                if let Some(expires_at) = expires_at {
+                    if expires_at - now < chrono::Duration::days(WARN_DAYS) {
+                        ctx.notification_manager
+                            .notify(
+                                &mut ctx.db.handle(),
+                                None,
+                                if expires_at < now {
+                                    NotificationLevel::Error
+                                } else {
+                                    NotificationLevel::Warning
+                                },
+                                "Certificate Expiring".to_owned(),
+                                format!(
+                                    "The certificate for {} expires {} and could not be renewed: {}",
+                                    r.domain,
+                                    expires_at.format("%Y-%m-%d %H:%M UTC"),
+                                    e
+                                ),
+                                CertificateExpiring {
+                                    hostname: r.domain.clone(),
+                                    expires_at,
+                                },
+                                Some(86400),
+                            )
+                            .await?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[command(subcommands(list, add, remove, renew))]
+pub fn acme() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Secrets are redacted
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<BTreeMap<String, AcmeCertInfo>, Error> {
+    sqlx::query!(
+        "SELECT domain, config, expires_at, last_attempt, last_error FROM acme_certs ORDER BY domain"
+    )
+    .fetch_all(&ctx.secret_store)
+    .await?
+    .into_iter()
+    .map(|r| {
+        Ok((
+            r.domain,
+            AcmeCertInfo {
+                config: serde_json::from_str::<AcmeConfig>(&r.config)
+                    .with_kind(ErrorKind::Deserialization)?
+                    .redacted(),
+                expires_at: r.expires_at.map(|t| DateTime::from_utc(t, Utc)),
+                last_attempt: r.last_attempt.map(|t| DateTime::from_utc(t, Utc)),
+                last_error: r.last_error,
+            },
+        ))
+    })
+    .collect()
+}
+
+/// Obtains a certificate for a domain, which is then served to clients asking for it by SNI and
+/// renewed automatically. `config` is its JSON config, using Let's Encrypt and HTTP-01 by default.
+/// Adding a domain again replaces its config.
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] domain: String,
+    #[arg] config: Option<AcmeConfig>,
+) -> Result<(), Error> {
+    let domain = normalize_domain(&domain)?;
+    let config =
+        serde_json::to_string(&config.unwrap_or_default()).with_kind(ErrorKind::Serialization)?;
+    let existed = sqlx::query!("SELECT domain FROM acme_certs WHERE domain = $1", domain)
+        .fetch_optional(&ctx.secret_store)
+        .await?
+        .is_some();
+    sqlx::query!(
+        "INSERT INTO acme_certs (domain, config) VALUES ($1, $2) ON CONFLICT (domain) DO UPDATE SET config = $2",
+        domain,
+        config,
+    )
+    .execute(&ctx.secret_store)
+    .await?;
+    if let Err(e) = ctx
+        .net_controller
+        .acme
+        .issue(&ctx.secret_store, &domain)
+        .await
+    {
+        if !existed {
+            sqlx::query!("DELETE FROM acme_certs WHERE domain = $1", domain)
+                .execute(&ctx.secret_store)
+                .await?;
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove(#[context] ctx: RpcContext, #[arg] domain: String) -> Result<(), Error> {
+    let domain = normalize_domain(&domain)?;
+    if sqlx::query!("DELETE FROM acme_certs WHERE domain = $1", domain)
+        .execute(&ctx.secret_store)
+        .await?
+        .rows_affected()
+        == 0
+    {
+        return Err(Error::new(
+            eyre!("ACME Domain {} Not Found", domain),
+            ErrorKind::NotFound,
+        ));
+    }
+    ctx.net_controller.ssl.remove_acme_cert(&domain).await;
+    Ok(())
+}
+
+/// Renews the certificate for a domain now instead of waiting until it is due
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn renew(#[context] ctx: RpcContext, #[arg] domain: String) -> Result<(), Error> {
+    let domain = normalize_domain(&domain)?;
+    ctx.net_controller
+        .acme
+        .issue(&ctx.secret_store, &domain)
+        .await?;
+    Ok(())
+}
+
+#[test]
+fn test_jws() {
+    let key = generate_key().unwrap();
+    let jwk = Jwk::new(&key).unwrap();
+    assert_eq!(jwk.x.len(), 43);
+    assert_eq!(jwk.y.len(), 43);
+    assert!(serde_json::to_string(&jwk)
+        .unwrap()
+        .starts_with(r#"{"crv":"P-256","kty":"EC","x":""#));
+    assert_eq!(jwk.thumbprint().unwrap().len(), 43);
+
+    let signature = sign_es256(&key, b"protected.payload").unwrap();
+    assert_eq!(signature.len(), 64);
+    let sig = EcdsaSig::from_private_components(
+        BigNum::from_slice(&signature[..32]).unwrap(),
+        BigNum::from_slice(&signature[32..]).unwrap(),
+    )
+    .unwrap();
+    assert!(sig
+        .verify(&sha256(b"protected.payload"), &*key.ec_key().unwrap())
+        .unwrap());
+}
+
+/// Needs a Pebble test CA started with `PEBBLE_VA_ALWAYS_VALID=1`, so that challenges pass without
+/// being served. Set `PEBBLE_DIRECTORY` if it is not at `https://localhost:14000/dir`, and
+/// `PEBBLE_CA` to the path of `pebble.minica.pem`.
+#[tokio::test]
+#[ignore]
+async fn test_pebble_issue_and_renew() {
+    let config = AcmeConfig {
+        directory: std::env::var("PEBBLE_DIRECTORY")
+            .unwrap_or_else(|_| "https://localhost:14000/dir".to_owned())
+            .parse()
+            .unwrap(),
+        directory_ca: Some(
+            std::fs::read_to_string(
+                std::env::var("PEBBLE_CA").unwrap_or_else(|_| "pebble.minica.pem".to_owned()),
+            )
+            .unwrap(),
+        ),
+        ..AcmeConfig::default()
+    };
+    let domain = normalize_domain("Pebble-Test.Example.com.").unwrap();
+    let http_tokens = RwLock::new(BTreeMap::new());
+    let mut client = AcmeClient::new(&config, generate_key().unwrap(), None)
+        .await
+        .unwrap();
+    client.register(None).await.unwrap();
+    // renewing is ordering again for a domain that already has a certificate
+    let mut serials = Vec::new();
+    for _ in 0..2 {
+        let (key, chain) = client
+            .issue(&domain, &config.challenge, &http_tokens)
+            .await
+            .unwrap();
+        let leaf = chain.first().unwrap();
+        assert!(leaf.public_key().unwrap().public_eq(&*key));
+        let names = leaf
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|n| n.dnsname().map(|n| n.to_owned()))
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![domain.clone()]);
+        assert!(not_after(leaf).unwrap() > Utc::now());
+        serials.push(leaf.serial_number().to_bn().unwrap());
+    }
+    assert_ne!(serials[0], serials[1]);
+}
@@ -74,6 +74,11 @@ pub async fn update(#[context] ctx: RpcContext, #[arg] interface: String) -> Res
                     .chain(ip_info.ipv6.map(IpAddr::from)),
             );
         }
+        drop(cached);
+        if let Err(e) = crate::net::dns_forward::sync_lan_listeners(&ctx).await {
+            tracing::error!("Could not serve DNS to the LAN: {}", e);
+            tracing::debug!("{:?}", e);
+        }
     }
     Ok(())
 }
@@ -1,37 +1,65 @@
 use std::borrow::Borrow;
-use std::collections::BTreeMap;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::{Arc, Weak};
 use std::time::Duration;
 
 use color_eyre::eyre::eyre;
 use futures::TryFutureExt;
 use helpers::NonDetachingJoinHandle;
+use ipnet::IpNet;
 use models::PackageId;
 use tokio::net::{TcpListener, UdpSocket};
 use tokio::process::Command;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use trust_dns_server::authority::MessageResponseBuilder;
 use trust_dns_server::client::op::{Header, ResponseCode};
-use trust_dns_server::client::rr::{Name, Record, RecordType};
+use trust_dns_server::client::rr::{Name, RData, Record, RecordType};
 use trust_dns_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
 use trust_dns_server::ServerFuture;
 
+use crate::net::dns_forward::DnsForwarder;
 use crate::util::Invoke;
 use crate::{Error, ErrorKind, ResultExt};
 
 pub struct DnsController {
-    services: Weak<RwLock<BTreeMap<Option<PackageId>, BTreeMap<Ipv4Addr, Weak<()>>>>>,
+    services: Weak<RwLock<BTreeMap<Option<PackageId>, BTreeMap<IpAddr, Weak<()>>>>>,
+    local: Weak<RwLock<BTreeMap<String, Weak<()>>>>,
+    pub(super) forwarder: Arc<DnsForwarder>,
     #[allow(dead_code)]
     dns_server: NonDetachingJoinHandle<Result<(), Error>>,
+    /// Servers on interfaces that come and go, like the WireGuard server
+    listeners: Mutex<BTreeMap<SocketAddr, NonDetachingJoinHandle<Result<(), Error>>>>,
+    /// Addresses of the box on its LAN interfaces that `listeners` serves on
+    lan: Mutex<BTreeSet<Ipv4Addr>>,
 }
 
 struct Resolver {
-    services: Arc<RwLock<BTreeMap<Option<PackageId>, BTreeMap<Ipv4Addr, Weak<()>>>>>,
+    services: Arc<RwLock<BTreeMap<Option<PackageId>, BTreeMap<IpAddr, Weak<()>>>>>,
+    /// `.local` names served by the box, for clients that cannot use mDNS
+    local: Arc<RwLock<BTreeMap<String, Weak<()>>>>,
+    /// Address of the box on the network this resolver listens on, which `.local` names resolve to
+    local_ip: Option<Ipv4Addr>,
+    forwarder: Arc<DnsForwarder>,
 }
 impl Resolver {
-    async fn resolve(&self, name: &Name) -> Option<Vec<Ipv4Addr>> {
+    async fn resolve(&self, name: &Name) -> Option<Vec<IpAddr>> {
         match name.iter().next_back() {
+            Some(b"local") => {
+                let local_ip = self.local_ip?;
+                let name = name.to_utf8().trim_end_matches('.').to_lowercase();
+                if self
+                    .local
+                    .read()
+                    .await
+                    .get(&name)
+                    .map_or(false, |rc| rc.strong_count() > 0)
+                {
+                    Some(vec![local_ip.into()])
+                } else {
+                    None
+                }
+            }
             Some(b"embassy") => {
                 if let Some(pkg) = name.iter().rev().skip(1).next() {
                     if let Some(ip) = self.services.read().await.get(&Some(
@@ -65,6 +93,74 @@ impl Resolver {
             _ => None,
         }
     }
+
+    /// The name of the package with address `name`, if it is an `in-addr.arpa` or `ip6.arpa` name
+    async fn resolve_ptr(&self, name: &Name) -> Option<Name> {
+        let ip = parse_ptr(&name.to_lowercase().to_utf8())?;
+        let services = self.services.read().await;
+        let (pkg, _) = services
+            .iter()
+            .find(|(_, ips)| ips.get(&ip).map_or(false, |rc| rc.strong_count() > 0))?;
+        Name::from_ascii(match pkg {
+            Some(pkg) => format!("{}.embassy.", pkg),
+            None => "embassy.".to_owned(),
+        })
+        .ok()
+    }
+}
+
+/// The address a reverse lookup name is for
+fn parse_ptr(name: &str) -> Option<IpAddr> {
+    let name = name.trim_end_matches('.');
+    if let Some(octets) = name.strip_suffix(".in-addr.arpa") {
+        let octets = octets
+            .split('.')
+            .rev()
+            .map(|o| o.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        Some(IpAddr::from(<[u8; 4]>::try_from(octets).ok()?))
+    } else if let Some(nibbles) = name.strip_suffix(".ip6.arpa") {
+        let nibbles = nibbles
+            .split('.')
+            .rev()
+            .map(|n| match n.len() {
+                1 => u8::from_str_radix(n, 16).ok(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if nibbles.len() != 32 {
+            return None;
+        }
+        let mut octets = [0; 16];
+        for (octet, pair) in octets.iter_mut().zip(nibbles.chunks(2)) {
+            *octet = pair[0] << 4 | pair[1];
+        }
+        Some(Ipv6Addr::from(octets).into())
+    } else {
+        None
+    }
+}
+
+/// Networks the box hands out addresses on: packages, their IPv6 range and WireGuard peers
+const BOX_SUBNETS: [&str; 3] = ["172.18.0.0/24", "10.59.0.0/24", "fd53:7a2e:9c40::/64"];
+
+/// Whether `name` is a reverse lookup for an address on one of the box's own networks, which
+/// upstreams cannot know about
+fn is_box_ptr(name: &Name) -> bool {
+    parse_ptr(&name.to_lowercase().to_utf8()).map_or(false, |ip| {
+        BOX_SUBNETS
+            .iter()
+            .filter_map(|subnet| subnet.parse::<IpNet>().ok())
+            .any(|subnet| subnet.contains(&ip))
+    })
+}
+
+/// Names that must never leave the box
+fn is_local(name: &Name) -> bool {
+    matches!(
+        name.iter().next_back(),
+        Some(b"embassy" | b"local" | b"localhost" | b"onion")
+    )
 }
 
 #[async_trait::async_trait]
@@ -75,83 +171,102 @@ impl RequestHandler for Resolver {
         mut response_handle: R,
     ) -> ResponseInfo {
         let query = request.request_info().query;
-        if let Some(ip) = self.resolve(query.name().borrow()).await {
+        let name: &Name = query.name().borrow();
+        let mut header = Header::response_from_request(request.header());
+        let mut answers = Vec::new();
+        let mut name_servers = Vec::new();
+        let mut additionals = Vec::new();
+        if let Some(ip) = self.resolve(name).await {
             match query.query_type() {
                 RecordType::A => {
-                    response_handle
-                        .send_response(
-                            MessageResponseBuilder::from_message_request(&*request).build(
-                                Header::response_from_request(request.header()),
-                                &ip.into_iter()
-                                    .map(|ip| {
-                                        Record::from_rdata(
-                                            request.request_info().query.name().to_owned().into(),
-                                            0,
-                                            trust_dns_server::client::rr::RData::A(ip),
-                                        )
-                                    })
-                                    .collect::<Vec<_>>(),
-                                [],
-                                [],
-                                [],
-                            ),
-                        )
-                        .await
+                    answers = ip
+                        .into_iter()
+                        .filter_map(|ip| match ip {
+                            IpAddr::V4(ip) => {
+                                Some(Record::from_rdata(name.clone(), 0, RData::A(ip)))
+                            }
+                            IpAddr::V6(_) => None,
+                        })
+                        .collect()
+                }
+                RecordType::AAAA => {
+                    answers = ip
+                        .into_iter()
+                        .filter_map(|ip| match ip {
+                            IpAddr::V4(_) => None,
+                            IpAddr::V6(ip) => {
+                                Some(Record::from_rdata(name.clone(), 0, RData::AAAA(ip)))
+                            }
+                        })
+                        .collect()
                 }
                 a => {
-                    if a != RecordType::AAAA {
-                        tracing::warn!(
-                            "Non A-Record requested for {}: {:?}",
-                            query.name(),
-                            query.query_type()
-                        );
-                    }
-                    let mut res = Header::response_from_request(request.header());
-                    res.set_response_code(ResponseCode::NXDomain);
-                    response_handle
-                        .send_response(
-                            MessageResponseBuilder::from_message_request(&*request).build(
-                                res.into(),
-                                [],
-                                [],
-                                [],
-                                [],
-                            ),
-                        )
-                        .await
+                    tracing::warn!(
+                        "Non A or AAAA Record requested for {}: {:?}",
+                        query.name(),
+                        a
+                    );
                 }
             }
+        } else if let Some(target) = self.resolve_ptr(name).await {
+            if query.query_type() == RecordType::PTR {
+                answers = vec![Record::from_rdata(name.clone(), 0, RData::PTR(target))];
+            }
+        } else if is_local(name)
+            || is_box_ptr(name)
+            || self.forwarder.is_blocked(&name.to_utf8()).await
+        {
+            header.set_response_code(ResponseCode::NXDomain);
         } else {
-            let mut res = Header::response_from_request(request.header());
-            res.set_response_code(ResponseCode::NXDomain);
-            response_handle
-                .send_response(
-                    MessageResponseBuilder::from_message_request(&*request).build(
-                        res.into(),
-                        [],
-                        [],
-                        [],
-                        [],
-                    ),
-                )
-                .await
+            match self.forwarder.forward(query.original()).await {
+                Ok(Some(mut res)) => {
+                    header.set_response_code(res.response_code());
+                    header.set_recursion_available(true);
+                    answers = res.take_answers();
+                    name_servers = res.take_name_servers();
+                    additionals = res.take_additionals();
+                }
+                Ok(None) => {
+                    header.set_response_code(ResponseCode::NXDomain);
+                }
+                Err(e) => {
+                    tracing::debug!("{:?}", e);
+                    header.set_response_code(ResponseCode::ServFail);
+                }
+            }
         }
-        .unwrap_or_else(|e| {
-            tracing::error!("{}", e);
-            tracing::debug!("{:?}", e);
-            let mut res = Header::response_from_request(request.header());
-            res.set_response_code(ResponseCode::ServFail);
-            res.into()
-        })
+        response_handle
+            .send_response(
+                MessageResponseBuilder::from_message_request(&*request).build(
+                    header,
+                    &answers,
+                    &name_servers,
+                    [],
+                    &additionals,
+                ),
+            )
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!("{}", e);
+                tracing::debug!("{:?}", e);
+                let mut res = Header::response_from_request(request.header());
+                res.set_response_code(ResponseCode::ServFail);
+                res.into()
+            })
     }
 }
 
 impl DnsController {
     pub async fn init(bind: &[SocketAddr]) -> Result<Self, Error> {
         let services = Arc::new(RwLock::new(BTreeMap::new()));
+        let local = Arc::new(RwLock::new(BTreeMap::new()));
+        let forwarder = Arc::new(DnsForwarder::new());
 
         let mut server = ServerFuture::new(Resolver {
             services: services.clone(),
+            local: local.clone(),
+            local_ip: None,
+            forwarder: forwarder.clone(),
         });
         server.register_listener(
             TcpListener::bind(bind)
@@ -177,11 +292,113 @@ impl DnsController {
 
         Ok(Self {
             services: Arc::downgrade(&services),
+            local: Arc::downgrade(&local),
+            forwarder,
             dns_server,
+            listeners: Mutex::new(BTreeMap::new()),
+            lan: Mutex::new(BTreeSet::new()),
         })
     }
 
-    pub async fn add(&self, pkg_id: Option<PackageId>, ip: Ipv4Addr) -> Result<Arc<()>, Error> {
+    /// Also serves on `bind`, answering `.local` names with `local_ip`
+    pub async fn listen(&self, bind: SocketAddr, local_ip: Ipv4Addr) -> Result<(), Error> {
+        let mut listeners = self.listeners.lock().await;
+        if listeners.contains_key(&bind) {
+            return Ok(());
+        }
+        let (services, local) = match (Weak::upgrade(&self.services), Weak::upgrade(&self.local)) {
+            (Some(services), Some(local)) => (services, local),
+            _ => {
+                return Err(Error::new(
+                    eyre!("DNS Server Thread has exited"),
+                    crate::ErrorKind::Network,
+                ))
+            }
+        };
+        let mut server = ServerFuture::new(Resolver {
+            services,
+            local,
+            local_ip: Some(local_ip),
+            forwarder: self.forwarder.clone(),
+        });
+        server.register_listener(
+            TcpListener::bind(bind)
+                .await
+                .with_kind(ErrorKind::Network)?,
+            Duration::from_secs(30),
+        );
+        server.register_socket(UdpSocket::bind(bind).await.with_kind(ErrorKind::Network)?);
+        listeners.insert(
+            bind,
+            tokio::spawn(
+                server
+                    .block_until_done()
+                    .map_err(|e| Error::new(e, ErrorKind::Network)),
+            )
+            .into(),
+        );
+        Ok(())
+    }
+
+    pub async fn unlisten(&self, bind: SocketAddr) {
+        self.listeners.lock().await.remove(&bind);
+    }
+
+    /// Serves on port 53 of each of `ips`, and stops serving on LAN addresses no longer in it
+    pub async fn set_lan(&self, ips: BTreeSet<Ipv4Addr>) {
+        let mut lan = self.lan.lock().await;
+        for ip in lan.difference(&ips) {
+            self.unlisten(SocketAddr::from((*ip, 53))).await;
+        }
+        let mut listening = BTreeSet::new();
+        for ip in ips {
+            match self.listen(SocketAddr::from((ip, 53)), ip).await {
+                Ok(()) => {
+                    listening.insert(ip);
+                }
+                Err(e) => {
+                    tracing::error!("Could not serve DNS on {}: {}", ip, e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+        }
+        *lan = listening;
+    }
+
+    pub async fn add_local(&self, name: String) -> Result<Arc<()>, Error> {
+        if let Some(local) = Weak::upgrade(&self.local) {
+            let mut writable = local.write().await;
+            let rc = if let Some(rc) = Weak::upgrade(&writable.remove(&name).unwrap_or_default()) {
+                rc
+            } else {
+                Arc::new(())
+            };
+            writable.insert(name, Arc::downgrade(&rc));
+            Ok(rc)
+        } else {
+            Err(Error::new(
+                eyre!("DNS Server Thread has exited"),
+                crate::ErrorKind::Network,
+            ))
+        }
+    }
+
+    pub async fn gc_local(&self, name: String) -> Result<(), Error> {
+        if let Some(local) = Weak::upgrade(&self.local) {
+            let mut writable = local.write().await;
+            if let Some(rc) = Weak::upgrade(&writable.remove(&name).unwrap_or_default()) {
+                writable.insert(name, Arc::downgrade(&rc));
+            }
+            Ok(())
+        } else {
+            Err(Error::new(
+                eyre!("DNS Server Thread has exited"),
+                crate::ErrorKind::Network,
+            ))
+        }
+    }
+
+    pub async fn add(&self, pkg_id: Option<PackageId>, ip: IpAddr) -> Result<Arc<()>, Error> {
         if let Some(services) = Weak::upgrade(&self.services) {
             let mut writable = services.write().await;
             let mut ips = writable.remove(&pkg_id).unwrap_or_default();
@@ -201,7 +418,7 @@ impl DnsController {
         }
     }
 
-    pub async fn gc(&self, pkg_id: Option<PackageId>, ip: Ipv4Addr) -> Result<(), Error> {
+    pub async fn gc(&self, pkg_id: Option<PackageId>, ip: IpAddr) -> Result<(), Error> {
         if let Some(services) = Weak::upgrade(&self.services) {
             let mut writable = services.write().await;
             let mut ips = writable.remove(&pkg_id).unwrap_or_default();
@@ -220,3 +437,31 @@ impl DnsController {
         }
     }
 }
+
+#[test]
+fn test_parse_ptr() {
+    assert_eq!(
+        parse_ptr("2.0.18.172.in-addr.arpa."),
+        Some(IpAddr::from([172, 18, 0, 2]))
+    );
+    assert_eq!(
+        parse_ptr("2.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.4.c.9.e.2.a.7.3.5.d.f.ip6.arpa."),
+        Some(IpAddr::from([0xfd53, 0x7a2e, 0x9c40, 0, 0, 0, 0, 2]))
+    );
+    assert_eq!(parse_ptr("18.172.in-addr.arpa."), None);
+    assert_eq!(parse_ptr("300.0.18.172.in-addr.arpa."), None);
+    assert_eq!(parse_ptr("bitcoind.embassy."), None);
+}
+
+#[test]
+fn test_is_box_ptr() {
+    let ptr = |name: &str| Name::from_ascii(name).unwrap();
+    assert!(is_box_ptr(&ptr("9.0.18.172.in-addr.arpa.")));
+    assert!(is_box_ptr(&ptr("5.0.59.10.in-addr.arpa.")));
+    assert!(is_box_ptr(&ptr(
+        "9.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.4.c.9.e.2.a.7.3.5.d.f.ip6.arpa."
+    )));
+    assert!(!is_box_ptr(&ptr("9.1.18.172.in-addr.arpa.")));
+    assert!(!is_box_ptr(&ptr("1.1.1.1.in-addr.arpa.")));
+    assert!(!is_box_ptr(&ptr("bitcoind.embassy.")));
+}
@@ -0,0 +1,902 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::Url;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::{Mutex, RwLock};
+use tracing::instrument;
+use trust_dns_server::client::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_server::client::rr::{RData, Record};
+
+use crate::context::RpcContext;
+use crate::shutdown::Shutdown;
+use crate::util::display_none;
+use crate::util::net::{connect, tls};
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Per upstream, the next one is tried after this
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+const CACHE_SIZE: usize = 10_000;
+const MAX_TTL: u32 = 86400;
+/// For NXDOMAIN and empty answers without an SOA to take the TTL from
+const NEGATIVE_TTL: u32 = 60;
+const MAX_NEGATIVE_TTL: u32 = 900;
+const EDNS_PAYLOAD: u16 = 1232;
+/// Blocklists are downloaded again once they are older than this many hours
+const BLOCKLIST_REFRESH_HOURS: i64 = 24;
+const BLOCKLIST_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Where the names of each blocklist are kept between downloads, relative to the data dir
+const BLOCKLIST_DIR: &str = "main/dns-blocklists";
+/// Names hosts files map to themselves, which must never be blocked
+const HOSTS_RESERVED: [&str; 6] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+];
+
+/// A server queries are forwarded to: `1.1.1.1` or `udp://1.1.1.1:53` for plain DNS,
+/// `tls://1.1.1.1` for DNS over TLS, or an `https://` URL for DNS over HTTPS
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Upstream {
+    Plain { host: String, port: u16 },
+    Tls { host: String, port: u16 },
+    Https { url: Url },
+}
+impl Upstream {
+    /// Plain DNS goes over TCP through the proxy, since Tor does not carry UDP
+    async fn query(&self, req: &[u8], proxy: Option<SocketAddr>) -> Result<Vec<u8>, Error> {
+        match self {
+            Upstream::Plain { host, port } if proxy.is_none() => {
+                let res = udp_query(host, *port, req).await?;
+                if Message::from_vec(&res)
+                    .with_kind(ErrorKind::Network)?
+                    .truncated()
+                {
+                    tcp_query(connect(host, *port, None).await?, req).await
+                } else {
+                    Ok(res)
+                }
+            }
+            Upstream::Plain { host, port } => {
+                tcp_query(connect(host, *port, proxy).await?, req).await
+            }
+            Upstream::Tls { host, port } => {
+                tcp_query(tls(host, connect(host, *port, proxy).await?).await?, req).await
+            }
+            Upstream::Https { url } => {
+                let mut client = reqwest::Client::builder().timeout(QUERY_TIMEOUT);
+                if let Some(proxy) = proxy {
+                    client = client.proxy(
+                        reqwest::Proxy::all(format!("socks5h://{}", proxy))
+                            .with_kind(ErrorKind::Network)?,
+                    );
+                }
+                Ok(client
+                    .build()
+                    .with_kind(ErrorKind::Network)?
+                    .post(url.clone())
+                    .header(CONTENT_TYPE, "application/dns-message")
+                    .header(ACCEPT, "application/dns-message")
+                    .body(req.to_vec())
+                    .send()
+                    .await
+                    .with_kind(ErrorKind::Network)?
+                    .error_for_status()
+                    .with_kind(ErrorKind::Network)?
+                    .bytes()
+                    .await
+                    .with_kind(ErrorKind::Network)?
+                    .to_vec())
+            }
+        }
+    }
+}
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host_port = |f: &mut fmt::Formatter<'_>, host: &str, port: u16| {
+            if host.contains(':') {
+                write!(f, "[{}]:{}", host, port)
+            } else {
+                write!(f, "{}:{}", host, port)
+            }
+        };
+        match self {
+            Upstream::Plain { host, port } => {
+                write!(f, "udp://")?;
+                host_port(f, host, *port)
+            }
+            Upstream::Tls { host, port } => {
+                write!(f, "tls://")?;
+                host_port(f, host, *port)
+            }
+            Upstream::Https { url } => write!(f, "{}", url),
+        }
+    }
+}
+impl FromStr for Upstream {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("https://") {
+            return Ok(Upstream::Https {
+                url: s.parse().with_kind(ErrorKind::ParseUrl)?,
+            });
+        }
+        let (tls, rest) = if let Some(rest) = s.strip_prefix("tls://") {
+            (true, rest)
+        } else {
+            (false, s.strip_prefix("udp://").unwrap_or(s))
+        };
+        let invalid = || {
+            Error::new(
+                eyre!("Invalid DNS upstream: {}", s),
+                ErrorKind::InvalidRequest,
+            )
+        };
+        let (host, port) = if let Some(rest) = rest.strip_prefix('[') {
+            let (host, port) = rest.split_once(']').ok_or_else(invalid)?;
+            (host, port.strip_prefix(':'))
+        } else if rest.parse::<IpAddr>().is_ok() {
+            (rest, None)
+        } else if let Some((host, port)) = rest.split_once(':') {
+            (host, Some(port))
+        } else {
+            (rest, None)
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid());
+        }
+        let port = port
+            .map(|p| p.parse::<u16>())
+            .transpose()?
+            .unwrap_or(if tls { 853 } else { 53 });
+        let host = host.to_owned();
+        Ok(if tls {
+            Upstream::Tls { host, port }
+        } else {
+            Upstream::Plain { host, port }
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UpstreamInfo {
+    #[serde(serialize_with = "crate::util::serde::serialize_display")]
+    #[serde(deserialize_with = "crate::util::serde::deserialize_from_str")]
+    pub upstream: Upstream,
+    pub tor: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BlocklistInfo {
+    pub url: Url,
+    /// Names blocked by the last successful download
+    pub entries: Option<i32>,
+    pub last_updated: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+async fn udp_query(host: &str, port: u16, req: &[u8]) -> Result<Vec<u8>, Error> {
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| Error::new(eyre!("Could not resolve {}", host), ErrorKind::Network))?;
+    let socket = UdpSocket::bind(if addr.is_ipv6() {
+        SocketAddr::from(([0u16; 8], 0))
+    } else {
+        SocketAddr::from(([0u8; 4], 0))
+    })
+    .await?;
+    socket.connect(addr).await?;
+    socket.send(req).await?;
+    let mut buf = vec![0; 4096];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // responses to queries that timed out earlier can still arrive
+        if len >= 2 && buf[..2] == req[..2] {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+/// Sends a query over a stream, each message prefixed with its length
+async fn tcp_query<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    req: &[u8],
+) -> Result<Vec<u8>, Error> {
+    stream.write_all(&(req.len() as u16).to_be_bytes()).await?;
+    stream.write_all(req).await?;
+    stream.flush().await?;
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Names listed by a hosts file, or by a file with one name per line
+fn parse_blocklist(list: &str) -> BTreeSet<String> {
+    let mut res = BTreeSet::new();
+    for line in list.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line.split_whitespace().peekable();
+        if words
+            .peek()
+            .map_or(false, |word| word.parse::<IpAddr>().is_ok())
+        {
+            words.next();
+        }
+        for name in words {
+            let name = name.trim_end_matches('.').to_lowercase();
+            if !name.is_empty() && name.contains('.') && !HOSTS_RESERVED.contains(&name.as_str()) {
+                res.insert(name);
+            }
+        }
+    }
+    res
+}
+
+struct CacheEntry {
+    expires: Instant,
+    response_code: ResponseCode,
+    answers: Vec<Record>,
+    name_servers: Vec<Record>,
+    additionals: Vec<Record>,
+}
+impl CacheEntry {
+    fn new(res: &Message, now: Instant) -> Option<Self> {
+        let ttl = match res.response_code() {
+            ResponseCode::NoError if !res.answers().is_empty() => res
+                .answers()
+                .iter()
+                .chain(res.name_servers())
+                .map(|r| r.ttl())
+                .min()
+                .unwrap_or(0)
+                .min(MAX_TTL),
+            ResponseCode::NoError | ResponseCode::NXDomain => res
+                .name_servers()
+                .iter()
+                .find_map(|r| match r.data() {
+                    Some(RData::SOA(soa)) => Some(soa.minimum().min(r.ttl())),
+                    _ => None,
+                })
+                .unwrap_or(NEGATIVE_TTL)
+                .min(MAX_NEGATIVE_TTL),
+            _ => return None,
+        };
+        if ttl == 0 {
+            return None;
+        }
+        Some(CacheEntry {
+            expires: now + Duration::from_secs(ttl as u64),
+            response_code: res.response_code(),
+            answers: res.answers().to_vec(),
+            name_servers: res.name_servers().to_vec(),
+            additionals: res.additionals().to_vec(),
+        })
+    }
+
+    /// The cached response, with TTLs counting down from when it was cached
+    fn response(&self, now: Instant) -> Option<Message> {
+        let remaining = self.expires.checked_duration_since(now)?.as_secs() as u32;
+        if remaining == 0 {
+            return None;
+        }
+        let age = |records: &[Record]| {
+            records
+                .iter()
+                .cloned()
+                .map(|mut r| {
+                    r.set_ttl(r.ttl().min(remaining));
+                    r
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut res = Message::new();
+        res.set_message_type(MessageType::Response)
+            .set_response_code(self.response_code)
+            .add_answers(age(&self.answers))
+            .add_name_servers(age(&self.name_servers))
+            .add_additionals(age(&self.additionals));
+        Some(res)
+    }
+}
+
+#[derive(Clone)]
+struct ActiveUpstream {
+    upstream: Upstream,
+    proxy: Option<SocketAddr>,
+}
+
+/// Answers queries for names the box does not serve itself, from the cache or the upstreams
+pub struct DnsForwarder {
+    upstreams: RwLock<Vec<ActiveUpstream>>,
+    cache: Mutex<BTreeMap<(String, u16), CacheEntry>>,
+    blocklists: RwLock<BTreeMap<String, BTreeSet<String>>>,
+}
+impl DnsForwarder {
+    pub fn new() -> Self {
+        DnsForwarder {
+            upstreams: RwLock::new(Vec::new()),
+            cache: Mutex::new(BTreeMap::new()),
+            blocklists: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Reads the upstreams from the secret store, dropping the cache
+    #[instrument(skip_all)]
+    pub async fn load(&self, secrets: &PgPool, tor_socks: SocketAddr) -> Result<(), Error> {
+        let upstreams = list_upstreams(secrets)
+            .await?
+            .into_iter()
+            .map(|info| ActiveUpstream {
+                upstream: info.upstream,
+                proxy: if info.tor { Some(tor_socks) } else { None },
+            })
+            .collect();
+        *self.upstreams.write().await = upstreams;
+        self.cache.lock().await.clear();
+        Ok(())
+    }
+
+    /// Whether `name` or any domain above it is on a blocklist
+    pub async fn is_blocked(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let blocklists = self.blocklists.read().await;
+        let mut domain = name.as_str();
+        loop {
+            if blocklists.values().any(|list| list.contains(domain)) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) if parent.contains('.') => domain = parent,
+                _ => return false,
+            }
+        }
+    }
+
+    async fn set_blocklist(&self, url: String, names: BTreeSet<String>) {
+        self.blocklists.write().await.insert(url, names);
+    }
+
+    async fn remove_blocklist(&self, url: &str) {
+        self.blocklists.write().await.remove(url);
+    }
+
+    pub async fn clear_cache(&self) {
+        self.cache.lock().await.clear();
+    }
+
+    /// Resolves `query` through the first upstream that answers. Returns `None` if there are no
+    /// upstreams.
+    #[instrument(skip_all)]
+    pub async fn forward(&self, query: &Query) -> Result<Option<Message>, Error> {
+        let key = (
+            query.name().to_lowercase().to_utf8(),
+            u16::from(query.query_type()),
+        );
+        if let Some(res) = self
+            .cache
+            .lock()
+            .await
+            .get(&key)
+            .and_then(|entry| entry.response(Instant::now()))
+        {
+            return Ok(Some(res));
+        }
+        // not held across the queries, so the upstreams can be reloaded meanwhile
+        let upstreams = self.upstreams.read().await.clone();
+        if upstreams.is_empty() {
+            return Ok(None);
+        }
+        let mut req = Message::new();
+        let mut edns = Edns::new();
+        edns.set_max_payload(EDNS_PAYLOAD);
+        req.set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(query.clone());
+        req.set_edns(edns);
+        let bytes = req.to_vec().with_kind(ErrorKind::Serialization)?;
+        let mut err = None;
+        for active in upstreams.iter() {
+            let res =
+                tokio::time::timeout(QUERY_TIMEOUT, active.upstream.query(&bytes, active.proxy))
+                    .await
+                    .map_err(|_| {
+                        Error::new(
+                            eyre!("Timed out waiting for {}", active.upstream),
+                            ErrorKind::Network,
+                        )
+                    })
+                    .and_then(|res| res)
+                    .and_then(|res| Message::from_vec(&res).with_kind(ErrorKind::Network))
+                    .and_then(|res| {
+                        if res.id() == req.id() || matches!(active.upstream, Upstream::Https { .. })
+                        {
+                            Ok(res)
+                        } else {
+                            Err(Error::new(
+                                eyre!("Mismatched response from {}", active.upstream),
+                                ErrorKind::Network,
+                            ))
+                        }
+                    });
+            match res {
+                Ok(res) => {
+                    self.cache(key, &res).await;
+                    return Ok(Some(res));
+                }
+                Err(e) => {
+                    tracing::warn!("DNS upstream {} failed: {}", active.upstream, e);
+                    tracing::debug!("{:?}", e);
+                    err = Some(e);
+                }
+            }
+        }
+        Err(err.unwrap_or_else(|| Error::new(eyre!("No DNS upstreams"), ErrorKind::Network)))
+    }
+
+    async fn cache(&self, key: (String, u16), res: &Message) {
+        let now = Instant::now();
+        if let Some(entry) = CacheEntry::new(res, now) {
+            let mut cache = self.cache.lock().await;
+            if cache.len() >= CACHE_SIZE {
+                cache.retain(|_, entry| entry.expires > now);
+            }
+            if cache.len() >= CACHE_SIZE {
+                if let Some(oldest) = cache
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone())
+                {
+                    cache.remove(&oldest);
+                }
+            }
+            cache.insert(key, entry);
+        }
+    }
+}
+
+async fn list_upstreams(secrets: &PgPool) -> Result<Vec<UpstreamInfo>, Error> {
+    sqlx::query!("SELECT upstream, tor FROM dns_upstreams ORDER BY id")
+        .fetch_all(secrets)
+        .await?
+        .into_iter()
+        .map(|r| {
+            Ok(UpstreamInfo {
+                upstream: r.upstream.parse()?,
+                tor: r.tor,
+            })
+        })
+        .collect()
+}
+
+#[instrument(skip_all)]
+async fn download_blocklist(url: &str) -> Result<BTreeSet<String>, Error> {
+    let list = reqwest::Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .with_kind(ErrorKind::Network)?
+        .get(url)
+        .send()
+        .await
+        .with_kind(ErrorKind::Network)?
+        .error_for_status()
+        .with_kind(ErrorKind::Network)?
+        .text()
+        .await
+        .with_kind(ErrorKind::Network)?;
+    Ok(parse_blocklist(&list))
+}
+
+fn blocklist_path(datadir: &Path, url: &str) -> PathBuf {
+    datadir
+        .join(BLOCKLIST_DIR)
+        .join(hex::encode(Sha256::digest(url.as_bytes())))
+}
+
+/// Keeps the names of a downloaded blocklist so they are blocked right away after a restart
+async fn save_blocklist(datadir: &Path, url: &str, names: &BTreeSet<String>) -> Result<(), Error> {
+    let path = blocklist_path(datadir, url);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, parent.display().to_string()))?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut contents = names
+        .iter()
+        .map(|n| n.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    contents.push('\n');
+    tokio::fs::write(&tmp, contents)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, tmp.display().to_string()))?;
+    tokio::fs::rename(&tmp, &path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))
+}
+
+async fn load_saved_blocklist(
+    datadir: &Path,
+    url: &str,
+) -> Result<Option<BTreeSet<String>>, Error> {
+    let path = blocklist_path(datadir, url);
+    match tokio::fs::read_to_string(&path).await {
+        Ok(list) => Ok(Some(parse_blocklist(&list))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string())),
+    }
+}
+
+async fn remove_saved_blocklist(datadir: &Path, url: &str) -> Result<(), Error> {
+    let path = blocklist_path(datadir, url);
+    match tokio::fs::remove_file(&path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Downloads blocklists that are not loaded yet or are out of date, or all of them if `force`.
+/// Saved copies are loaded first, so a list that cannot be downloaded keeps blocking.
+#[instrument(skip_all)]
+async fn refresh_blocklists(ctx: &RpcContext, force: bool) -> Result<(), Error> {
+    let forwarder = &ctx.net_controller.dns.forwarder;
+    let now = Utc::now();
+    for r in sqlx::query!("SELECT url, last_updated FROM dns_blocklists")
+        .fetch_all(&ctx.secret_store)
+        .await?
+    {
+        let mut loaded = forwarder.blocklists.read().await.contains_key(&r.url);
+        if !loaded {
+            match load_saved_blocklist(&ctx.datadir, &r.url).await {
+                Ok(Some(names)) => {
+                    forwarder.set_blocklist(r.url.clone(), names).await;
+                    loaded = true;
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    tracing::warn!("Could not load saved blocklist {}: {}", r.url, e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+        }
+        let stale = r.last_updated.map_or(true, |t| {
+            now - DateTime::<Utc>::from_utc(t, Utc)
+                > chrono::Duration::hours(BLOCKLIST_REFRESH_HOURS)
+        });
+        if loaded && !stale && !force {
+            continue;
+        }
+        match download_blocklist(&r.url).await {
+            Ok(names) => {
+                if let Err(e) = save_blocklist(&ctx.datadir, &r.url, &names).await {
+                    tracing::warn!("Could not save blocklist {}: {}", r.url, e);
+                    tracing::debug!("{:?}", e);
+                }
+                sqlx::query!(
+                    "UPDATE dns_blocklists SET entries = $1, last_updated = $2, last_error = NULL WHERE url = $3",
+                    names.len() as i32,
+                    Utc::now().naive_utc(),
+                    r.url,
+                )
+                .execute(&ctx.secret_store)
+                .await?;
+                forwarder.set_blocklist(r.url, names).await;
+            }
+            Err(e) => {
+                tracing::warn!("Could not download blocklist {}: {}", r.url, e);
+                tracing::debug!("{:?}", e);
+                sqlx::query!(
+                    "UPDATE dns_blocklists SET last_error = $1 WHERE url = $2",
+                    e.to_string(),
+                    r.url,
+                )
+                .execute(&ctx.secret_store)
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Serves DNS on the address of each LAN interface if `server-info.dns-lan` is set, otherwise
+/// stops serving on them
+#[instrument(skip_all)]
+pub async fn sync_lan_listeners(ctx: &RpcContext) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let enabled = *crate::db::DatabaseModel::new()
+        .server_info()
+        .dns_lan()
+        .get(&mut db)
+        .await?;
+    let ips = if enabled {
+        crate::db::DatabaseModel::new()
+            .server_info()
+            .ip_info()
+            .get(&mut db)
+            .await?
+            .values()
+            .filter_map(|info| info.ipv4)
+            .collect()
+    } else {
+        BTreeSet::new()
+    };
+    ctx.net_controller.dns.set_lan(ips).await;
+    Ok(())
+}
+
+pub async fn launch_blocklist_refresh_task(
+    ctx: &RpcContext,
+    mut shutdown: Receiver<Option<Shutdown>>,
+) {
+    if let Err(e) = sync_lan_listeners(ctx).await {
+        tracing::error!("Could not serve DNS to the LAN: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+    if let Err(e) = ctx
+        .net_controller
+        .dns
+        .forwarder
+        .load(&ctx.secret_store, ctx.tor_socks)
+        .await
+    {
+        tracing::error!("Could not load DNS upstreams: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+    loop {
+        if let Err(e) = refresh_blocklists(ctx, false).await {
+            tracing::error!("Error refreshing DNS blocklists: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = tokio::time::sleep(BLOCKLIST_CHECK_INTERVAL) => (),
+        }
+    }
+}
+
+#[command(subcommands(upstream, blocklist, flush_cache, lan))]
+pub fn dns() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Whether devices on the LAN can use the box as their DNS server, on port 53 of its LAN address
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn lan(#[context] ctx: RpcContext, #[arg] enabled: bool) -> Result<(), Error> {
+    crate::db::DatabaseModel::new()
+        .server_info()
+        .dns_lan()
+        .put(&mut ctx.db.handle(), &enabled)
+        .await?;
+    sync_lan_listeners(&ctx).await
+}
+
+/// Servers names outside `.embassy` and `.local` are resolved through, tried in order. Without
+/// any, those names do not resolve.
+#[command(subcommands(upstream_list, upstream_add, upstream_remove))]
+pub fn upstream() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "list", display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn upstream_list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<UpstreamInfo>, Error> {
+    list_upstreams(&ctx.secret_store).await
+}
+
+/// Adds an upstream after the existing ones, reaching it over Tor if `--tor` is passed
+#[command(rename = "add", display(display_none))]
+#[instrument(skip_all)]
+pub async fn upstream_add(
+    #[context] ctx: RpcContext,
+    #[arg] upstream: Upstream,
+    #[arg(long = "tor", default)] tor: bool,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT INTO dns_upstreams (upstream, tor) VALUES ($1, $2) ON CONFLICT (upstream) DO UPDATE SET tor = $2",
+        upstream.to_string(),
+        tor,
+    )
+    .execute(&ctx.secret_store)
+    .await?;
+    ctx.net_controller
+        .dns
+        .forwarder
+        .load(&ctx.secret_store, ctx.tor_socks)
+        .await
+}
+
+#[command(rename = "remove", display(display_none))]
+#[instrument(skip_all)]
+pub async fn upstream_remove(
+    #[context] ctx: RpcContext,
+    #[arg] upstream: Upstream,
+) -> Result<(), Error> {
+    let res = sqlx::query!(
+        "DELETE FROM dns_upstreams WHERE upstream = $1",
+        upstream.to_string()
+    )
+    .execute(&ctx.secret_store)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(Error::new(
+            eyre!("DNS upstream {} not found", upstream),
+            ErrorKind::NotFound,
+        ));
+    }
+    ctx.net_controller
+        .dns
+        .forwarder
+        .load(&ctx.secret_store, ctx.tor_socks)
+        .await
+}
+
+/// Hosts files whose names resolve to NXDOMAIN, along with every name below them
+#[command(subcommands(blocklist_list, blocklist_add, blocklist_remove, blocklist_refresh))]
+pub fn blocklist() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "list", display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn blocklist_list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<BlocklistInfo>, Error> {
+    sqlx::query!("SELECT url, entries, last_updated, last_error FROM dns_blocklists ORDER BY url")
+        .fetch_all(&ctx.secret_store)
+        .await?
+        .into_iter()
+        .map(|r| {
+            Ok(BlocklistInfo {
+                url: r.url.parse().with_kind(ErrorKind::ParseUrl)?,
+                entries: r.entries,
+                last_updated: r.last_updated.map(|t| DateTime::from_utc(t, Utc)),
+                last_error: r.last_error,
+            })
+        })
+        .collect()
+}
+
+/// Downloads the blocklist at `url` and keeps it up to date
+#[command(rename = "add", display(display_none))]
+#[instrument(skip_all)]
+pub async fn blocklist_add(#[context] ctx: RpcContext, #[arg] url: Url) -> Result<(), Error> {
+    let url = url.to_string();
+    let names = download_blocklist(&url).await?;
+    save_blocklist(&ctx.datadir, &url, &names).await?;
+    sqlx::query!(
+        "INSERT INTO dns_blocklists (url, entries, last_updated) VALUES ($1, $2, $3) ON CONFLICT (url) DO UPDATE SET entries = $2, last_updated = $3, last_error = NULL",
+        url,
+        names.len() as i32,
+        Utc::now().naive_utc(),
+    )
+    .execute(&ctx.secret_store)
+    .await?;
+    ctx.net_controller
+        .dns
+        .forwarder
+        .set_blocklist(url, names)
+        .await;
+    Ok(())
+}
+
+#[command(rename = "remove", display(display_none))]
+#[instrument(skip_all)]
+pub async fn blocklist_remove(#[context] ctx: RpcContext, #[arg] url: Url) -> Result<(), Error> {
+    let url = url.to_string();
+    let res = sqlx::query!("DELETE FROM dns_blocklists WHERE url = $1", url)
+        .execute(&ctx.secret_store)
+        .await?;
+    if res.rows_affected() == 0 {
+        return Err(Error::new(
+            eyre!("Blocklist {} not found", url),
+            ErrorKind::NotFound,
+        ));
+    }
+    ctx.net_controller
+        .dns
+        .forwarder
+        .remove_blocklist(&url)
+        .await;
+    remove_saved_blocklist(&ctx.datadir, &url).await
+}
+
+/// Downloads every blocklist again
+#[command(rename = "refresh", display(display_none))]
+#[instrument(skip_all)]
+pub async fn blocklist_refresh(#[context] ctx: RpcContext) -> Result<(), Error> {
+    refresh_blocklists(&ctx, true).await
+}
+
+#[command(rename = "flush-cache", display(display_none))]
+#[instrument(skip_all)]
+pub async fn flush_cache(#[context] ctx: RpcContext) -> Result<(), Error> {
+    ctx.net_controller.dns.forwarder.clear_cache().await;
+    Ok(())
+}
+
+#[test]
+fn test_parse_upstream() {
+    assert_eq!(
+        "1.1.1.1".parse::<Upstream>().unwrap(),
+        Upstream::Plain {
+            host: "1.1.1.1".to_owned(),
+            port: 53
+        }
+    );
+    assert_eq!(
+        "tls://dns.quad9.net".parse::<Upstream>().unwrap(),
+        Upstream::Tls {
+            host: "dns.quad9.net".to_owned(),
+            port: 853
+        }
+    );
+    assert_eq!(
+        "2606:4700:4700::1111".parse::<Upstream>().unwrap(),
+        Upstream::Plain {
+            host: "2606:4700:4700::1111".to_owned(),
+            port: 53
+        }
+    );
+    let upstream = "udp://[2606:4700:4700::1111]:5353"
+        .parse::<Upstream>()
+        .unwrap();
+    assert_eq!(upstream.to_string(), "udp://[2606:4700:4700::1111]:5353");
+    assert!(matches!(
+        "https://cloudflare-dns.com/dns-query".parse::<Upstream>(),
+        Ok(Upstream::Https { .. })
+    ));
+    assert!("tls://".parse::<Upstream>().is_err());
+}
+
+#[test]
+fn test_parse_blocklist() {
+    let names = parse_blocklist(
+        "# comment\n127.0.0.1 localhost\n0.0.0.0 ads.example.com tracker.example.com # inline\n:: v6.example.com\nplain.example.net.\n\n",
+    );
+    assert_eq!(
+        names.into_iter().collect::<Vec<_>>(),
+        vec![
+            "ads.example.com",
+            "plain.example.net",
+            "tracker.example.com",
+            "v6.example.com"
+        ]
+    );
+}
@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+
+use color_eyre::eyre::eyre;
+use patch_db::{DbHandle, LockType};
+use rpc_toolkit::command;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::db::model::AllPackageData;
+use crate::net::interface::{ClearnetAddress, InterfaceId};
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind};
+
+/// Ports the box already uses, see the note in `vhost`
+const RESERVED_PORTS: [u16; 6] = [5353, 5355, 5432, 6010, 9050, 9051];
+/// TLDs the box resolves itself, which can never be clearnet domains
+const LOCAL_TLDS: [&str; 4] = ["local", "onion", "embassy", "localhost"];
+
+/// Lowercases `domain` and drops a trailing dot, failing if it is not a fully qualified name
+pub fn normalize_domain(domain: &str) -> Result<String, Error> {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let invalid = |reason: &str| {
+        Error::new(
+            eyre!("Invalid domain {}: {}", domain, reason),
+            ErrorKind::InvalidRequest,
+        )
+    };
+    if domain.len() > 253 {
+        return Err(invalid("too long"));
+    }
+    let labels = domain.split('.').collect::<Vec<_>>();
+    if labels.len() < 2 {
+        return Err(invalid("not fully qualified"));
+    }
+    for label in &labels {
+        if label.is_empty()
+            || label.len() > 63
+            || label.starts_with('-')
+            || label.ends_with('-')
+            || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(invalid("bad label"));
+        }
+    }
+    if LOCAL_TLDS.contains(labels.last().unwrap()) {
+        return Err(invalid("not a clearnet domain"));
+    }
+    Ok(domain)
+}
+
+/// `https://` URLs of every clearnet address of an installed package
+pub fn clearnet_urls(packages: &AllPackageData) -> Vec<String> {
+    packages
+        .0
+        .values()
+        .filter_map(|pde| pde.installed())
+        .flat_map(|installed| installed.interface_addresses.0.values())
+        .flat_map(|addrs| {
+            addrs
+                .clearnet_addresses
+                .iter()
+                .map(|(domain, addr)| addr.url(domain))
+        })
+        .collect()
+}
+
+/// Keeps `server-info.connection-addresses.clearnet` in line with the package bindings
+#[instrument(skip_all)]
+pub async fn sync_connection_addresses<Db: DbHandle>(
+    db: &mut Db,
+    packages: &AllPackageData,
+) -> Result<(), Error> {
+    let mut addrs = crate::db::DatabaseModel::new()
+        .server_info()
+        .connection_addresses()
+        .get_mut(db)
+        .await?;
+    addrs.clearnet = clearnet_urls(packages);
+    addrs.save(db).await
+}
+
+#[command(subcommands(list, add, remove))]
+pub fn domain() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Clearnet domains of each interface of a package
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<BTreeMap<InterfaceId, BTreeMap<String, ClearnetAddress>>, Error> {
+    let mut db = ctx.db.handle();
+    let installed = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&id)
+        .and_then(|pde| pde.installed())
+        .get(&mut db)
+        .await?
+        .into_owned()
+        .ok_or_else(|| Error::new(eyre!("{} is not installed", id), ErrorKind::NotFound))?;
+    Ok(installed
+        .interface_addresses
+        .0
+        .into_iter()
+        .filter(|(_, addrs)| !addrs.clearnet_addresses.is_empty())
+        .map(|(iface, addrs)| (iface, addrs.clearnet_addresses))
+        .collect())
+}
+
+/// Serves an interface of a package at `domain` on port `external`, 443 by default. The domain
+/// must resolve to the box and the port must be forwarded to it. `internal` defaults to the port
+/// the interface serves `external` on over LAN. A running service is bound right away.
+/// Browsers only trust the certificate once one is issued with `net acme add`.
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] domain: String,
+    #[arg(long = "external")] external: Option<u16>,
+    #[arg(long = "internal")] internal: Option<u16>,
+) -> Result<(), Error> {
+    let domain = normalize_domain(&domain)?;
+    let external = external.unwrap_or(443);
+    if external != 443
+        && (external <= 1024 || external >= 32768 || RESERVED_PORTS.contains(&external))
+    {
+        return Err(Error::new(
+            eyre!("Port {} is not available for clearnet domains", external),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    crate::db::DatabaseModel::new()
+        .package_data()
+        .lock(&mut tx, LockType::Write)
+        .await?;
+    let mut packages = crate::db::DatabaseModel::new()
+        .package_data()
+        .get_mut(&mut tx)
+        .await?;
+    if let Some((other, _)) = packages.0.iter().find(|(other, pde)| {
+        *other != &id
+            && pde.installed().map_or(false, |installed| {
+                installed
+                    .interface_addresses
+                    .0
+                    .values()
+                    .any(|addrs| addrs.clearnet_addresses.contains_key(&domain))
+            })
+    }) {
+        return Err(Error::new(
+            eyre!("{} is already bound to {}", domain, other),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let installed = packages
+        .0
+        .get_mut(&id)
+        .and_then(|pde| pde.installed_mut())
+        .ok_or_else(|| Error::new(eyre!("{} is not installed", id), ErrorKind::NotFound))?;
+    let lan_config = installed
+        .manifest
+        .interfaces
+        .0
+        .get(&interface)
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("{} has no interface {}", id, interface),
+                ErrorKind::NotFound,
+            )
+        })?
+        .lan_config
+        .as_ref()
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("Interface {} of {} is not served over http", interface, id),
+                ErrorKind::InvalidRequest,
+            )
+        })?;
+    let internal = internal
+        .or_else(|| {
+            lan_config
+                .iter()
+                .find(|(port, _)| port.0 == external)
+                .or_else(|| lan_config.iter().next())
+                .map(|(_, lan)| lan.internal)
+        })
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("Interface {} of {} has no ports", interface, id),
+                ErrorKind::InvalidRequest,
+            )
+        })?;
+    let version = installed.manifest.version.clone();
+    let addr = ClearnetAddress { external, internal };
+    for (iface, addrs) in installed.interface_addresses.0.iter_mut() {
+        if iface != &interface {
+            addrs.clearnet_addresses.remove(&domain);
+        }
+    }
+    installed
+        .interface_addresses
+        .0
+        .get_mut(&interface)
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("{} has no interface {}", id, interface),
+                ErrorKind::NotFound,
+            )
+        })?
+        .clearnet_addresses
+        .insert(domain.clone(), addr.clone());
+    packages.save(&mut tx).await?;
+    sync_connection_addresses(&mut tx, &packages).await?;
+    tx.commit().await?;
+    if let Some(manager) = ctx.managers.get(&(id, version)).await {
+        manager.add_clearnet(interface, domain, &addr).await?;
+    }
+    Ok(())
+}
+
+/// A running service stops serving `domain` right away
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn remove(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] domain: String,
+) -> Result<(), Error> {
+    let domain = normalize_domain(&domain)?;
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    crate::db::DatabaseModel::new()
+        .package_data()
+        .lock(&mut tx, LockType::Write)
+        .await?;
+    let mut packages = crate::db::DatabaseModel::new()
+        .package_data()
+        .get_mut(&mut tx)
+        .await?;
+    let installed = packages.0.get_mut(&id).and_then(|pde| pde.installed_mut());
+    let version = installed.as_ref().map(|i| i.manifest.version.clone());
+    if installed
+        .and_then(|installed| installed.interface_addresses.0.get_mut(&interface))
+        .and_then(|addrs| addrs.clearnet_addresses.remove(&domain))
+        .is_none()
+    {
+        return Err(Error::new(
+            eyre!(
+                "{} is not bound to interface {} of {}",
+                domain,
+                interface,
+                id
+            ),
+            ErrorKind::NotFound,
+        ));
+    }
+    packages.save(&mut tx).await?;
+    sync_connection_addresses(&mut tx, &packages).await?;
+    tx.commit().await?;
+    if let Some(version) = version {
+        if let Some(manager) = ctx.managers.get(&(id, version)).await {
+            manager.remove_clearnet(&domain).await?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_normalize_domain() {
+    assert_eq!(
+        normalize_domain("Nextcloud.Example.COM.").unwrap(),
+        "nextcloud.example.com"
+    );
+    assert!(normalize_domain("example").is_err());
+    assert!(normalize_domain("-bad.example.com").is_err());
+    assert!(normalize_domain("a..example.com").is_err());
+    assert!(normalize_domain("under_score.example.com").is_err());
+    assert!(normalize_domain("adjective-noun.local").is_err());
+    assert!(normalize_domain("bitcoind.embassy").is_err());
+}
@@ -42,6 +42,7 @@ impl Interfaces {
             let mut addrs = InterfaceAddresses {
                 tor_address: None,
                 lan_address: None,
+                clearnet_addresses: BTreeMap::new(),
             };
             if iface.tor_config.is_some() || iface.lan_config.is_some() {
                 let key = TorSecretKeyV3::generate();
@@ -111,6 +112,23 @@ pub struct TorConfig {
     pub port_mapping: BTreeMap<Port, Port>,
 }
 
+/// A domain the owner has pointed at the box, and forwards `external` to it from the internet
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ClearnetAddress {
+    pub external: u16,
+    pub internal: u16,
+}
+impl ClearnetAddress {
+    pub fn url(&self, domain: &str) -> String {
+        if self.external == 443 {
+            format!("https://{}", domain)
+        } else {
+            format!("https://{}:{}", domain, self.external)
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct LanPortConfig {
@@ -6,8 +6,11 @@ use rpc_toolkit::command;
 
 use crate::Error;
 
+pub mod acme;
 pub mod dhcp;
 pub mod dns;
+pub mod dns_forward;
+pub mod domain;
 pub mod interface;
 pub mod keys;
 #[cfg(feature = "avahi")]
@@ -20,10 +23,17 @@ pub mod utils;
 pub mod vhost;
 pub mod web_server;
 pub mod wifi;
+pub mod wireguard;
 
 pub const PACKAGE_CERT_PATH: &str = "/var/lib/embassy/ssl";
 
-#[command(subcommands(tor::tor, dhcp::dhcp))]
+#[command(subcommands(
+    tor::tor,
+    dhcp::dhcp,
+    acme::acme,
+    dns_forward::dns,
+    wireguard::wireguard
+))]
 pub fn net() -> Result<(), Error> {
     Ok(())
 }
@@ -1,5 +1,5 @@
 use std::collections::BTreeMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::{Arc, Weak};
 
 use color_eyre::eyre::eyre;
@@ -9,6 +9,7 @@ use tracing::instrument;
 
 use crate::error::ErrorCollection;
 use crate::hostname::Hostname;
+use crate::net::acme::AcmeManager;
 use crate::net::dns::DnsController;
 use crate::net::keys::Key;
 #[cfg(feature = "avahi")]
@@ -18,7 +19,7 @@ use crate::net::tor::TorController;
 use crate::net::vhost::VHostController;
 use crate::s9pk::manifest::PackageId;
 use crate::volume::cert_dir;
-use crate::{Error, HOST_IP};
+use crate::{Error, HOST_IP, HOST_IPV6};
 
 pub struct NetController {
     pub(super) tor: TorController,
@@ -27,6 +28,7 @@ pub struct NetController {
     pub(super) vhost: VHostController,
     pub(super) dns: DnsController,
     pub(super) ssl: Arc<SslManager>,
+    pub(super) acme: AcmeManager,
     pub(super) os_bindings: Vec<Arc<()>>,
 }
 
@@ -46,6 +48,7 @@ impl NetController {
             mdns: MdnsController::init().await?,
             vhost: VHostController::new(ssl.clone()),
             dns: DnsController::init(dns_bind).await?,
+            acme: AcmeManager::new(ssl.clone()),
             ssl,
             os_bindings: Vec::new(),
         };
@@ -65,7 +68,9 @@ impl NetController {
             )
             .await?;
         self.os_bindings
-            .push(self.dns.add(None, HOST_IP.into()).await?);
+            .push(self.dns.add(None, Ipv4Addr::from(HOST_IP).into()).await?);
+        self.os_bindings
+            .push(self.dns.add(None, Ipv6Addr::from(HOST_IPV6).into()).await?);
 
         // LAN IP
         self.os_bindings.push(
@@ -99,6 +104,8 @@ impl NetController {
         );
 
         // LAN mDNS
+        self.os_bindings
+            .push(self.dns.add_local(hostname.local_domain_name()).await?);
         self.os_bindings.push(
             self.vhost
                 .add(
@@ -144,16 +151,22 @@ impl NetController {
         self: &Arc<Self>,
         package: PackageId,
         ip: Ipv4Addr,
+        ipv6: Option<Ipv6Addr>,
     ) -> Result<NetService, Error> {
-        let dns = self.dns.add(Some(package.clone()), ip).await?;
+        let mut dns = vec![self.dns.add(Some(package.clone()), ip.into()).await?];
+        if let Some(ipv6) = ipv6 {
+            dns.push(self.dns.add(Some(package.clone()), ipv6.into()).await?);
+        }
 
         Ok(NetService {
             id: package,
             ip,
+            ipv6,
             dns,
             controller: Arc::downgrade(self),
             tor: BTreeMap::new(),
             lan: BTreeMap::new(),
+            clearnet: BTreeMap::new(),
         })
     }
 
@@ -180,7 +193,7 @@ impl NetController {
         target: SocketAddr,
         connect_ssl: bool,
     ) -> Result<Vec<Arc<()>>, Error> {
-        let mut rcs = Vec::with_capacity(2);
+        let mut rcs = Vec::with_capacity(3);
         rcs.push(
             self.vhost
                 .add(
@@ -194,6 +207,7 @@ impl NetController {
         );
         #[cfg(feature = "avahi")]
         rcs.push(self.mdns.add(key.base_address()).await?);
+        rcs.push(self.dns.add_local(key.local_address()).await?);
         Ok(rcs)
     }
 
@@ -201,17 +215,48 @@ impl NetController {
         drop(rcs);
         #[cfg(feature = "avahi")]
         self.mdns.gc(key.base_address()).await?;
+        self.dns.gc_local(key.local_address()).await?;
         self.vhost.gc(Some(key.local_address()), external).await
     }
+
+    async fn add_clearnet(
+        &self,
+        key: Key,
+        domain: String,
+        external: u16,
+        target: SocketAddr,
+        connect_ssl: bool,
+    ) -> Result<Vec<Arc<()>>, Error> {
+        let mut rcs = Vec::with_capacity(1);
+        rcs.push(
+            self.vhost
+                .add(key, Some(domain), external, target, connect_ssl)
+                .await?,
+        );
+        Ok(rcs)
+    }
+
+    async fn remove_clearnet(
+        &self,
+        domain: String,
+        external: u16,
+        rcs: Vec<Arc<()>>,
+    ) -> Result<(), Error> {
+        drop(rcs);
+        self.vhost.gc(Some(domain), external).await
+    }
 }
 
 pub struct NetService {
     id: PackageId,
     ip: Ipv4Addr,
-    dns: Arc<()>,
+    /// Only set if the docker network has IPv6
+    ipv6: Option<Ipv6Addr>,
+    dns: Vec<Arc<()>>,
     controller: Weak<NetController>,
     tor: BTreeMap<(InterfaceId, u16), (Key, Vec<Arc<()>>)>,
     lan: BTreeMap<(InterfaceId, u16), (Key, Vec<Arc<()>>)>,
+    clearnet: BTreeMap<String, (u16, Vec<Arc<()>>)>,
 }
 impl NetService {
     fn net_controller(&self) -> Result<Arc<NetController>, Error> {
@@ -292,18 +337,55 @@ impl NetService {
         }
         Ok(())
     }
-    pub async fn export_cert<Ex>(
-        &self,
+    /// Serves interface `id` at `domain` on `external`, replacing an earlier binding of `domain`
+    pub async fn add_clearnet<Ex>(
+        &mut self,
         secrets: &mut Ex,
-        id: &InterfaceId,
-        ip: IpAddr,
+        id: InterfaceId,
+        domain: String,
+        external: u16,
+        internal: u16,
+        connect_ssl: bool,
     ) -> Result<(), Error>
+    where
+        for<'a> &'a mut Ex: PgExecutor<'a>,
+    {
+        self.remove_clearnet(&domain).await?;
+        let key = Key::for_interface(secrets, Some((self.id.clone(), id))).await?;
+        let ctrl = self.net_controller()?;
+        let rcs = ctrl
+            .add_clearnet(
+                key,
+                domain.clone(),
+                external,
+                SocketAddr::new(self.ip.into(), internal),
+                connect_ssl,
+            )
+            .await?;
+        self.clearnet.insert(domain, (external, rcs));
+        Ok(())
+    }
+    pub async fn remove_clearnet(&mut self, domain: &str) -> Result<(), Error> {
+        let ctrl = self.net_controller()?;
+        if let Some((external, rcs)) = self.clearnet.remove(domain) {
+            ctrl.remove_clearnet(domain.to_owned(), external, rcs)
+                .await?;
+        }
+        Ok(())
+    }
+    pub async fn export_cert<Ex>(&self, secrets: &mut Ex, id: &InterfaceId) -> Result<(), Error>
     where
         for<'a> &'a mut Ex: PgExecutor<'a>,
     {
         let key = Key::for_interface(secrets, Some((self.id.clone(), id.clone()))).await?;
         let ctrl = self.net_controller()?;
-        let cert = ctrl.ssl.with_certs(key, ip).await?;
+        let cert = ctrl
+            .ssl
+            .with_certs(
+                key,
+                std::iter::once(IpAddr::from(self.ip)).chain(self.ipv6.map(IpAddr::from)),
+            )
+            .await?;
         let cert_dir = cert_dir(&self.id, id);
         tokio::fs::create_dir_all(&cert_dir).await?;
         export_key(
@@ -327,8 +409,14 @@ impl NetService {
             for ((_, external), (key, rcs)) in std::mem::take(&mut self.tor) {
                 errors.handle(ctrl.remove_tor(&key, external, rcs).await);
             }
+            for (domain, (external, rcs)) in std::mem::take(&mut self.clearnet) {
+                errors.handle(ctrl.remove_clearnet(domain, external, rcs).await);
+            }
             std::mem::take(&mut self.dns);
-            errors.handle(ctrl.dns.gc(Some(self.id.clone()), self.ip).await);
+            errors.handle(ctrl.dns.gc(Some(self.id.clone()), self.ip.into()).await);
+            if let Some(ipv6) = self.ipv6.take() {
+                errors.handle(ctrl.dns.gc(Some(self.id.clone()), ipv6.into()).await);
+            }
             self.ip = Ipv4Addr::new(0, 0, 0, 0);
             errors.into_result()
         } else {
@@ -349,10 +437,12 @@ impl Drop for NetService {
                 NetService {
                     id: Default::default(),
                     ip: Ipv4Addr::new(0, 0, 0, 0),
+                    ipv6: None,
                     dns: Default::default(),
                     controller: Default::default(),
                     tor: Default::default(),
                     lan: Default::default(),
+                    clearnet: Default::default(),
                 },
             );
             tokio::spawn(async move { svc.remove_all().await.unwrap() });
@@ -85,6 +85,8 @@ pub struct SslManager {
     int_key: PKey<Private>,
     int_cert: X509,
     cert_cache: RwLock<BTreeMap<Key, CertPair>>,
+    /// Key and fullchain for clearnet domains, issued by an ACME CA instead of the local root
+    acme_certs: RwLock<BTreeMap<String, (PKey<Private>, Vec<X509>)>>,
 }
 impl SslManager {
     pub fn new(account: &AccountInfo) -> Result<Self, Error> {
@@ -96,11 +98,17 @@ impl SslManager {
             int_key,
             int_cert,
             cert_cache: RwLock::new(BTreeMap::new()),
+            acme_certs: RwLock::new(BTreeMap::new()),
         })
     }
-    pub async fn with_certs(&self, key: Key, ip: IpAddr) -> Result<KeyInfo, Error> {
+    /// Certs for `key`, valid for the IPs of the box and `ip`, which can include IPv6 addresses
+    pub async fn with_certs(
+        &self,
+        key: Key,
+        ip: impl IntoIterator<Item = IpAddr>,
+    ) -> Result<KeyInfo, Error> {
         let mut ips = ips().await?;
-        ips.insert(ip);
+        ips.extend(ip);
         let (pair, updated) = CertPair::updated(
             self.cert_cache.read().await.get(&key),
             &self.hostname,
@@ -117,6 +125,19 @@ impl SslManager {
 
         Ok(key.with_certs(pair, self.int_cert.clone(), self.root_cert.clone()))
     }
+    pub async fn set_acme_cert(&self, domain: String, key: PKey<Private>, fullchain: Vec<X509>) {
+        self.acme_certs
+            .write()
+            .await
+            .insert(domain, (key, fullchain));
+    }
+    pub async fn remove_acme_cert(&self, domain: &str) {
+        self.acme_certs.write().await.remove(domain);
+    }
+    /// The ACME issued key and fullchain to serve for an SNI hostname, if there is one
+    pub async fn acme_cert(&self, domain: &str) -> Option<(PKey<Private>, Vec<X509>)> {
+        self.acme_certs.read().await.get(domain).cloned()
+    }
 }
 
 const EC_CURVE_NAME: nid::Nid = nid::Nid::X9_62_PRIME256V1;
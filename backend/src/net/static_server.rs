@@ -28,6 +28,7 @@ use crate::middleware::auth::{auth as auth_middleware, HasValidSession};
 use crate::middleware::cors::cors;
 use crate::middleware::db::db as db_middleware;
 use crate::middleware::diagnostic::diagnostic as diagnostic_middleware;
+use crate::net::acme::ACME_CHALLENGE_PATH;
 use crate::net::HttpHandler;
 use crate::{diagnostic_api, install_api, main_api, setup_api, Error, ErrorKind, ResultExt};
 
@@ -211,6 +212,22 @@ pub async fn main_ui_server_router(ctx: RpcContext) -> Result<HttpHandler, Error
                         },
                     }
                 }
+                "/metrics" => crate::prometheus::handle(&ctx, req).await,
+                path if path.starts_with(ACME_CHALLENGE_PATH) => {
+                    match ctx
+                        .net_controller
+                        .acme
+                        .http_challenge(path.strip_prefix(ACME_CHALLENGE_PATH).unwrap())
+                        .await
+                    {
+                        Some(key_authorization) => Response::builder()
+                            .status(StatusCode::OK)
+                            .header(http::header::CONTENT_TYPE, "application/octet-stream")
+                            .body(key_authorization.into())
+                            .with_kind(ErrorKind::Network),
+                        None => Ok(not_found()),
+                    }
+                }
                 _ => main_embassy_ui(req, ctx).await,
             };
 
@@ -42,6 +42,7 @@ pub async fn get_iface_ipv4_addr(iface: &str) -> Result<Option<(Ipv4Addr, Ipv4Ne
     .transpose()?)
 }
 
+/// The first global address, since every interface also has a link-local one
 pub async fn get_iface_ipv6_addr(iface: &str) -> Result<Option<(Ipv6Addr, Ipv6Net)>, Error> {
     Ok(parse_iface_ip(&String::from_utf8(
         Command::new("ip")
@@ -50,6 +51,8 @@ pub async fn get_iface_ipv6_addr(iface: &str) -> Result<Option<(Ipv6Addr, Ipv6Ne
             .arg("addr")
             .arg("show")
             .arg(iface)
+            .arg("scope")
+            .arg("global")
             .invoke(crate::ErrorKind::Network)
             .await?,
     )?)?
@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 use std::convert::Infallible;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
 use std::str::FromStr;
 use std::sync::{Arc, Weak};
 
@@ -10,7 +11,8 @@ use http::{Response, Uri};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Body;
 use models::ResultExt;
-use tokio::net::{TcpListener, TcpStream};
+use nix::sys::socket::{setsockopt, sockopt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::sync::{Mutex, RwLock};
 use tokio_rustls::rustls::server::Acceptor;
 use tokio_rustls::rustls::{RootCertStore, ServerConfig};
@@ -22,6 +24,30 @@ use crate::net::utils::SingleAccept;
 use crate::util::io::BackTrackingReader;
 use crate::Error;
 
+lazy_static::lazy_static! {
+    static ref PROXIED_PEERS: std::sync::Mutex<BTreeMap<SocketAddr, SocketAddr>> =
+        std::sync::Mutex::new(BTreeMap::new());
+}
+
+/// Looks up the client a vhost connection was accepted from, given the local end of the
+/// connection the vhost opened to its target
+pub fn proxied_peer(local: SocketAddr) -> Option<SocketAddr> {
+    PROXIED_PEERS.lock().unwrap().get(&local).copied()
+}
+
+struct ProxiedPeer(SocketAddr);
+impl ProxiedPeer {
+    fn new(local: SocketAddr, peer: SocketAddr) -> Self {
+        PROXIED_PEERS.lock().unwrap().insert(local, peer);
+        Self(local)
+    }
+}
+impl Drop for ProxiedPeer {
+    fn drop(&mut self) {
+        PROXIED_PEERS.lock().unwrap().remove(&self.0);
+    }
+}
+
 // not allowed: <=1024, >=32768, 5355, 5432, 9050, 6010, 9051, 5353
 
 pub struct VHostController {
@@ -74,6 +100,26 @@ impl VHostController {
     }
 }
 
+/// Listens on IPv6 and IPv4 with one socket, or on IPv4 alone if the box has IPv6 disabled
+async fn bind_dual_stack(port: u16) -> Result<TcpListener, Error> {
+    let v6 = || -> std::io::Result<TcpListener> {
+        let socket = TcpSocket::new_v6()?;
+        setsockopt(socket.as_raw_fd(), sockopt::Ipv6V6Only, &false)?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port))?;
+        socket.listen(1024)
+    };
+    match v6() {
+        Ok(listener) => Ok(listener),
+        Err(e) => {
+            tracing::warn!("Could not listen on IPv6 port {}: {}", port, e);
+            TcpListener::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))
+                .await
+                .with_kind(crate::ErrorKind::Network)
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 struct TargetInfo {
     addr: SocketAddr,
@@ -88,16 +134,14 @@ struct VHostServer {
 impl VHostServer {
     async fn new(port: u16, ssl: Arc<SslManager>) -> Result<Self, Error> {
         // check if port allowed
-        let listener = TcpListener::bind(SocketAddr::new([0, 0, 0, 0].into(), port))
-            .await
-            .with_kind(crate::ErrorKind::Network)?;
+        let listener = bind_dual_stack(port).await?;
         let mapping = Arc::new(RwLock::new(BTreeMap::new()));
         Ok(Self {
             mapping: Arc::downgrade(&mapping),
             _thread: tokio::spawn(async move {
                 loop {
                     match listener.accept().await {
-                        Ok((stream, _)) => {
+                        Ok((stream, peer)) => {
                             let mut stream = BackTrackingReader::new(stream);
                             stream.start_buffering();
                             let mapping = mapping.clone();
@@ -147,6 +191,11 @@ impl VHostServer {
                                     };
                                     let target_name =
                                         mid.client_hello().server_name().map(|s| s.to_owned());
+                                    let acme_cert = if let Some(name) = &target_name {
+                                        ssl.acme_cert(name).await
+                                    } else {
+                                        None
+                                    };
                                     let target = {
                                         let mapping = mapping.read().await;
                                         mapping
@@ -173,47 +222,62 @@ impl VHostServer {
                                     if let Some(target) = target {
                                         let mut tcp_stream =
                                             TcpStream::connect(target.addr).await?;
+                                        let _peer =
+                                            ProxiedPeer::new(tcp_stream.local_addr()?, peer);
                                         let key =
-                                            ssl.with_certs(target.key, target.addr.ip()).await?;
+                                            ssl.with_certs(target.key, [target.addr.ip()]).await?;
                                         let cfg = ServerConfig::builder()
                                             .with_safe_defaults()
                                             .with_no_client_auth();
-                                        let cfg =
-                                            if mid.client_hello().signature_schemes().contains(
-                                                &tokio_rustls::rustls::SignatureScheme::ED25519,
-                                            ) {
-                                                cfg.with_single_cert(
-                                                    key.fullchain_ed25519()
-                                                        .into_iter()
-                                                        .map(|c| {
-                                                            Ok(tokio_rustls::rustls::Certificate(
-                                                                c.to_der()?,
-                                                            ))
-                                                        })
-                                                        .collect::<Result<_, Error>>()?,
-                                                    tokio_rustls::rustls::PrivateKey(
-                                                        key.key()
-                                                            .openssl_key_ed25519()
-                                                            .private_key_to_der()?,
-                                                    ),
-                                                )
-                                            } else {
-                                                cfg.with_single_cert(
-                                                    key.fullchain_nistp256()
-                                                        .into_iter()
-                                                        .map(|c| {
-                                                            Ok(tokio_rustls::rustls::Certificate(
-                                                                c.to_der()?,
-                                                            ))
-                                                        })
-                                                        .collect::<Result<_, Error>>()?,
-                                                    tokio_rustls::rustls::PrivateKey(
-                                                        key.key()
-                                                            .openssl_key_nistp256()
-                                                            .private_key_to_der()?,
-                                                    ),
-                                                )
-                                            };
+                                        let cfg = if let Some((acme_key, acme_chain)) = acme_cert {
+                                            cfg.with_single_cert(
+                                                acme_chain
+                                                    .into_iter()
+                                                    .map(|c| {
+                                                        Ok(tokio_rustls::rustls::Certificate(
+                                                            c.to_der()?,
+                                                        ))
+                                                    })
+                                                    .collect::<Result<_, Error>>()?,
+                                                tokio_rustls::rustls::PrivateKey(
+                                                    acme_key.private_key_to_der()?,
+                                                ),
+                                            )
+                                        } else if mid.client_hello().signature_schemes().contains(
+                                            &tokio_rustls::rustls::SignatureScheme::ED25519,
+                                        ) {
+                                            cfg.with_single_cert(
+                                                key.fullchain_ed25519()
+                                                    .into_iter()
+                                                    .map(|c| {
+                                                        Ok(tokio_rustls::rustls::Certificate(
+                                                            c.to_der()?,
+                                                        ))
+                                                    })
+                                                    .collect::<Result<_, Error>>()?,
+                                                tokio_rustls::rustls::PrivateKey(
+                                                    key.key()
+                                                        .openssl_key_ed25519()
+                                                        .private_key_to_der()?,
+                                                ),
+                                            )
+                                        } else {
+                                            cfg.with_single_cert(
+                                                key.fullchain_nistp256()
+                                                    .into_iter()
+                                                    .map(|c| {
+                                                        Ok(tokio_rustls::rustls::Certificate(
+                                                            c.to_der()?,
+                                                        ))
+                                                    })
+                                                    .collect::<Result<_, Error>>()?,
+                                                tokio_rustls::rustls::PrivateKey(
+                                                    key.key()
+                                                        .openssl_key_nistp256()
+                                                        .private_key_to_der()?,
+                                                ),
+                                            )
+                                        };
                                         let mut tls_stream = mid
                                             .into_stream(Arc::new(
                                                 cfg.with_kind(crate::ErrorKind::OpenSsl)?,
@@ -4,17 +4,24 @@ use std::net::SocketAddr;
 use futures::future::ready;
 use futures::FutureExt;
 use helpers::NonDetachingJoinHandle;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::Server;
+use hyper::{Body, Request, Server};
 use tokio::sync::oneshot;
 
 use crate::context::{DiagnosticContext, InstallContext, RpcContext, SetupContext};
 use crate::net::static_server::{
     diag_ui_file_router, install_ui_file_router, main_ui_server_router, setup_ui_file_router,
 };
+use crate::net::vhost::proxied_peer;
 use crate::net::HttpHandler;
 use crate::Error;
 
+/// The client a request came from, inserted into the request extensions. For connections proxied
+/// through the TLS vhost this is the peer the vhost accepted, not loopback.
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteAddr(pub SocketAddr);
+
 pub struct WebServer {
     shutdown: oneshot::Sender<()>,
     thread: NonDetachingJoinHandle<()>,
@@ -26,9 +33,20 @@ impl WebServer {
             let server = Server::bind(&bind)
                 .http1_preserve_header_case(true)
                 .http1_title_case_headers(true)
-                .serve(make_service_fn(move |_| {
+                .serve(make_service_fn(move |conn: &AddrStream| {
                     let router = router.clone();
-                    ready(Ok::<_, Infallible>(service_fn(move |req| router(req))))
+                    let remote_addr = conn.remote_addr();
+                    ready(Ok::<_, Infallible>(service_fn(
+                        move |mut req: Request<Body>| {
+                            let remote_addr = if remote_addr.ip().is_loopback() {
+                                proxied_peer(remote_addr).unwrap_or(remote_addr)
+                            } else {
+                                remote_addr
+                            };
+                            req.extensions_mut().insert(RemoteAddr(remote_addr));
+                            router(req)
+                        },
+                    )))
                 }))
                 .with_graceful_shutdown(shutdown_recv.map(|_| ()));
             if let Err(e) = server.await {
@@ -0,0 +1,659 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::Stdio;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke};
+use crate::{Error, ErrorKind, ResultExt};
+
+pub const WIREGUARD_INTERFACE: &str = "wg-start9";
+/// Address of the box on the VPN, peers get the rest of the /24
+pub const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 59, 0, 1);
+const VPN_SUBNET: &str = "10.59.0.0/24";
+/// The docker network packages run on, see `init`
+const PACKAGE_SUBNET: &str = "172.18.0.0/24";
+const PACKAGE_BRIDGE: &str = "br-start9";
+pub const DEFAULT_PORT: u16 = 51820;
+const KEEPALIVE_SECONDS: u16 = 25;
+/// Stands in for the private key of a peer that generated its own
+const PRIVATE_KEY_PLACEHOLDER: &str = "<PRIVATE KEY>";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WireguardStatus {
+    pub enabled: bool,
+    pub port: u16,
+    /// Host or IP clients reach the box at, needed to generate client configs
+    pub endpoint: Option<String>,
+    pub public_key: Option<String>,
+    pub peers: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WireguardPeer {
+    pub name: String,
+    pub ip: Ipv4Addr,
+    pub public_key: String,
+    pub created_at: DateTime<Utc>,
+    /// Where the peer last connected from
+    pub endpoint: Option<String>,
+    pub latest_handshake: Option<DateTime<Utc>>,
+    pub transfer_rx: u64,
+    pub transfer_tx: u64,
+}
+
+struct Server {
+    private_key: String,
+    port: u16,
+    endpoint: Option<String>,
+    enabled: bool,
+}
+
+struct Peer {
+    name: String,
+    ip: Ipv4Addr,
+    public_key: String,
+    private_key: Option<String>,
+    preshared_key: String,
+    created_at: NaiveDateTime,
+}
+
+async fn invoke_with_stdin(
+    cmd: &mut Command,
+    input: &[u8],
+    error_kind: ErrorKind,
+) -> Result<Vec<u8>, Error> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input).await?;
+    }
+    let res = child.wait_with_output().await?;
+    crate::ensure_code!(
+        res.status.success(),
+        error_kind,
+        "{}",
+        std::str::from_utf8(&res.stderr).unwrap_or("Unknown Error")
+    );
+    Ok(res.stdout)
+}
+
+fn generate_key() -> String {
+    base64::encode(rand::random::<[u8; 32]>())
+}
+
+async fn public_key(private_key: &str) -> Result<String, Error> {
+    Ok(String::from_utf8(
+        invoke_with_stdin(
+            Command::new("wg").arg("pubkey"),
+            private_key.as_bytes(),
+            ErrorKind::Network,
+        )
+        .await?,
+    )?
+    .trim()
+    .to_owned())
+}
+
+fn validate_key(key: &str) -> Result<(), Error> {
+    match base64::decode(key) {
+        Ok(k) if k.len() == 32 => Ok(()),
+        _ => Err(Error::new(
+            eyre!("Invalid WireGuard key: {}", key),
+            ErrorKind::InvalidRequest,
+        )),
+    }
+}
+
+/// Lowest free address on the VPN
+fn next_ip(used: &BTreeSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+    let [a, b, c, _] = SERVER_IP.octets();
+    (2..255)
+        .map(|d| Ipv4Addr::new(a, b, c, d))
+        .find(|ip| !used.contains(ip))
+}
+
+fn server_config(private_key: &str, port: u16, peers: &[Peer]) -> String {
+    let mut res = format!(
+        "[Interface]\nPrivateKey = {}\nListenPort = {}\n",
+        private_key, port
+    );
+    for peer in peers {
+        res += &format!(
+            "\n# {}\n[Peer]\nPublicKey = {}\nPresharedKey = {}\nAllowedIPs = {}/32\n",
+            peer.name, peer.public_key, peer.preshared_key, peer.ip
+        );
+    }
+    res
+}
+
+/// Routes the VPN and the package network through the tunnel, and resolves through the box
+fn client_config(
+    peer_ip: Ipv4Addr,
+    peer_private_key: &str,
+    preshared_key: &str,
+    server_public_key: &str,
+    endpoint: &str,
+    port: u16,
+) -> String {
+    format!(
+        "[Interface]\nPrivateKey = {}\nAddress = {}/32\nDNS = {}\n\n[Peer]\nPublicKey = {}\nPresharedKey = {}\nEndpoint = {}:{}\nAllowedIPs = {}, {}\nPersistentKeepalive = {}\n",
+        peer_private_key,
+        peer_ip,
+        SERVER_IP,
+        server_public_key,
+        preshared_key,
+        endpoint,
+        port,
+        VPN_SUBNET,
+        PACKAGE_SUBNET,
+        KEEPALIVE_SECONDS,
+    )
+}
+
+async fn load_server(secrets: &sqlx::PgPool) -> Result<Option<Server>, Error> {
+    Ok(
+        sqlx::query!("SELECT private_key, port, endpoint, enabled FROM wireguard_server")
+            .fetch_optional(secrets)
+            .await?
+            .map(|r| Server {
+                private_key: r.private_key,
+                port: r.port as u16,
+                endpoint: r.endpoint,
+                enabled: r.enabled,
+            }),
+    )
+}
+
+/// Peers cannot be given a config without it
+fn endpoint(server: &Server) -> Result<&str, Error> {
+    server.endpoint.as_deref().ok_or_else(|| {
+        Error::new(
+            eyre!("WireGuard endpoint is not set, pass --endpoint to `net wireguard enable`"),
+            ErrorKind::InvalidRequest,
+        )
+    })
+}
+
+async fn load_peers(secrets: &sqlx::PgPool) -> Result<Vec<Peer>, Error> {
+    sqlx::query!(
+        "SELECT name, ip, public_key, private_key, preshared_key, created_at FROM wireguard_peers ORDER BY created_at"
+    )
+    .fetch_all(secrets)
+    .await?
+    .into_iter()
+    .map(|r| {
+        Ok(Peer {
+            name: r.name,
+            ip: r.ip.parse().with_kind(ErrorKind::ParseNetAddress)?,
+            public_key: r.public_key,
+            private_key: r.private_key,
+            preshared_key: r.preshared_key,
+            created_at: r.created_at,
+        })
+    })
+    .collect()
+}
+
+async fn load_peer(secrets: &sqlx::PgPool, name: &str) -> Result<Peer, Error> {
+    load_peers(secrets)
+        .await?
+        .into_iter()
+        .find(|p| p.name == name)
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("WireGuard peer {} not found", name),
+                ErrorKind::NotFound,
+            )
+        })
+}
+
+fn iptables_rules() -> [Vec<&'static str>; 2] {
+    [
+        vec![
+            "-i",
+            WIREGUARD_INTERFACE,
+            "-o",
+            PACKAGE_BRIDGE,
+            "-j",
+            "ACCEPT",
+        ],
+        vec![
+            "-i",
+            PACKAGE_BRIDGE,
+            "-o",
+            WIREGUARD_INTERFACE,
+            "-m",
+            "conntrack",
+            "--ctstate",
+            "RELATED,ESTABLISHED",
+            "-j",
+            "ACCEPT",
+        ],
+    ]
+}
+
+#[instrument(skip_all)]
+async fn up(ctx: &RpcContext, server: &Server, peers: &[Peer]) -> Result<(), Error> {
+    if tokio::fs::metadata(format!("/sys/class/net/{}", WIREGUARD_INTERFACE))
+        .await
+        .is_err()
+    {
+        Command::new("ip")
+            .arg("link")
+            .arg("add")
+            .arg("dev")
+            .arg(WIREGUARD_INTERFACE)
+            .arg("type")
+            .arg("wireguard")
+            .invoke(ErrorKind::Network)
+            .await?;
+        Command::new("ip")
+            .arg("address")
+            .arg("add")
+            .arg(format!("{}/24", SERVER_IP))
+            .arg("dev")
+            .arg(WIREGUARD_INTERFACE)
+            .invoke(ErrorKind::Network)
+            .await?;
+    }
+    invoke_with_stdin(
+        Command::new("wg")
+            .arg("syncconf")
+            .arg(WIREGUARD_INTERFACE)
+            .arg("/dev/stdin"),
+        server_config(&server.private_key, server.port, peers).as_bytes(),
+        ErrorKind::Network,
+    )
+    .await?;
+    Command::new("ip")
+        .arg("link")
+        .arg("set")
+        .arg(WIREGUARD_INTERFACE)
+        .arg("up")
+        .invoke(ErrorKind::Network)
+        .await?;
+    // docker drops forwarded traffic to its networks unless allowed in DOCKER-USER
+    for rule in iptables_rules() {
+        if Command::new("iptables")
+            .arg("-C")
+            .arg("DOCKER-USER")
+            .args(&rule)
+            .invoke(ErrorKind::Network)
+            .await
+            .is_err()
+        {
+            Command::new("iptables")
+                .arg("-I")
+                .arg("DOCKER-USER")
+                .args(&rule)
+                .invoke(ErrorKind::Network)
+                .await?;
+        }
+    }
+    ctx.net_controller
+        .dns
+        .listen(SocketAddr::from((SERVER_IP, 53)), SERVER_IP)
+        .await
+}
+
+#[instrument(skip_all)]
+async fn down(ctx: &RpcContext) -> Result<(), Error> {
+    ctx.net_controller
+        .dns
+        .unlisten(SocketAddr::from((SERVER_IP, 53)))
+        .await;
+    for rule in iptables_rules() {
+        Command::new("iptables")
+            .arg("-D")
+            .arg("DOCKER-USER")
+            .args(&rule)
+            .invoke(ErrorKind::Network)
+            .await
+            .ok();
+    }
+    if tokio::fs::metadata(format!("/sys/class/net/{}", WIREGUARD_INTERFACE))
+        .await
+        .is_ok()
+    {
+        Command::new("ip")
+            .arg("link")
+            .arg("del")
+            .arg(WIREGUARD_INTERFACE)
+            .invoke(ErrorKind::Network)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Brings the interface in line with the secret store
+#[instrument(skip_all)]
+pub async fn sync(ctx: &RpcContext) -> Result<(), Error> {
+    match load_server(&ctx.secret_store).await? {
+        Some(server) if server.enabled => {
+            let peers = load_peers(&ctx.secret_store).await?;
+            up(ctx, &server, &peers).await
+        }
+        _ => down(ctx).await,
+    }
+}
+
+#[command(subcommands(status, enable, disable, peer))]
+pub fn wireguard() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn status(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<WireguardStatus, Error> {
+    let server = load_server(&ctx.secret_store).await?;
+    let peers = load_peers(&ctx.secret_store).await?.len();
+    Ok(match server {
+        Some(server) => WireguardStatus {
+            enabled: server.enabled,
+            port: server.port,
+            endpoint: server.endpoint,
+            public_key: Some(public_key(&server.private_key).await?),
+            peers,
+        },
+        None => WireguardStatus {
+            enabled: false,
+            port: DEFAULT_PORT,
+            endpoint: None,
+            public_key: None,
+            peers,
+        },
+    })
+}
+
+/// Starts the WireGuard server, generating its key the first time. The port must be forwarded to
+/// the box for clients outside the LAN to connect.
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn enable(
+    #[context] ctx: RpcContext,
+    #[arg(long = "port")] port: Option<u16>,
+    #[arg(long = "endpoint")] endpoint: Option<String>,
+) -> Result<(), Error> {
+    let existing = load_server(&ctx.secret_store).await?;
+    let private_key = existing
+        .as_ref()
+        .map(|s| s.private_key.clone())
+        .unwrap_or_else(generate_key);
+    let port = port
+        .or_else(|| existing.as_ref().map(|s| s.port))
+        .unwrap_or(DEFAULT_PORT);
+    let endpoint = endpoint.or_else(|| existing.and_then(|s| s.endpoint));
+    sqlx::query!(
+        "INSERT INTO wireguard_server (id, private_key, port, endpoint, enabled) VALUES (0, $1, $2, $3, true) ON CONFLICT (id) DO UPDATE SET port = $2, endpoint = $3, enabled = true",
+        private_key,
+        port as i32,
+        endpoint,
+    )
+    .execute(&ctx.secret_store)
+    .await?;
+    sync(&ctx).await
+}
+
+/// Stops the WireGuard server, keeping its key and peers
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn disable(#[context] ctx: RpcContext) -> Result<(), Error> {
+    sqlx::query!("UPDATE wireguard_server SET enabled = false")
+        .execute(&ctx.secret_store)
+        .await?;
+    down(&ctx).await
+}
+
+#[command(subcommands(peer_list, peer_add, peer_remove, peer_config, peer_qr))]
+pub fn peer() -> Result<(), Error> {
+    Ok(())
+}
+
+fn display_string(s: String, matches: &ArgMatches) {
+    if matches.is_present("format") {
+        return display_serializable(s, matches);
+    }
+    print!("{}", s);
+}
+
+/// Peers with their latest handshake and transfer, if the server is up
+#[command(rename = "list", display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn peer_list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<WireguardPeer>, Error> {
+    // public-key preshared-key endpoint allowed-ips latest-handshake transfer-rx transfer-tx keepalive
+    let dump = Command::new("wg")
+        .arg("show")
+        .arg(WIREGUARD_INTERFACE)
+        .arg("dump")
+        .invoke(ErrorKind::Network)
+        .await
+        .map(|out| String::from_utf8_lossy(&out).into_owned())
+        .unwrap_or_default();
+    let stats = dump
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            if fields.len() < 7 {
+                return None;
+            }
+            Some((fields[0].to_owned(), fields))
+        })
+        .map(|(key, fields)| {
+            let endpoint = Some(fields[2])
+                .filter(|e| *e != "(none)")
+                .map(|e| e.to_owned());
+            let latest_handshake = fields[4]
+                .parse::<i64>()
+                .ok()
+                .filter(|t| *t > 0)
+                .and_then(|t| NaiveDateTime::from_timestamp_opt(t, 0))
+                .map(|t| DateTime::from_utc(t, Utc));
+            let rx = fields[5].parse().unwrap_or_default();
+            let tx = fields[6].parse().unwrap_or_default();
+            (key, (endpoint, latest_handshake, rx, tx))
+        })
+        .collect::<BTreeMap<_, _>>();
+    Ok(load_peers(&ctx.secret_store)
+        .await?
+        .into_iter()
+        .map(|peer| {
+            let (endpoint, latest_handshake, transfer_rx, transfer_tx) =
+                stats.get(&peer.public_key).cloned().unwrap_or_default();
+            WireguardPeer {
+                name: peer.name,
+                ip: peer.ip,
+                public_key: peer.public_key,
+                created_at: DateTime::from_utc(peer.created_at, Utc),
+                endpoint,
+                latest_handshake,
+                transfer_rx,
+                transfer_tx,
+            }
+        })
+        .collect())
+}
+
+/// Adds a peer and returns its client config. If `--public-key` is passed the peer keeps its own
+/// private key, and the config has a placeholder for it.
+#[command(rename = "add", display(display_string))]
+#[instrument(skip_all)]
+pub async fn peer_add(
+    #[context] ctx: RpcContext,
+    #[arg] name: String,
+    #[arg(long = "public-key")] public_key: Option<String>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<String, Error> {
+    let server = load_server(&ctx.secret_store).await?.ok_or_else(|| {
+        Error::new(
+            eyre!("WireGuard server has never been enabled"),
+            ErrorKind::InvalidRequest,
+        )
+    })?;
+    endpoint(&server)?;
+    let peers = load_peers(&ctx.secret_store).await?;
+    if peers.iter().any(|p| p.name == name) {
+        return Err(Error::new(
+            eyre!("WireGuard peer {} already exists", name),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let ip = next_ip(&peers.iter().map(|p| p.ip).collect()).ok_or_else(|| {
+        Error::new(
+            eyre!("No addresses left on the WireGuard network"),
+            ErrorKind::Network,
+        )
+    })?;
+    let (public_key, private_key) = if let Some(public_key) = public_key {
+        validate_key(&public_key)?;
+        (public_key, None)
+    } else {
+        let private_key = generate_key();
+        (self::public_key(&private_key).await?, Some(private_key))
+    };
+    sqlx::query!(
+        "INSERT INTO wireguard_peers (name, ip, public_key, private_key, preshared_key, created_at) VALUES ($1, $2, $3, $4, $5, $6)",
+        name,
+        ip.to_string(),
+        public_key,
+        private_key,
+        generate_key(),
+        Utc::now().naive_utc(),
+    )
+    .execute(&ctx.secret_store)
+    .await?;
+    if server.enabled {
+        sync(&ctx).await?;
+    }
+    peer_config_impl(&ctx, &name).await
+}
+
+#[command(rename = "remove", display(display_none))]
+#[instrument(skip_all)]
+pub async fn peer_remove(#[context] ctx: RpcContext, #[arg] name: String) -> Result<(), Error> {
+    let res = sqlx::query!("DELETE FROM wireguard_peers WHERE name = $1", name)
+        .execute(&ctx.secret_store)
+        .await?;
+    if res.rows_affected() == 0 {
+        return Err(Error::new(
+            eyre!("WireGuard peer {} not found", name),
+            ErrorKind::NotFound,
+        ));
+    }
+    sync(&ctx).await
+}
+
+async fn peer_config_impl(ctx: &RpcContext, name: &str) -> Result<String, Error> {
+    let server = load_server(&ctx.secret_store).await?.ok_or_else(|| {
+        Error::new(
+            eyre!("WireGuard server has never been enabled"),
+            ErrorKind::InvalidRequest,
+        )
+    })?;
+    let endpoint = endpoint(&server)?;
+    let peer = load_peer(&ctx.secret_store, name).await?;
+    Ok(client_config(
+        peer.ip,
+        peer.private_key
+            .as_deref()
+            .unwrap_or(PRIVATE_KEY_PLACEHOLDER),
+        &peer.preshared_key,
+        &public_key(&server.private_key).await?,
+        endpoint,
+        server.port,
+    ))
+}
+
+/// Client config for a peer, to import into a WireGuard app
+#[command(rename = "config", display(display_string))]
+#[instrument(skip_all)]
+pub async fn peer_config(
+    #[context] ctx: RpcContext,
+    #[arg] name: String,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<String, Error> {
+    peer_config_impl(&ctx, &name).await
+}
+
+/// Client config for a peer as a QR code for the mobile apps, as SVG or, with `--terminal`, as
+/// text to print in a terminal
+#[command(rename = "qr", display(display_string))]
+#[instrument(skip_all)]
+pub async fn peer_qr(
+    #[context] ctx: RpcContext,
+    #[arg] name: String,
+    #[arg(long = "terminal", default)] terminal: bool,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<String, Error> {
+    let config = peer_config_impl(&ctx, &name).await?;
+    Ok(String::from_utf8(
+        invoke_with_stdin(
+            Command::new("qrencode")
+                .arg("-t")
+                .arg(if terminal { "UTF8" } else { "SVG" })
+                .arg("-o")
+                .arg("-"),
+            config.as_bytes(),
+            ErrorKind::Network,
+        )
+        .await?,
+    )?)
+}
+
+#[test]
+fn test_client_config() {
+    let config = client_config(
+        Ipv4Addr::new(10, 59, 0, 2),
+        "cGVlcg==",
+        "cHNr",
+        "c2VydmVy",
+        "embassy.example.com",
+        DEFAULT_PORT,
+    );
+    assert!(config.contains("Address = 10.59.0.2/32\n"));
+    assert!(config.contains("DNS = 10.59.0.1\n"));
+    assert!(config.contains("Endpoint = embassy.example.com:51820\n"));
+    assert!(config.contains("AllowedIPs = 10.59.0.0/24, 172.18.0.0/24\n"));
+}
+
+#[test]
+fn test_next_ip() {
+    let mut used = BTreeSet::new();
+    assert_eq!(next_ip(&used), Some(Ipv4Addr::new(10, 59, 0, 2)));
+    used.insert(Ipv4Addr::new(10, 59, 0, 2));
+    used.insert(Ipv4Addr::new(10, 59, 0, 4));
+    assert_eq!(next_ip(&used), Some(Ipv4Addr::new(10, 59, 0, 3)));
+    let used = (2..255).map(|d| Ipv4Addr::new(10, 59, 0, d)).collect();
+    assert_eq!(next_ip(&used), None);
+}
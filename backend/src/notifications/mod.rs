@@ -0,0 +1,540 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use patch_db::{DbHandle, LockType};
+use rpc_toolkit::command;
+use sqlx::PgPool;
+use tokio::sync::Mutex;
+use tracing::instrument;
+
+use crate::action::ActionId;
+use crate::backup::BackupReport;
+use crate::context::RpcContext;
+use crate::s9pk::manifest::PackageId;
+use crate::status::health_check::HealthCheckId;
+use crate::util::serde::display_serializable;
+use crate::util::{display_none, Version};
+use crate::{Error, ErrorKind, ResultExt};
+
+pub mod channel;
+pub mod smtp;
+
+#[command(subcommands(
+    list,
+    delete,
+    delete_before,
+    create,
+    mark_read,
+    mark_unread,
+    ack,
+    dismiss,
+    channel::channel
+))]
+pub async fn notification() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Notifications that were dismissed are left out unless `--dismissed` is passed. The ones that are
+/// returned are marked as read.
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[arg] before: Option<i32>,
+    #[arg] limit: Option<u32>,
+    #[arg(long = "package")] package: Option<PackageId>,
+    #[arg(long = "level")] level: Option<NotificationLevel>,
+    #[arg(long = "code")] code: Option<i32>,
+    #[arg(long = "read")] read: Option<bool>,
+    #[arg(long = "dismissed")] dismissed: bool,
+) -> Result<Vec<Notification>, Error> {
+    let limit = limit.unwrap_or(40);
+    let mut handle = ctx.db.handle();
+    let model = crate::db::DatabaseModel::new()
+        .server_info()
+        .unread_notification_count();
+    model.lock(&mut handle, LockType::Write).await?;
+    let records = sqlx::query!(
+        "SELECT id, package_id, created_at, code, level, title, message, data, read_at, acked_at, dismissed_at, action_package_id, action_id FROM notifications WHERE ($1::integer IS NULL OR id < $1) AND ($2::text IS NULL OR package_id = $2) AND ($3::text IS NULL OR level = $3) AND ($4::integer IS NULL OR code = $4) AND ($5::boolean IS NULL OR (read_at IS NOT NULL) = $5) AND ($6 OR dismissed_at IS NULL) ORDER BY id DESC LIMIT $7",
+        before,
+        package.as_ref().map(|p| &**p),
+        level.as_ref().map(|l| l.to_string()),
+        code,
+        read,
+        dismissed,
+        limit as i64
+    )
+    .fetch_all(&ctx.secret_store)
+    .await?;
+    let notifs = records
+        .into_iter()
+        .map(|r| {
+            Ok(Notification {
+                id: r.id as u32,
+                package_id: r.package_id.and_then(|p| p.parse().ok()),
+                created_at: DateTime::from_utc(r.created_at, Utc),
+                code: r.code as u32,
+                level: match r.level.parse::<NotificationLevel>() {
+                    Ok(a) => a,
+                    Err(e) => return Err(e.into()),
+                },
+                title: r.title,
+                message: r.message,
+                data: match r.data {
+                    None => serde_json::Value::Null,
+                    Some(v) => match v.parse::<serde_json::Value>() {
+                        Ok(a) => a,
+                        Err(e) => {
+                            return Err(Error::new(
+                                eyre!("Invalid Notification Data: {}", e),
+                                ErrorKind::ParseDbField,
+                            ))
+                        }
+                    },
+                },
+                read_at: r.read_at.map(|t| DateTime::from_utc(t, Utc)),
+                acked_at: r.acked_at.map(|t| DateTime::from_utc(t, Utc)),
+                dismissed_at: r.dismissed_at.map(|t| DateTime::from_utc(t, Utc)),
+                action: match (r.action_package_id, r.action_id) {
+                    (Some(package_id), Some(action_id)) => Some(NotificationAction {
+                        package_id: package_id.parse()?,
+                        action_id: action_id.parse()?,
+                    }),
+                    _ => None,
+                },
+            })
+        })
+        .collect::<Result<Vec<Notification>, Error>>()?;
+    let unread = notifs
+        .iter()
+        .filter(|n| n.read_at.is_none())
+        .map(|n| n.id as i32)
+        .collect::<Vec<_>>();
+    if !unread.is_empty() {
+        sqlx::query!(
+            "UPDATE notifications SET read_at = CURRENT_TIMESTAMP WHERE id = ANY($1) AND read_at IS NULL",
+            &unread
+        )
+        .execute(&ctx.secret_store)
+        .await?;
+    }
+    model
+        .put(&mut handle, &unread_count(&ctx.secret_store).await?)
+        .await?;
+    Ok(notifs)
+}
+
+async fn unread_count(secrets: &PgPool) -> Result<u64, Error> {
+    Ok(sqlx::query!(
+        "SELECT count(*) AS \"count!\" FROM notifications WHERE read_at IS NULL AND dismissed_at IS NULL"
+    )
+    .fetch_one(secrets)
+    .await?
+    .count as u64)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NotificationState {
+    Read,
+    Unread,
+    Acked,
+    Dismissed,
+}
+
+#[instrument(skip_all)]
+async fn set_state(ctx: RpcContext, ids: Vec<i32>, state: NotificationState) -> Result<(), Error> {
+    let mut handle = ctx.db.handle();
+    let model = crate::db::DatabaseModel::new()
+        .server_info()
+        .unread_notification_count();
+    model.lock(&mut handle, LockType::Write).await?;
+    match state {
+        NotificationState::Read => sqlx::query!(
+            "UPDATE notifications SET read_at = COALESCE(read_at, CURRENT_TIMESTAMP) WHERE id = ANY($1)",
+            &ids
+        )
+        .execute(&ctx.secret_store)
+        .await?,
+        NotificationState::Unread => sqlx::query!(
+            "UPDATE notifications SET read_at = NULL WHERE id = ANY($1)",
+            &ids
+        )
+        .execute(&ctx.secret_store)
+        .await?,
+        NotificationState::Acked => sqlx::query!(
+            "UPDATE notifications SET read_at = COALESCE(read_at, CURRENT_TIMESTAMP), acked_at = COALESCE(acked_at, CURRENT_TIMESTAMP) WHERE id = ANY($1)",
+            &ids
+        )
+        .execute(&ctx.secret_store)
+        .await?,
+        NotificationState::Dismissed => sqlx::query!(
+            "UPDATE notifications SET dismissed_at = COALESCE(dismissed_at, CURRENT_TIMESTAMP) WHERE id = ANY($1)",
+            &ids
+        )
+        .execute(&ctx.secret_store)
+        .await?,
+    };
+    model
+        .put(&mut handle, &unread_count(&ctx.secret_store).await?)
+        .await?;
+    Ok(())
+}
+
+fn parse_ids(arg: &str, _: &ArgMatches) -> Result<Vec<i32>, Error> {
+    arg.split(',')
+        .map(|s| s.trim().parse().map_err(Error::from))
+        .collect()
+}
+
+#[command(rename = "mark-read", display(display_none))]
+pub async fn mark_read(
+    #[context] ctx: RpcContext,
+    #[arg(parse(parse_ids))] ids: Vec<i32>,
+) -> Result<(), Error> {
+    set_state(ctx, ids, NotificationState::Read).await
+}
+
+#[command(rename = "mark-unread", display(display_none))]
+pub async fn mark_unread(
+    #[context] ctx: RpcContext,
+    #[arg(parse(parse_ids))] ids: Vec<i32>,
+) -> Result<(), Error> {
+    set_state(ctx, ids, NotificationState::Unread).await
+}
+
+/// Acknowledging a notification also marks it as read
+#[command(display(display_none))]
+pub async fn ack(
+    #[context] ctx: RpcContext,
+    #[arg(parse(parse_ids))] ids: Vec<i32>,
+) -> Result<(), Error> {
+    set_state(ctx, ids, NotificationState::Acked).await
+}
+
+/// Hides notifications from `list` without deleting them
+#[command(display(display_none))]
+pub async fn dismiss(
+    #[context] ctx: RpcContext,
+    #[arg(parse(parse_ids))] ids: Vec<i32>,
+) -> Result<(), Error> {
+    set_state(ctx, ids, NotificationState::Dismissed).await
+}
+
+#[command(display(display_none))]
+pub async fn delete(#[context] ctx: RpcContext, #[arg] id: i32) -> Result<(), Error> {
+    sqlx::query!("DELETE FROM notifications WHERE id = $1", id)
+        .execute(&ctx.secret_store)
+        .await?;
+    Ok(())
+}
+
+#[command(rename = "delete-before", display(display_none))]
+pub async fn delete_before(#[context] ctx: RpcContext, #[arg] before: i32) -> Result<(), Error> {
+    sqlx::query!("DELETE FROM notifications WHERE id < $1", before)
+        .execute(&ctx.secret_store)
+        .await?;
+    Ok(())
+}
+
+#[command(display(display_none))]
+pub async fn create(
+    #[context] ctx: RpcContext,
+    #[arg] package: Option<PackageId>,
+    #[arg] level: NotificationLevel,
+    #[arg] title: String,
+    #[arg] message: String,
+    #[arg(rename = "action-id", long = "action-id")] action_id: Option<ActionId>,
+) -> Result<(), Error> {
+    let action = match (&package, action_id) {
+        (Some(package_id), Some(action_id)) => Some(NotificationAction {
+            package_id: package_id.clone(),
+            action_id,
+        }),
+        (None, Some(_)) => {
+            return Err(Error::new(
+                eyre!("An action requires a package"),
+                ErrorKind::InvalidRequest,
+            ))
+        }
+        (_, None) => None,
+    };
+    ctx.notification_manager
+        .notify_with_action(
+            &mut ctx.db.handle(),
+            package,
+            level,
+            title,
+            message,
+            (),
+            None,
+            action,
+        )
+        .await
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotificationLevel {
+    Success,
+    Info,
+    Warning,
+    Error,
+}
+impl fmt::Display for NotificationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NotificationLevel::Success => write!(f, "success"),
+            NotificationLevel::Info => write!(f, "info"),
+            NotificationLevel::Warning => write!(f, "warning"),
+            NotificationLevel::Error => write!(f, "error"),
+        }
+    }
+}
+pub struct InvalidNotificationLevel(String);
+impl From<InvalidNotificationLevel> for crate::Error {
+    fn from(val: InvalidNotificationLevel) -> Self {
+        Error::new(
+            eyre!("Invalid Notification Level: {}", val.0),
+            ErrorKind::ParseDbField,
+        )
+    }
+}
+impl FromStr for NotificationLevel {
+    type Err = InvalidNotificationLevel;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            s if s == "success" => Ok(NotificationLevel::Success),
+            s if s == "info" => Ok(NotificationLevel::Info),
+            s if s == "warning" => Ok(NotificationLevel::Warning),
+            s if s == "error" => Ok(NotificationLevel::Error),
+            s => Err(InvalidNotificationLevel(s.to_string())),
+        }
+    }
+}
+impl fmt::Display for InvalidNotificationLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid Notification Level: {}", self.0)
+    }
+}
+/// An action of a package the UI offers to run from the notification
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NotificationAction {
+    pub package_id: PackageId,
+    pub action_id: ActionId,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Notification {
+    id: u32,
+    package_id: Option<PackageId>, // TODO change for package id newtype
+    created_at: DateTime<Utc>,
+    code: u32,
+    level: NotificationLevel,
+    title: String,
+    message: String,
+    data: serde_json::Value,
+    read_at: Option<DateTime<Utc>>,
+    acked_at: Option<DateTime<Utc>>,
+    dismissed_at: Option<DateTime<Utc>>,
+    action: Option<NotificationAction>,
+}
+
+pub trait NotificationType:
+    serde::Serialize + for<'de> serde::Deserialize<'de> + std::fmt::Debug
+{
+    const CODE: i32;
+}
+
+impl NotificationType for () {
+    const CODE: i32 = 0;
+}
+impl NotificationType for BackupReport {
+    const CODE: i32 = 1;
+}
+
+/// A newer version of a package, or of embassyOS if the notification has no package
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct UpdateAvailable {
+    pub version: Version,
+}
+impl NotificationType for UpdateAvailable {
+    const CODE: i32 = 2;
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthDegraded {
+    pub check: HealthCheckId,
+    pub error: String,
+}
+impl NotificationType for HealthDegraded {
+    const CODE: i32 = 3;
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DiskNearlyFull {
+    pub used_percentage: f64,
+    pub available_gb: f64,
+}
+impl NotificationType for DiskNearlyFull {
+    const CODE: i32 = 4;
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CertificateExpiring {
+    pub hostname: String,
+    pub expires_at: DateTime<Utc>,
+}
+impl NotificationType for CertificateExpiring {
+    const CODE: i32 = 5;
+}
+
+pub struct NotificationManager {
+    sqlite: PgPool,
+    tor_socks: SocketAddr,
+    cache: Mutex<HashMap<(Option<PackageId>, NotificationLevel, String), i64>>,
+}
+impl NotificationManager {
+    pub fn new(sqlite: PgPool, tor_socks: SocketAddr) -> Self {
+        NotificationManager {
+            sqlite,
+            tor_socks,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+    #[instrument(skip_all)]
+    pub async fn notify<Db: DbHandle, T: NotificationType>(
+        &self,
+        db: &mut Db,
+        package_id: Option<PackageId>,
+        level: NotificationLevel,
+        title: String,
+        message: String,
+        subtype: T,
+        debounce_interval: Option<u32>,
+    ) -> Result<(), Error> {
+        self.notify_with_action(
+            db,
+            package_id,
+            level,
+            title,
+            message,
+            subtype,
+            debounce_interval,
+            None,
+        )
+        .await
+    }
+    #[instrument(skip_all)]
+    pub async fn notify_with_action<Db: DbHandle, T: NotificationType>(
+        &self,
+        db: &mut Db,
+        package_id: Option<PackageId>,
+        level: NotificationLevel,
+        title: String,
+        message: String,
+        subtype: T,
+        debounce_interval: Option<u32>,
+        action: Option<NotificationAction>,
+    ) -> Result<(), Error> {
+        if !self
+            .should_notify(&package_id, &level, &title, debounce_interval)
+            .await
+        {
+            return Ok(());
+        }
+        let mut count = crate::db::DatabaseModel::new()
+            .server_info()
+            .unread_notification_count()
+            .get_mut(db)
+            .await?;
+        let sql_package_id = package_id.as_ref().map(|p| &**p);
+        let sql_code = T::CODE;
+        let sql_level = format!("{}", level);
+        let sql_data =
+            serde_json::to_string(&subtype).with_kind(crate::ErrorKind::Serialization)?;
+        let record = sqlx::query!(
+        "INSERT INTO notifications (package_id, code, level, title, message, data, action_package_id, action_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id, created_at",
+        sql_package_id,
+        sql_code as i32,
+        sql_level,
+        title,
+        message,
+        sql_data,
+        action.as_ref().map(|a| &*a.package_id),
+        action.as_ref().map(|a| a.action_id.to_string()),
+    ).fetch_one(&self.sqlite).await?;
+        *count += 1;
+        count.save(db).await?;
+        channel::dispatch(
+            self.sqlite.clone(),
+            self.tor_socks,
+            Notification {
+                id: record.id as u32,
+                package_id,
+                created_at: DateTime::from_utc(record.created_at, Utc),
+                code: T::CODE as u32,
+                level,
+                title,
+                message,
+                data: serde_json::to_value(&subtype).with_kind(crate::ErrorKind::Serialization)?,
+                read_at: None,
+                acked_at: None,
+                dismissed_at: None,
+                action,
+            },
+        );
+        Ok(())
+    }
+    async fn should_notify(
+        &self,
+        package_id: &Option<PackageId>,
+        level: &NotificationLevel,
+        title: &String,
+        debounce_interval: Option<u32>,
+    ) -> bool {
+        let mut guard = self.cache.lock().await;
+        let k = (package_id.clone(), level.clone(), title.clone());
+        let v = (*guard).get(&k);
+        match v {
+            None => {
+                (*guard).insert(k, Utc::now().timestamp());
+                true
+            }
+            Some(last_issued) => match debounce_interval {
+                None => {
+                    (*guard).insert(k, Utc::now().timestamp());
+                    true
+                }
+                Some(interval) => {
+                    if last_issued + interval as i64 > Utc::now().timestamp() {
+                        false
+                    } else {
+                        (*guard).insert(k, Utc::now().timestamp());
+                        true
+                    }
+                }
+            },
+        }
+    }
+}
+
+#[test]
+fn serialization() {
+    println!(
+        "{}",
+        serde_json::json!({ "test": "abcdefg", "num": 32, "nested": { "inner": null, "xyz": [0,2,4]}})
+    )
+}
@@ -0,0 +1,305 @@
+use std::net::SocketAddr;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::instrument;
+
+use crate::util::net::{connect, tls};
+use crate::{Error, ErrorKind};
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SmtpSecurity {
+    None,
+    Starttls,
+    Tls,
+}
+impl SmtpSecurity {
+    fn default_port(&self) -> u16 {
+        match self {
+            SmtpSecurity::None => 25,
+            SmtpSecurity::Starttls => 587,
+            SmtpSecurity::Tls => 465,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+struct SmtpConn<S>(BufReader<S>);
+impl<S: AsyncRead + AsyncWrite + Unpin> SmtpConn<S> {
+    fn new(stream: S) -> Self {
+        SmtpConn(BufReader::new(stream))
+    }
+
+    fn into_inner(self) -> S {
+        self.0.into_inner()
+    }
+
+    /// Reads a possibly multiline reply and fails unless it has the `expected` code
+    async fn expect(&mut self, expected: u16) -> Result<String, Error> {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            if self.0.read_line(&mut line).await? == 0 {
+                return Err(Error::new(
+                    eyre!("SMTP server closed the connection"),
+                    ErrorKind::Network,
+                ));
+            }
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse::<u16>().ok())
+                .ok_or_else(|| {
+                    Error::new(eyre!("Invalid SMTP reply: {}", line), ErrorKind::Network)
+                })?;
+            reply.push_str(line.get(4..).unwrap_or_default());
+            reply.push('\n');
+            if line.as_bytes().get(3) != Some(&b'-') {
+                if code != expected {
+                    return Err(Error::new(
+                        eyre!("SMTP server replied {}: {}", code, reply.trim_end()),
+                        ErrorKind::Network,
+                    ));
+                }
+                return Ok(reply);
+            }
+        }
+    }
+
+    async fn command(&mut self, command: &str, expected: u16) -> Result<String, Error> {
+        self.0
+            .get_mut()
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        self.0.get_mut().flush().await?;
+        self.expect(expected).await
+    }
+
+    async fn deliver(mut self, config: &SmtpConfig, message: &str) -> Result<(), Error> {
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            self.command(
+                &format!(
+                    "AUTH PLAIN {}",
+                    base64::encode(format!("\0{}\0{}", username, password))
+                ),
+                235,
+            )
+            .await?;
+        }
+        self.command(&format!("MAIL FROM:<{}>", config.from), 250)
+            .await?;
+        for to in &config.to {
+            self.command(&format!("RCPT TO:<{}>", to), 250).await?;
+        }
+        self.command("DATA", 354).await?;
+        let mut data = String::with_capacity(message.len() + 5);
+        for line in message.lines() {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push_str(".\r\n");
+        self.0.get_mut().write_all(data.as_bytes()).await?;
+        self.0.get_mut().flush().await?;
+        self.expect(250).await?;
+        if let Err(e) = self.command("QUIT", 221).await {
+            tracing::debug!("SMTP QUIT failed: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Encodes a header value as RFC 2047 if it is not plain ASCII
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_owned()
+    } else {
+        format!("=?utf-8?b?{}?=", base64::encode(value))
+    }
+}
+
+/// Addresses go into both the envelope and the headers verbatim, so a line break would let them
+/// inject commands or headers
+fn check_address(address: &str) -> Result<&str, Error> {
+    if address.contains(['\r', '\n']) {
+        return Err(Error::new(
+            eyre!("Invalid email address: {:?}", address),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    Ok(address)
+}
+
+fn message(config: &SmtpConfig, subject: &str, body: &str) -> Result<String, Error> {
+    let from = check_address(&config.from)?;
+    let to = config
+        .to
+        .iter()
+        .map(|to| check_address(to))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(format!(
+        "Date: {}\r\nFrom: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n",
+        chrono::Utc::now().to_rfc2822(),
+        from,
+        to.join(", "),
+        encode_header(&subject.replace(['\r', '\n'], " ")),
+        body,
+    ))
+}
+
+#[instrument(skip_all)]
+pub async fn send(
+    config: &SmtpConfig,
+    proxy: Option<SocketAddr>,
+    subject: &str,
+    body: &str,
+) -> Result<(), Error> {
+    if config.to.is_empty() {
+        return Err(Error::new(
+            eyre!("No recipients configured"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    if config.security == SmtpSecurity::None
+        && (config.username.is_some() || config.password.is_some())
+    {
+        return Err(Error::new(
+            eyre!("Refusing to send SMTP credentials over an unencrypted connection"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let message = message(config, subject, body)?;
+    let port = config
+        .port
+        .unwrap_or_else(|| config.security.default_port());
+    let stream = connect(&config.host, port, proxy).await?;
+    let ehlo = "EHLO embassy";
+    match config.security {
+        SmtpSecurity::None => {
+            let mut conn = SmtpConn::new(stream);
+            conn.expect(220).await?;
+            conn.command(ehlo, 250).await?;
+            conn.deliver(config, &message).await
+        }
+        SmtpSecurity::Starttls => {
+            let mut conn = SmtpConn::new(stream);
+            conn.expect(220).await?;
+            conn.command(ehlo, 250).await?;
+            conn.command("STARTTLS", 220).await?;
+            let mut conn = SmtpConn::new(tls(&config.host, conn.into_inner()).await?);
+            conn.command(ehlo, 250).await?;
+            conn.deliver(config, &message).await
+        }
+        SmtpSecurity::Tls => {
+            let mut conn = SmtpConn::new(tls(&config.host, stream).await?);
+            conn.expect(220).await?;
+            conn.command(ehlo, 250).await?;
+            conn.deliver(config, &message).await
+        }
+    }
+}
+
+#[test]
+fn test_message_headers() {
+    let mut config = SmtpConfig {
+        host: "localhost".into(),
+        port: None,
+        security: SmtpSecurity::None,
+        username: None,
+        password: None,
+        from: "embassy@example.com".into(),
+        to: vec!["me@example.com".into()],
+    };
+    let sent = message(&config, "Backup\r\nBcc: evil@example.com", "body").unwrap();
+    assert!(sent.contains("Subject: Backup  Bcc: evil@example.com\r\n"));
+    assert!(!sent.contains("\r\nBcc:"));
+    config
+        .to
+        .push("you@example.com\r\nBcc: evil@example.com".into());
+    assert!(message(&config, "Backup", "body").is_err());
+}
+
+#[tokio::test]
+async fn test_send() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut received = Vec::new();
+        stream
+            .get_mut()
+            .write_all(b"220 localhost ESMTP\r\n")
+            .await
+            .unwrap();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                break;
+            }
+            let line = line.trim_end().to_owned();
+            let reply: &[u8] = if in_data {
+                if line == "." {
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else {
+                    received.push(line);
+                    continue;
+                }
+            } else if line.starts_with("EHLO") {
+                b"250-localhost\r\n250 AUTH PLAIN\r\n"
+            } else if line.starts_with("AUTH PLAIN") {
+                received.push(line);
+                b"235 ok\r\n"
+            } else if line == "DATA" {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line == "QUIT" {
+                stream.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            stream.get_mut().write_all(reply).await.unwrap();
+        }
+        received
+    });
+    let mut config = SmtpConfig {
+        host: "127.0.0.1".to_owned(),
+        port: Some(port),
+        security: SmtpSecurity::None,
+        username: Some("user".to_owned()),
+        password: Some("pass".to_owned()),
+        from: "embassy@example.com".to_owned(),
+        to: vec!["me@example.com".to_owned()],
+    };
+    assert!(send(&config, None, "Backup Failed", "body").await.is_err());
+    config.username = None;
+    config.password = None;
+    send(&config, None, "Backup Failed", "first\n.second")
+        .await
+        .unwrap();
+    let received = server.await.unwrap();
+    assert!(!received.iter().any(|line| line.starts_with("AUTH")));
+    assert!(received.contains(&"Subject: Backup Failed".to_owned()));
+    assert!(received.contains(&"..second".to_owned()));
+}
@@ -1,7 +1,7 @@
 use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::ffi::{OsStr, OsString};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
@@ -26,11 +26,12 @@ use tracing::instrument;
 
 use super::ProcedureName;
 use crate::context::RpcContext;
+use crate::resources::ResourceLimits;
 use crate::s9pk::manifest::{PackageId, SYSTEM_PACKAGE_ID};
 use crate::util::serde::{Duration as SerdeDuration, IoFormat};
 use crate::util::Version;
 use crate::volume::{VolumeId, Volumes};
-use crate::{Error, ResultExt, HOST_IP};
+use crate::{Error, ResultExt, HOST_IP, HOST_IPV6};
 
 pub const NET_TLD: &str = "embassy";
 
@@ -68,6 +69,9 @@ pub struct DockerContainer {
     pub sigterm_timeout: Option<SerdeDuration>,
     #[serde(default)]
     pub system: bool,
+    /// Defaults that the owner can override with `package resources set`
+    #[serde(default)]
+    pub resources: ResourceLimits,
 }
 
 impl DockerContainer {
@@ -154,6 +158,10 @@ pub struct DockerProcedure {
     pub sigterm_timeout: Option<SerdeDuration>,
     #[serde(default)]
     pub shm_size_mb: Option<usize>, // TODO: use postfix sizing? like 1k vs 1m vs 1g
+    /// Defaults that the owner can override with `package resources set`, if this is the main
+    /// procedure
+    #[serde(default)]
+    pub resources: ResourceLimits,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, Default)]
@@ -184,6 +192,7 @@ impl DockerProcedure {
             io_format: injectable.io_format,
             sigterm_timeout: injectable.sigterm_timeout,
             shm_size_mb: container.shm_size_mb,
+            resources: container.resources.clone(),
         }
     }
 
@@ -209,6 +218,7 @@ impl DockerProcedure {
         if expected_io && self.io_format.is_none() {
             color_eyre::eyre::bail!("expected io-format");
         }
+        self.resources.validate()?;
         Ok(())
     }
 
@@ -223,6 +233,15 @@ impl DockerProcedure {
         input: Option<I>,
         timeout: Option<Duration>,
     ) -> Result<Result<O, (i32, String)>, Error> {
+        // only the main procedure runs outside of a db transaction, so that is the only one that
+        // can safely look up the owner's overrides
+        let resources = if matches!(name, ProcedureName::Main) {
+            crate::resources::overrides(&mut ctx.db.handle(), pkg_id)
+                .await?
+                .or(&self.resources)
+        } else {
+            self.resources.clone()
+        };
         let name = name.docker_name();
         let name: Option<&str> = name.as_ref().map(|x| &**x);
         let mut cmd = tokio::process::Command::new("docker");
@@ -231,6 +250,7 @@ impl DockerProcedure {
             .arg("--rm")
             .arg("--network=start9")
             .arg(format!("--add-host=embassy:{}", Ipv4Addr::from(HOST_IP)))
+            .arg(format!("--add-host=embassy:{}", Ipv6Addr::from(HOST_IPV6)))
             .arg("--name")
             .arg(&container_name)
             .arg(format!("--hostname={}", &container_name))
@@ -255,7 +275,10 @@ impl DockerProcedure {
             }) => Ok(()),
             Err(e) => Err(e),
         }?;
-        cmd.args(self.docker_args(ctx, pkg_id, pkg_version, volumes).await?);
+        cmd.args(
+            self.docker_args(ctx, pkg_id, pkg_version, volumes, &resources)
+                .await?,
+        );
         let input_buf = if let (Some(input), Some(format)) = (&input, &self.io_format) {
             cmd.stdin(std::process::Stdio::piped());
             Some(format.to_vec(input)?)
@@ -558,9 +581,16 @@ impl DockerProcedure {
     ) -> Result<Result<O, (i32, String)>, Error> {
         let mut cmd = tokio::process::Command::new("docker");
         cmd.arg("run").arg("--rm").arg("--network=none");
+        // sandboxed procedures are never main, so the owner's overrides do not apply
         cmd.args(
-            self.docker_args(ctx, pkg_id, pkg_version, &volumes.to_readonly())
-                .await?,
+            self.docker_args(
+                ctx,
+                pkg_id,
+                pkg_version,
+                &volumes.to_readonly(),
+                &self.resources,
+            )
+            .await?,
         );
         let input_buf = if let (Some(input), Some(format)) = (&input, &self.io_format) {
             cmd.stdin(std::process::Stdio::piped());
@@ -682,6 +712,7 @@ impl DockerProcedure {
         pkg_id: &PackageId,
         pkg_version: &Version,
         volumes: &Volumes,
+        resources: &ResourceLimits,
     ) -> Result<Vec<Cow<'_, OsStr>>, Error> {
         let mut res = self.new_docker_args();
         for (volume_id, dst) in &self.mounts {
@@ -709,6 +740,13 @@ impl DockerProcedure {
             res.push(OsStr::new("--shm-size").into());
             res.push(OsString::from(format!("{}m", shm_size_mb)).into());
         }
+        let data_drive = data_drive(ctx, resources).await;
+        res.extend(
+            resources
+                .docker_args(data_drive.as_deref())
+                .into_iter()
+                .map(|arg| OsString::from(arg).into()),
+        );
         res.push(OsStr::new("--interactive").into());
         res.push(OsStr::new("--log-driver=journald").into());
         res.push(OsStr::new("--entrypoint").into());
@@ -814,6 +852,7 @@ impl LongRunning {
         cmd.arg("run")
             .arg("--network=start9")
             .arg(format!("--add-host=embassy:{}", Ipv4Addr::from(HOST_IP)))
+            .arg(format!("--add-host=embassy:{}", Ipv6Addr::from(HOST_IPV6)))
             .arg("--mount")
             .arg(format!(
                 "type=bind,src={BIND_LOCATION},dst=/start9/bin/,readonly"
@@ -852,6 +891,10 @@ impl LongRunning {
         if let Some(shm_size_mb) = docker.shm_size_mb {
             cmd.arg("--shm-size").arg(format!("{}m", shm_size_mb));
         }
+        let resources = crate::resources::overrides(&mut ctx.db.handle(), pkg_id)
+            .await?
+            .or(&docker.resources);
+        cmd.args(resources.docker_args(data_drive(ctx, &resources).await.as_deref()));
         cmd.arg("--log-driver=journald");
         if docker.system {
             cmd.arg(docker.image.for_package(&*SYSTEM_PACKAGE_ID, None));
@@ -889,6 +932,21 @@ impl LongRunning {
         }
     }
 }
+/// The disk the data volumes live on, if `resources` has caps that apply to it. The caps are
+/// dropped with a warning if it cannot be found.
+async fn data_drive(ctx: &RpcContext, resources: &ResourceLimits) -> Option<PathBuf> {
+    if !resources.has_disk_caps() {
+        return None;
+    }
+    match crate::disk::util::get_block_device(&ctx.datadir).await {
+        Ok(drive) => Some(drive),
+        Err(e) => {
+            tracing::warn!("Cannot apply disk caps: {}", e);
+            tracing::debug!("{:?}", e);
+            None
+        }
+    }
+}
 async fn buf_reader_to_lines(
     reader: impl AsyncBufRead + Unpin,
     limit: impl Into<Option<usize>>,
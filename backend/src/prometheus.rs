@@ -0,0 +1,477 @@
+use std::fmt::{Display, Write};
+use std::net::IpAddr;
+
+use chrono::Utc;
+use hyper::{Body, Request, Response, StatusCode};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::db::model::PackageDataEntry;
+use crate::middleware::auth::{HasValidSession, HashSessionToken};
+use crate::net::web_server::RemoteAddr;
+use crate::status::health_check::HealthCheckResult;
+use crate::status::MainStatus;
+use crate::util::display_none;
+use crate::{Error, ErrorKind, ResultExt};
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+const MAIN_STATUSES: &[&str] = &[
+    "stopped",
+    "restarting",
+    "stopping",
+    "starting",
+    "running",
+    "backing-up",
+];
+const HEALTH_CHECK_RESULTS: &[&str] = &["success", "disabled", "starting", "loading", "failure"];
+
+/// Settings for scraping `/metrics`
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetricsExporter {
+    /// Refuse scrapes that do not come from the local network
+    pub lan_only: bool,
+    /// Hash of the bearer token scrapers can use in place of a session
+    pub token_hash: Option<String>,
+}
+
+/// Writes the OpenMetrics text format
+#[derive(Default)]
+struct OpenMetrics(String);
+impl OpenMetrics {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(self.0, "# HELP {} {}", name, help).unwrap();
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            self.0.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.0.push(',');
+                }
+                write!(self.0, "{}=\"", label).unwrap();
+                for c in value.chars() {
+                    match c {
+                        '\\' => self.0.push_str("\\\\"),
+                        '"' => self.0.push_str("\\\""),
+                        '\n' => self.0.push_str("\\n"),
+                        c => self.0.push(c),
+                    }
+                }
+                self.0.push('"');
+            }
+            self.0.push('}');
+        }
+        writeln!(self.0, " {}", value).unwrap();
+    }
+
+    /// One sample per possible state, where only the current one is 1
+    fn stateset(&mut self, name: &str, labels: &[(&str, &str)], states: &[&str], current: &str) {
+        for state in states {
+            let mut labels = labels.to_vec();
+            labels.push((name, *state));
+            self.sample(name, &labels, (*state == current) as u8);
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.0.push_str("# EOF\n");
+        self.0
+    }
+}
+
+fn main_status_name(status: &MainStatus) -> &'static str {
+    match status {
+        MainStatus::Stopped => "stopped",
+        MainStatus::Restarting => "restarting",
+        MainStatus::Stopping => "stopping",
+        MainStatus::Starting { .. } => "starting",
+        MainStatus::Running { .. } => "running",
+        MainStatus::BackingUp { .. } => "backing-up",
+    }
+}
+
+fn health_check_result_name(result: &HealthCheckResult) -> &'static str {
+    match result {
+        HealthCheckResult::Success => "success",
+        HealthCheckResult::Disabled => "disabled",
+        HealthCheckResult::Starting => "starting",
+        HealthCheckResult::Loading { .. } => "loading",
+        HealthCheckResult::Failure { .. } => "failure",
+    }
+}
+
+#[instrument(skip_all)]
+async fn render(ctx: &RpcContext) -> Result<String, Error> {
+    let mut res = OpenMetrics::default();
+
+    if let Some(metrics) = ctx.metrics_cache.read().await.clone() {
+        if let Some(temperature) = metrics.general.temperature {
+            res.family("embassy_temperature_celsius", "gauge", "CPU temperature");
+            res.sample("embassy_temperature_celsius", &[], temperature.0);
+        }
+        res.family(
+            "embassy_cpu_percent",
+            "gauge",
+            "Share of CPU time spent in each mode",
+        );
+        for (mode, value) in [
+            ("user", &metrics.cpu.user_space),
+            ("kernel", &metrics.cpu.kernel_space),
+            ("iowait", &metrics.cpu.wait),
+            ("idle", &metrics.cpu.idle),
+            ("usage", &metrics.cpu.usage),
+        ] {
+            res.sample("embassy_cpu_percent", &[("mode", mode)], value.0);
+        }
+        res.family("embassy_memory_bytes", "gauge", "Memory and swap");
+        for (kind, value) in [
+            ("total", &metrics.memory.total),
+            ("available", &metrics.memory.available),
+            ("used", &metrics.memory.used),
+            ("swap-total", &metrics.memory.swap_total),
+            ("swap-free", &metrics.memory.swap_free),
+            ("swap-used", &metrics.memory.swap_used),
+        ] {
+            res.sample(
+                "embassy_memory_bytes",
+                &[("kind", kind)],
+                (value.0 * 1024.0 * 1024.0) as u64,
+            );
+        }
+        res.family("embassy_disk_bytes", "gauge", "Space on the data drive");
+        for (kind, value) in [
+            ("size", &metrics.disk.size),
+            ("used", &metrics.disk.used),
+            ("available", &metrics.disk.available),
+        ] {
+            res.sample(
+                "embassy_disk_bytes",
+                &[("kind", kind)],
+                (value.0 * 1_000_000_000.0) as u64,
+            );
+        }
+    }
+
+    let packages = crate::db::DatabaseModel::new()
+        .package_data()
+        .get(&mut ctx.db.handle())
+        .await?
+        .into_owned();
+    let installed = packages
+        .0
+        .iter()
+        .filter_map(|(id, pde)| match pde {
+            PackageDataEntry::Installed { installed, .. }
+            | PackageDataEntry::Updating { installed, .. } => Some((id.to_string(), installed)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let now = Utc::now();
+
+    res.family(
+        "embassy_package_status",
+        "stateset",
+        "Status of the service",
+    );
+    for (id, installed) in &installed {
+        res.stateset(
+            "embassy_package_status",
+            &[("package", id.as_str())],
+            MAIN_STATUSES,
+            main_status_name(&installed.status.main),
+        );
+    }
+    res.family(
+        "embassy_package_health_check",
+        "stateset",
+        "Result of each health check of a running service",
+    );
+    for (id, installed) in &installed {
+        if let MainStatus::Running { health, .. } | MainStatus::BackingUp { health, .. } =
+            &installed.status.main
+        {
+            for (check, result) in health {
+                res.stateset(
+                    "embassy_package_health_check",
+                    &[("package", id.as_str()), ("check", &check.to_string())],
+                    HEALTH_CHECK_RESULTS,
+                    health_check_result_name(result),
+                );
+            }
+        }
+    }
+    res.family(
+        "embassy_package_backup_age_seconds",
+        "gauge",
+        "Time since the last successful backup",
+    );
+    for (id, installed) in &installed {
+        if let Some(last_backup) = installed.last_backup {
+            res.sample(
+                "embassy_package_backup_age_seconds",
+                &[("package", id.as_str())],
+                (now - last_backup).num_seconds(),
+            );
+        }
+    }
+
+    let history = ctx.metrics_history.read().await;
+    let latest = history
+        .packages
+        .iter()
+        .filter_map(|(id, series)| Some((id.to_string(), &series.latest()?.value)))
+        .collect::<Vec<_>>();
+    let containers = latest
+        .iter()
+        .filter_map(|(id, metrics)| Some((id, metrics.container.as_ref()?)))
+        .collect::<Vec<_>>();
+    res.family(
+        "embassy_package_cpu_percent",
+        "gauge",
+        "CPU usage of the service across all cores",
+    );
+    for (id, container) in &containers {
+        res.sample(
+            "embassy_package_cpu_percent",
+            &[("package", id.as_str())],
+            container.cpu_percentage,
+        );
+    }
+    res.family("embassy_package_memory_bytes", "gauge", "Memory used");
+    for (id, container) in &containers {
+        res.sample(
+            "embassy_package_memory_bytes",
+            &[("package", id.as_str())],
+            container.memory_bytes,
+        );
+    }
+    res.family(
+        "embassy_package_network_bytes",
+        "counter",
+        "Network traffic",
+    );
+    for (id, container) in &containers {
+        res.sample(
+            "embassy_package_network_bytes_total",
+            &[("package", id.as_str()), ("direction", "rx")],
+            container.net_rx_bytes,
+        );
+        res.sample(
+            "embassy_package_network_bytes_total",
+            &[("package", id.as_str()), ("direction", "tx")],
+            container.net_tx_bytes,
+        );
+    }
+    res.family("embassy_package_block_io_bytes", "counter", "Block IO");
+    for (id, container) in &containers {
+        res.sample(
+            "embassy_package_block_io_bytes_total",
+            &[("package", id.as_str()), ("direction", "read")],
+            container.block_read_bytes,
+        );
+        res.sample(
+            "embassy_package_block_io_bytes_total",
+            &[("package", id.as_str()), ("direction", "write")],
+            container.block_write_bytes,
+        );
+    }
+    res.family(
+        "embassy_package_volume_bytes",
+        "gauge",
+        "Disk used by each data volume",
+    );
+    for (id, metrics) in &latest {
+        for (volume, bytes) in &metrics.volumes {
+            res.sample(
+                "embassy_package_volume_bytes",
+                &[("package", id.as_str()), ("volume", &volume.to_string())],
+                bytes,
+            );
+        }
+    }
+
+    Ok(res.finish())
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// `remote` is the real client, as resolved by the web server for connections proxied through the
+/// TLS vhost. Anything still on loopback came in through tor, and the package subnet is not the LAN.
+fn is_lan(remote: IpAddr) -> bool {
+    let remote = match remote {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(remote),
+        ip => ip,
+    };
+    if remote.is_loopback() {
+        return false;
+    }
+    if let IpAddr::V4(ip) = remote {
+        if ip.octets()[..2] == crate::HOST_IP[..2] {
+            return false;
+        }
+    }
+    is_private(remote)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(status.canonical_reason().unwrap_or_default().into())
+        .unwrap()
+}
+
+#[instrument(skip_all)]
+pub async fn handle(ctx: &RpcContext, req: Request<Body>) -> Result<Response<Body>, Error> {
+    if req.method() != hyper::Method::GET {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let exporter = crate::db::DatabaseModel::new()
+        .server_info()
+        .metrics_exporter()
+        .get(&mut ctx.db.handle())
+        .await?
+        .into_owned();
+    let (parts, _) = req.into_parts();
+    if exporter.lan_only {
+        match parts.extensions.get::<RemoteAddr>() {
+            Some(RemoteAddr(remote)) if is_lan(remote.ip()) => (),
+            _ => return Ok(status(StatusCode::FORBIDDEN)),
+        }
+    }
+    let bearer = parts
+        .headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let authorized = match (bearer, &exporter.token_hash) {
+        (Some(token), Some(hash)) => constant_time_eq(
+            HashSessionToken::from_token(token.to_owned())
+                .hashed()
+                .as_bytes(),
+            hash.as_bytes(),
+        ),
+        _ => HasValidSession::from_request_parts(&parts, ctx)
+            .await
+            .is_ok(),
+    };
+    if !authorized {
+        return Ok(status(StatusCode::UNAUTHORIZED));
+    }
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, CONTENT_TYPE)
+        .body(render(ctx).await?.into())
+        .with_kind(ErrorKind::Network)
+}
+
+#[command(subcommands(token, revoke_token, lan_only))]
+pub fn exporter() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Creates a bearer token for `/metrics`, replacing any previous one. It is only shown once.
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn token(#[context] ctx: RpcContext) -> Result<String, Error> {
+    let token = base32::encode(
+        base32::Alphabet::RFC4648 { padding: false },
+        &rand::random::<[u8; 16]>(),
+    )
+    .to_lowercase();
+    let mut db = ctx.db.handle();
+    let mut exporter = crate::db::DatabaseModel::new()
+        .server_info()
+        .metrics_exporter()
+        .get_mut(&mut db)
+        .await?;
+    exporter.token_hash = Some(HashSessionToken::from_token(token.clone()).as_hash());
+    exporter.save(&mut db).await?;
+    Ok(token)
+}
+
+#[command(rename = "revoke-token", display(display_none))]
+#[instrument(skip_all)]
+pub async fn revoke_token(#[context] ctx: RpcContext) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let mut exporter = crate::db::DatabaseModel::new()
+        .server_info()
+        .metrics_exporter()
+        .get_mut(&mut db)
+        .await?;
+    exporter.token_hash = None;
+    exporter.save(&mut db).await
+}
+
+#[command(rename = "lan-only", display(display_none))]
+#[instrument(skip_all)]
+pub async fn lan_only(#[context] ctx: RpcContext, #[arg] enabled: bool) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let mut exporter = crate::db::DatabaseModel::new()
+        .server_info()
+        .metrics_exporter()
+        .get_mut(&mut db)
+        .await?;
+    exporter.lan_only = enabled;
+    exporter.save(&mut db).await
+}
+
+#[test]
+fn test_open_metrics() {
+    let mut res = OpenMetrics::default();
+    res.family(
+        "embassy_package_status",
+        "stateset",
+        "Status of the service",
+    );
+    res.stateset(
+        "embassy_package_status",
+        &[("package", "bit\"coind")],
+        &["stopped", "running"],
+        "running",
+    );
+    assert_eq!(
+        res.finish(),
+        "# TYPE embassy_package_status stateset\n\
+         # HELP embassy_package_status Status of the service\n\
+         embassy_package_status{package=\"bit\\\"coind\",embassy_package_status=\"stopped\"} 0\n\
+         embassy_package_status{package=\"bit\\\"coind\",embassy_package_status=\"running\"} 1\n\
+         # EOF\n"
+    );
+}
+
+#[test]
+fn test_is_lan() {
+    assert!(is_lan("192.168.1.20".parse().unwrap()));
+    assert!(is_lan("::ffff:192.168.1.20".parse().unwrap()));
+    assert!(is_lan("fe80::1".parse().unwrap()));
+    assert!(!is_lan("8.8.8.8".parse().unwrap()));
+    assert!(!is_lan("127.0.0.1".parse().unwrap()));
+    assert!(!is_lan("::1".parse().unwrap()));
+    assert!(!is_lan("172.18.0.5".parse().unwrap()));
+}
+
+#[test]
+fn test_constant_time_eq() {
+    assert!(constant_time_eq(b"abc", b"abc"));
+    assert!(!constant_time_eq(b"abc", b"abd"));
+    assert!(!constant_time_eq(b"abc", b"ab"));
+}
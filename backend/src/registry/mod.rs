@@ -0,0 +1,229 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Utc};
+use emver::VersionRange;
+use futures::TryStreamExt;
+use tokio::sync::RwLock;
+use tracing::instrument;
+
+use crate::install::MinMax;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::s9pk::reader::S9pkReader;
+use crate::util::Version;
+use crate::{Error, ResultExt};
+
+pub mod server;
+
+/// A package in the registry directory
+#[derive(Debug)]
+pub struct RegistryPackage {
+    pub path: PathBuf,
+    pub manifest: Manifest,
+    pub icon: Vec<u8>,
+    /// Architectures the docker images are built for, `None` if they are not multi-arch
+    pub arches: Option<BTreeSet<String>>,
+    pub published_at: DateTime<Utc>,
+}
+impl RegistryPackage {
+    #[instrument(skip_all)]
+    async fn load(path: PathBuf) -> Result<Self, Error> {
+        let published_at = tokio::fs::metadata(&path)
+            .await
+            .with_ctx(|_| (crate::ErrorKind::Filesystem, path.display().to_string()))?
+            .modified()
+            .with_kind(crate::ErrorKind::Filesystem)?
+            .into();
+        let mut rdr = S9pkReader::open(&path, true).await?;
+        rdr.validate().await?;
+        let manifest = rdr.manifest().await?;
+        let icon = rdr.icon().await?.to_vec().await?;
+        let arches = rdr.docker_multiarch().await?.map(|m| m.available);
+        Ok(RegistryPackage {
+            path,
+            manifest,
+            icon,
+            arches,
+            published_at,
+        })
+    }
+
+    pub fn supports_arch(&self, arch: &str) -> bool {
+        self.arches
+            .as_ref()
+            .map(|arches| arches.contains(arch))
+            .unwrap_or(true)
+    }
+
+    pub fn icon_data_url(&self) -> String {
+        format!(
+            "data:image/{};base64,{}",
+            self.manifest.assets.icon_type(),
+            base64::encode(&self.icon)
+        )
+    }
+}
+
+/// Every version of every package in a directory of s9pk files
+#[derive(Debug, Default)]
+pub struct RegistryIndex {
+    pub packages: BTreeMap<PackageId, BTreeMap<Version, RegistryPackage>>,
+}
+impl RegistryIndex {
+    /// Files that are not valid, signed s9pks are skipped with a warning, so that one bad upload
+    /// does not take down the whole registry.
+    #[instrument(skip_all)]
+    pub async fn load(paths: impl IntoIterator<Item = &Path>) -> Result<Self, Error> {
+        let mut index = RegistryIndex::default();
+        for path in paths {
+            match RegistryPackage::load(path.to_owned()).await {
+                Ok(pkg) => {
+                    index
+                        .packages
+                        .entry(pkg.manifest.id.clone())
+                        .or_default()
+                        .insert(pkg.manifest.version.clone(), pkg);
+                }
+                Err(e) => {
+                    tracing::warn!("Skipping {}: {}", path.display(), e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    pub fn get(
+        &self,
+        id: &PackageId,
+        spec: &VersionRange,
+        priority: MinMax,
+        eos_version_compat: Option<&VersionRange>,
+        arch: Option<&str>,
+    ) -> Option<&RegistryPackage> {
+        select(
+            self.packages
+                .get(id)?
+                .iter()
+                .filter(|(_, pkg)| arch.map(|arch| pkg.supports_arch(arch)).unwrap_or(true)),
+            spec,
+            priority,
+            eos_version_compat,
+            |pkg| &pkg.manifest.eos_version,
+        )
+    }
+}
+
+/// Picks the lowest or highest version that satisfies `spec` and runs on an OS in
+/// `eos_version_compat`
+fn select<'a, T>(
+    versions: impl DoubleEndedIterator<Item = (&'a Version, &'a T)>,
+    spec: &VersionRange,
+    priority: MinMax,
+    eos_version_compat: Option<&VersionRange>,
+    eos_version: impl Fn(&T) -> &Version,
+) -> Option<&'a T> {
+    let mut matches = versions.filter(|(version, pkg)| {
+        version.satisfies(spec)
+            && eos_version_compat
+                .map(|compat| eos_version(pkg).satisfies(compat))
+                .unwrap_or(true)
+    });
+    match priority {
+        MinMax::Min => matches.next(),
+        MinMax::Max => matches.next_back(),
+    }
+    .map(|(_, pkg)| pkg)
+}
+
+/// Modification time and size of every s9pk in a directory
+type DirState = BTreeMap<PathBuf, (Option<SystemTime>, u64)>;
+
+async fn dir_state(dir: &Path) -> Result<DirState, Error> {
+    let mut state = DirState::new();
+    let mut entries = tokio_stream::wrappers::ReadDirStream::new(
+        tokio::fs::read_dir(dir)
+            .await
+            .with_ctx(|_| (crate::ErrorKind::Filesystem, dir.display().to_string()))?,
+    );
+    while let Some(entry) = entries.try_next().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("s9pk") {
+            continue;
+        }
+        let metadata = tokio::fs::metadata(&path)
+            .await
+            .with_ctx(|_| (crate::ErrorKind::Filesystem, path.display().to_string()))?;
+        state.insert(path, (metadata.modified().ok(), metadata.len()));
+    }
+    Ok(state)
+}
+
+/// Reloads the index whenever an s9pk is added, removed or replaced. Files are compared by
+/// modification time and size rather than the directory's modification time, which does not
+/// change when a file is overwritten in place.
+pub struct Registry {
+    pub name: String,
+    dir: PathBuf,
+    index: RwLock<(Option<DirState>, RegistryIndex)>,
+}
+impl Registry {
+    pub fn new(name: String, dir: PathBuf) -> Self {
+        Registry {
+            name,
+            dir,
+            index: RwLock::new((None, RegistryIndex::default())),
+        }
+    }
+
+    pub async fn index(&self) -> Result<tokio::sync::RwLockReadGuard<'_, RegistryIndex>, Error> {
+        let state = dir_state(&self.dir).await?;
+        // without modification times a replaced file of the same size cannot be detected
+        let stale = |indexed: &Option<DirState>| {
+            indexed.as_ref() != Some(&state) || state.values().any(|(m, _)| m.is_none())
+        };
+        if stale(&self.index.read().await.0) {
+            let mut index = self.index.write().await;
+            if stale(&index.0) {
+                tracing::info!("Indexing {}", self.dir.display());
+                let loaded = RegistryIndex::load(state.keys().map(|p| p.as_path())).await?;
+                *index = (Some(state), loaded);
+            }
+        }
+        Ok(tokio::sync::RwLockReadGuard::map(
+            self.index.read().await,
+            |(_, index)| index,
+        ))
+    }
+}
+
+#[test]
+fn test_select() {
+    let versions: BTreeMap<Version, Version> =
+        [("0.1.0", "0.3.0"), ("0.2.0", "0.3.2"), ("0.3.0", "0.3.4")]
+            .into_iter()
+            .map(|(v, eos)| (v.parse().unwrap(), eos.parse().unwrap()))
+            .collect();
+    let select = |spec: &str, priority, compat: Option<&str>| {
+        select(
+            versions.iter(),
+            &spec.parse().unwrap(),
+            priority,
+            compat.map(|c| c.parse().unwrap()).as_ref(),
+            |eos| eos,
+        )
+        .map(|eos| eos.to_string())
+    };
+    assert_eq!(select("*", MinMax::Max, None).as_deref(), Some("0.3.4"));
+    assert_eq!(select("*", MinMax::Min, None).as_deref(), Some("0.3.0"));
+    assert_eq!(
+        select("*", MinMax::Max, Some("<0.3.3")).as_deref(),
+        Some("0.3.2")
+    );
+    assert_eq!(
+        select("<0.2.0", MinMax::Max, None).as_deref(),
+        Some("0.3.0")
+    );
+    assert_eq!(select(">0.3.0", MinMax::Max, None), None);
+}
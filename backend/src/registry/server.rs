@@ -0,0 +1,364 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use emver::VersionRange;
+use futures::FutureExt;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+
+use super::{Registry, RegistryIndex, RegistryPackage};
+use crate::install::MinMax;
+use crate::net::HttpHandler;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::s9pk::reader::S9pkReader;
+use crate::{Error, ErrorKind, ResultExt};
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct StoreInfo<'a> {
+    name: &'a str,
+    categories: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct DependencyMetadata<'a> {
+    title: &'a str,
+    icon: String,
+    hidden: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct MarketplacePackage<'a> {
+    icon: String,
+    license: String,
+    instructions: String,
+    manifest: &'a Manifest,
+    categories: Vec<String>,
+    versions: Vec<String>,
+    dependency_metadata: BTreeMap<&'a PackageId, DependencyMetadata<'a>>,
+    published_at: String,
+}
+impl<'a> MarketplacePackage<'a> {
+    fn new(index: &'a RegistryIndex, pkg: &'a RegistryPackage) -> Self {
+        let id = &pkg.manifest.id;
+        let version = &pkg.manifest.version;
+        MarketplacePackage {
+            icon: pkg.icon_data_url(),
+            license: format!("/package/v0/license/{}?spec=={}", id, version),
+            instructions: format!("/package/v0/instructions/{}?spec=={}", id, version),
+            manifest: &pkg.manifest,
+            categories: Vec::new(),
+            versions: index
+                .packages
+                .get(id)
+                .into_iter()
+                .flat_map(|versions| versions.keys())
+                .map(|v| v.to_string())
+                .collect(),
+            dependency_metadata: pkg
+                .manifest
+                .dependencies
+                .0
+                .keys()
+                .filter_map(|dep| {
+                    let dep_pkg = index.packages.get(dep)?.values().next_back()?;
+                    Some((
+                        dep,
+                        DependencyMetadata {
+                            title: &dep_pkg.manifest.title,
+                            icon: dep_pkg.icon_data_url(),
+                            hidden: false,
+                        },
+                    ))
+                })
+                .collect(),
+            published_at: pkg.published_at.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct IdVersion {
+    id: PackageId,
+    version: String,
+}
+
+/// Parses a version spec, treating a bare version as an exact match
+fn parse_spec(spec: &str) -> Result<VersionRange, Error> {
+    if let Ok(version) = spec.parse::<emver::Version>() {
+        Ok(VersionRange::Anchor(emver::EQ, version))
+    } else {
+        Ok(spec.parse()?)
+    }
+}
+
+struct Query(BTreeMap<String, String>);
+impl Query {
+    fn new(req: &Request<Body>) -> Self {
+        Query(
+            url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+                .into_owned()
+                .collect(),
+        )
+    }
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(|s| s.as_str())
+    }
+    fn spec(&self) -> Result<VersionRange, Error> {
+        self.get("spec")
+            .map(parse_spec)
+            .unwrap_or(Ok(VersionRange::Any))
+    }
+    fn priority(&self) -> Result<MinMax, Error> {
+        self.get("version-priority")
+            .map(|p| p.parse())
+            .unwrap_or(Ok(MinMax::default()))
+    }
+    fn eos_version_compat(&self) -> Result<Option<VersionRange>, Error> {
+        Ok(self
+            .get("eos-version-compat")
+            .map(|c| c.parse())
+            .transpose()?)
+    }
+    fn arch(&self) -> Option<&str> {
+        self.get("arch")
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Result<Response<Body>, Error> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_vec(value).with_kind(ErrorKind::Serialization)?,
+        ))
+        .with_kind(ErrorKind::Network)
+}
+
+fn status(status: StatusCode, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "text/plain")
+        .body(message.into())
+        .unwrap()
+}
+
+async fn handle(registry: &Registry, req: Request<Body>) -> Result<Response<Body>, Error> {
+    if req.method() != Method::GET {
+        return Ok(status(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method Not Allowed".to_owned(),
+        ));
+    }
+    let query = Query::new(&req);
+    if req.uri().path() == "/eos/v0/latest" {
+        return latest_eos(&query);
+    }
+    let index = registry.index().await?;
+    let path = match req.uri().path().strip_prefix("/package/v0/") {
+        Some(path) => path,
+        None => return Ok(status(StatusCode::NOT_FOUND, "Not Found".to_owned())),
+    };
+    match path.split_once('/') {
+        None if path == "info" => json(&StoreInfo {
+            name: &registry.name,
+            categories: Vec::new(),
+        }),
+        // there are no categories, so they do not filter anything
+        None if path == "index" => {
+            let eos_version_compat = query.eos_version_compat()?;
+            let mut pkgs: Vec<&RegistryPackage> = if let Some(ids) = query.get("ids") {
+                let ids: Vec<IdVersion> =
+                    serde_json::from_str(ids).with_kind(ErrorKind::Deserialization)?;
+                let mut pkgs = Vec::with_capacity(ids.len());
+                for IdVersion { id, version } in ids {
+                    pkgs.extend(index.get(
+                        &id,
+                        &parse_spec(&version)?,
+                        MinMax::Max,
+                        eos_version_compat.as_ref(),
+                        query.arch(),
+                    ));
+                }
+                pkgs
+            } else {
+                index
+                    .packages
+                    .keys()
+                    .filter_map(|id| {
+                        index.get(
+                            id,
+                            &VersionRange::Any,
+                            MinMax::Max,
+                            eos_version_compat.as_ref(),
+                            query.arch(),
+                        )
+                    })
+                    .collect()
+            };
+            if let Some(search) = query.get("query").map(|q| q.to_lowercase()) {
+                pkgs.retain(|pkg| {
+                    pkg.manifest.id.to_string().contains(&search)
+                        || pkg.manifest.title.to_lowercase().contains(&search)
+                        || pkg
+                            .manifest
+                            .description
+                            .short
+                            .to_lowercase()
+                            .contains(&search)
+                });
+            }
+            let per_page = query
+                .get("per-page")
+                .and_then(|p| p.parse().ok())
+                .unwrap_or(20);
+            let page: usize = query.get("page").and_then(|p| p.parse().ok()).unwrap_or(1);
+            json(
+                &pkgs
+                    .into_iter()
+                    .skip(page.saturating_sub(1) * per_page)
+                    .take(per_page)
+                    .map(|pkg| MarketplacePackage::new(&index, pkg))
+                    .collect::<Vec<_>>(),
+            )
+        }
+        None => {
+            let id = match path.strip_suffix(".s9pk") {
+                Some(id) => id,
+                None => return Ok(status(StatusCode::NOT_FOUND, "Not Found".to_owned())),
+            };
+            let pkg = match find(&index, id, &query)? {
+                Ok(pkg) => pkg,
+                Err(res) => return Ok(res),
+            };
+            let file = File::open(&pkg.path)
+                .await
+                .with_ctx(|_| (ErrorKind::Filesystem, pkg.path.display().to_string()))?;
+            let len = file.metadata().await?.len();
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(http::header::CONTENT_TYPE, "application/octet-stream")
+                .header(http::header::CONTENT_LENGTH, len)
+                .body(Body::wrap_stream(ReaderStream::new(file)))
+                .with_kind(ErrorKind::Network)
+        }
+        Some(("release-notes", id)) => {
+            let id: PackageId = id.parse()?;
+            json(
+                &index
+                    .packages
+                    .get(&id)
+                    .into_iter()
+                    .flat_map(|versions| versions.iter())
+                    .map(|(version, pkg)| (version.to_string(), &pkg.manifest.release_notes))
+                    .collect::<BTreeMap<_, _>>(),
+            )
+        }
+        Some(("manifest", id)) => match find(&index, id, &query)? {
+            Ok(pkg) => json(&pkg.manifest),
+            Err(res) => Ok(res),
+        },
+        Some(("icon", id)) => match find(&index, id, &query)? {
+            Ok(pkg) => Response::builder()
+                .status(StatusCode::OK)
+                .header(
+                    http::header::CONTENT_TYPE,
+                    format!("image/{}", pkg.manifest.assets.icon_type()),
+                )
+                .body(Body::from(pkg.icon.clone()))
+                .with_kind(ErrorKind::Network),
+            Err(res) => Ok(res),
+        },
+        Some((section @ ("license" | "instructions"), id)) => {
+            let pkg = match find(&index, id, &query)? {
+                Ok(pkg) => pkg,
+                Err(res) => return Ok(res),
+            };
+            let mut rdr = S9pkReader::open(&pkg.path, false).await?;
+            let text = if section == "license" {
+                rdr.license().await?.to_vec().await?
+            } else {
+                rdr.instructions().await?.to_vec().await?
+            };
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(http::header::CONTENT_TYPE, "text/plain")
+                .body(Body::from(text))
+                .with_kind(ErrorKind::Network)
+        }
+        Some(_) => Ok(status(StatusCode::NOT_FOUND, "Not Found".to_owned())),
+    }
+}
+
+/// The registry only serves packages, so it reports the version the client is already running
+/// as the latest, and a check for updates finds none
+fn latest_eos(query: &Query) -> Result<Response<Body>, Error> {
+    let version: emver::Version = match query.get("eos-version") {
+        Some(version) => version.parse()?,
+        None => {
+            return Ok(status(
+                StatusCode::BAD_REQUEST,
+                "Missing eos-version".to_owned(),
+            ))
+        }
+    };
+    json(&serde_json::json!({
+        "release-notes": {},
+        "headline": "This registry does not serve embassyOS updates",
+        "version": version.to_string(),
+    }))
+}
+
+/// Install treats 400 as "no such package" when looking up dependencies, so a missing package is a
+/// bad request rather than a 404
+fn find<'a>(
+    index: &'a RegistryIndex,
+    id: &str,
+    query: &Query,
+) -> Result<Result<&'a RegistryPackage, Response<Body>>, Error> {
+    let id: PackageId = id.parse()?;
+    let spec = query.spec()?;
+    Ok(index
+        .get(
+            &id,
+            &spec,
+            query.priority()?,
+            query.eos_version_compat()?.as_ref(),
+            query.arch(),
+        )
+        .ok_or_else(|| {
+            status(
+                StatusCode::BAD_REQUEST,
+                format!("No version of {} satisfies {}", id, spec),
+            )
+        }))
+}
+
+pub fn registry_router(registry: Arc<Registry>) -> HttpHandler {
+    Arc::new(move |req| {
+        let registry = registry.clone();
+        async move {
+            match handle(&registry, req).await {
+                Ok(res) => Ok(res),
+                Err(e) => {
+                    tracing::error!("{}", e);
+                    tracing::debug!("{:?}", e);
+                    Ok(status(
+                        match e.kind {
+                            ErrorKind::InvalidPackageId
+                            | ErrorKind::ParseVersion
+                            | ErrorKind::Deserialization => StatusCode::BAD_REQUEST,
+                            _ => StatusCode::INTERNAL_SERVER_ERROR,
+                        },
+                        e.to_string(),
+                    ))
+                }
+            }
+        }
+        .boxed()
+    })
+}
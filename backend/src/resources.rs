@@ -0,0 +1,269 @@
+use std::path::Path;
+
+use color_eyre::eyre::eyre;
+use patch_db::DbHandle;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::procedure::PackageProcedure;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind, ResultExt};
+
+/// cgroup limits for the containers of a service. Unset fields are left to docker.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourceLimits {
+    /// Relative weight against other services when the CPU is contended. Docker uses 1024.
+    #[serde(default)]
+    pub cpu_shares: Option<u32>,
+    /// Hard cap in number of CPUs, e.g. 1.5
+    #[serde(default)]
+    pub cpus: Option<f64>,
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    #[serde(default)]
+    pub pids_limit: Option<u32>,
+    /// Relative weight for block IO, from 10 to 1000
+    #[serde(default)]
+    pub blkio_weight: Option<u16>,
+    /// Cap on reads from the data drive, in MB/s
+    #[serde(default)]
+    pub disk_read_mbps: Option<u64>,
+    /// Cap on writes to the data drive, in MB/s
+    #[serde(default)]
+    pub disk_write_mbps: Option<u64>,
+}
+impl ResourceLimits {
+    pub fn validate(&self) -> Result<(), color_eyre::eyre::Report> {
+        if let Some(cpu_shares) = self.cpu_shares {
+            if cpu_shares < 2 {
+                color_eyre::eyre::bail!("cpu-shares must be at least 2");
+            }
+        }
+        if let Some(cpus) = self.cpus {
+            if !(cpus >= 0.01) {
+                color_eyre::eyre::bail!("cpus must be at least 0.01");
+            }
+        }
+        if let Some(memory_mb) = self.memory_mb {
+            // the smallest limit docker accepts
+            if memory_mb < 6 {
+                color_eyre::eyre::bail!("memory-mb must be at least 6");
+            }
+        }
+        if self.pids_limit == Some(0) {
+            color_eyre::eyre::bail!("pids-limit must be at least 1");
+        }
+        if let Some(blkio_weight) = self.blkio_weight {
+            if !(10..=1000).contains(&blkio_weight) {
+                color_eyre::eyre::bail!("blkio-weight must be between 10 and 1000");
+            }
+        }
+        if self.disk_read_mbps == Some(0) {
+            color_eyre::eyre::bail!("disk-read-mbps must be at least 1");
+        }
+        if self.disk_write_mbps == Some(0) {
+            color_eyre::eyre::bail!("disk-write-mbps must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Fields set in `self` take precedence over `defaults`
+    pub fn or(&self, defaults: &Self) -> Self {
+        ResourceLimits {
+            cpu_shares: self.cpu_shares.or(defaults.cpu_shares),
+            cpus: self.cpus.or(defaults.cpus),
+            memory_mb: self.memory_mb.or(defaults.memory_mb),
+            pids_limit: self.pids_limit.or(defaults.pids_limit),
+            blkio_weight: self.blkio_weight.or(defaults.blkio_weight),
+            disk_read_mbps: self.disk_read_mbps.or(defaults.disk_read_mbps),
+            disk_write_mbps: self.disk_write_mbps.or(defaults.disk_write_mbps),
+        }
+    }
+
+    pub fn has_disk_caps(&self) -> bool {
+        self.disk_read_mbps.is_some() || self.disk_write_mbps.is_some()
+    }
+
+    /// `data_drive` is the disk the disk caps apply to. They are left out if it is not known.
+    pub fn docker_args(&self, data_drive: Option<&Path>) -> Vec<String> {
+        let mut res = Vec::new();
+        if let Some(cpu_shares) = self.cpu_shares {
+            res.push(format!("--cpu-shares={}", cpu_shares));
+        }
+        if let Some(cpus) = self.cpus {
+            res.push(format!("--cpus={}", cpus));
+        }
+        if let Some(memory_mb) = self.memory_mb {
+            res.push(format!("--memory={}m", memory_mb));
+        }
+        if let Some(pids_limit) = self.pids_limit {
+            res.push(format!("--pids-limit={}", pids_limit));
+        }
+        if let Some(blkio_weight) = self.blkio_weight {
+            res.push(format!("--blkio-weight={}", blkio_weight));
+        }
+        if let Some(data_drive) = data_drive {
+            if let Some(mbps) = self.disk_read_mbps {
+                res.push(format!(
+                    "--device-read-bps={}:{}mb",
+                    data_drive.display(),
+                    mbps
+                ));
+            }
+            if let Some(mbps) = self.disk_write_mbps {
+                res.push(format!(
+                    "--device-write-bps={}:{}mb",
+                    data_drive.display(),
+                    mbps
+                ));
+            }
+        }
+        res
+    }
+}
+
+/// The limits the manifest declares for the main container
+pub fn manifest_defaults(manifest: &Manifest) -> ResourceLimits {
+    if let Some(containers) = &manifest.containers {
+        containers.main.resources.clone()
+    } else if let PackageProcedure::Docker(main) = &manifest.main {
+        main.resources.clone()
+    } else {
+        ResourceLimits::default()
+    }
+}
+
+/// The limits the owner has set for a package, which are empty if it is not installed
+#[instrument(skip_all)]
+pub async fn overrides<Db: DbHandle>(db: &mut Db, id: &PackageId) -> Result<ResourceLimits, Error> {
+    Ok(crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(id)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.resource_limits())
+        .get(db)
+        .await?
+        .into_owned()
+        .unwrap_or_default())
+}
+
+#[command(subcommands(get, set))]
+pub fn resources() -> Result<(), Error> {
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourcesRes {
+    pub defaults: ResourceLimits,
+    pub overrides: ResourceLimits,
+    pub effective: ResourceLimits,
+}
+
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn get(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<ResourcesRes, Error> {
+    let mut db = ctx.db.handle();
+    let installed = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&id)
+        .and_then(|pde| pde.installed())
+        .get(&mut db)
+        .await?
+        .into_owned()
+        .ok_or_else(|| Error::new(eyre!("{} is not installed", id), ErrorKind::NotFound))?;
+    let defaults = manifest_defaults(&installed.manifest);
+    Ok(ResourcesRes {
+        effective: installed.resource_limits.or(&defaults),
+        defaults,
+        overrides: installed.resource_limits,
+    })
+}
+
+/// Overrides the limits from the manifest. They take effect the next time the service starts.
+/// `--reset` drops every existing override before applying the new ones.
+#[command(display(display_none))]
+#[instrument(skip_all)]
+pub async fn set(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(rename = "cpu-shares", long = "cpu-shares")] cpu_shares: Option<u32>,
+    #[arg(long = "cpus")] cpus: Option<f64>,
+    #[arg(rename = "memory-mb", long = "memory-mb")] memory_mb: Option<u64>,
+    #[arg(rename = "pids-limit", long = "pids-limit")] pids_limit: Option<u32>,
+    #[arg(rename = "blkio-weight", long = "blkio-weight")] blkio_weight: Option<u16>,
+    #[arg(rename = "disk-read-mbps", long = "disk-read-mbps")] disk_read_mbps: Option<u64>,
+    #[arg(rename = "disk-write-mbps", long = "disk-write-mbps")] disk_write_mbps: Option<u64>,
+    #[arg(long = "reset")] reset: bool,
+) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let mut limits = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&id)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.resource_limits())
+        .get_mut(&mut db)
+        .await?;
+    let limits_ref = limits
+        .as_mut()
+        .ok_or_else(|| Error::new(eyre!("{} is not installed", id), ErrorKind::NotFound))?;
+    let new = ResourceLimits {
+        cpu_shares,
+        cpus,
+        memory_mb,
+        pids_limit,
+        blkio_weight,
+        disk_read_mbps,
+        disk_write_mbps,
+    };
+    new.validate().with_kind(ErrorKind::InvalidRequest)?;
+    *limits_ref = if reset { new } else { new.or(limits_ref) };
+    limits.save(&mut db).await
+}
+
+#[test]
+fn test_resource_limits() {
+    let defaults = ResourceLimits {
+        cpu_shares: Some(512),
+        memory_mb: Some(2048),
+        ..Default::default()
+    };
+    let overrides = ResourceLimits {
+        memory_mb: Some(1024),
+        pids_limit: Some(256),
+        disk_read_mbps: Some(50),
+        ..Default::default()
+    };
+    let effective = overrides.or(&defaults);
+    assert_eq!(
+        effective.docker_args(None),
+        vec!["--cpu-shares=512", "--memory=1024m", "--pids-limit=256"]
+    );
+    assert_eq!(
+        effective.docker_args(Some(Path::new("/dev/sda"))),
+        vec![
+            "--cpu-shares=512",
+            "--memory=1024m",
+            "--pids-limit=256",
+            "--device-read-bps=/dev/sda:50mb"
+        ]
+    );
+    assert!(effective.validate().is_ok());
+    assert!(ResourceLimits {
+        blkio_weight: Some(5),
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+}
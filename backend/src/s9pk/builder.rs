@@ -1,4 +1,4 @@
-use sha2_old::{Digest, Sha512};
+use sha2_old::{Digest, Sha256};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
 use tracing::instrument;
 use typed_builder::TypedBuilder;
@@ -57,80 +57,51 @@ impl<
         })?;
         let mut position = self.writer.stream_position().await?;
 
-        let mut writer = HashWriter::new(Sha512::new(), &mut self.writer);
         // manifest
+        let mut writer = HashWriter::new(Sha256::new(), &mut self.writer);
         to_cbor_async_writer(&mut writer, self.manifest).await?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.manifest = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
+        header.table_of_contents.manifest = finish_section(writer, &mut position).await?;
         // license
+        let mut writer = HashWriter::new(Sha256::new(), &mut self.writer);
         tokio::io::copy(&mut self.license, &mut writer)
             .await
             .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying License"))?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.license = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
+        header.table_of_contents.license = finish_section(writer, &mut position).await?;
         // instructions
+        let mut writer = HashWriter::new(Sha256::new(), &mut self.writer);
         tokio::io::copy(&mut self.instructions, &mut writer)
             .await
             .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Instructions"))?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.instructions = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
+        header.table_of_contents.instructions = finish_section(writer, &mut position).await?;
         // icon
+        let mut writer = HashWriter::new(Sha256::new(), &mut self.writer);
         tokio::io::copy(&mut self.icon, &mut writer)
             .await
             .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Icon"))?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.icon = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
+        header.table_of_contents.icon = finish_section(writer, &mut position).await?;
         // docker_images
+        let mut writer = HashWriter::new(Sha256::new(), &mut self.writer);
         tokio::io::copy(&mut self.docker_images, &mut writer)
             .await
             .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Docker Images"))?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.docker_images = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
+        header.table_of_contents.docker_images = finish_section(writer, &mut position).await?;
         // assets
+        let mut writer = HashWriter::new(Sha256::new(), &mut self.writer);
         tokio::io::copy(&mut self.assets, &mut writer)
             .await
             .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Assets"))?;
-        let new_pos = writer.inner_mut().stream_position().await?;
-        header.table_of_contents.assets = FileSection {
-            position,
-            length: new_pos - position,
-        };
-        position = new_pos;
+        header.table_of_contents.assets = finish_section(writer, &mut position).await?;
         // scripts
         if let Some(mut scripts) = self.scripts {
+            let mut writer = HashWriter::new(Sha256::new(), &mut self.writer);
             tokio::io::copy(&mut scripts, &mut writer)
                 .await
                 .with_ctx(|_| (crate::ErrorKind::Filesystem, "Copying Scripts"))?;
-            let new_pos = writer.inner_mut().stream_position().await?;
-            header.table_of_contents.scripts = Some(FileSection {
-                position,
-                length: new_pos - position,
-            });
-            position = new_pos;
+            header.table_of_contents.scripts = Some(finish_section(writer, &mut position).await?);
         }
 
         // header
-        let (hash, _) = writer.finish();
+        let hash = header.table_of_contents.signing_digest().await?;
         self.writer.seek(SeekFrom::Start(header_pos)).await?;
         header.pubkey = key.public.clone();
         header.signature = key.sign_prehashed(hash, Some(SIG_CONTEXT))?;
@@ -143,3 +114,19 @@ impl<
         Ok(())
     }
 }
+
+/// Records the section just written through `writer`, which started at `position`
+async fn finish_section<W: AsyncWriteExt + AsyncSeekExt + Unpin>(
+    writer: HashWriter<Sha256, &mut W>,
+    position: &mut u64,
+) -> Result<FileSection, Error> {
+    let (hash, writer) = writer.finish();
+    let new_pos = writer.stream_position().await?;
+    let section = FileSection {
+        position: *position,
+        length: new_pos - *position,
+        sha256: Some(hash.finalize().into()),
+    };
+    *position = new_pos;
+    Ok(section)
+}
@@ -18,16 +18,11 @@ pub struct DockerMultiArch {
     pub default: String,
     pub available: BTreeSet<String>,
 }
-
-#[pin_project::pin_project(project = DockerReaderProject)]
-#[derive(Debug)]
-pub enum DockerReader<R: AsyncRead + Unpin> {
-    SingleArch(#[pin] R),
-    MultiArch(#[pin] Entry<Archive<R>>),
-}
-impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> DockerReader<R> {
-    pub async fn new(mut rdr: R) -> Result<Self, Error> {
-        let arch = if let Some(multiarch) = tokio_tar::Archive::new(&mut rdr)
+impl DockerMultiArch {
+    /// Reads `multiarch.cbor` from the start of a docker images section, `None` if the section is
+    /// a single image
+    pub async fn read<R: AsyncRead + Unpin + Send + Sync>(rdr: R) -> Result<Option<Self>, Error> {
+        if let Some(multiarch) = tokio_tar::Archive::new(rdr)
             .entries()?
             .try_filter_map(|e| {
                 async move {
@@ -42,15 +37,28 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> DockerReader<R> {
             .try_next()
             .await?
         {
-            let multiarch: DockerMultiArch = from_cbor_async_reader(multiarch).await?;
-            Some(if multiarch.available.contains(&**ARCH) {
+            Ok(Some(from_cbor_async_reader(multiarch).await?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[pin_project::pin_project(project = DockerReaderProject)]
+#[derive(Debug)]
+pub enum DockerReader<R: AsyncRead + Unpin> {
+    SingleArch(#[pin] R),
+    MultiArch(#[pin] Entry<Archive<R>>),
+}
+impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> DockerReader<R> {
+    pub async fn new(mut rdr: R) -> Result<Self, Error> {
+        let arch = DockerMultiArch::read(&mut rdr).await?.map(|multiarch| {
+            if multiarch.available.contains(&**ARCH) {
                 Cow::Borrowed(&**ARCH)
             } else {
                 Cow::Owned(multiarch.default)
-            })
-        } else {
-            None
-        };
+            }
+        });
         rdr.seek(SeekFrom::Start(0)).await?;
         if let Some(arch) = arch {
             if let Some(image) = tokio_tar::Archive::new(rdr)
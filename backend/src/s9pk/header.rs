@@ -2,15 +2,21 @@ use std::collections::BTreeMap;
 
 use color_eyre::eyre::eyre;
 use ed25519_dalek::{PublicKey, Signature};
+use sha2_old::{Digest, Sha512};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
 use crate::Error;
 
 pub const MAGIC: [u8; 2] = [59, 59];
-pub const VERSION: u8 = 1;
+pub const COSIGNATURE_MAGIC: [u8; 2] = [59, 60];
+/// Version written by [`S9pkPacker`](super::builder::S9pkPacker). Version 1 signs the whole
+/// archive. Version 2 records the sha256 of every section in the table of contents, and signs the
+/// table of contents instead.
+pub const VERSION: u8 = 2;
 
 #[derive(Debug)]
 pub struct Header {
+    pub version: u8,
     pub pubkey: PublicKey,
     pub signature: Signature,
     pub table_of_contents: TableOfContents,
@@ -18,6 +24,7 @@ pub struct Header {
 impl Header {
     pub fn placeholder() -> Self {
         Header {
+            version: VERSION,
             pubkey: PublicKey::default(),
             signature: Signature::from_bytes(&[0; 64]).expect("Invalid ed25519 signature"),
             table_of_contents: Default::default(),
@@ -26,10 +33,12 @@ impl Header {
     // MUST BE SAME SIZE REGARDLESS OF DATA
     pub async fn serialize<W: AsyncWriteExt + Unpin>(&self, mut writer: W) -> std::io::Result<()> {
         writer.write_all(&MAGIC).await?;
-        writer.write_all(&[VERSION]).await?;
+        writer.write_all(&[self.version]).await?;
         writer.write_all(self.pubkey.as_bytes()).await?;
         writer.write_all(self.signature.as_ref()).await?;
-        self.table_of_contents.serialize(writer).await?;
+        self.table_of_contents
+            .serialize(self.version, writer)
+            .await?;
         Ok(())
     }
     pub async fn deserialize<R: AsyncRead + Unpin>(mut reader: R) -> Result<Self, Error> {
@@ -43,7 +52,7 @@ impl Header {
         }
         let mut version = [0];
         reader.read_exact(&mut version).await?;
-        if version[0] != VERSION {
+        if version[0] == 0 || version[0] > VERSION {
             return Err(Error::new(
                 eyre!("Unknown Version: {}", version[0]),
                 crate::ErrorKind::ParseS9pk,
@@ -56,9 +65,10 @@ impl Header {
         let mut sig_bytes = [0; 64];
         reader.read_exact(&mut sig_bytes).await?;
         let signature = Signature::from_bytes(&sig_bytes).expect("Invalid ed25519 signature");
-        let table_of_contents = TableOfContents::deserialize(reader).await?;
+        let table_of_contents = TableOfContents::deserialize(version[0], reader).await?;
 
         Ok(Header {
+            version: version[0],
             pubkey,
             signature,
             table_of_contents,
@@ -77,40 +87,79 @@ pub struct TableOfContents {
     pub scripts: Option<FileSection>,
 }
 impl TableOfContents {
-    pub async fn serialize<W: AsyncWriteExt + Unpin>(&self, mut writer: W) -> std::io::Result<()> {
-        let len: u32 = ((1 + "manifest".len() + 16)
-            + (1 + "license".len() + 16)
-            + (1 + "instructions".len() + 16)
-            + (1 + "icon".len() + 16)
-            + (1 + "docker_images".len() + 16)
-            + (1 + "assets".len() + 16)
-            + (1 + "scripts".len() + 16)) as u32;
+    pub async fn serialize<W: AsyncWriteExt + Unpin>(
+        &self,
+        version: u8,
+        mut writer: W,
+    ) -> std::io::Result<()> {
+        let entry_len = FileSection::entry_len(version);
+        let len: u32 = ((1 + "manifest".len() + entry_len)
+            + (1 + "license".len() + entry_len)
+            + (1 + "instructions".len() + entry_len)
+            + (1 + "icon".len() + entry_len)
+            + (1 + "docker_images".len() + entry_len)
+            + (1 + "assets".len() + entry_len)
+            + (1 + "scripts".len() + entry_len)) as u32;
         writer.write_all(&u32::to_be_bytes(len)).await?;
         self.manifest
-            .serialize_entry("manifest", &mut writer)
+            .serialize_entry(version, "manifest", &mut writer)
+            .await?;
+        self.license
+            .serialize_entry(version, "license", &mut writer)
             .await?;
-        self.license.serialize_entry("license", &mut writer).await?;
         self.instructions
-            .serialize_entry("instructions", &mut writer)
+            .serialize_entry(version, "instructions", &mut writer)
+            .await?;
+        self.icon
+            .serialize_entry(version, "icon", &mut writer)
             .await?;
-        self.icon.serialize_entry("icon", &mut writer).await?;
         self.docker_images
-            .serialize_entry("docker_images", &mut writer)
+            .serialize_entry(version, "docker_images", &mut writer)
+            .await?;
+        self.assets
+            .serialize_entry(version, "assets", &mut writer)
             .await?;
-        self.assets.serialize_entry("assets", &mut writer).await?;
         self.scripts
             .unwrap_or_default()
-            .serialize_entry("scripts", &mut writer)
+            .serialize_entry(version, "scripts", &mut writer)
             .await?;
         Ok(())
     }
-    pub async fn deserialize<R: AsyncRead + Unpin>(mut reader: R) -> std::io::Result<Self> {
+    /// What the developer signs in a version 2 header. It commits to the position, length, and
+    /// sha256 of every section.
+    pub async fn signing_digest(&self) -> std::io::Result<Sha512> {
+        let mut toc = Vec::new();
+        self.serialize(2, &mut toc).await?;
+        Ok(Sha512::new().chain(&toc))
+    }
+    /// The end of the last section, where co-signatures begin
+    pub fn end(&self) -> u64 {
+        [
+            self.manifest,
+            self.license,
+            self.instructions,
+            self.icon,
+            self.docker_images,
+            self.assets,
+            self.scripts.unwrap_or_default(),
+        ]
+        .into_iter()
+        .map(|s| s.position + s.length)
+        .max()
+        .unwrap_or_default()
+    }
+    pub async fn deserialize<R: AsyncRead + Unpin>(
+        version: u8,
+        mut reader: R,
+    ) -> std::io::Result<Self> {
         let mut toc_len = [0; 4];
         reader.read_exact(&mut toc_len).await?;
         let toc_len = u32::from_be_bytes(toc_len);
         let mut reader = reader.take(toc_len as u64);
         let mut table = BTreeMap::new();
-        while let Some((label, section)) = FileSection::deserialize_entry(&mut reader).await? {
+        while let Some((label, section)) =
+            FileSection::deserialize_entry(version, &mut reader).await?
+        {
             table.insert(label, section);
         }
         fn from_table(
@@ -145,14 +194,79 @@ impl TableOfContents {
     }
 }
 
+/// Signatures from keys other than the one in the header, appended after the last section of a
+/// version 2 package. Each signs the same digest as the header, so they can be added to a package
+/// without changing it.
+#[derive(Debug, Default)]
+pub struct CoSignatures(pub Vec<(PublicKey, Signature)>);
+impl CoSignatures {
+    pub async fn serialize<W: AsyncWriteExt + Unpin>(&self, mut writer: W) -> Result<(), Error> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let count = u8::try_from(self.0.len()).map_err(|_| {
+            Error::new(
+                eyre!("Too many co-signatures: {} (max 255)", self.0.len()),
+                crate::ErrorKind::InvalidRequest,
+            )
+        })?;
+        writer.write_all(&COSIGNATURE_MAGIC).await?;
+        writer.write_all(&[count]).await?;
+        for (pubkey, signature) in &self.0 {
+            writer.write_all(pubkey.as_bytes()).await?;
+            writer.write_all(signature.as_ref()).await?;
+        }
+        Ok(())
+    }
+    pub async fn deserialize<R: AsyncRead + Unpin>(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0; 2];
+        if reader.read(&mut magic[..1]).await? == 0 {
+            return Ok(Self::default());
+        }
+        reader.read_exact(&mut magic[1..]).await?;
+        if magic != COSIGNATURE_MAGIC {
+            return Err(Error::new(
+                eyre!("Incorrect Co-Signature Magic: {:?}", magic),
+                crate::ErrorKind::ParseS9pk,
+            ));
+        }
+        let mut count = [0];
+        reader.read_exact(&mut count).await?;
+        let mut res = Vec::with_capacity(count[0] as usize);
+        for _ in 0..count[0] {
+            let mut pubkey_bytes = [0; 32];
+            reader.read_exact(&mut pubkey_bytes).await?;
+            let pubkey = PublicKey::from_bytes(&pubkey_bytes)
+                .map_err(|e| Error::new(e, crate::ErrorKind::ParseS9pk))?;
+            let mut sig_bytes = [0; 64];
+            reader.read_exact(&mut sig_bytes).await?;
+            let signature = Signature::from_bytes(&sig_bytes)
+                .map_err(|e| Error::new(e, crate::ErrorKind::ParseS9pk))?;
+            res.push((pubkey, signature));
+        }
+        Ok(CoSignatures(res))
+    }
+}
+
 #[derive(Clone, Copy, Debug, Default)]
 pub struct FileSection {
     pub position: u64,
     pub length: u64,
+    /// Only present in version 2 headers
+    pub sha256: Option<[u8; 32]>,
 }
 impl FileSection {
+    /// Size of an entry in the table of contents, excluding its label
+    fn entry_len(version: u8) -> usize {
+        if version >= 2 {
+            16 + 32
+        } else {
+            16
+        }
+    }
     pub async fn serialize_entry<W: AsyncWriteExt + Unpin>(
         self,
+        version: u8,
         label: &str,
         mut writer: W,
     ) -> std::io::Result<()> {
@@ -160,9 +274,13 @@ impl FileSection {
         writer.write_all(label.as_bytes()).await?;
         writer.write_all(&u64::to_be_bytes(self.position)).await?;
         writer.write_all(&u64::to_be_bytes(self.length)).await?;
+        if version >= 2 {
+            writer.write_all(&self.sha256.unwrap_or_default()).await?;
+        }
         Ok(())
     }
     pub async fn deserialize_entry<R: AsyncRead + Unpin>(
+        version: u8,
         mut reader: R,
     ) -> std::io::Result<Option<(Vec<u8>, Self)>> {
         let mut label_len = [0];
@@ -176,12 +294,93 @@ impl FileSection {
         reader.read_exact(&mut pos).await?;
         let mut len = [0; 8];
         reader.read_exact(&mut len).await?;
+        let sha256 = if version >= 2 {
+            let mut sha256 = [0; 32];
+            reader.read_exact(&mut sha256).await?;
+            Some(sha256)
+        } else {
+            None
+        };
         Ok(Some((
             label,
             FileSection {
                 position: u64::from_be_bytes(pos),
                 length: u64::from_be_bytes(len),
+                sha256,
             },
         )))
     }
 }
+
+#[tokio::test]
+async fn test_header_roundtrip() {
+    for version in [1, VERSION] {
+        let mut header = Header::placeholder();
+        header.version = version;
+        header.table_of_contents.icon = FileSection {
+            position: 1024,
+            length: 2048,
+            sha256: Some([7; 32]).filter(|_| version >= 2),
+        };
+        let mut serialized = Vec::new();
+        header.serialize(&mut serialized).await.unwrap();
+        let mut placeholder = Vec::new();
+        let mut empty = Header::placeholder();
+        empty.version = version;
+        empty.serialize(&mut placeholder).await.unwrap();
+        assert_eq!(serialized.len(), placeholder.len());
+        let deserialized = Header::deserialize(serialized.as_slice()).await.unwrap();
+        assert_eq!(deserialized.version, version);
+        assert_eq!(deserialized.table_of_contents.icon.position, 1024);
+        assert_eq!(deserialized.table_of_contents.icon.length, 2048);
+        assert_eq!(
+            deserialized.table_of_contents.icon.sha256,
+            header.table_of_contents.icon.sha256
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_cosignatures_roundtrip() {
+    let toc = TableOfContents::default();
+    let keys = [
+        ed25519_dalek::Keypair::generate(&mut rand_old::thread_rng()),
+        ed25519_dalek::Keypair::generate(&mut rand_old::thread_rng()),
+    ];
+    let mut cosignatures = CoSignatures::default();
+    for key in &keys {
+        cosignatures.0.push((
+            key.public,
+            key.sign_prehashed(
+                toc.signing_digest().await.unwrap(),
+                Some(super::SIG_CONTEXT),
+            )
+            .unwrap(),
+        ));
+    }
+    let mut serialized = Vec::new();
+    cosignatures.serialize(&mut serialized).await.unwrap();
+    let deserialized = CoSignatures::deserialize(serialized.as_slice())
+        .await
+        .unwrap();
+    assert_eq!(deserialized.0.len(), keys.len());
+    for ((pubkey, signature), key) in deserialized.0.into_iter().zip(&keys) {
+        assert_eq!(pubkey, key.public);
+        pubkey
+            .verify_prehashed(
+                toc.signing_digest().await.unwrap(),
+                Some(super::SIG_CONTEXT),
+                &signature,
+            )
+            .unwrap();
+    }
+    assert!(CoSignatures::deserialize(&[][..])
+        .await
+        .unwrap()
+        .0
+        .is_empty());
+    let too_many = CoSignatures(vec![cosignatures.0[0]; 256]);
+    let mut serialized = Vec::new();
+    assert!(too_many.serialize(&mut serialized).await.is_err());
+    assert!(serialized.is_empty());
+}
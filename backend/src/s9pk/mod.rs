@@ -13,6 +13,7 @@ use crate::context::SdkContext;
 use crate::s9pk::builder::S9pkPacker;
 use crate::s9pk::docker::DockerMultiArch;
 use crate::s9pk::git_hash::GitHash;
+use crate::s9pk::header::{CoSignatures, Header};
 use crate::s9pk::manifest::Manifest;
 use crate::s9pk::reader::S9pkReader;
 use crate::util::display_none;
@@ -203,6 +204,54 @@ pub async fn verify(#[arg] path: PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
+/// Adds a signature from the developer key to a package that someone else has already packed, so
+/// that it can satisfy signing policies that require more than one signer.
+#[command(cli_only, display(display_none))]
+#[instrument(skip_all)]
+pub async fn sign(#[context] ctx: SdkContext, #[arg] path: PathBuf) -> Result<(), Error> {
+    use tokio::io::{AsyncSeekExt, SeekFrom};
+
+    let key = ctx.developer_key()?;
+    let signers = S9pkReader::open(&path, true).await?.signers().to_vec();
+    if signers.contains(&key.public) {
+        tracing::warn!("{} is already signed by this key", path.display());
+        return Ok(());
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .await
+        .with_ctx(|_| (crate::ErrorKind::Filesystem, path.display().to_string()))?;
+    let header = Header::deserialize(&mut file).await?;
+    if header.version < 2 {
+        return Err(Error::new(
+            eyre!(
+                "Only s9pk version 2 or later can be co-signed, repack {} to upgrade it",
+                path.display()
+            ),
+            crate::ErrorKind::InvalidRequest,
+        ));
+    }
+    let end = header.table_of_contents.end();
+    file.seek(SeekFrom::Start(end)).await?;
+    let mut cosignatures = CoSignatures::deserialize(&mut file).await?;
+    cosignatures.0.push((
+        key.public,
+        key.sign_prehashed(
+            header.table_of_contents.signing_digest().await?,
+            Some(SIG_CONTEXT),
+        )?,
+    ));
+    file.seek(SeekFrom::Start(end)).await?;
+    cosignatures.serialize(&mut file).await?;
+    let len = file.stream_position().await?;
+    file.set_len(len).await?;
+    file.sync_all().await?;
+
+    Ok(())
+}
+
 fn enumerate_extra_keys(reference: &Value, candidate: &Value) -> Vec<String> {
     match (reference, candidate) {
         (Value::Object(m_r), Value::Object(m_c)) => {
@@ -11,27 +11,101 @@ use digest_old::Output;
 use ed25519_dalek::PublicKey;
 use futures::TryStreamExt;
 use models::ImageId;
-use sha2_old::{Digest, Sha512};
+use sha2_old::{Digest, Sha256, Sha512};
 use tokio::fs::File;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
 use tracing::instrument;
 
-use super::header::{FileSection, Header, TableOfContents};
+use super::header::{CoSignatures, FileSection, Header, TableOfContents};
 use super::manifest::{Manifest, PackageId};
 use super::SIG_CONTEXT;
 use crate::install::progress::InstallProgressTracker;
-use crate::s9pk::docker::DockerReader;
+use crate::s9pk::docker::{DockerMultiArch, DockerReader};
 use crate::util::Version;
 use crate::{Error, ResultExt};
 
 const MAX_REPLACES: usize = 10;
 const MAX_TITLE_LEN: usize = 30;
 
+/// Checks a section against the sha256 in a version 2 header as it is read. Reads must be
+/// sequential from the start of the section, seeking back to the start starts over.
+struct SectionVerifier {
+    expected: [u8; 32],
+    hasher: Sha256,
+    hashed: u64,
+}
+impl std::fmt::Debug for SectionVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SectionVerifier")
+            .field("expected", &hex::encode(self.expected))
+            .field("hashed", &self.hashed)
+            .finish()
+    }
+}
+impl SectionVerifier {
+    fn new(expected: [u8; 32]) -> Self {
+        SectionVerifier {
+            expected,
+            hasher: Sha256::new(),
+            hashed: 0,
+        }
+    }
+    fn seek(&mut self, offset: u64) -> std::io::Result<()> {
+        if offset == 0 {
+            self.hasher = Sha256::new();
+            self.hashed = 0;
+        }
+        if offset != self.hashed {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "Non-sequential read at {} of a section only verified up to {}",
+                    offset, self.hashed
+                ),
+            ));
+        }
+        Ok(())
+    }
+    /// Returns whether the whole section has been read and matches
+    fn update(&mut self, data: &[u8], eof: bool, len: u64) -> std::io::Result<bool> {
+        self.hasher.update(data);
+        self.hashed += data.len() as u64;
+        if self.hashed == len {
+            let actual = std::mem::take(&mut self.hasher).finalize();
+            if actual.as_slice() != self.expected {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Section Hash Mismatch: expected {} actual {}",
+                        hex::encode(self.expected),
+                        hex::encode(actual)
+                    ),
+                ));
+            }
+            Ok(true)
+        } else if eof {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Section Length Mismatch: expected {} actual {}",
+                    len, self.hashed
+                ),
+            ))
+        } else {
+            Ok(false)
+        }
+    }
+}
+
 #[pin_project::pin_project]
 #[derive(Debug)]
 pub struct ReadHandle<'a, R = File> {
     pos: &'a mut u64,
     range: Range<u64>,
+    /// `None` once the section is verified, or if it has no hash
+    verifier: Option<SectionVerifier>,
+    /// Start of every section that has been read in full and matched its hash
+    verified: &'a mut BTreeSet<u64>,
     #[pin]
     rdr: &'a mut R,
 }
@@ -49,13 +123,24 @@ impl<'a, R: AsyncRead + Unpin> AsyncRead for ReadHandle<'a, R> {
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
         let this = self.project();
+        let len = this.range.end - this.range.start;
+        if let Some(verifier) = this.verifier {
+            verifier.seek(**this.pos - this.range.start)?;
+        }
         let start = buf.filled().len();
         let mut take_buf = buf.take(this.range.end.saturating_sub(**this.pos) as usize);
+        let wanted = take_buf.remaining();
         let res = AsyncRead::poll_read(this.rdr, cx, &mut take_buf);
         let n = take_buf.filled().len();
         unsafe { buf.assume_init(start + n) };
         buf.advance(n);
         **this.pos += n as u64;
+        if let (Poll::Ready(Ok(())), Some(verifier)) = (&res, this.verifier.as_mut()) {
+            if verifier.update(&buf.filled()[start..], wanted > 0 && n == 0, len)? {
+                this.verified.insert(this.range.start);
+                *this.verifier = None;
+            }
+        }
         res
     }
 }
@@ -148,7 +233,9 @@ pub struct S9pkReader<R: AsyncRead + AsyncSeek + Unpin + Send + Sync = File> {
     hash: Option<Output<Sha512>>,
     hash_string: Option<String>,
     developer_key: PublicKey,
+    signers: Vec<PublicKey>,
     toc: TableOfContents,
+    verified: BTreeSet<u64>,
     pos: u64,
     rdr: R,
 }
@@ -263,6 +350,13 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
             ));
         }
 
+        if let Some(containers) = &man.containers {
+            containers
+                .main
+                .resources
+                .validate()
+                .with_ctx(|_| (crate::ErrorKind::ValidateS9pk, "Containers"))?;
+        }
         if man.containers.is_some()
             && matches!(man.main, crate::procedure::PackageProcedure::Docker(_))
         {
@@ -314,11 +408,35 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
             crate::ErrorKind::ParseS9pk,
         ))
     }
+    /// For version 2 packages, `check_sig` only checks the signatures on the table of contents.
+    /// Whether or not it is set, each section is checked against its hash as it is read, failing
+    /// once the end of the section is reached with a different hash or length.
     #[instrument(skip_all)]
     pub async fn from_reader(mut rdr: R, check_sig: bool) -> Result<Self, Error> {
         let header = Header::deserialize(&mut rdr).await?;
 
-        let (hash, hash_string) = if check_sig {
+        let mut signers = Vec::new();
+        let (hash, hash_string) = if check_sig && header.version >= 2 {
+            let hasher = header.table_of_contents.signing_digest().await?;
+            let hash = hasher.clone().finalize();
+            header
+                .pubkey
+                .verify_prehashed(hasher.clone(), Some(SIG_CONTEXT), &header.signature)?;
+            signers.push(header.pubkey);
+            rdr.seek(SeekFrom::Start(header.table_of_contents.end()))
+                .await?;
+            for (pubkey, signature) in CoSignatures::deserialize(&mut rdr).await?.0 {
+                pubkey.verify_prehashed(hasher.clone(), Some(SIG_CONTEXT), &signature)?;
+                signers.push(pubkey);
+            }
+            (
+                Some(hash),
+                Some(base32::encode(
+                    base32::Alphabet::RFC4648 { padding: false },
+                    hash.as_slice(),
+                )),
+            )
+        } else if check_sig {
             let mut hasher = Sha512::new();
             let mut buf = [0; 1024];
             let mut read;
@@ -332,6 +450,7 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
             header
                 .pubkey
                 .verify_prehashed(hasher, Some(SIG_CONTEXT), &header.signature)?;
+            signers.push(header.pubkey);
             (
                 Some(hash),
                 Some(base32::encode(
@@ -349,7 +468,9 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
             hash_string,
             hash,
             developer_key: header.pubkey,
+            signers,
             toc: header.table_of_contents,
+            verified: BTreeSet::new(),
             pos,
             rdr,
         })
@@ -367,6 +488,12 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
         &self.developer_key
     }
 
+    /// Every key with a valid signature on the package, starting with the developer key. Empty if
+    /// signatures were not checked.
+    pub fn signers(&self) -> &[PublicKey] {
+        &self.signers
+    }
+
     pub async fn reset(&mut self) -> Result<(), Error> {
         self.rdr.seek(SeekFrom::Start(0)).await?;
         Ok(())
@@ -380,8 +507,15 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
             self.rdr.seek(SeekFrom::Start(section.position)).await?;
             self.pos = section.position;
         }
+        let verifier = if self.verified.contains(&section.position) {
+            None
+        } else {
+            section.sha256.map(SectionVerifier::new)
+        };
         Ok(ReadHandle {
             range: self.pos..(self.pos + section.length),
+            verifier,
+            verified: &mut self.verified,
             pos: &mut self.pos,
             rdr: &mut self.rdr,
         })
@@ -413,6 +547,10 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
         DockerReader::new(self.read_handle(self.toc.docker_images).await?).await
     }
 
+    pub async fn docker_multiarch(&mut self) -> Result<Option<DockerMultiArch>, Error> {
+        DockerMultiArch::read(self.read_handle(self.toc.docker_images).await?).await
+    }
+
     pub async fn assets<'a>(&'a mut self) -> Result<ReadHandle<'a, R>, Error> {
         Ok(self.read_handle(self.toc.assets).await?)
     }
@@ -424,3 +562,51 @@ impl<R: AsyncRead + AsyncSeek + Unpin + Send + Sync> S9pkReader<R> {
         })
     }
 }
+
+#[tokio::test]
+async fn test_section_verification() {
+    let data = b"hello world".to_vec();
+    let sha256: [u8; 32] = Sha256::digest(&data).into();
+    let read_section = |mut rdr: std::io::Cursor<Vec<u8>>| async move {
+        let mut pos = 0;
+        let mut verified = BTreeSet::new();
+        let res = ReadHandle {
+            range: 0..11,
+            verifier: Some(SectionVerifier::new(sha256)),
+            verified: &mut verified,
+            pos: &mut pos,
+            rdr: &mut rdr,
+        }
+        .to_vec()
+        .await;
+        (res, verified.contains(&0))
+    };
+    let (res, verified) = read_section(std::io::Cursor::new(data.clone())).await;
+    assert_eq!(res.unwrap(), data);
+    assert!(verified);
+    let mut corrupted = data.clone();
+    corrupted[4] = b'0';
+    let (res, verified) = read_section(std::io::Cursor::new(corrupted)).await;
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    assert!(!verified);
+    let (res, verified) = read_section(std::io::Cursor::new(data[..8].to_vec())).await;
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    assert!(!verified);
+
+    let mut pos = 0;
+    let mut verified = BTreeSet::new();
+    let mut rdr = std::io::Cursor::new(data);
+    let mut handle = ReadHandle {
+        range: 0..11,
+        verifier: Some(SectionVerifier::new(sha256)),
+        verified: &mut verified,
+        pos: &mut pos,
+        rdr: &mut rdr,
+    };
+    handle.seek(SeekFrom::Start(6)).await.unwrap();
+    let mut buf = [0; 5];
+    assert_eq!(
+        handle.read_exact(&mut buf).await.unwrap_err().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+}
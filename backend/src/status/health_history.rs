@@ -0,0 +1,393 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, PgPool, Postgres};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::s9pk::manifest::PackageId;
+use crate::status::health_check::{HealthCheckId, HealthCheckResult};
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Transitions older than this are pruned. It is a day longer than the longest uptime window, so
+/// the state at the start of that window is still known.
+const HEALTH_HISTORY_RETENTION_DAYS: i64 = 31;
+/// A check that changes between failing and not this many times within `FLAP_WINDOW_MINUTES` is
+/// flapping, and stops producing a notification for every failure
+const FLAP_THRESHOLD: usize = 5;
+const FLAP_WINDOW_MINUTES: i64 = 60;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthState {
+    Success,
+    Disabled,
+    Starting,
+    Loading,
+    Failure,
+    /// The service is not running
+    Stopped,
+}
+impl HealthState {
+    /// `None` for states that count neither as up nor as down
+    fn up(&self) -> Option<bool> {
+        match self {
+            HealthState::Success => Some(true),
+            HealthState::Failure | HealthState::Stopped => Some(false),
+            HealthState::Disabled | HealthState::Starting | HealthState::Loading => None,
+        }
+    }
+}
+impl std::str::FromStr for HealthState {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_owned()))
+            .with_kind(ErrorKind::ParseDbField)
+    }
+}
+impl std::fmt::Display for HealthState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthState::Success => write!(f, "success"),
+            HealthState::Disabled => write!(f, "disabled"),
+            HealthState::Starting => write!(f, "starting"),
+            HealthState::Loading => write!(f, "loading"),
+            HealthState::Failure => write!(f, "failure"),
+            HealthState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+impl From<&HealthCheckResult> for HealthState {
+    fn from(result: &HealthCheckResult) -> Self {
+        match result {
+            HealthCheckResult::Success => HealthState::Success,
+            HealthCheckResult::Disabled => HealthState::Disabled,
+            HealthCheckResult::Starting => HealthState::Starting,
+            HealthCheckResult::Loading { .. } => HealthState::Loading,
+            HealthCheckResult::Failure { .. } => HealthState::Failure,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthTransition {
+    pub check: HealthCheckId,
+    pub at: DateTime<Utc>,
+    pub state: HealthState,
+    pub message: Option<String>,
+}
+
+#[instrument(skip_all)]
+pub async fn record(
+    secrets: &PgPool,
+    id: &PackageId,
+    check: &HealthCheckId,
+    result: &HealthCheckResult,
+) -> Result<(), Error> {
+    let message = match result {
+        HealthCheckResult::Loading { message } => Some(message.as_str()),
+        HealthCheckResult::Failure { error } => Some(error.as_str()),
+        _ => None,
+    };
+    insert(secrets, id, check, HealthState::from(result), message).await
+}
+
+/// Marks every check of a service as down once its main process is gone
+#[instrument(skip_all)]
+pub async fn record_stopped<'a>(
+    secrets: &PgPool,
+    id: &PackageId,
+    checks: impl IntoIterator<Item = &'a HealthCheckId>,
+) -> Result<(), Error> {
+    for check in checks {
+        insert(secrets, id, check, HealthState::Stopped, None).await?;
+    }
+    Ok(())
+}
+
+async fn insert(
+    secrets: &PgPool,
+    id: &PackageId,
+    check: &HealthCheckId,
+    state: HealthState,
+    message: Option<&str>,
+) -> Result<(), Error> {
+    let now = Utc::now();
+    sqlx::query!(
+        "INSERT INTO health_history (package_id, check_id, at, state, message) VALUES ($1, $2, $3, $4, $5)",
+        id.as_str(),
+        check.to_string(),
+        now.naive_utc(),
+        state.to_string(),
+        message,
+    )
+    .execute(secrets)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM health_history WHERE package_id = $1 AND at < $2",
+        id.as_str(),
+        (now - Duration::days(HEALTH_HISTORY_RETENTION_DAYS)).naive_utc(),
+    )
+    .execute(secrets)
+    .await?;
+    Ok(())
+}
+
+#[instrument(skip_all)]
+pub async fn remove<Ex>(secrets: &mut Ex, id: &PackageId) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!(
+        "DELETE FROM health_history WHERE package_id = $1",
+        id.as_str()
+    )
+    .execute(secrets)
+    .await?;
+    Ok(())
+}
+
+/// Oldest first
+#[instrument(skip_all)]
+pub async fn load(
+    secrets: &PgPool,
+    id: &PackageId,
+    since: DateTime<Utc>,
+) -> Result<Vec<HealthTransition>, Error> {
+    sqlx::query!(
+        "SELECT check_id, at, state, message FROM health_history WHERE package_id = $1 AND at >= $2 ORDER BY at, id",
+        id.as_str(),
+        since.naive_utc(),
+    )
+    .fetch_all(secrets)
+    .await?
+    .into_iter()
+    .map(|r| {
+        Ok(HealthTransition {
+            check: r.check_id.parse()?,
+            at: DateTime::from_utc(r.at, Utc),
+            state: r.state.parse()?,
+            message: r.message,
+        })
+    })
+    .collect()
+}
+
+/// Percentage of the time between `from` and `to` a package was up, where it is down while any of
+/// its checks is. Time where no check was up or down is left out, and `None` means there was none.
+pub fn uptime(
+    transitions: &[HealthTransition],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Option<f64> {
+    let mut states = BTreeMap::new();
+    let mut up = Duration::zero();
+    let mut down = Duration::zero();
+    let mut last = from;
+    let mut tally = |states: &BTreeMap<&HealthCheckId, HealthState>, until: DateTime<Utc>| {
+        if until <= last {
+            return;
+        }
+        let ups = states.values().filter_map(|s| s.up()).collect::<Vec<_>>();
+        if ups.contains(&false) {
+            down = down + (until - last);
+        } else if !ups.is_empty() {
+            up = up + (until - last);
+        }
+        last = until;
+    };
+    for transition in transitions {
+        if transition.at >= to {
+            break;
+        }
+        tally(&states, transition.at);
+        states.insert(&transition.check, transition.state);
+    }
+    tally(&states, to);
+    let total = (up + down).num_milliseconds();
+    if total == 0 {
+        None
+    } else {
+        Some(up.num_milliseconds() as f64 / total as f64 * 100.0)
+    }
+}
+
+/// Whether a check has gone in and out of failing too often lately. `transitions` must only be for
+/// that check.
+pub fn is_flapping(transitions: &[HealthTransition], now: DateTime<Utc>) -> bool {
+    let window_start = now - Duration::minutes(FLAP_WINDOW_MINUTES);
+    let mut failing = None;
+    let mut changes = 0;
+    for transition in transitions {
+        let now_failing = transition.state == HealthState::Failure;
+        if failing.map_or(false, |failing| failing != now_failing) && transition.at >= window_start
+        {
+            changes += 1;
+        }
+        failing = Some(now_failing);
+    }
+    changes >= FLAP_THRESHOLD
+}
+
+#[instrument(skip_all)]
+pub async fn check_is_flapping(
+    secrets: &PgPool,
+    id: &PackageId,
+    check: &HealthCheckId,
+) -> Result<bool, Error> {
+    let now = Utc::now();
+    let transitions = load(
+        secrets,
+        id,
+        now - Duration::minutes(FLAP_WINDOW_MINUTES * 2),
+    )
+    .await?
+    .into_iter()
+    .filter(|t| &t.check == check)
+    .collect::<Vec<_>>();
+    Ok(is_flapping(&transitions, now))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Uptime {
+    #[serde(rename = "24h")]
+    pub day: Option<f64>,
+    #[serde(rename = "7d")]
+    pub week: Option<f64>,
+    #[serde(rename = "30d")]
+    pub month: Option<f64>,
+}
+impl Uptime {
+    fn new(transitions: &[HealthTransition], now: DateTime<Utc>) -> Self {
+        Uptime {
+            day: uptime(transitions, now - Duration::days(1), now),
+            week: uptime(transitions, now - Duration::days(7), now),
+            month: uptime(transitions, now - Duration::days(30), now),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CheckHistory {
+    pub uptime: Uptime,
+    pub flapping: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthHistoryRes {
+    pub uptime: Uptime,
+    pub checks: BTreeMap<HealthCheckId, CheckHistory>,
+    pub transitions: Vec<HealthTransition>,
+}
+
+#[command(subcommands(history))]
+pub fn health() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Uptime over the last 24 hours, 7 days and 30 days, and the transitions since `--since`, or all
+/// that are kept if it is not passed
+#[command(display(display_serializable))]
+#[instrument(skip_all)]
+pub async fn history(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(long = "since")] since: Option<DateTime<Utc>>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<HealthHistoryRes, Error> {
+    let now = Utc::now();
+    let transitions = load(
+        &ctx.secret_store,
+        &id,
+        now - Duration::days(HEALTH_HISTORY_RETENTION_DAYS),
+    )
+    .await?;
+    let mut by_check = BTreeMap::<_, Vec<_>>::new();
+    for transition in &transitions {
+        by_check
+            .entry(transition.check.clone())
+            .or_default()
+            .push(transition.clone());
+    }
+    Ok(HealthHistoryRes {
+        uptime: Uptime::new(&transitions, now),
+        checks: by_check
+            .into_iter()
+            .map(|(check, transitions)| {
+                (
+                    check,
+                    CheckHistory {
+                        uptime: Uptime::new(&transitions, now),
+                        flapping: is_flapping(&transitions, now),
+                    },
+                )
+            })
+            .collect(),
+        transitions: transitions
+            .into_iter()
+            .filter(|t| since.map_or(true, |since| t.at >= since))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+fn transition(
+    check: &str,
+    minutes_ago: i64,
+    state: HealthState,
+    now: DateTime<Utc>,
+) -> HealthTransition {
+    HealthTransition {
+        check: check.parse().unwrap(),
+        at: now - Duration::minutes(minutes_ago),
+        state,
+        message: None,
+    }
+}
+
+#[test]
+fn test_uptime() {
+    let now = Utc::now();
+    let transitions = vec![
+        transition("rpc", 300, HealthState::Success, now),
+        transition("sync", 300, HealthState::Starting, now),
+        transition("sync", 240, HealthState::Success, now),
+        transition("sync", 120, HealthState::Failure, now),
+        transition("sync", 90, HealthState::Success, now),
+        transition("rpc", 60, HealthState::Stopped, now),
+        transition("sync", 60, HealthState::Stopped, now),
+        transition("rpc", 30, HealthState::Success, now),
+        transition("sync", 30, HealthState::Success, now),
+    ];
+    // up 300-120, down 120-90, up 90-60, down 60-30, up 30-0
+    assert_eq!(
+        uptime(&transitions, now - Duration::days(1), now),
+        Some(240.0 / 300.0 * 100.0)
+    );
+    assert_eq!(
+        uptime(&transitions, now - Duration::minutes(60), now),
+        Some(50.0)
+    );
+    assert_eq!(uptime(&[], now - Duration::days(1), now), None);
+}
+
+#[test]
+fn test_is_flapping() {
+    let now = Utc::now();
+    let mut transitions = vec![transition("rpc", 600, HealthState::Success, now)];
+    for i in 0..4 {
+        transitions.push(transition("rpc", 50 - i * 10, HealthState::Failure, now));
+        transitions.push(transition("rpc", 45 - i * 10, HealthState::Success, now));
+    }
+    assert!(is_flapping(&transitions, now));
+    assert!(!is_flapping(&transitions[..4], now));
+    assert!(!is_flapping(&transitions, now + Duration::hours(2)));
+}
@@ -9,6 +9,7 @@ use crate::dependencies::DependencyErrors;
 use crate::status::health_check::HealthCheckResult;
 
 pub mod health_check;
+pub mod health_history;
 #[derive(Clone, Debug, Deserialize, Serialize, HasModel)]
 #[serde(rename_all = "kebab-case")]
 pub struct Status {
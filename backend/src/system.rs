@@ -1,6 +1,6 @@
 use std::fmt;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use color_eyre::eyre::eyre;
 use futures::FutureExt;
 use rpc_toolkit::command;
@@ -11,10 +11,11 @@ use tokio::sync::RwLock;
 use tracing::instrument;
 
 use crate::context::{CliContext, RpcContext};
+use crate::core::rpc_continuations::RequestGuid;
 use crate::disk::util::{get_available, get_used};
 use crate::logs::{
-    cli_logs_generic_follow, cli_logs_generic_nofollow, fetch_logs, follow_logs, LogFollowResponse,
-    LogResponse, LogSource,
+    cli_logs_generic_follow, cli_logs_generic_nofollow, export_logs, fetch_logs, follow_logs,
+    LogFilter, LogFollowResponse, LogResponse, LogSource,
 };
 use crate::shutdown::Shutdown;
 use crate::util::display_none;
@@ -30,7 +31,7 @@ pub async fn time() -> Result<String, Error> {
 
 #[command(
     custom_cli(cli_logs(async, context(CliContext))),
-    subcommands(self(logs_nofollow(async)), logs_follow),
+    subcommands(self(logs_nofollow(async)), logs_follow, logs_export),
     display(display_none)
 )]
 pub async fn logs(
@@ -38,12 +39,29 @@ pub async fn logs(
     #[arg(short = 'c', long = "cursor")] cursor: Option<String>,
     #[arg(short = 'B', long = "before", default)] before: bool,
     #[arg(short = 'f', long = "follow", default)] follow: bool,
-) -> Result<(Option<usize>, Option<String>, bool, bool), Error> {
-    Ok((limit, cursor, before, follow))
+    #[arg(short = 's', long = "search")] search: Option<String>,
+    #[arg(short = 'r', long = "regex")] regex: Option<String>,
+    #[arg(short = 'p', long = "priority")] priority: Option<u8>,
+    #[arg(long = "since")] since: Option<DateTime<Utc>>,
+    #[arg(long = "until")] until: Option<DateTime<Utc>>,
+) -> Result<(Option<usize>, Option<String>, bool, bool, LogFilter), Error> {
+    Ok((
+        limit,
+        cursor,
+        before,
+        follow,
+        LogFilter {
+            search,
+            regex,
+            priority,
+            since,
+            until,
+        },
+    ))
 }
 pub async fn cli_logs(
     ctx: CliContext,
-    (limit, cursor, before, follow): (Option<usize>, Option<String>, bool, bool),
+    (limit, cursor, before, follow, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
 ) -> Result<(), RpcError> {
     if follow {
         if cursor.is_some() {
@@ -58,30 +76,49 @@ pub async fn cli_logs(
                 crate::ErrorKind::InvalidRequest,
             )));
         }
-        cli_logs_generic_follow(ctx, "server.logs.follow", None, limit).await
+        cli_logs_generic_follow(ctx, "server.logs.follow", None, limit, filter).await
     } else {
-        cli_logs_generic_nofollow(ctx, "server.logs", None, limit, cursor, before).await
+        cli_logs_generic_nofollow(ctx, "server.logs", None, limit, cursor, before, filter).await
     }
 }
 pub async fn logs_nofollow(
     _ctx: (),
-    (limit, cursor, before, _): (Option<usize>, Option<String>, bool, bool),
+    (limit, cursor, before, _, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
 ) -> Result<LogResponse, Error> {
-    fetch_logs(LogSource::Service(SYSTEMD_UNIT), limit, cursor, before).await
+    fetch_logs(
+        LogSource::Service(SYSTEMD_UNIT),
+        limit,
+        cursor,
+        before,
+        filter,
+    )
+    .await
 }
 
 #[command(rpc_only, rename = "follow", display(display_none))]
 pub async fn logs_follow(
     #[context] ctx: RpcContext,
-    #[parent_data] (limit, _, _, _): (Option<usize>, Option<String>, bool, bool),
+    #[parent_data] (limit, _, _, _, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
 ) -> Result<LogFollowResponse, Error> {
-    follow_logs(ctx, LogSource::Service(SYSTEMD_UNIT), limit).await
+    follow_logs(ctx, LogSource::Service(SYSTEMD_UNIT), limit, filter).await
+}
+
+#[command(rpc_only, rename = "export", display(display_none))]
+pub async fn logs_export(
+    #[context] ctx: RpcContext,
+    #[parent_data] (_, _, _, _, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
+) -> Result<RequestGuid, Error> {
+    export_logs(ctx, LogSource::Service(SYSTEMD_UNIT), filter).await
 }
 
 #[command(
     rename = "kernel-logs",
     custom_cli(cli_kernel_logs(async, context(CliContext))),
-    subcommands(self(kernel_logs_nofollow(async)), kernel_logs_follow),
+    subcommands(
+        self(kernel_logs_nofollow(async)),
+        kernel_logs_follow,
+        kernel_logs_export
+    ),
     display(display_none)
 )]
 pub async fn kernel_logs(
@@ -89,12 +126,29 @@ pub async fn kernel_logs(
     #[arg(short = 'c', long = "cursor")] cursor: Option<String>,
     #[arg(short = 'B', long = "before", default)] before: bool,
     #[arg(short = 'f', long = "follow", default)] follow: bool,
-) -> Result<(Option<usize>, Option<String>, bool, bool), Error> {
-    Ok((limit, cursor, before, follow))
+    #[arg(short = 's', long = "search")] search: Option<String>,
+    #[arg(short = 'r', long = "regex")] regex: Option<String>,
+    #[arg(short = 'p', long = "priority")] priority: Option<u8>,
+    #[arg(long = "since")] since: Option<DateTime<Utc>>,
+    #[arg(long = "until")] until: Option<DateTime<Utc>>,
+) -> Result<(Option<usize>, Option<String>, bool, bool, LogFilter), Error> {
+    Ok((
+        limit,
+        cursor,
+        before,
+        follow,
+        LogFilter {
+            search,
+            regex,
+            priority,
+            since,
+            until,
+        },
+    ))
 }
 pub async fn cli_kernel_logs(
     ctx: CliContext,
-    (limit, cursor, before, follow): (Option<usize>, Option<String>, bool, bool),
+    (limit, cursor, before, follow, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
 ) -> Result<(), RpcError> {
     if follow {
         if cursor.is_some() {
@@ -109,24 +163,41 @@ pub async fn cli_kernel_logs(
                 crate::ErrorKind::InvalidRequest,
             )));
         }
-        cli_logs_generic_follow(ctx, "server.kernel-logs.follow", None, limit).await
+        cli_logs_generic_follow(ctx, "server.kernel-logs.follow", None, limit, filter).await
     } else {
-        cli_logs_generic_nofollow(ctx, "server.kernel-logs", None, limit, cursor, before).await
+        cli_logs_generic_nofollow(
+            ctx,
+            "server.kernel-logs",
+            None,
+            limit,
+            cursor,
+            before,
+            filter,
+        )
+        .await
     }
 }
 pub async fn kernel_logs_nofollow(
     _ctx: (),
-    (limit, cursor, before, _): (Option<usize>, Option<String>, bool, bool),
+    (limit, cursor, before, _, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
 ) -> Result<LogResponse, Error> {
-    fetch_logs(LogSource::Kernel, limit, cursor, before).await
+    fetch_logs(LogSource::Kernel, limit, cursor, before, filter).await
 }
 
 #[command(rpc_only, rename = "follow", display(display_none))]
 pub async fn kernel_logs_follow(
     #[context] ctx: RpcContext,
-    #[parent_data] (limit, _, _, _): (Option<usize>, Option<String>, bool, bool),
+    #[parent_data] (limit, _, _, _, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
 ) -> Result<LogFollowResponse, Error> {
-    follow_logs(ctx, LogSource::Kernel, limit).await
+    follow_logs(ctx, LogSource::Kernel, limit, filter).await
+}
+
+#[command(rpc_only, rename = "export", display(display_none))]
+pub async fn kernel_logs_export(
+    #[context] ctx: RpcContext,
+    #[parent_data] (_, _, _, _, filter): (Option<usize>, Option<String>, bool, bool, LogFilter),
+) -> Result<RequestGuid, Error> {
+    export_logs(ctx, LogSource::Kernel, filter).await
 }
 
 #[derive(Serialize, Deserialize)]
@@ -136,7 +207,7 @@ pub struct MetricLeaf<T> {
 }
 
 #[derive(Clone, Debug)]
-pub struct Celsius(f64);
+pub struct Celsius(pub f64);
 impl fmt::Display for Celsius {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:.1}°C", self.0)
@@ -164,7 +235,7 @@ impl<'de> Deserialize<'de> for Celsius {
     }
 }
 #[derive(Clone, Debug)]
-pub struct Percentage(f64);
+pub struct Percentage(pub f64);
 impl Serialize for Percentage {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -190,7 +261,7 @@ impl<'de> Deserialize<'de> for Percentage {
 }
 
 #[derive(Clone, Debug)]
-pub struct MebiBytes(f64);
+pub struct MebiBytes(pub f64);
 impl Serialize for MebiBytes {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -216,7 +287,7 @@ impl<'de> Deserialize<'de> for MebiBytes {
 }
 
 #[derive(Clone, Debug)]
-pub struct GigaBytes(f64);
+pub struct GigaBytes(pub f64);
 impl Serialize for GigaBytes {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -244,68 +315,78 @@ impl<'de> Deserialize<'de> for GigaBytes {
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct MetricsGeneral {
     #[serde(rename = "Temperature")]
-    temperature: Option<Celsius>,
+    pub temperature: Option<Celsius>,
 }
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct MetricsMemory {
     #[serde(rename = "Percentage Used")]
-    percentage_used: Percentage,
+    pub percentage_used: Percentage,
     #[serde(rename = "Total")]
-    total: MebiBytes,
+    pub total: MebiBytes,
     #[serde(rename = "Available")]
-    available: MebiBytes,
+    pub available: MebiBytes,
     #[serde(rename = "Used")]
-    used: MebiBytes,
+    pub used: MebiBytes,
     #[serde(rename = "Swap Total")]
-    swap_total: MebiBytes,
+    pub swap_total: MebiBytes,
     #[serde(rename = "Swap Free")]
-    swap_free: MebiBytes,
+    pub swap_free: MebiBytes,
     #[serde(rename = "Swap Used")]
-    swap_used: MebiBytes,
+    pub swap_used: MebiBytes,
 }
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct MetricsCpu {
     #[serde(rename = "User Space")]
-    user_space: Percentage,
+    pub user_space: Percentage,
     #[serde(rename = "Kernel Space")]
-    kernel_space: Percentage,
+    pub kernel_space: Percentage,
     #[serde(rename = "I/O Wait")]
-    wait: Percentage,
+    pub wait: Percentage,
     #[serde(rename = "Idle")]
-    idle: Percentage,
+    pub idle: Percentage,
     #[serde(rename = "Usage")]
-    usage: Percentage,
+    pub usage: Percentage,
 }
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct MetricsDisk {
     #[serde(rename = "Size")]
-    size: GigaBytes,
+    pub size: GigaBytes,
     #[serde(rename = "Used")]
-    used: GigaBytes,
+    pub used: GigaBytes,
     #[serde(rename = "Available")]
-    available: GigaBytes,
+    pub available: GigaBytes,
     #[serde(rename = "Percentage Used")]
-    used_percentage: Percentage,
+    pub used_percentage: Percentage,
 }
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Metrics {
     #[serde(rename = "General")]
-    general: MetricsGeneral,
+    pub general: MetricsGeneral,
     #[serde(rename = "Memory")]
-    memory: MetricsMemory,
+    pub memory: MetricsMemory,
     #[serde(rename = "CPU")]
-    cpu: MetricsCpu,
+    pub cpu: MetricsCpu,
     #[serde(rename = "Disk")]
-    disk: MetricsDisk,
+    pub disk: MetricsDisk,
 }
 
-#[command(display(display_serializable))]
-pub async fn metrics(
-    #[context] ctx: RpcContext,
+#[command(
+    subcommands(
+        self(metrics_impl(async)),
+        crate::metrics::history,
+        crate::prometheus::exporter
+    ),
+    display(display_serializable)
+)]
+pub fn metrics(
     #[allow(unused_variables)]
     #[arg(long = "format")]
     format: Option<IoFormat>,
-) -> Result<Metrics, Error> {
+) -> Result<(), Error> {
+    Ok(())
+}
+
+pub async fn metrics_impl(ctx: RpcContext, _: ()) -> Result<Metrics, Error> {
     match ctx.metrics_cache.read().await.clone() {
         None => Err(Error {
             source: color_eyre::eyre::eyre!("No Metrics Found"),
@@ -19,11 +19,12 @@ use crate::db::model::UpdateProgress;
 use crate::disk::mount::filesystem::bind::Bind;
 use crate::disk::mount::filesystem::ReadWrite;
 use crate::disk::mount::guard::MountGuard;
-use crate::notifications::NotificationLevel;
+use crate::notifications::{NotificationLevel, UpdateAvailable};
 use crate::sound::{
     CIRCLE_OF_5THS_SHORT, UPDATE_FAILED_1, UPDATE_FAILED_2, UPDATE_FAILED_3, UPDATE_FAILED_4,
 };
 use crate::update::latest_information::LatestInformation;
+use crate::util::serde::display_serializable;
 use crate::util::Invoke;
 use crate::version::{Current, VersionT};
 use crate::{Error, ErrorKind, ResultExt, IS_RASPBERRY_PI};
@@ -75,18 +76,50 @@ fn display_update_result(status: UpdateResult, _: &ArgMatches) {
     }
 }
 
+/// Asks the marketplace for the latest embassyOS version, posting an update-available
+/// notification if it is newer than the running one
+#[command(rename = "check-update", display(display_serializable))]
 #[instrument(skip_all)]
-async fn maybe_do_update(
-    ctx: RpcContext,
-    marketplace_url: Url,
-) -> Result<Option<Arc<Revision>>, Error> {
+pub async fn check_update(
+    #[context] ctx: RpcContext,
+    #[arg(rename = "marketplace-url")] marketplace_url: Option<Url>,
+) -> Result<Option<crate::util::Version>, Error> {
+    let marketplace_url =
+        marketplace_url.unwrap_or_else(|| crate::DEFAULT_MARKETPLACE.parse().unwrap());
+    let latest_version = latest_version(&marketplace_url).await?;
     let mut db = ctx.db.handle();
+    let current_version = crate::db::DatabaseModel::new()
+        .server_info()
+        .version()
+        .get(&mut db)
+        .await?
+        .into_owned();
+    if latest_version <= *current_version {
+        return Ok(None);
+    }
+    ctx.notification_manager
+        .notify(
+            &mut db,
+            None,
+            NotificationLevel::Info,
+            "embassyOS Update Available".to_owned(),
+            format!("embassyOS {} is available.", latest_version),
+            UpdateAvailable {
+                version: latest_version.clone().into(),
+            },
+            Some(86400),
+        )
+        .await?;
+    Ok(Some(latest_version.into()))
+}
+
+async fn latest_version(marketplace_url: &Url) -> Result<Version, Error> {
     let arch = if *IS_RASPBERRY_PI {
         "raspberrypi"
     } else {
         *crate::ARCH
     };
-    let latest_version: Version = reqwest::get(format!(
+    Ok(reqwest::get(format!(
         "{}/eos/v0/latest?eos-version={}&arch={}",
         marketplace_url,
         Current::new().semver(),
@@ -97,7 +130,16 @@ async fn maybe_do_update(
     .json::<LatestInformation>()
     .await
     .with_kind(ErrorKind::Network)?
-    .version;
+    .version)
+}
+
+#[instrument(skip_all)]
+async fn maybe_do_update(
+    ctx: RpcContext,
+    marketplace_url: Url,
+) -> Result<Option<Arc<Revision>>, Error> {
+    let mut db = ctx.db.handle();
+    let latest_version = latest_version(&marketplace_url).await?;
     crate::db::DatabaseModel::new()
         .server_info()
         .lock(&mut db, LockType::Write)
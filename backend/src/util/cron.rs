@@ -0,0 +1,249 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::util::serde::{deserialize_from_str, serialize_display};
+use crate::{Error, ErrorKind};
+
+/// How far ahead [`CronSchedule::next_after`] will search before giving up on an expression that
+/// can never match (e.g. `0 0 31 2 *`)
+const MAX_SEARCH_DAYS: i64 = 366 * 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CronField {
+    mask: u64,
+    restricted: bool,
+}
+impl CronField {
+    fn parse(s: &str, min: u8, max: u8) -> Result<Self, Error> {
+        let mut mask = 0;
+        for part in s.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u8>()?),
+                None => (part, 1),
+            };
+            if step == 0 {
+                return Err(Error::new(
+                    eyre!("Invalid step in cron field: {}", part),
+                    ErrorKind::ParseTimestamp,
+                ));
+            }
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some((start, end)) = range.split_once('-') {
+                (start.parse()?, end.parse()?)
+            } else if part.contains('/') {
+                (range.parse()?, max)
+            } else {
+                let single = range.parse()?;
+                (single, single)
+            };
+            if start < min || end > max || start > end {
+                return Err(Error::new(
+                    eyre!("Cron field out of range {}-{}: {}", min, max, part),
+                    ErrorKind::ParseTimestamp,
+                ));
+            }
+            for value in (start..=end).step_by(step as usize) {
+                mask |= 1 << value;
+            }
+        }
+        Ok(CronField {
+            mask,
+            // like Vixie cron, a field starting with `*` (including `*/n`) counts as unrestricted
+            // when deciding how to combine day-of-month and day-of-week
+            restricted: !s.starts_with('*'),
+        })
+    }
+    fn matches(&self, value: u32) -> bool {
+        self.mask & (1 << value) != 0
+    }
+}
+
+/// A standard 5 field cron expression (`minute hour day-of-month month day-of-week`), evaluated
+/// in UTC
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CronSchedule {
+    src: String,
+    minute: CronField,
+    hour: CronField,
+    day_of_month: CronField,
+    month: CronField,
+    day_of_week: CronField,
+}
+impl CronSchedule {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let dom = self.day_of_month.matches(date.day());
+        let dow = self
+            .day_of_week
+            .matches(date.weekday().num_days_from_sunday());
+        match (self.day_of_month.restricted, self.day_of_week.restricted) {
+            (true, true) => dom || dow,
+            (true, false) => dom,
+            (false, true) => dow,
+            (false, false) => true,
+        }
+    }
+
+    /// The first time strictly after `after` that matches this schedule
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.naive_utc().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let end = start + Duration::days(MAX_SEARCH_DAYS);
+        let mut t = start;
+        while t < end {
+            if !self.month.matches(t.month()) {
+                let (year, month) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.matches_day(t.date()) {
+                t = (t.date() + Duration::days(1)).and_hms_opt(0, 0, 0)?;
+            } else if !self.hour.matches(t.hour()) {
+                t = t.date().and_hms_opt(t.hour(), 0, 0)? + Duration::hours(1);
+            } else if !self.minute.matches(t.minute()) {
+                t += Duration::minutes(1);
+            } else {
+                return Some(DateTime::<Utc>::from_utc(t, Utc));
+            }
+        }
+        None
+    }
+}
+impl FromStr for CronSchedule {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expanded = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            a => a,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(Error::new(
+                eyre!("Cron expression must have 5 fields: {}", s),
+                ErrorKind::ParseTimestamp,
+            ));
+        }
+        let mut day_of_week = CronField::parse(fields[4], 0, 7)?;
+        if day_of_week.matches(7) {
+            // 7 is an alias for sunday
+            day_of_week.mask |= 1;
+        }
+        Ok(CronSchedule {
+            src: s.trim().to_owned(),
+            minute: CronField::parse(fields[0], 0, 59)?,
+            hour: CronField::parse(fields[1], 0, 23)?,
+            day_of_month: CronField::parse(fields[2], 1, 31)?,
+            month: CronField::parse(fields[3], 1, 12)?,
+            day_of_week,
+        })
+    }
+}
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.src)
+    }
+}
+impl<'de> Deserialize<'de> for CronSchedule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserialize_from_str(deserializer)
+    }
+}
+impl Serialize for CronSchedule {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serialize_display(self, serializer)
+    }
+}
+
+#[cfg(test)]
+fn utc(s: &str) -> DateTime<Utc> {
+    DateTime::<Utc>::from_utc(
+        chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap(),
+        Utc,
+    )
+}
+
+#[test]
+fn cron_next_daily() {
+    let sched: CronSchedule = "30 2 * * *".parse().unwrap();
+    assert_eq!(
+        sched.next_after(utc("2023-01-01 00:00")),
+        Some(utc("2023-01-01 02:30"))
+    );
+    assert_eq!(
+        sched.next_after(utc("2023-01-01 02:30")),
+        Some(utc("2023-01-02 02:30"))
+    );
+}
+
+#[test]
+fn cron_next_steps_and_lists() {
+    let sched: CronSchedule = "*/15 1,13 * * *".parse().unwrap();
+    assert_eq!(
+        sched.next_after(utc("2023-01-01 01:50")),
+        Some(utc("2023-01-01 13:00"))
+    );
+    assert_eq!(
+        sched.next_after(utc("2023-12-31 13:45")),
+        Some(utc("2024-01-01 01:00"))
+    );
+}
+
+#[test]
+fn cron_next_day_of_week() {
+    // 2023-01-01 is a sunday
+    let sched: CronSchedule = "0 3 * * 7".parse().unwrap();
+    assert_eq!(
+        sched.next_after(utc("2023-01-01 04:00")),
+        Some(utc("2023-01-08 03:00"))
+    );
+    let sched: CronSchedule = "@weekly".parse().unwrap();
+    assert_eq!(
+        sched.next_after(utc("2023-01-02 00:00")),
+        Some(utc("2023-01-08 00:00"))
+    );
+}
+
+#[test]
+fn cron_next_stepped_day_of_month() {
+    // `*/2` does not restrict the day of month, so this is every monday, not every monday and
+    // every odd day
+    let sched: CronSchedule = "0 0 */2 * 1".parse().unwrap();
+    assert_eq!(
+        sched.next_after(utc("2023-01-02 00:00")),
+        Some(utc("2023-01-09 00:00"))
+    );
+    let sched: CronSchedule = "0 0 1-31/2 * 1".parse().unwrap();
+    assert_eq!(
+        sched.next_after(utc("2023-01-02 00:00")),
+        Some(utc("2023-01-03 00:00"))
+    );
+}
+
+#[test]
+fn cron_never_matches() {
+    let sched: CronSchedule = "0 0 31 2 *".parse().unwrap();
+    assert_eq!(sched.next_after(utc("2023-01-01 00:00")), None);
+}
+
+#[test]
+fn cron_invalid() {
+    assert!("* * * *".parse::<CronSchedule>().is_err());
+    assert!("60 * * * *".parse::<CronSchedule>().is_err());
+    assert!("*/0 * * * *".parse::<CronSchedule>().is_err());
+    assert!("5-1 * * * *".parse::<CronSchedule>().is_err());
+}
@@ -1,7 +1,9 @@
 use std::future::Future;
 use std::io::Cursor;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::task::Poll;
+use std::time::{Duration, Instant};
 
 use futures::future::{BoxFuture, Fuse};
 use futures::{AsyncSeek, FutureExt, TryStreamExt};
@@ -241,6 +243,98 @@ pub fn dir_size<'a, P: AsRef<Path> + 'a + Send + Sync>(
     .boxed()
 }
 
+/// Like [`dir_size`], but only counts files whose contents or metadata changed at or after
+/// `since`, in seconds since the unix epoch. Uses ctime, which tools that preserve mtime cannot
+/// set back.
+pub fn dir_size_changed_since<'a, P: AsRef<Path> + 'a + Send + Sync>(
+    path: P,
+    since: i64,
+) -> BoxFuture<'a, Result<u64, std::io::Error>> {
+    use std::os::unix::fs::MetadataExt;
+
+    async move {
+        tokio_stream::wrappers::ReadDirStream::new(tokio::fs::read_dir(path.as_ref()).await?)
+            .try_fold(0, |acc, e| async move {
+                let m = e.metadata().await?;
+                Ok(acc
+                    + if m.is_file() && m.ctime() >= since {
+                        m.len()
+                    } else if m.is_dir() {
+                        dir_size_changed_since(e.path(), since).await?
+                    } else {
+                        0
+                    })
+            })
+            .await
+    }
+    .boxed()
+}
+
+/// Caps the combined throughput of everything sharing it, in bytes per second. Up to one second
+/// worth of bytes may be sent as a burst.
+#[derive(Clone, Debug)]
+pub struct RateLimiter(Arc<Mutex<RateLimiterState>>);
+#[derive(Debug)]
+struct RateLimiterState {
+    bytes_per_sec: f64,
+    available: f64,
+    last: Instant,
+}
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        RateLimiter(Arc::new(Mutex::new(RateLimiterState {
+            bytes_per_sec,
+            available: bytes_per_sec,
+            last: Instant::now(),
+        })))
+    }
+    /// How long the caller has to wait before sending `bytes`
+    fn reserve(&self, bytes: usize) -> Duration {
+        let mut state = self.0.lock().unwrap();
+        let now = Instant::now();
+        state.available = (state.available
+            + now.duration_since(state.last).as_secs_f64() * state.bytes_per_sec)
+            .min(state.bytes_per_sec);
+        state.last = now;
+        state.available -= bytes as f64;
+        if state.available < 0.0 {
+            Duration::from_secs_f64(-state.available / state.bytes_per_sec)
+        } else {
+            Duration::ZERO
+        }
+    }
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Like [`tokio::io::copy`], but waits on `limiter` (if provided) before each write
+pub async fn copy_limited<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    r: &mut R,
+    w: &mut W,
+    limiter: Option<&RateLimiter>,
+) -> Result<u64, std::io::Error> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = r.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        if let Some(limiter) = limiter {
+            limiter.acquire(n).await;
+        }
+        w.write_all(&buf[..n]).await?;
+        copied += n as u64;
+    }
+    w.flush().await?;
+    Ok(copied)
+}
+
 pub fn response_to_reader(response: reqwest::Response) -> impl AsyncRead + Unpin {
     tokio_util::io::StreamReader::new(response.bytes_stream().map_err(|e| {
         std::io::Error::new(
@@ -416,3 +510,11 @@ impl<T: AsyncWrite> AsyncWrite for BackTrackingReader<T> {
         self.project().reader.poll_write_vectored(cx, bufs)
     }
 }
+
+#[test]
+fn rate_limiter_allows_burst_then_waits() {
+    let limiter = RateLimiter::new(1000);
+    assert_eq!(limiter.reserve(1000), Duration::ZERO);
+    let wait = limiter.reserve(500);
+    assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+}
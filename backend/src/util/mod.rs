@@ -24,9 +24,12 @@ use tracing::instrument;
 use crate::shutdown::Shutdown;
 use crate::{Error, ErrorKind, ResultExt as _};
 pub mod config;
+pub mod cron;
 pub mod http_reader;
 pub mod io;
 pub mod logger;
+pub mod net;
+pub mod s3;
 pub mod serde;
 
 #[derive(Clone, Copy, Debug)]
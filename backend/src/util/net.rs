@@ -0,0 +1,37 @@
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Connects through the SOCKS5 proxy if there is one, letting the proxy resolve `host`
+pub async fn connect(host: &str, port: u16, proxy: Option<SocketAddr>) -> Result<TcpStream, Error> {
+    if let Some(proxy) = proxy {
+        Ok(tokio_socks::tcp::Socks5Stream::connect(proxy, (host, port))
+            .await
+            .with_ctx(|_| {
+                (
+                    ErrorKind::Network,
+                    format!("{}:{} via {}", host, port, proxy),
+                )
+            })?
+            .into_inner())
+    } else {
+        TcpStream::connect((host, port))
+            .await
+            .with_ctx(|_| (ErrorKind::Network, format!("{}:{}", host, port)))
+    }
+}
+
+pub async fn tls<S: AsyncRead + AsyncWrite + Unpin>(
+    host: &str,
+    stream: S,
+) -> Result<tokio_native_tls::TlsStream<S>, Error> {
+    tokio_native_tls::TlsConnector::from(
+        tokio_native_tls::native_tls::TlsConnector::new().with_kind(ErrorKind::Network)?,
+    )
+    .connect(host, stream)
+    .await
+    .with_ctx(|_| (ErrorKind::Network, format!("TLS handshake with {}", host)))
+}
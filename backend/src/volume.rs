@@ -140,6 +140,10 @@ pub enum Volume {
     #[serde(rename_all = "kebab-case")]
     #[serde(skip)]
     Backup { readonly: bool },
+    /// A read-only copy of a data volume, see [`crate::backup::live::VolumeSnapshot`]
+    #[serde(rename_all = "kebab-case")]
+    #[serde(skip)]
+    Snapshot { path: PathBuf },
 }
 impl Volume {
     #[instrument(skip_all)]
@@ -191,6 +195,7 @@ impl Volume {
             }),
             Volume::Certificate { interface_id } => cert_dir(pkg_id, &interface_id),
             Volume::Backup { .. } => backup_dir(pkg_id),
+            Volume::Snapshot { path } => path.clone(),
         }
     }
 
@@ -237,6 +242,7 @@ impl Volume {
             Volume::Pointer { readonly, .. } => *readonly,
             Volume::Certificate { .. } => true,
             Volume::Backup { readonly } => *readonly,
+            Volume::Snapshot { .. } => true,
         }
     }
 }
@@ -531,7 +531,7 @@ export class MockApiService extends ApiService {
         value: ids.reduce((acc, val) => {
           return {
             ...acc,
-            [val]: { complete: false },
+            [val]: { complete: false, bytes: 0, 'total-bytes': null, eta: null },
           }
         }, {}),
       },
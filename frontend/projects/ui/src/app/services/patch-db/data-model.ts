@@ -90,6 +90,9 @@ export interface ServerStatusInfo {
   'backup-progress': null | {
     [packageId: string]: {
       complete: boolean
+      bytes: number
+      'total-bytes': number | null
+      eta: string | null
     }
   }
   updated: boolean